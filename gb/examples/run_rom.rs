@@ -0,0 +1,18 @@
+// the README's example, kept here so it's built with everything else
+// cargo run --example run_rom -- path/to/game.gb
+use gb::{Emulator, Model};
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let path = std::env::args().nth(1).ok_or("usage: run_rom <cartridge>")?;
+
+    let mut emulator = Emulator::builder()
+        .cartridge(std::fs::read(path)?)
+        .model(Model::Dmg) // the boot ROM's model if there is one, the DMG otherwise
+        .build()?;
+    emulator.run_frames(60);
+    let shades = emulator.display(); // a shade from 0 to 3 for each of the 160 * 144 pixels
+    let state = emulator.save_state();
+
+    println!("{} pixels drawn, {} byte save state", shades.len(), state.len());
+    Ok(())
+}
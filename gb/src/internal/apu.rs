@@ -1,146 +1,1810 @@
-// NRxy: nr0-4 IS THE REGISTER ID AND THE INDEX [X] IS THE CHANNEL
-pub struct APU {
-    prev_div_apu_bit: u8,
-    div_apu_counter: u8,
+use std::collections::VecDeque;
+use wasm_bindgen::prelude::*;
+use crate::internal::snapshot::{StateWriter, StateReader, StateError};
 
-    nr1: [u8; 5],
-    nr2: [u8; 5],
-    nr3: [u8; 5],
-    nr4: [u8; 5],
-    nr5: [u8; 5],
+// bits of FF10-FF2F that can't be read back and always read as 1, FF26 is built separately since its lower bits
+// are the channel status
+const READ_MASKS: [u8; 0x20] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // unused, NR21-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // unused, NR41-NR44
+    0x00, 0x00, 0x70, // NR50-NR52
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF // unused
+];
 
-    ch1_dac: bool,
+pub const M_CYCLE_RATE: u32 = 1 << 20; // the APU is stepped once per M-cycle, about 1 MiHz
+const MAX_BUFFERED_FRAMES: usize = 16384; // about a third of a second at 48 kHz
+const FIR_ZERO_CROSSINGS: f64 = 8.0; // on each side of the windowed sinc
+const FIR_PHASES: usize = 32; // output frames land between inputs, the kernel is tabulated at this many offsets
+const DECIMATION: i32 = 4; // M-cycles that go into each input of the resampler at the usual rates, a quarter of the taps
+const WAVE_ACCESS_WINDOW: u16 = 2; // T-cycles around a wave RAM fetch the CPU can get in while channel 3 plays
+const HIGH_PASS_CHARGE: f64 = 0.999958; // of the capacitors the DMG's output goes through, per T-cycle
+const T_CYCLE_RATE: f64 = (M_CYCLE_RATE * 4) as f64;
 
-    /* NR11 */
-    ch1_wave_duty: u8, // controls output waveform
-    ch1_initial_length_timer: u8, // controls how long channel stays alive until being shut down automatically
-    
-    /* NR12 */
-    ch1_initial_volume: u8, // bits 7-4
-    ch1_envelope_direction: u8, // bit 3
-    ch1_sweep_pace: u8, // bits 2 - 0
+// all four channels at 15 through an 8x master volume still fits in an i16
+const MIX_SCALE: i16 = i16::MAX / (4 * 15 * 8);
 
-    /* NR13 */
-    ch1_period_lower: u8, // lower 8 bits
+// 12.5%, 25%, 50% and 75% high, one step per (2048 - period) * 4 T-cycles
+const DUTY_WAVEFORMS: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 1, 1, 1],
+    [0, 1, 1, 1, 1, 1, 1, 0]
+];
 
-    /* NR14 */
-    ch1_period_upper: u8, // upper 3 bits
-    ch1_length_enable: bool, // when enabled tick the length
-    ch1_length_timer_lock: bool, // obscure behavior when length timer overflows: lock incrementing after overflow and channel gets disabled until channel is retriggered or length counter is written.
+// NRx2 of the square and noise channels, the volume moves by 1 every `pace` envelope clocks
+#[derive(Clone, Copy, Default)]
+pub struct Envelope {
+    initial_volume: u8, // bits 7-4
+    increase: bool, // bit 3
+    pace: u8, // bits 2-0, 0 stops the envelope
+    volume: u8,
+    timer: u8
 }
 
-impl APU {
-    pub fn read_registers(&self, addr: u16) -> u8 {
-        match addr {
-            // 0xFF10 => {}, ??
-            0xFF11 => self.ch1_wave_duty << 6, // 00 - 12.5% | 01 - 25% | 10 - 50% | 11 - 75% (output waveform)
-            0xFF12 => self.nr1[2],
-            0xFF14 => if self.ch1_length_enable { 1 << 6 } else { 0 },
+impl Envelope {
+    fn write(&mut self, val: u8) {
+        self.initial_volume = val >> 4;
+        self.increase = (val >> 3) & 0x1 == 1;
+        self.pace = val & 0x07;
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.initial_volume;
+        self.timer = self.pace;
+    }
 
-            0xFF24 => self.nr5[0], // bits 6-4 left volume | bits 2-0 right volume
-            0xFF25 => self.nr5[1],
-            0xFF26 => self.nr5[2], // bit 3 - CH4 on? | bit 2 - CH3 on? | bit 1 - CH2 on? | bit 0 - CH1 on?
+    fn write_state(&self, w: &mut StateWriter) {
+        w.u8(self.initial_volume);
+        w.bool(self.increase);
+        w.u8(self.pace);
+        w.u8(self.volume);
+        w.u8(self.timer);
+    }
 
-            _ => 0x00
+    fn read_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.initial_volume = r.u8()?;
+        self.increase = r.bool()?;
+        self.pace = r.u8()?;
+        self.volume = r.u8()?;
+        self.timer = r.u8()?;
+        if self.initial_volume > 15 || self.volume > 15 || self.pace > 7 {
+            return Err(StateError::InvalidData("APU envelope out of range"));
         }
+        Ok(())
     }
 
-    pub fn write_registers(&mut self, addr: u16, val: u8) {
-        match addr {
-            0xFF10 => { // NR10
-                // TODO
+    fn clock(&mut self) {
+        if self.pace == 0 {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = self.pace;
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+// NR10, only channel 1 has one
+#[derive(Clone, Copy, Default)]
+pub struct Sweep {
+    pace: u8, // bits 6-4
+    decrease: bool, // bit 3
+    step: u8, // bits 2-0
+    enabled: bool,
+    shadow: u16, // period the sweep works from, copied on trigger
+    timer: u8
+}
+
+impl Sweep {
+    fn write(&mut self, val: u8) {
+        self.pace = (val >> 4) & 0x07;
+        self.decrease = (val >> 3) & 0x1 == 1;
+        self.step = val & 0x07;
+    }
+
+    fn reload_timer(&mut self) {
+        self.timer = if self.pace == 0 { 8 } else { self.pace };
+    }
+
+    fn write_state(&self, w: &mut StateWriter) {
+        w.u8(self.pace);
+        w.bool(self.decrease);
+        w.u8(self.step);
+        w.bool(self.enabled);
+        w.u16(self.shadow);
+        w.u8(self.timer);
+    }
+
+    fn read_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.pace = r.u8()?;
+        self.decrease = r.bool()?;
+        self.step = r.u8()?;
+        self.enabled = r.bool()?;
+        self.shadow = r.u16()?;
+        self.timer = r.u8()?;
+        if self.pace > 7 || self.step > 7 || self.shadow > 2047 {
+            return Err(StateError::InvalidData("APU sweep out of range"));
+        }
+        Ok(())
+    }
+
+    // None once the new period wouldn't fit in 11 bits, which switches the channel off
+    fn next_period(&self) -> Option<u16> {
+        let delta = self.shadow >> self.step;
+        let period = if self.decrease { self.shadow - delta } else { self.shadow + delta };
+        if period > 2047 { None } else { Some(period) }
+    }
+}
+
+// NRx1 and NRx4 bit 6 of every channel, counts down to 0 while enabled, which switches the channel off. While the
+// frame sequencer's next step doesn't clock lengths, enabling the counter or triggering with it enabled clocks it
+// once straight away, which is what blargg's dmg_sound 03 checks
+#[derive(Clone, Copy, Default)]
+pub struct LengthCounter {
+    remaining: u16,
+    enabled: bool
+}
+
+impl LengthCounter {
+    fn write_state(&self, w: &mut StateWriter) {
+        w.u16(self.remaining);
+        w.bool(self.enabled);
+    }
+
+    fn read_state(&mut self, r: &mut StateReader, max: u16) -> Result<(), StateError> {
+        self.remaining = r.u16()?;
+        self.enabled = r.bool()?;
+        if self.remaining > max {
+            return Err(StateError::InvalidData("APU length out of range"));
+        }
+        Ok(())
+    }
+
+    // true once it runs out
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.remaining > 0 {
+            self.remaining -= 1;
+            return self.remaining == 0;
+        }
+        false
+    }
+
+    // true if the extra clock ran it out
+    fn write_enable(&mut self, enabled: bool, extra_clock: bool) -> bool {
+        let was_enabled = std::mem::replace(&mut self.enabled, enabled);
+        if extra_clock && !was_enabled && enabled {
+            return self.clock();
+        }
+        false
+    }
+
+    // an expired length starts over at max, or one short of it if it gets the extra clock
+    fn trigger(&mut self, max: u16, extra_clock: bool) {
+        if self.remaining == 0 {
+            self.remaining = if self.enabled && extra_clock { max - 1 } else { max };
+        }
+    }
+}
+
+// channels 1 and 2, the same duty generator with an envelope and a length counter, channel 1 adds the sweep
+#[derive(Clone, Default)]
+pub struct SquareChannel {
+    pub enabled: bool, // status bit in NR52
+    dac: bool, // NRx2 bits 7-3 not all 0
+    duty: u8, // NRx1 bits 7-6
+    duty_step: u8,
+    length: LengthCounter,
+    period: u16, // NRx3 and NRx4 bits 2-0
+    timer: u16, // T-cycles until the next duty step
+    envelope: Envelope,
+    sweep: Option<Sweep>
+}
+
+impl SquareChannel {
+    pub fn with_sweep() -> Self {
+        Self { sweep: Some(Sweep::default()), ..Self::default() }
+    }
+
+    // reg is the x in NRxy
+    fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => if let Some(sweep) = self.sweep.as_mut() { sweep.write(val) },
+            1 => {
+                self.duty = val >> 6;
+                self.write_length(val);
             },
-            0xFF11 => { // NR11
-                self.ch1_initial_length_timer = val & 0x1F;
-                self.ch1_wave_duty = (val & 0xC0) >> 6;
-                self.ch1_length_timer_lock = false;
+            2 => {
+                self.envelope.write(val);
+                self.dac = val & 0xF8 != 0;
+                if !self.dac {
+                    self.enabled = false; // switches the channel off when the DAC is disabled
+                }
             },
-            0xFF12 => { // NR12
-                self.ch1_initial_volume = (val & 0xF0) >> 4; // how loud the channel initially is
-                self.ch1_envelope_direction = (val & 0x04) >> 2; // 0 - decrease volume over time | 1 - increase volume over time
-                self.ch1_sweep_pace = val & 0x03; // envelope ticks at 64 Hz and channels envelope will be increased/decreased (depending on the direction) every sweep pace ? of those ticks. A setting of 0 disables the envelope  
-
-                self.ch1_dac = (val & 0xF8) >> 3 != 0;
-                if !self.ch1_dac {
-                    self.nr5[2] &= !(1 << 0); // switches channel 1 off when DAC is disabled.
+            3 => self.period = (self.period & 0x700) | val as u16,
+            _ => unreachable!()
+        }
+    }
+
+    fn write_length(&mut self, val: u8) {
+        self.length.remaining = 64 - (val & 0x3F) as u16;
+    }
+
+    // NRx4
+    fn write_control(&mut self, val: u8, extra_length_clock: bool) {
+        self.period = (self.period & 0xFF) | (((val & 0x07) as u16) << 8);
+        let expired = self.length.write_enable((val >> 6) & 0x1 == 1, extra_length_clock);
+        if (val >> 7) & 0x1 == 1 {
+            self.trigger(extra_length_clock);
+        } else if expired {
+            self.enabled = false;
+        }
+    }
+
+    fn trigger(&mut self, extra_length_clock: bool) {
+        self.enabled = self.dac; // only turns on while the DAC is
+        self.length.trigger(64, extra_length_clock);
+        self.timer = (2048 - self.period) * 4;
+        self.envelope.trigger();
+
+        if let Some(sweep) = self.sweep.as_mut() {
+            sweep.shadow = self.period;
+            sweep.reload_timer();
+            sweep.enabled = sweep.pace != 0 || sweep.step != 0;
+            if sweep.step != 0 && sweep.next_period().is_none() {
+                self.enabled = false;
+            }
+        }
+    }
+
+    // one M-cycle, true when the duty step moved on
+    pub fn tick(&mut self) -> bool {
+        self.timer = self.timer.saturating_sub(4);
+        if self.timer != 0 {
+            return false;
+        }
+        self.timer = (2048 - self.period) * 4;
+        self.duty_step = (self.duty_step + 1) % 8;
+        true
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_sweep(&mut self) {
+        let Some(sweep) = self.sweep.as_mut() else { return };
+        sweep.timer = sweep.timer.saturating_sub(1);
+        if sweep.timer != 0 {
+            return;
+        }
+        sweep.reload_timer();
+        if !sweep.enabled || sweep.pace == 0 {
+            return;
+        }
+
+        match sweep.next_period() {
+            Some(period) if sweep.step != 0 => {
+                sweep.shadow = period;
+                self.period = period;
+                // the new period is checked for overflow once more but not written back
+                if sweep.next_period().is_none() {
+                    self.enabled = false;
                 }
+            },
+            Some(_) => (),
+            None => self.enabled = false
+        }
+    }
+
+    fn restore_status(&mut self, enabled: bool) {
+        self.enabled = enabled && self.dac;
+        self.envelope.trigger();
+    }
 
-                self.nr1[2] = val;
+    // the sweep is written even for channel 2 so both channels take the same space
+    fn write_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.bool(self.dac);
+        w.u8(self.duty);
+        w.u8(self.duty_step);
+        self.length.write_state(w);
+        w.u16(self.period);
+        w.u16(self.timer);
+        self.envelope.write_state(w);
+        w.bool(self.sweep.is_some());
+        self.sweep.unwrap_or_default().write_state(w);
+    }
+
+    fn read_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = r.bool()?;
+        self.dac = r.bool()?;
+        self.duty = r.u8()?;
+        self.duty_step = r.u8()?;
+        self.length.read_state(r, 64)?;
+        self.period = r.u16()?;
+        self.timer = r.u16()?;
+        self.envelope.read_state(r)?;
+        let has_sweep = r.bool()?;
+        let mut sweep = Sweep::default();
+        sweep.read_state(r)?;
+        if has_sweep != self.sweep.is_some() {
+            return Err(StateError::InvalidData("APU sweep on the wrong channel"));
+        }
+        self.sweep = has_sweep.then_some(sweep);
+        if self.duty > 3 || self.duty_step > 7 || self.period > 2047 {
+            return Err(StateError::InvalidData("APU square channel out of range"));
+        }
+        Ok(())
+    }
+
+    // digital output from -15 to 15
+    pub fn output(&self) -> i8 {
+        if !self.enabled {
+            return 0;
+        }
+        let volume = self.envelope.volume as i8;
+        if DUTY_WAVEFORMS[self.duty as usize][self.duty_step as usize] == 1 { volume } else { -volume }
+    }
+}
+
+// channel 3, plays the 32 4-bit samples in wave RAM high nibble first
+#[derive(Clone, Default)]
+pub struct WaveChannel {
+    pub enabled: bool, // status bit in NR52
+    dac: bool, // NR30 bit 7
+    length: LengthCounter, // up to 256
+    output_level: u8, // NR32 bits 6-5: mute, 100%, 50%, 25%
+    period: u16, // NR33 and NR34 bits 2-0
+    timer: u16, // T-cycles until the next sample is fetched
+    position: u8, // sample being played
+    sample: u8, // last one fetched, keeps playing until the next fetch even across a trigger
+    since_fetch: u16, // T-cycles, the CPU only gets at wave RAM right after a fetch while the channel plays
+    ram: [u8; 0x10]
+}
+
+impl WaveChannel {
+    fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            0 => {
+                self.dac = (val >> 7) & 0x1 == 1;
+                if !self.dac {
+                    self.enabled = false;
+                }
             },
-            0xFF13 => self.ch1_period_lower = val,
-            0xFF14 => { // NR14
-                // turn channel on ONLY when dac is set and MSB is set
-                if ((val >> 7) & 0x1 == 1) && self.ch1_dac {
-                    self.nr5[2] |= 1 << 0;
-                    self.ch1_length_timer_lock = false;
+            1 => self.write_length(val),
+            2 => self.output_level = (val >> 5) & 0x3,
+            3 => self.period = (self.period & 0x700) | val as u16,
+            _ => unreachable!()
+        }
+    }
+
+    fn write_length(&mut self, val: u8) {
+        self.length.remaining = 256 - val as u16;
+    }
+
+    // NR34
+    fn write_control(&mut self, val: u8, extra_length_clock: bool) {
+        self.period = (self.period & 0xFF) | (((val & 0x07) as u16) << 8);
+        let expired = self.length.write_enable((val >> 6) & 0x1 == 1, extra_length_clock);
+        if (val >> 7) & 0x1 == 1 {
+            self.trigger(extra_length_clock);
+        } else if expired {
+            self.enabled = false;
+        }
+    }
+
+    fn trigger(&mut self, extra_length_clock: bool) {
+        // retriggering just as the next byte is read overwrites the start of wave RAM on the DMG, with that byte
+        // if it's one of the first four or with the four aligned bytes it's in
+        if self.enabled && self.timer <= WAVE_ACCESS_WINDOW {
+            let byte = (((self.position + 1) % 32) / 2) as usize;
+            if byte < 4 {
+                self.ram[0] = self.ram[byte];
+            } else {
+                let aligned = byte & !0x3;
+                self.ram.copy_within(aligned..aligned + 4, 0);
+            }
+        }
+        self.enabled = self.dac;
+        self.length.trigger(256, extra_length_clock);
+        // the position goes back to the start but the first fetch comes 6 T-cycles late, and it fetches sample 1
+        self.position = 0;
+        self.timer = (2048 - self.period) * 2 + 6;
+    }
+
+    // while the channel plays, the CPU gets at the byte it's playing instead of the one addressed, and only right
+    // after it was fetched. otherwise reads are 0xFF and writes are lost
+    fn ram_index(&self, offset: u16) -> Option<usize> {
+        match self.enabled {
+            true if self.since_fetch < WAVE_ACCESS_WINDOW => Some((self.position / 2) as usize),
+            true => None,
+            false => Some(offset as usize)
+        }
+    }
+
+    fn read_ram(&self, offset: u16) -> u8 {
+        self.ram_index(offset).map_or(0xFF, |index| self.ram[index])
+    }
+
+    fn write_ram(&mut self, offset: u16, val: u8) {
+        if let Some(index) = self.ram_index(offset) {
+            self.ram[index] = val;
+        }
+    }
+
+    // one M-cycle, a sample lasts (2048 - period) * 2 T-cycles so several can go by in one. true when any did
+    pub fn tick(&mut self) -> bool {
+        let mut cycles = 4;
+        let mut fetched = false;
+        while cycles >= self.timer {
+            fetched = true;
+            cycles -= self.timer;
+            self.timer = (2048 - self.period) * 2;
+            self.position = (self.position + 1) % 32;
+            let byte = self.ram[(self.position / 2) as usize];
+            self.sample = if self.position % 2 == 0 { byte >> 4 } else { byte & 0x0F };
+        }
+        self.timer -= cycles;
+        self.since_fetch = if fetched { cycles } else { self.since_fetch.saturating_add(4) };
+        fetched
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    fn write_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.bool(self.dac);
+        self.length.write_state(w);
+        w.u8(self.output_level);
+        w.u16(self.period);
+        w.u16(self.timer);
+        w.u8(self.position);
+        w.u8(self.sample);
+        w.bytes(&self.ram);
+    }
+
+    fn read_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = r.bool()?;
+        self.dac = r.bool()?;
+        self.length.read_state(r, 256)?;
+        self.output_level = r.u8()?;
+        self.period = r.u16()?;
+        self.timer = r.u16()?;
+        self.position = r.u8()?;
+        self.sample = r.u8()?;
+        r.fill(&mut self.ram)?;
+        if self.output_level > 3 || self.period > 2047 || self.position > 31 || self.sample > 15 {
+            return Err(StateError::InvalidData("APU wave channel out of range"));
+        }
+        Ok(())
+    }
+
+    // digital output from -15 to 15, centered on the middle of the shifted range
+    pub fn output(&self) -> i8 {
+        if !self.enabled || self.output_level == 0 {
+            return 0;
+        }
+        let shift = self.output_level - 1;
+        ((self.sample >> shift) * 2) as i8 - (15 >> shift)
+    }
+}
+
+// NR43 divisor codes, 0 stands for 8
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+// channel 4, the output follows bit 0 of the LFSR inverted
+#[derive(Clone)]
+pub struct NoiseChannel {
+    pub enabled: bool, // status bit in NR52
+    dac: bool, // NR42 bits 7-3 not all 0
+    length: LengthCounter,
+    shift: u8, // NR43 bits 7-4
+    short: bool, // NR43 bit 3, also feeds the new bit into bit 6 for a 7-bit LFSR
+    divisor: u8, // NR43 bits 2-0
+    timer: u32, // T-cycles until the LFSR steps
+    lfsr: u16,
+    envelope: Envelope
+}
+
+impl NoiseChannel {
+    fn write(&mut self, reg: u16, val: u8) {
+        match reg {
+            1 => self.write_length(val),
+            2 => {
+                self.envelope.write(val);
+                self.dac = val & 0xF8 != 0;
+                if !self.dac {
+                    self.enabled = false;
                 }
-                self.ch1_length_enable = ((val >> 6) & 0x1) == 1;
-                self.ch1_period_upper = val & 0x07; // 3 upper bits for the whole periods value; lower 8 stored in NR13
             },
+            3 => {
+                self.shift = val >> 4;
+                self.short = (val >> 3) & 0x1 == 1;
+                self.divisor = val & 0x07;
+            },
+            _ => unreachable!()
+        }
+    }
 
-            0xFF24 => self.nr5[0] = val,
-            0xFF25 => self.nr5[1] = val,
-            0xFF26 => self.nr5[2] = val & 0x80, // controls whether audio is on or off
+    fn write_length(&mut self, val: u8) {
+        self.length.remaining = 64 - (val & 0x3F) as u16;
+    }
 
-            _ => ()
+    // NR44
+    fn write_control(&mut self, val: u8, extra_length_clock: bool) {
+        let expired = self.length.write_enable((val >> 6) & 0x1 == 1, extra_length_clock);
+        if (val >> 7) & 0x1 == 1 {
+            self.trigger(extra_length_clock);
+        } else if expired {
+            self.enabled = false;
+        }
+    }
+
+    fn period(&self) -> u32 {
+        NOISE_DIVISORS[self.divisor as usize] << self.shift
+    }
+
+    fn trigger(&mut self, extra_length_clock: bool) {
+        self.enabled = self.dac;
+        self.length.trigger(64, extra_length_clock);
+        self.timer = self.period();
+        self.lfsr = 0x7FFF;
+        self.envelope.trigger();
+    }
+
+    fn step_lfsr(&mut self) {
+        let bit = (self.lfsr ^ (self.lfsr >> 1)) & 0x1;
+        self.lfsr = (self.lfsr >> 1) | (bit << 14);
+        if self.short {
+            self.lfsr = (self.lfsr & !(1 << 6)) | (bit << 6);
         }
     }
 
-    pub fn update(&mut self, current_div_apu_bit: u8) {
-        if self.prev_div_apu_bit == 1 && current_div_apu_bit == 0 {
-            self.div_apu_counter = self.div_apu_counter.wrapping_add(1);
+    // one M-cycle, every period is a multiple of 8 T-cycles. true when the LFSR was due to shift
+    pub fn tick(&mut self) -> bool {
+        self.timer = self.timer.saturating_sub(4);
+        if self.timer != 0 {
+            return false;
+        }
+        self.timer = self.period();
+        // shifts of 14 and 15 never clock the LFSR, so the channel holds whatever it last output
+        if self.shift < 14 {
+            self.step_lfsr();
+        }
+        true
+    }
 
-            // Envelope sweep
-            if self.div_apu_counter % 8 == 0 {
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    fn restore_status(&mut self, enabled: bool) {
+        self.enabled = enabled && self.dac;
+        self.envelope.trigger();
+    }
+
+    fn write_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.bool(self.dac);
+        self.length.write_state(w);
+        w.u8(self.shift);
+        w.bool(self.short);
+        w.u8(self.divisor);
+        w.u32(self.timer);
+        w.u16(self.lfsr);
+        self.envelope.write_state(w);
+    }
+
+    fn read_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = r.bool()?;
+        self.dac = r.bool()?;
+        self.length.read_state(r, 64)?;
+        self.shift = r.u8()?;
+        self.short = r.bool()?;
+        self.divisor = r.u8()?;
+        self.timer = r.u32()?;
+        self.lfsr = r.u16()?;
+        self.envelope.read_state(r)?;
+        if self.shift > 15 || self.divisor > 7 || self.lfsr > 0x7FFF {
+            return Err(StateError::InvalidData("APU noise channel out of range"));
+        }
+        Ok(())
+    }
+
+    // digital output from -15 to 15
+    pub fn output(&self) -> i8 {
+        if !self.enabled {
+            return 0;
+        }
+        let volume = self.envelope.volume as i8;
+        if self.lfsr & 0x1 == 0 { volume } else { -volume }
+    }
+}
 
+impl Default for NoiseChannel {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dac: false,
+            length: LengthCounter::default(),
+            shift: 0,
+            short: false,
+            divisor: 0,
+            timer: NOISE_DIVISORS[0],
+            lfsr: 0x7FFF,
+            envelope: Envelope::default()
+        }
+    }
+}
+
+// Fast averages every M-cycle since the last frame, cheap but the square channels' harmonics above the output's
+// Nyquist frequency alias back into it. High low-passes through a windowed sinc first
+#[wasm_bindgen]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum AudioQuality {
+    Fast, High
+}
+
+// FIR low-pass at 90% of the output's Nyquist frequency over the mix taken in every `decimation` M-cycles, the taps
+// for each of FIR_PHASES offsets between two inputs are stored back to back, oldest sample first
+struct Resampler {
+    decimation: i32, // DECIMATION up to 64 kHz, above that the inputs would come too close to the cutoff
+    taps: usize,
+    kernel: Vec<f32>,
+    history: Vec<[f32; 2]>, // the last `taps` inputs twice over, so they can always be read as one slice
+    next: usize
+}
+
+impl Resampler {
+    fn new(sample_rate: u32) -> Self {
+        let decimation = if sample_rate * DECIMATION as u32 * 4 <= M_CYCLE_RATE { DECIMATION } else { 1 };
+        let cutoff = 0.45 * sample_rate as f64 * decimation as f64 / M_CYCLE_RATE as f64; // in cycles per input
+        let taps = (FIR_ZERO_CROSSINGS / cutoff).ceil() as usize | 1;
+        let center = (taps - 1) as f64 / 2.0;
+
+        let mut kernel = Vec::with_capacity(taps * FIR_PHASES);
+        for phase in 0..FIR_PHASES {
+            let offset = phase as f64 / FIR_PHASES as f64; // inputs between the frame and the newest one
+            let start = kernel.len();
+            for i in 0..taps {
+                let t = center - i as f64 - offset;
+                let sinc = if t == 0.0 { 1.0 } else { (2.0 * std::f64::consts::PI * cutoff * t).sin() / (std::f64::consts::PI * t) / (2.0 * cutoff) };
+                let x = (t / (center + 1.0)).clamp(-1.0, 1.0); // Blackman window
+                let window = 0.42 + 0.5 * (std::f64::consts::PI * x).cos() + 0.08 * (2.0 * std::f64::consts::PI * x).cos();
+                kernel.push((sinc * window) as f32);
             }
+            // unity gain for every phase so a constant level comes out unchanged
+            let sum: f32 = kernel[start..].iter().sum();
+            kernel[start..].iter_mut().for_each(|tap| *tap /= sum);
+        }
 
-            // Sound length
-            if self.div_apu_counter % 2 == 0 {
-                if self.ch1_length_enable && !self.ch1_length_timer_lock {
-                    self.ch1_initial_length_timer += 1;
+        Self { decimation, taps, kernel, history: vec![[0.0; 2]; taps * 2], next: 0 }
+    }
 
-                    if self.ch1_initial_length_timer == 64 {
-                        // console_log!("channel 1 length counter overflow!");
+    fn push(&mut self, mix: [f32; 2]) {
+        self.history[self.next] = mix;
+        self.history[self.next + self.taps] = mix;
+        self.next += 1;
+        if self.next == self.taps {
+            self.next = 0;
+        }
+    }
+
+    // the last `taps` inputs, oldest first
+    fn history(&self) -> &[[f32; 2]] {
+        &self.history[self.next..self.next + self.taps]
+    }
 
-                        self.nr5[2] &= !(1 << 0); // switches channel 1 off when length timer gets overflowed.
-                        self.ch1_initial_length_timer = 0;
-                        self.ch1_length_timer_lock = true;
-                    }
+    // starts over from silence if the history was kept for another sample rate
+    fn restore_history(&mut self, history: &[[f32; 2]]) {
+        self.next = 0;
+        if history.len() == self.taps {
+            self.history[..self.taps].copy_from_slice(history);
+            self.history[self.taps..].copy_from_slice(history);
+        } else {
+            self.history.fill([0.0; 2]);
+        }
+    }
+
+    // offset is how far back from the newest input the frame falls, from 0 to 1 inputs
+    fn frame(&self, offset: f64) -> [i16; 2] {
+        let phase = ((offset * FIR_PHASES as f64) as usize).min(FIR_PHASES - 1);
+        let kernel = &self.kernel[phase * self.taps..(phase + 1) * self.taps];
+        let history = &self.history[self.next..self.next + self.taps];
+        let mut out = [0.0f32; 2];
+        for (tap, mix) in kernel.iter().zip(history) {
+            out[0] += tap * mix[0];
+            out[1] += tap * mix[1];
+        }
+        out.map(|side| side.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16)
+    }
+}
+
+// the capacitors on the output, a one-pole high-pass that lets the DC offset of a channel starting or stopping
+// decay instead of clicking
+struct HighPass {
+    charge: f64, // left on the capacitor after one output frame
+    capacitors: [f64; 2]
+}
+
+impl HighPass {
+    fn new(sample_rate: u32) -> Self {
+        Self { charge: HIGH_PASS_CHARGE.powf(T_CYCLE_RATE / sample_rate as f64), capacitors: [0.0; 2] }
+    }
+
+    fn filter(&mut self, frame: [i16; 2]) -> [i16; 2] {
+        let mut out = [0; 2];
+        for (side, capacitor) in self.capacitors.iter_mut().enumerate() {
+            let input = frame[side] as f64;
+            let output = input - *capacitor;
+            *capacitor = input - output * self.charge;
+            out[side] = output.round().clamp(i16::MIN as f64, i16::MAX as f64) as i16;
+        }
+        out
+    }
+}
+
+// the mixed output brought down to the frontend's sample rate, the oldest frames are dropped once nothing reads
+// them for a while
+#[derive(Default)]
+struct AudioOutput {
+    sample_rate: u32, // 0 while no one takes samples, which skips the mixing
+    phase: u32, // counts up by sample_rate every M-cycle, a whole input at a time for High. a frame is due every M_CYCLE_RATE
+    sum: [i32; 2],
+    rising: [i32; 2], // the next input's share of the mixes so far, AudioQuality::High only
+    count: i32,
+    resampler: Option<Box<Resampler>>, // for AudioQuality::High, takes sum in once count reaches its decimation
+    high_pass: Option<HighPass>,
+    samples: VecDeque<i16>, // interleaved left and right
+    produced: u64, // frames ever produced, dropped or not, the clock for pacing emulation off audio
+    overruns: u32 // frames dropped because the buffer was full
+}
+
+impl AudioOutput {
+    fn new(sample_rate: u32, quality: AudioQuality, high_pass: bool, previous: &AudioOutput) -> Self {
+        let resampler = (quality == AudioQuality::High && sample_rate != 0).then(|| Box::new(Resampler::new(sample_rate)));
+        let high_pass = (high_pass && sample_rate != 0).then(|| HighPass::new(sample_rate));
+        Self { sample_rate, resampler, high_pass, produced: previous.produced, overruns: previous.overruns, ..Self::default() }
+    }
+
+    fn snapshot_into(&self, snapshot: &mut ApuSnapshot) {
+        snapshot.output_phase = self.phase;
+        snapshot.output_sum = self.sum;
+        snapshot.output_rising = self.rising;
+        snapshot.output_count = self.count;
+        snapshot.resampler_history.clear();
+        if let Some(resampler) = self.resampler.as_ref() {
+            snapshot.resampler_history.extend_from_slice(resampler.history());
+        }
+        snapshot.capacitors = self.high_pass.as_ref().map_or([0.0; 2], |high_pass| high_pass.capacitors);
+    }
+
+    fn restore(&mut self, snapshot: &ApuSnapshot) {
+        self.phase = snapshot.output_phase;
+        self.sum = snapshot.output_sum;
+        self.rising = snapshot.output_rising;
+        self.count = snapshot.output_count;
+        if let Some(resampler) = self.resampler.as_mut() {
+            resampler.restore_history(&snapshot.resampler_history);
+        }
+        if let Some(high_pass) = self.high_pass.as_mut() {
+            high_pass.capacitors = snapshot.capacitors;
+        }
+    }
+
+    fn push(&mut self, mix: [i16; 2]) {
+        match self.resampler.as_mut() {
+            // a plain average would let too much of what's above half the decimated rate alias back down, so each
+            // input is a triangle over 2 * decimation - 1 mixes: sum falls off over this block, rising builds up
+            Some(resampler) => {
+                let decimation = resampler.decimation;
+                let (falling, rising) = (decimation - 1 - self.count, self.count + 1);
+                for ((sum, next), side) in self.sum.iter_mut().zip(self.rising.iter_mut()).zip(mix) {
+                    *sum += side as i32 * falling;
+                    *next += side as i32 * rising;
+                }
+                self.count += 1;
+                if self.count < decimation {
+                    return;
                 }
+                resampler.push(self.sum.map(|side| side as f32 / (decimation * decimation) as f32));
+                self.sum = std::mem::take(&mut self.rising);
+                self.count = 0;
+                // frames are only taken right after an input, so they always fall between the newest two
+                self.phase += self.sample_rate * decimation as u32;
+            },
+            None => {
+                self.sum[0] += mix[0] as i32;
+                self.sum[1] += mix[1] as i32;
+                self.count += 1;
+                self.phase += self.sample_rate;
             }
+        }
+
+        if self.phase < M_CYCLE_RATE {
+            return;
+        }
+        self.phase -= M_CYCLE_RATE;
+        let frame = match self.resampler.as_ref() {
+            Some(resampler) => resampler.frame(self.phase as f64 / (self.sample_rate * resampler.decimation as u32) as f64),
+            None => {
+                let frame = [(self.sum[0] / self.count) as i16, (self.sum[1] / self.count) as i16];
+                self.sum = [0, 0];
+                self.count = 0;
+                frame
+            }
+        };
+        let frame = match self.high_pass.as_mut() {
+            Some(high_pass) => high_pass.filter(frame),
+            None => frame
+        };
+
+        self.produced += 1;
+        if self.samples.len() >= MAX_BUFFERED_FRAMES * 2 {
+            self.samples.drain(..2);
+            self.overruns += 1;
+        }
+        self.samples.push_back(frame[0]);
+        self.samples.push_back(frame[1]);
+    }
+}
 
-            // CH1 freq sweep
-            if self.div_apu_counter % 4 == 0 {
+// everything the APU emulates plus how far the output is into its next frame and what its filters hold, so a
+// restored state carries on sample for sample. the filters only come back with the same sample rate and quality
+#[derive(Clone)]
+pub struct ApuSnapshot {
+    frame_sequencer_step: u8,
+    registers: [u8; 0x20],
+    power: bool,
+    ch1: SquareChannel,
+    ch2: SquareChannel,
+    ch3: WaveChannel,
+    ch4: NoiseChannel,
+    output_phase: u32,
+    output_sum: [i32; 2],
+    output_rising: [i32; 2],
+    output_count: i32,
+    resampler_history: Vec<[f32; 2]>, // oldest first, empty for AudioQuality::Fast
+    capacitors: [f64; 2] // of the high-pass, 0 while it's off
+}
+
+impl ApuSnapshot {
+    pub fn write_state(&self, w: &mut StateWriter) {
+        w.u8(self.frame_sequencer_step);
+        w.bytes(&self.registers);
+        w.bool(self.power);
+        self.ch1.write_state(w);
+        self.ch2.write_state(w);
+        self.ch3.write_state(w);
+        self.ch4.write_state(w);
+        w.u32(self.output_phase);
+        w.u32(self.output_sum[0] as u32);
+        w.u32(self.output_sum[1] as u32);
+        w.u32(self.output_count as u32);
+        w.u32(self.resampler_history.len() as u32);
+        for side in self.resampler_history.iter().flatten() {
+            w.u32(side.to_bits());
+        }
+        for capacitor in self.capacitors {
+            w.u64(capacitor.to_bits());
+        }
+        w.u16(self.ch3.since_fetch);
+        w.u32(self.output_rising[0] as u32);
+        w.u32(self.output_rising[1] as u32);
+    }
+
+    pub fn read_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.frame_sequencer_step = r.u8()?;
+        r.fill(&mut self.registers)?;
+        self.power = r.bool()?;
+        self.ch1.read_state(r)?;
+        self.ch2.read_state(r)?;
+        self.ch3.read_state(r)?;
+        self.ch4.read_state(r)?;
+        self.output_phase = r.u32()?;
+        self.output_sum = [r.u32()? as i32, r.u32()? as i32];
+        self.output_count = r.u32()? as i32;
+        let history_len = r.u32()? as usize;
+        if self.frame_sequencer_step > 7 || self.output_phase >= M_CYCLE_RATE || self.output_count < 0 {
+            return Err(StateError::InvalidData("APU out of range"));
+        }
+        self.resampler_history.clear();
+        for _ in 0..history_len {
+            self.resampler_history.push([f32::from_bits(r.u32()?), f32::from_bits(r.u32()?)]);
+        }
+        self.capacitors = [f64::from_bits(r.u64()?), f64::from_bits(r.u64()?)];
+        self.ch3.since_fetch = r.u16()?;
+        self.output_rising = [r.u32()? as i32, r.u32()? as i32];
+        Ok(())
+    }
+}
+
+impl Default for ApuSnapshot {
+    fn default() -> Self {
+        APU::default().snapshot()
+    }
+}
+
+pub struct APU {
+    frame_sequencer_step: u8, // 0-7, advanced at 512 Hz whenever bit 4 of DIV falls
+
+    registers: [u8; 0x20], // FF10-FF2F as last written, reads OR in READ_MASKS
+    power: bool, // NR52 bit 7
+
+    pub ch1: SquareChannel,
+    pub ch2: SquareChannel,
+    pub ch3: WaveChannel,
+    pub ch4: NoiseChannel,
+
+    muted: u8, // bit per channel like NR51, only the mixer looks at it so games can't tell
+    mixed: [i16; 2], // only mixed again after a channel stepped or anything else that could change the mix
+    mix_stale: bool,
+    quality: AudioQuality,
+    high_pass: bool,
+    output: AudioOutput
+}
+
+impl APU {
+    pub fn read_registers(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF26 => {
+                let status = ((self.ch4.enabled as u8) << 3) | ((self.ch3.enabled as u8) << 2) | ((self.ch2.enabled as u8) << 1) | (self.ch1.enabled as u8);
+                READ_MASKS[0x16] | ((self.power as u8) << 7) | status
+            },
+            0xFF30..=0xFF3F => self.ch3.read_ram(addr - 0xFF30),
+            0xFF10..=0xFF2F => self.registers[(addr - 0xFF10) as usize] | READ_MASKS[(addr - 0xFF10) as usize],
+            _ => panic!("recieved invalid address")
+        }
+    }
 
+    pub fn write_registers(&mut self, addr: u16, val: u8) {
+        self.mix_stale = true;
+        if !self.power && addr <= 0xFF25 {
+            // switched off only the length counters can be written, the DMG lets them through
+            match addr {
+                0xFF11 => self.ch1.write_length(val),
+                0xFF16 => self.ch2.write_length(val),
+                0xFF1B => self.ch3.write_length(val),
+                0xFF20 => self.ch4.write_length(val),
+                _ => ()
             }
+            return;
+        }
+        if let 0xFF10..=0xFF2F = addr {
+            self.registers[(addr - 0xFF10) as usize] = val;
+        }
+
+        match addr {
+            0xFF26 => self.write_power((val >> 7) & 0x1 == 1),
+            0xFF30..=0xFF3F => self.ch3.write_ram(addr - 0xFF30, val),
+            _ => self.write_channel(addr, val, self.extra_length_clock())
+        }
+    }
+
+    fn write_channel(&mut self, addr: u16, val: u8, extra_length_clock: bool) {
+        match addr {
+            0xFF14 => self.ch1.write_control(val, extra_length_clock),
+            0xFF19 => self.ch2.write_control(val, extra_length_clock),
+            0xFF1E => self.ch3.write_control(val, extra_length_clock),
+            0xFF23 => self.ch4.write_control(val, extra_length_clock),
+            0xFF10..=0xFF13 => self.ch1.write(addr - 0xFF10, val),
+            0xFF16..=0xFF18 => self.ch2.write(addr - 0xFF15, val),
+            0xFF1A..=0xFF1D => self.ch3.write(addr - 0xFF1A, val),
+            0xFF20..=0xFF22 => self.ch4.write(addr - 0xFF1F, val),
+            _ => ()
+        }
+    }
+
+    // FF10-FF3F as BESS stores them: the last value written to every register, NR52 with the channel status
+    // bits, and wave RAM as it is rather than the byte channel 3 is playing
+    pub fn bess_registers(&self) -> [u8; 0x30] {
+        let mut registers = [0x0; 0x30];
+        registers[..0x20].copy_from_slice(&self.registers);
+        registers[0x16] = self.read_registers(0xFF26);
+        registers[0x20..].copy_from_slice(&self.ch3.ram);
+        registers
+    }
+
+    // the other way around, in address order. a BESS file has no timers or positions so the channels that were
+    // playing carry on from the start of their note without being triggered
+    pub fn restore_bess_register(&mut self, addr: u16, val: u8) {
+        self.mix_stale = true;
+        match addr {
+            0xFF26 => {
+                self.power = (val >> 7) & 0x1 == 1;
+                self.frame_sequencer_step = 0;
+                self.ch1.restore_status(val & 0x1 != 0);
+                self.ch2.restore_status((val >> 1) & 0x1 != 0);
+                self.ch3.enabled = (val >> 2) & 0x1 != 0 && self.ch3.dac;
+                self.ch4.restore_status((val >> 3) & 0x1 != 0);
+            },
+            0xFF30..=0xFF3F => self.ch3.ram[(addr - 0xFF30) as usize] = val,
+            0xFF10..=0xFF2F => {
+                self.registers[(addr - 0xFF10) as usize] = val;
+                let val = if matches!(addr, 0xFF14 | 0xFF19 | 0xFF1E | 0xFF23) { val & 0x7F } else { val };
+                self.write_channel(addr, val, false);
+            },
+            _ => ()
+        }
+    }
+
+    pub fn snapshot(&self) -> ApuSnapshot {
+        let mut snapshot = ApuSnapshot {
+            frame_sequencer_step: self.frame_sequencer_step,
+            registers: self.registers,
+            power: self.power,
+            ch1: self.ch1.clone(),
+            ch2: self.ch2.clone(),
+            ch3: self.ch3.clone(),
+            ch4: self.ch4.clone(),
+            output_phase: 0,
+            output_sum: [0, 0],
+            output_rising: [0, 0],
+            output_count: 0,
+            resampler_history: vec![],
+            capacitors: [0.0; 2]
+        };
+        self.output.snapshot_into(&mut snapshot);
+        snapshot
+    }
+
+    pub fn snapshot_into(&self, snapshot: &mut ApuSnapshot) {
+        snapshot.frame_sequencer_step = self.frame_sequencer_step;
+        snapshot.registers = self.registers;
+        snapshot.power = self.power;
+        snapshot.ch1.clone_from(&self.ch1);
+        snapshot.ch2.clone_from(&self.ch2);
+        snapshot.ch3.clone_from(&self.ch3);
+        snapshot.ch4.clone_from(&self.ch4);
+        self.output.snapshot_into(snapshot);
+    }
+
+    // the buffered samples and the frontend's settings stay as they are
+    pub fn restore(&mut self, snapshot: &ApuSnapshot) {
+        self.frame_sequencer_step = snapshot.frame_sequencer_step;
+        self.registers = snapshot.registers;
+        self.power = snapshot.power;
+        self.ch1.clone_from(&snapshot.ch1);
+        self.ch2.clone_from(&snapshot.ch2);
+        self.ch3.clone_from(&snapshot.ch3);
+        self.ch4.clone_from(&snapshot.ch4);
+        self.mix_stale = true;
+        self.output.restore(snapshot);
+    }
+
+    // the next step of the frame sequencer won't clock the length counters
+    fn extra_length_clock(&self) -> bool {
+        self.frame_sequencer_step % 2 == 1
+    }
+
+    // NR52 bit 7, switching off clears FF10-FF25 and every channel except for the length counters and wave RAM
+    fn write_power(&mut self, on: bool) {
+        if on && !self.power {
+            self.frame_sequencer_step = 0;
+        }
+        if !on && self.power {
+            self.registers[..0x16].fill(0x0);
+            let lengths = [self.ch1.length, self.ch2.length, self.ch3.length, self.ch4.length].map(|length| LengthCounter { remaining: length.remaining, enabled: false });
+            self.ch1 = SquareChannel { length: lengths[0], ..SquareChannel::with_sweep() };
+            self.ch2 = SquareChannel { length: lengths[1], ..SquareChannel::default() };
+            self.ch3 = WaveChannel { length: lengths[2], ram: self.ch3.ram, ..WaveChannel::default() };
+            self.ch4 = NoiseChannel { length: lengths[3], ..NoiseChannel::default() };
+        }
+        self.power = on;
+    }
+
+    // left and right sample, NR51 routes each channel to either side and NR50 scales each side by 1-8x,
+    // channels whose DAC is off output 0 so they don't add an offset
+    pub fn mix(&self) -> [i16; 2] {
+        let outputs = [self.ch1.output(), self.ch2.output(), self.ch3.output(), self.ch4.output()];
+        let (nr50, nr51) = (self.registers[0x14], self.registers[0x15] & !(self.muted | (self.muted << 4)));
+        let (mut left, mut right) = (0, 0);
+        for (i, &output) in outputs.iter().enumerate() {
+            left += output as i16 * ((nr51 >> (i + 4)) & 0x1) as i16;
+            right += output as i16 * ((nr51 >> i) & 0x1) as i16;
+        }
+        [left * (((nr50 >> 4) & 0x7) as i16 + 1) * MIX_SCALE, right * ((nr50 & 0x7) as i16 + 1) * MIX_SCALE]
+    }
+
+    // channel is 0-3
+    pub fn set_channel_enabled(&mut self, channel: u8, on: bool) {
+        self.muted = (self.muted & !(1 << channel)) | ((!on as u8) << channel);
+        self.mix_stale = true;
+    }
+
+    // amplitude of each channel right now, 0-15, whether it's muted or not
+    pub fn channel_levels(&self) -> [u8; 4] {
+        [self.ch1.output(), self.ch2.output(), self.ch3.output(), self.ch4.output()].map(|output| output.unsigned_abs())
+    }
+
+    // one M-cycle like the PPU and timer, div_apu_edge is Timer::take_div_apu_edge from the same M-cycle
+    pub fn update(&mut self, div_apu_edge: bool) {
+        self.tick();
+        if div_apu_edge {
+            self.clock_frame_sequencer();
+        }
+    }
+
+    // the channels and the output without the frame sequencer
+    pub fn tick(&mut self) {
+        // | so every channel ticks
+        let stepped = self.ch1.tick() | self.ch2.tick() | self.ch3.tick() | self.ch4.tick();
+        if self.output.sample_rate == 0 {
+            return;
+        }
+        if stepped || self.mix_stale {
+            self.mixed = self.mix();
+            self.mix_stale = false;
+        }
+        self.output.push(self.mixed);
+    }
+
+    // 0 stops producing samples, anything above the native rate is capped to it
+    pub fn set_sample_rate(&mut self, hz: u32) {
+        self.output = AudioOutput::new(hz.min(M_CYCLE_RATE), self.quality, self.high_pass, &self.output);
+    }
+
+    pub fn set_audio_quality(&mut self, quality: AudioQuality) {
+        self.quality = quality;
+        self.set_sample_rate(self.output.sample_rate);
+    }
+
+    pub fn set_high_pass(&mut self, enabled: bool) {
+        self.high_pass = enabled;
+        self.set_sample_rate(self.output.sample_rate);
+    }
+
+    // appends every buffered frame as interleaved left and right samples
+    pub fn drain_audio(&mut self, out: &mut Vec<i16>) {
+        out.extend(self.output.samples.drain(..));
+    }
+
+    pub fn audio_overruns(&self) -> u32 {
+        self.output.overruns
+    }
+
+    pub fn audio_frames_produced(&self) -> u64 {
+        self.output.produced
+    }
+
+    // length on even steps, sweep on 2 and 6, envelope on 7
+    pub fn clock_frame_sequencer(&mut self) {
+        let step = self.frame_sequencer_step;
+        self.frame_sequencer_step = (step + 1) % 8;
+        self.mix_stale = true;
+
+        if step % 2 == 0 {
+            self.ch1.clock_length();
+            self.ch2.clock_length();
+            self.ch3.clock_length();
+            self.ch4.clock_length();
+        }
+        if step == 2 || step == 6 {
+            self.ch1.clock_sweep();
+        }
+        if step == 7 {
+            self.ch1.clock_envelope();
+            self.ch2.clock_envelope();
+            self.ch4.clock_envelope();
         }
-        self.prev_div_apu_bit = current_div_apu_bit;
     }
 }
 
 impl Default for APU {
     fn default() -> Self {
         Self {
-            prev_div_apu_bit: 0,
-            div_apu_counter: 0,
-
-            nr1: [0x0; 5],
-            nr2: [0x0; 5],
-            nr3: [0x0; 5],
-            nr4: [0x0; 5],
-            nr5: [0x0; 5],
-
-            ch1_dac: false,
-            ch1_wave_duty: 0x00,
-            ch1_initial_length_timer: 0x00,
-            ch1_initial_volume: 0x0,
-            ch1_envelope_direction: 0x0,
-            ch1_sweep_pace: 0x0,
-            ch1_period_lower: 0x00,
-            ch1_period_upper: 0x00,
-            ch1_length_enable: false,
-            ch1_length_timer_lock: false
-        }
-    }
-}
\ No newline at end of file
+            frame_sequencer_step: 0,
+
+            registers: [0x0; 0x20],
+            power: false,
+
+            ch1: SquareChannel::with_sweep(),
+            ch2: SquareChannel::default(),
+            ch3: WaveChannel::default(),
+            ch4: NoiseChannel::default(),
+
+            muted: 0x0,
+            mixed: [0, 0],
+            mix_stale: true,
+            quality: AudioQuality::High,
+            high_pass: true,
+            output: AudioOutput::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // what the DMG reads back from FF10-FF2F after 0x00 was written, everything reads 0xFF after 0xFF. NR52 is
+    // what it reads while switched off
+    const DMG_READ_BACK: [u8; 0x20] = [
+        0x80, 0x3F, 0x00, 0xFF, 0xBF, 0xFF, 0x3F, 0x00, 0xFF, 0xBF, 0x7F, 0xFF, 0x9F, 0xFF, 0xBF, 0xFF,
+        0xFF, 0x00, 0x00, 0xBF, 0x00, 0x00, 0x70, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF
+    ];
+
+    #[test]
+    fn registers_read_back_like_the_dmg() {
+        let mut apu = APU::default();
+        apu.write_registers(0xFF26, 0x80);
+        for addr in (0xFF10..=0xFF2F).filter(|&addr| addr != 0xFF26) {
+            apu.write_registers(addr, 0x00);
+            assert_eq!(apu.read_registers(addr), DMG_READ_BACK[(addr - 0xFF10) as usize], "0x{:04X} after 0x00", addr);
+            apu.write_registers(addr, 0xFF);
+            assert_eq!(apu.read_registers(addr), 0xFF, "0x{:04X} after 0xFF", addr);
+        }
+        // every DAC is on and every channel was triggered on the way
+        assert_eq!(apu.read_registers(0xFF26), 0xFF);
+        apu.write_registers(0xFF26, 0x00);
+        assert_eq!(apu.read_registers(0xFF26), DMG_READ_BACK[0x16]);
+    }
+
+    // NR21-NR24 for a full volume channel 2 stepping its duty every M-cycle
+    fn triggered_ch2(duty: u8) -> APU {
+        let mut apu = APU::default();
+        apu.write_registers(0xFF26, 0x80);
+        apu.write_registers(0xFF16, duty << 6);
+        apu.write_registers(0xFF17, 0xF0);
+        apu.write_registers(0xFF18, 0xFF);
+        apu.write_registers(0xFF19, 0x87);
+        apu
+    }
+
+    #[test]
+    fn square_channel_2_steps_through_each_duty() {
+        for (duty, waveform) in DUTY_WAVEFORMS.iter().enumerate() {
+            let mut apu = triggered_ch2(duty as u8);
+            let mut samples = vec![];
+            for _ in 0..16 {
+                samples.push(apu.ch2.output());
+                apu.tick();
+            }
+            let expected: Vec<i8> = waveform.iter().chain(waveform).map(|&high| if high == 1 { 15 } else { -15 }).collect();
+            assert_eq!(samples, expected, "duty {}", duty);
+        }
+    }
+
+    #[test]
+    fn square_channel_2_trigger_and_length() {
+        let mut apu = triggered_ch2(2);
+        assert_eq!(apu.read_registers(0xFF26), 0xF2);
+
+        // a length of 1 runs out on the next length clock, but only while NR24 enables it
+        apu.write_registers(0xFF16, 0x3F);
+        apu.ch2.clock_length();
+        assert!(apu.ch2.enabled);
+        apu.write_registers(0xFF19, 0x47);
+        apu.ch2.clock_length();
+        assert_eq!(apu.read_registers(0xFF26), 0xF0);
+        assert_eq!(apu.ch2.output(), 0);
+
+        // retriggering reloads an expired length, with the DAC off it doesn't start at all
+        apu.write_registers(0xFF19, 0xC7);
+        assert!(apu.ch2.enabled);
+        apu.write_registers(0xFF17, 0x00);
+        assert!(!apu.ch2.enabled);
+        apu.write_registers(0xFF19, 0x87);
+        assert!(!apu.ch2.enabled);
+    }
+
+    #[test]
+    fn square_channel_2_envelope_fades_out() {
+        let mut apu = triggered_ch2(3);
+        apu.write_registers(0xFF17, 0x21); // volume 2, decreasing every clock
+        apu.write_registers(0xFF19, 0x87);
+        apu.tick();
+        assert_eq!(apu.ch2.output(), 2);
+        apu.ch2.clock_envelope();
+        assert_eq!(apu.ch2.output(), 1);
+        apu.ch2.clock_envelope();
+        apu.ch2.clock_envelope();
+        assert_eq!(apu.ch2.output(), 0);
+        assert!(apu.ch2.enabled);
+    }
+
+    // wave RAM counting up a sample at a time, played at 100% with a sample every M-cycle
+    fn triggered_ch3() -> APU {
+        let mut apu = APU::default();
+        apu.write_registers(0xFF26, 0x80);
+        for i in 0..16 {
+            apu.write_registers(0xFF30 + i, (((i * 2) as u8 & 0xF) << 4) | ((i * 2 + 1) as u8 & 0xF));
+        }
+        apu.write_registers(0xFF1A, 0x80);
+        apu.write_registers(0xFF1C, 0x20);
+        apu.write_registers(0xFF1D, 0xFE);
+        apu.write_registers(0xFF1E, 0x87);
+        apu
+    }
+
+    #[test]
+    fn wave_channel_delays_its_first_fetch_after_a_trigger() {
+        let mut apu = triggered_ch3();
+        assert_eq!(apu.read_registers(0xFF26), 0xF4);
+
+        let mut samples = vec![];
+        for _ in 0..6 {
+            apu.tick();
+            samples.push(apu.ch3.output());
+        }
+        // nothing fetched for 2 M-cycles, then samples 1, 2, 3...
+        assert_eq!(samples, [-15, -15, -13, -11, -9, -7]);
+
+        // sample 4 at 50% and 25%
+        apu.write_registers(0xFF1C, 0x40);
+        assert_eq!(apu.ch3.output(), -3);
+        apu.write_registers(0xFF1C, 0x60);
+        assert_eq!(apu.ch3.output(), -1);
+        apu.write_registers(0xFF1C, 0x00);
+        assert_eq!(apu.ch3.output(), 0);
+    }
+
+    #[test]
+    fn wave_ram_reads_the_playing_byte_while_channel_3_runs() {
+        // two samples every M-cycle, the second fetched right at its end
+        let mut apu = triggered_ch3();
+        apu.write_registers(0xFF1D, 0xFF);
+        apu.write_registers(0xFF1E, 0x87);
+        for _ in 0..5 {
+            apu.tick();
+        }
+        // playing sample 7, the fourth byte
+        assert_eq!(apu.read_registers(0xFF30), 0x67);
+        assert_eq!(apu.read_registers(0xFF3F), 0x67);
+
+        apu.write_registers(0xFF1A, 0x00);
+        assert_eq!(apu.read_registers(0xFF26), 0xF0);
+        assert_eq!(apu.read_registers(0xFF3F), 0xEF);
+    }
+
+    #[test]
+    fn wave_ram_is_only_reachable_right_after_a_fetch() {
+        // 30 T-cycles a sample, the first fetch lands on the end of the 9th M-cycle, the second halfway through one
+        let mut apu = triggered_ch3();
+        apu.write_registers(0xFF1D, 0xF1);
+        apu.write_registers(0xFF1E, 0x87);
+        for _ in 0..8 {
+            apu.tick();
+            assert_eq!(apu.read_registers(0xFF35), 0xFF);
+        }
+        apu.tick();
+        assert_eq!(apu.read_registers(0xFF35), 0x01);
+        apu.write_registers(0xFF35, 0x77);
+        apu.tick();
+        assert_eq!(apu.read_registers(0xFF35), 0xFF);
+        apu.write_registers(0xFF35, 0x66);
+        for _ in 0..7 {
+            apu.tick();
+        }
+        assert_eq!(apu.read_registers(0xFF30), 0xFF);
+
+        apu.write_registers(0xFF1A, 0x00);
+        assert_eq!(apu.read_registers(0xFF30), 0x77);
+        assert_eq!(apu.read_registers(0xFF35), 0xAB);
+    }
+
+    #[test]
+    fn retriggering_while_a_byte_is_read_corrupts_wave_ram() {
+        // with a sample every M-cycle every tick ends right before the next fetch
+        let retriggered = |ticks: usize| {
+            let mut apu = triggered_ch3();
+            for _ in 0..ticks {
+                apu.tick();
+            }
+            apu.write_registers(0xFF1E, 0x87);
+            apu.write_registers(0xFF1A, 0x00);
+            apu.ch3.ram
+        };
+        let ram = triggered_ch3().ch3.ram;
+
+        // about to read byte 5, so bytes 4-7 end up at the start
+        let corrupted = retriggered(11);
+        assert_eq!(corrupted[..4], ram[4..8]);
+        assert_eq!(corrupted[4..], ram[4..]);
+
+        // about to read byte 2, which only replaces the first byte
+        let corrupted = retriggered(5);
+        assert_eq!(corrupted[0], ram[2]);
+        assert_eq!(corrupted[1..], ram[1..]);
+    }
+
+    #[test]
+    fn wave_channel_length_counts_from_256() {
+        let mut apu = triggered_ch3();
+        apu.write_registers(0xFF1E, 0xC7);
+        for _ in 0..255 {
+            apu.ch3.clock_length();
+        }
+        assert!(apu.ch3.enabled);
+        apu.ch3.clock_length();
+        assert!(!apu.ch3.enabled);
+    }
+
+    // the LFSR as 15 separate bits, bit 0 first
+    fn reference_lfsr(short: bool, steps: usize) -> Vec<bool> {
+        let mut bits = [true; 15];
+        let mut outputs = vec![];
+        for _ in 0..steps {
+            let feedback = bits[0] ^ bits[1];
+            bits.rotate_left(1);
+            bits[14] = feedback;
+            if short {
+                bits[6] = feedback;
+            }
+            outputs.push(!bits[0]);
+        }
+        outputs
+    }
+
+    #[test]
+    fn noise_channel_lfsr_matches_reference() {
+        for short in [false, true] {
+            let mut apu = APU::default();
+            apu.write_registers(0xFF26, 0x80);
+            apu.write_registers(0xFF21, 0xF0);
+            apu.write_registers(0xFF22, if short { 0x08 } else { 0x00 });
+            apu.write_registers(0xFF23, 0x80);
+
+            let mut outputs = vec![];
+            for _ in 0..400 {
+                // the fastest LFSR steps every 8 T-cycles
+                apu.tick();
+                apu.tick();
+                outputs.push(apu.ch4.output() > 0);
+            }
+            assert_eq!(outputs, reference_lfsr(short, 400), "short: {}", short);
+        }
+    }
+
+    #[test]
+    fn noise_channel_trigger_and_frozen_shifts() {
+        let mut apu = APU::default();
+        apu.write_registers(0xFF26, 0x80);
+        apu.write_registers(0xFF21, 0xF0);
+        apu.write_registers(0xFF23, 0x80);
+        assert_eq!(apu.read_registers(0xFF26), 0xF8);
+        for _ in 0..10 {
+            apu.tick();
+        }
+        assert_ne!(apu.ch4.lfsr, 0x7FFF);
+
+        // retriggering resets the LFSR, with a shift of 14 it never moves again
+        apu.write_registers(0xFF22, 0xE0);
+        apu.write_registers(0xFF23, 0x80);
+        assert_eq!(apu.ch4.lfsr, 0x7FFF);
+        for _ in 0..(8 << 14) {
+            apu.tick();
+        }
+        assert_eq!(apu.ch4.lfsr, 0x7FFF);
+        assert_eq!(apu.ch4.output(), -15);
+    }
+
+    // channel 2 with its DAC on and a length of `length`, but not enabled yet, the frame sequencer is left on a
+    // step that clocks lengths next when `first_half` isn't set
+    fn ch2_between_length_clocks(length: u8, first_half: bool) -> APU {
+        let mut apu = APU::default();
+        apu.write_registers(0xFF26, 0x80);
+        apu.write_registers(0xFF17, 0xF0);
+        apu.write_registers(0xFF19, 0x80);
+        apu.write_registers(0xFF16, 64 - length);
+        if first_half {
+            apu.clock_frame_sequencer();
+        }
+        apu
+    }
+
+    #[test]
+    fn enabling_length_clocks_it_when_the_next_step_wont() {
+        let mut apu = ch2_between_length_clocks(2, true);
+        apu.write_registers(0xFF19, 0x40);
+        assert_eq!(apu.ch2.length.remaining, 1);
+        assert!(apu.ch2.enabled);
+
+        let mut apu = ch2_between_length_clocks(2, false);
+        apu.write_registers(0xFF19, 0x40);
+        assert_eq!(apu.ch2.length.remaining, 2);
+    }
+
+    #[test]
+    fn only_enabling_length_clocks_it() {
+        // already enabled or being disabled, the write leaves the counter alone
+        let mut apu = ch2_between_length_clocks(2, true);
+        apu.ch2.length.enabled = true;
+        apu.write_registers(0xFF19, 0x40);
+        assert_eq!(apu.ch2.length.remaining, 2);
+        apu.write_registers(0xFF19, 0x00);
+        assert_eq!(apu.ch2.length.remaining, 2);
+    }
+
+    #[test]
+    fn extra_length_clock_to_zero_switches_the_channel_off() {
+        let mut apu = ch2_between_length_clocks(1, true);
+        apu.write_registers(0xFF19, 0x40);
+        assert!(!apu.ch2.enabled);
+
+        // unless the same write triggers, which then reloads the expired length
+        let mut apu = ch2_between_length_clocks(1, true);
+        apu.write_registers(0xFF19, 0xC0);
+        assert!(apu.ch2.enabled);
+        assert_eq!(apu.ch2.length.remaining, 63);
+    }
+
+    #[test]
+    fn trigger_reloads_one_short_with_the_extra_clock() {
+        let mut apu = ch2_between_length_clocks(1, false);
+        apu.ch2.length.remaining = 0;
+        apu.clock_frame_sequencer();
+        apu.write_registers(0xFF19, 0xC0);
+        assert_eq!(apu.ch2.length.remaining, 63);
+
+        // with length disabled or the next step clocking it, it's the full 64
+        let mut apu = ch2_between_length_clocks(1, true);
+        apu.ch2.length.remaining = 0;
+        apu.write_registers(0xFF19, 0x80);
+        assert_eq!(apu.ch2.length.remaining, 64);
+        let mut apu = ch2_between_length_clocks(1, false);
+        apu.ch2.length.remaining = 0;
+        apu.write_registers(0xFF19, 0xC0);
+        assert_eq!(apu.ch2.length.remaining, 64);
+
+        // a length that hasn't run out is kept as is
+        let mut apu = ch2_between_length_clocks(5, true);
+        apu.ch2.length.enabled = true;
+        apu.write_registers(0xFF19, 0xC0);
+        assert_eq!(apu.ch2.length.remaining, 5);
+    }
+
+    #[test]
+    fn wave_channel_trigger_reloads_255_with_the_extra_clock() {
+        let mut apu = triggered_ch3();
+        apu.ch3.length = LengthCounter { remaining: 0, enabled: true };
+        apu.clock_frame_sequencer();
+        apu.write_registers(0xFF1E, 0xC7);
+        assert_eq!(apu.ch3.length.remaining, 255);
+    }
+
+    #[test]
+    fn powering_off_clears_and_locks_the_registers() {
+        let mut apu = triggered_ch2(2);
+        apu.write_registers(0xFF24, 0x77);
+        apu.write_registers(0xFF30, 0x5A);
+        apu.clock_frame_sequencer();
+
+        apu.write_registers(0xFF26, 0x00);
+        assert_eq!(apu.read_registers(0xFF26), 0x70);
+        assert_eq!(apu.read_registers(0xFF16), 0x3F);
+        assert_eq!(apu.read_registers(0xFF17), 0x00);
+        assert_eq!(apu.read_registers(0xFF24), 0x00);
+
+        // writes are dropped while off, apart from the length bits and wave RAM
+        apu.write_registers(0xFF17, 0xF0);
+        apu.write_registers(0xFF16, 0xBF);
+        apu.write_registers(0xFF31, 0xA5);
+        assert_eq!(apu.read_registers(0xFF17), 0x00);
+        assert_eq!(apu.read_registers(0xFF16), 0x3F);
+        assert_eq!((apu.read_registers(0xFF30), apu.read_registers(0xFF31)), (0x5A, 0xA5));
+
+        // back on, the length written while off is still there and the frame sequencer starts over at step 0
+        apu.write_registers(0xFF26, 0x80);
+        assert_eq!(apu.frame_sequencer_step, 0);
+        apu.write_registers(0xFF17, 0xF0);
+        apu.write_registers(0xFF19, 0xC7);
+        apu.clock_frame_sequencer();
+        assert_eq!(apu.read_registers(0xFF26), 0xF0);
+    }
+
+    #[test]
+    fn mixer_pans_and_scales_each_side() {
+        // channel 2 starts high at 15, channel 4's LFSR starts with bit 0 set so it sits at -8
+        let mut apu = triggered_ch2(2);
+        apu.write_registers(0xFF21, 0x80);
+        apu.write_registers(0xFF23, 0x80);
+        assert_eq!((apu.ch2.output(), apu.ch4.output()), (15, -8));
+
+        // hard panned: channel 4 only on the left at 8x, channel 2 only on the right at 1x
+        apu.write_registers(0xFF24, 0x70);
+        apu.write_registers(0xFF25, 0x82);
+        assert_eq!(apu.mix(), [-8 * 8 * MIX_SCALE, 15 * MIX_SCALE]);
+
+        apu.write_registers(0xFF24, 0x23);
+        apu.write_registers(0xFF25, 0xA2);
+        assert_eq!(apu.mix(), [(15 - 8) * 3 * MIX_SCALE, 15 * 4 * MIX_SCALE]);
+
+        // with its DAC off channel 2 drops out instead of holding its last level
+        apu.write_registers(0xFF17, 0x00);
+        assert_eq!(apu.mix(), [-8 * 3 * MIX_SCALE, 0]);
+
+        apu.write_registers(0xFF26, 0x00);
+        assert_eq!(apu.mix(), [0, 0]);
+    }
+
+    #[test]
+    fn muted_channels_only_leave_the_mix() {
+        let mut apu = triggered_ch2(2);
+        apu.write_registers(0xFF21, 0x80);
+        apu.write_registers(0xFF23, 0x80);
+        apu.write_registers(0xFF24, 0x00);
+        apu.write_registers(0xFF25, 0xAA);
+
+        apu.set_channel_enabled(1, false);
+        assert_eq!(apu.mix(), [-8 * MIX_SCALE, -8 * MIX_SCALE]);
+        assert_eq!(apu.read_registers(0xFF26), 0xFA);
+        assert_eq!(apu.read_registers(0xFF25), 0xAA);
+        assert_eq!(apu.channel_levels(), [0, 15, 0, 8]);
+
+        apu.set_channel_enabled(1, true);
+        apu.set_channel_enabled(3, false);
+        assert_eq!(apu.mix(), [15 * MIX_SCALE, 15 * MIX_SCALE]);
+    }
+
+    #[test]
+    fn fast_audio_averages_down_to_the_sample_rate() {
+        // channel 2 on both sides at 1x, stepping its 12.5% duty every M-cycle, so every 8 M-cycles average out to
+        // one high and seven low steps
+        let mut apu = triggered_ch2(0);
+        apu.write_registers(0xFF25, 0x22);
+        apu.set_audio_quality(AudioQuality::Fast);
+        apu.set_high_pass(false);
+        apu.set_sample_rate(M_CYCLE_RATE / 8);
+        for _ in 0..800 {
+            apu.tick();
+        }
+        let mut samples = vec![];
+        apu.drain_audio(&mut samples);
+        assert_eq!(samples.len(), 200);
+        assert!(samples.iter().all(|&sample| sample == (15 - 7 * 15) * MIX_SCALE / 8));
+
+        apu.drain_audio(&mut samples);
+        assert_eq!(samples.len(), 200);
+    }
+
+    #[test]
+    fn high_pass_lets_a_dc_offset_decay() {
+        // wave RAM all at 15 played at 100% is a constant 15 on both sides
+        let captured = |high_pass: bool| {
+            let mut apu = APU::default();
+            apu.write_registers(0xFF26, 0x80);
+            for i in 0..16 {
+                apu.write_registers(0xFF30 + i, 0xFF);
+            }
+            apu.write_registers(0xFF24, 0x00);
+            apu.write_registers(0xFF25, 0x44);
+            apu.set_audio_quality(AudioQuality::Fast);
+            apu.set_high_pass(high_pass);
+            apu.set_sample_rate(48000);
+            apu.write_registers(0xFF1A, 0x80);
+            apu.write_registers(0xFF1C, 0x20);
+            apu.write_registers(0xFF1E, 0x87);
+            for _ in 0..M_CYCLE_RATE / 4 {
+                apu.tick();
+            }
+            let mut samples = vec![];
+            apu.drain_audio(&mut samples);
+            samples
+        };
+
+        let raw = captured(false);
+        assert!(raw[raw.len() - 2..].iter().all(|&sample| sample == 15 * MIX_SCALE));
+
+        // the start still jumps, but it has died down a quarter of a second later instead of holding
+        let filtered = captured(true);
+        assert!(filtered.iter().step_by(2).any(|&sample| sample > 14 * MIX_SCALE));
+        assert!(filtered[filtered.len() - 2..].iter().all(|&sample| sample.abs() < MIX_SCALE / 8));
+    }
+
+    #[test]
+    fn audio_output_drops_the_oldest_frames_when_full() {
+        let mut apu = APU::default();
+        apu.set_sample_rate(48000);
+        for _ in 0..M_CYCLE_RATE {
+            apu.tick();
+        }
+        assert_eq!(apu.audio_overruns(), 48000 - MAX_BUFFERED_FRAMES as u32);
+
+        let mut samples = vec![];
+        apu.drain_audio(&mut samples);
+        assert_eq!(samples.len(), MAX_BUFFERED_FRAMES * 2);
+    }
+
+    // one second of channel 2 playing a 50% square at 131072 / 298 Hz, about 440, on both sides
+    fn playing_440_hz() -> APU {
+        let mut apu = APU::default();
+        apu.write_registers(0xFF26, 0x80);
+        apu.write_registers(0xFF25, 0x22);
+        apu.write_registers(0xFF16, 0x80);
+        apu.write_registers(0xFF17, 0xF0);
+        apu.write_registers(0xFF18, (1750 & 0xFF) as u8);
+        apu.write_registers(0xFF19, 0x80 | (1750 >> 8) as u8);
+        apu
+    }
+
+    fn capture_440_hz(quality: AudioQuality) -> Vec<f64> {
+        let mut apu = playing_440_hz();
+        apu.set_audio_quality(quality);
+        apu.set_sample_rate(48000);
+        for _ in 0..M_CYCLE_RATE / 4 {
+            apu.tick();
+        }
+        let mut samples = vec![];
+        apu.drain_audio(&mut samples);
+        samples.iter().step_by(2).map(|&left| left as f64).collect()
+    }
+
+    // share of the energy (Hann windowed DFT) that doesn't sit on an odd harmonic of the square
+    fn energy_between_harmonics(samples: &[f64]) -> f64 {
+        let (rate, fundamental) = (48000.0, 131072.0 / 298.0);
+        let n = samples.len();
+        let (mut harmonic, mut other) = (0.0, 0.0);
+        for bin in 1..n / 2 {
+            let (mut re, mut im) = (0.0, 0.0);
+            for (i, sample) in samples.iter().enumerate() {
+                let window = 0.5 - 0.5 * (2.0 * std::f64::consts::PI * i as f64 / n as f64).cos();
+                let angle = 2.0 * std::f64::consts::PI * (bin * i) as f64 / n as f64;
+                re += sample * window * angle.cos();
+                im -= sample * window * angle.sin();
+            }
+            let freq = bin as f64 * rate / n as f64;
+            let nearest = (freq / fundamental).round();
+            if nearest as usize % 2 == 1 && (freq - nearest * fundamental).abs() < 60.0 {
+                harmonic += re * re + im * im;
+            } else {
+                other += re * re + im * im;
+            }
+        }
+        other / (harmonic + other)
+    }
+
+    #[test]
+    fn high_quality_audio_keeps_aliases_out_of_a_440_hz_square() {
+        // skip the filter's start up, then a tenth of a second
+        let high = capture_440_hz(AudioQuality::High);
+        let fast = capture_440_hz(AudioQuality::Fast);
+        let (high, fast) = (energy_between_harmonics(&high[2000..6800]), energy_between_harmonics(&fast[2000..6800]));
+        assert!(high < 1e-4, "{} of the energy is aliasing", high);
+        assert!(fast > high * 10.0, "fast {} high {}", fast, high);
+    }
+
+    // cargo test --release audio_quality_cost -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn audio_quality_cost() {
+        for (name, quality, rate) in [("off", AudioQuality::Fast, 0), ("fast", AudioQuality::Fast, 48000), ("high", AudioQuality::High, 48000)] {
+            // best of 5, the first runs tend to be slower
+            let best = (0..5).map(|_| {
+                let mut apu = playing_440_hz();
+                apu.set_audio_quality(quality);
+                apu.set_sample_rate(rate);
+                let start = std::time::Instant::now();
+                for _ in 0..M_CYCLE_RATE {
+                    apu.tick();
+                }
+                std::hint::black_box(apu.output.samples.len());
+                start.elapsed()
+            }).min().unwrap();
+            eprintln!("{}: {:?} per emulated second", name, best);
+        }
+    }
+}
@@ -0,0 +1,331 @@
+use std::fmt;
+
+// https://github.com/LIJI32/SameBoy/blob/master/BESS.md
+const FOOTER_MAGIC: &[u8; 4] = b"BESS";
+const FOOTER_LEN: usize = 8;
+const BLOCK_HEADER_LEN: usize = 8;
+const INFO_LEN: usize = 0x12;
+const CORE_MIN_LEN: usize = 0xC0; // palettes (0xC0 - 0xCF) are only meaningful on CGB and older files of ours omit them
+
+#[derive(PartialEq, Debug)]
+pub enum BessError {
+    MissingFooter,
+    InvalidOffset(u32),
+    Truncated,
+    MissingBlock(&'static str),
+    InvalidBlock(&'static str),
+    UnsupportedVersion(u16),
+    UnsupportedModel(String),
+    BufferTooLarge { buffer: &'static str, size: u32, max: u32 },
+    WrongCartridge
+}
+
+impl fmt::Display for BessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BessError::MissingFooter => write!(f, "not a BESS save file"),
+            BessError::InvalidOffset(offset) => write!(f, "BESS footer points outside of the file (0x{:X})", offset),
+            BessError::Truncated => write!(f, "BESS save file is truncated"),
+            BessError::MissingBlock(ident) => write!(f, "BESS save file has no {} block", ident.trim_end()),
+            BessError::InvalidBlock(ident) => write!(f, "BESS {} block is malformed", ident.trim_end()),
+            BessError::UnsupportedVersion(major) => write!(f, "BESS major version {} is not supported", major),
+            BessError::UnsupportedModel(model) => write!(f, "save file was made for an unsupported model ({})", model.trim_end()),
+            BessError::BufferTooLarge { buffer, size, max } => write!(f, "{} buffer is 0x{:X} bytes but this emulator only has 0x{:X}", buffer, size, max),
+            BessError::WrongCartridge => write!(f, "save file belongs to a different game")
+        }
+    }
+}
+
+impl std::error::Error for BessError {}
+
+pub struct BessBlock<'a> {
+    pub ident: &'a [u8],
+    pub data: &'a [u8]
+}
+
+// walks the blocks starting at the offset stored in the footer, up to and excluding END
+pub fn read_blocks(file: &[u8]) -> Result<Vec<BessBlock<'_>>, BessError> {
+    if file.len() < FOOTER_LEN || file[(file.len() - 4)..] != *FOOTER_MAGIC {
+        return Err(BessError::MissingFooter);
+    }
+
+    let footer = file.len() - FOOTER_LEN;
+    let first_block = read_u32(&file[footer..]);
+    if first_block as usize > footer {
+        return Err(BessError::InvalidOffset(first_block));
+    }
+
+    let mut blocks = vec![];
+    let mut ptr = first_block as usize;
+    loop {
+        if footer - ptr < BLOCK_HEADER_LEN {
+            return Err(BessError::Truncated);
+        }
+        let ident = &file[ptr..ptr + 4];
+        let len = read_u32(&file[ptr + 4..]) as usize;
+        ptr += BLOCK_HEADER_LEN;
+
+        if footer - ptr < len {
+            return Err(BessError::Truncated);
+        }
+        if ident == b"END " {
+            return Ok(blocks);
+        }
+
+        blocks.push(BessBlock { ident, data: &file[ptr..ptr + len] });
+        ptr += len;
+    }
+}
+
+pub fn find_block<'a>(blocks: &'a [BessBlock], ident: &'static str) -> Option<&'a BessBlock<'a>> {
+    blocks.iter().find(|block| block.ident == ident.as_bytes())
+}
+
+// size/offset pair from the CORE block pointing at a buffer elsewhere in the file
+#[derive(Clone, Copy)]
+pub struct BessBuffer {
+    pub size: u32,
+    pub offset: u32
+}
+
+impl BessBuffer {
+    pub fn slice<'a>(&self, file: &'a [u8]) -> Result<&'a [u8], BessError> {
+        let start = self.offset as usize;
+        let end = start.checked_add(self.size as usize).ok_or(BessError::Truncated)?;
+        if end > file.len() {
+            return Err(BessError::Truncated);
+        }
+        Ok(&file[start..end])
+    }
+
+    fn check_size(&self, buffer: &'static str, max: usize) -> Result<(), BessError> {
+        if self.size as usize > max {
+            return Err(BessError::BufferTooLarge { buffer, size: self.size, max: max as u32 });
+        }
+        Ok(())
+    }
+}
+
+pub struct CoreBlock<'a> {
+    pub cpu_state: &'a [u8], // PC, AF, BC, DE, HL, SP, IME, IE, execution state
+    pub io_registers: &'a [u8],
+    pub wram: BessBuffer,
+    pub vram: BessBuffer,
+    pub sram: BessBuffer,
+    pub oam: BessBuffer,
+    pub hram: BessBuffer
+}
+
+// buffer capacities of the emulated model, everything in the file is validated against these before loading
+pub struct BufferLimits {
+    pub wram: usize,
+    pub vram: usize,
+    pub sram: usize,
+    pub oam: usize,
+    pub hram: usize
+}
+
+impl<'a> CoreBlock<'a> {
+    pub fn parse(block: &BessBlock<'a>, file: &[u8], limits: &BufferLimits) -> Result<CoreBlock<'a>, BessError> {
+        let chunk = block.data;
+        if chunk.len() < CORE_MIN_LEN {
+            return Err(BessError::InvalidBlock("CORE"));
+        }
+
+        let major = (chunk[0x00] as u16) | ((chunk[0x01] as u16) << 8);
+        if major != 1 {
+            return Err(BessError::UnsupportedVersion(major));
+        }
+
+        // G = DMG family, S = SGB family (different boot and palettes), C/A = CGB/AGB
+        let model = &chunk[0x04..0x08];
+        if model[0] != b'G' {
+            return Err(BessError::UnsupportedModel(String::from_utf8_lossy(model).into_owned()));
+        }
+
+        let buffer = |offset: usize| BessBuffer { size: read_u32(&chunk[offset..]), offset: read_u32(&chunk[offset + 4..]) };
+        let core = CoreBlock {
+            cpu_state: &chunk[0x08..0x18],
+            io_registers: &chunk[0x18..0x98],
+            wram: buffer(0x98),
+            vram: buffer(0xA0),
+            sram: buffer(0xA8),
+            oam: buffer(0xB0),
+            hram: buffer(0xB8)
+        };
+
+        core.wram.check_size("WRAM", limits.wram)?;
+        core.vram.check_size("VRAM", limits.vram)?;
+        core.sram.check_size("MBC RAM", limits.sram)?;
+        core.oam.check_size("OAM", limits.oam)?;
+        core.hram.check_size("HRAM", limits.hram)?;
+        for buffer in [core.wram, core.vram, core.sram, core.oam, core.hram] {
+            buffer.slice(file)?;
+        }
+
+        Ok(core)
+    }
+}
+
+// the INFO block holds the title and global checksum of the cartridge the state was made with
+pub fn check_info(blocks: &[BessBlock], rom_info: &[u8]) -> Result<(), BessError> {
+    match find_block(blocks, "INFO") {
+        Some(info) if info.data.len() != INFO_LEN => Err(BessError::InvalidBlock("INFO")),
+        Some(info) if info.data != rom_info => Err(BessError::WrongCartridge),
+        _ => Ok(())
+    }
+}
+
+pub fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use super::*;
+    use crate::Emulator;
+    use crate::internal::ppu::hash_display;
+
+    fn blargg_emulator() -> Emulator {
+        let mut emulator = Emulator::new();
+        emulator.load_catridge(fs::read("./tests/blargg/roms/2.gb").expect("File not found!")).unwrap();
+        emulator
+    }
+
+    fn frame_hashes(emulator: &mut Emulator, frames: usize) -> Vec<u64> {
+        (0..frames).map(|_| hash_display(&emulator.render(-1))).collect()
+    }
+
+    // dmg.s0 and cgb.s0 are put together by hand after SameBoy's layout rather than saved by SameBoy itself: foreign
+    // state ahead of the buffers, buffers in a different order than ours, SameBoy's NAME, a 0xD0 CORE block and an
+    // XOAM block we don't understand
+    fn fixture(name: &str) -> Vec<u8> {
+        fs::read(format!("./tests/bess/{}", name)).expect("File not found!")
+    }
+
+    // CORE block always immediately follows NAME and INFO in the fixtures
+    fn core_offset(file: &[u8]) -> usize {
+        let first_block = read_u32(&file[file.len() - FOOTER_LEN..]) as usize;
+        let name_len = read_u32(&file[first_block + 4..]) as usize;
+        first_block + BLOCK_HEADER_LEN + name_len + BLOCK_HEADER_LEN + INFO_LEN + BLOCK_HEADER_LEN
+    }
+
+    #[test]
+    fn loads_dmg_fixture() {
+        // the fixture was taken on an instruction boundary 10 frames into the rom, back when the frames the rom
+        // spends with the LCD off weren't counted, it's 12 now
+        let mut live = blargg_emulator();
+        let expected = frame_hashes(&mut live, 42).split_off(12);
+
+        let mut emulator = blargg_emulator();
+        emulator.load_save_file(fixture("dmg.s0")).unwrap();
+        assert_eq!(frame_hashes(&mut emulator, 30), expected);
+    }
+
+    #[test]
+    fn dmg_fixture_sets_the_registers_and_bank() {
+        let mut emulator = blargg_emulator();
+        emulator.load_save_file(fixture("dmg.s0")).unwrap();
+        assert_eq!(emulator.registers(), [0xAD, 0x60, 0x00, 0xFF, 0xC7, 0xBA, 0x90, 0x00, 0xDFF3, 0xC005, 0, 0]);
+        assert_eq!(emulator.core.bus.rom_bank(0x4000), Some(1));
+
+        // the MBC block writes 0 to 2000, which MBC1 reads as bank 1. 2 is kept as is and wraps to bank 0 of the two
+        let mut file = fixture("dmg.s0");
+        let mbc = file.windows(4).rposition(|ident| ident == b"MBC ").unwrap() + BLOCK_HEADER_LEN;
+        assert_eq!(&file[mbc + 3..mbc + 6], &[0x00, 0x20, 0x00]);
+        file[mbc + 5] = 0x02;
+        emulator.load_save_file(file).unwrap();
+        assert_eq!(emulator.core.bus.rom_bank(0x4000), Some(0));
+    }
+
+    #[test]
+    fn rejects_cgb_fixture() {
+        let mut emulator = blargg_emulator();
+        let before = emulator.save_state();
+
+        let err = emulator.load_save_file(fixture("cgb.s0")).unwrap_err();
+        assert_eq!(err, "save file was made for an unsupported model (CC)");
+        assert_eq!(emulator.save_state(), before);
+    }
+
+    #[test]
+    fn rejects_oversized_buffers_before_copying() {
+        let mut emulator = blargg_emulator();
+        let before = emulator.save_state();
+
+        // pretend the DMG file carries CGB sized WRAM, the buffer itself still lives inside the file
+        let mut file = fixture("dmg.s0");
+        let wram_size = core_offset(&file) + 0x98;
+        file[wram_size..wram_size + 4].copy_from_slice(&0x8000u32.to_le_bytes());
+        file[wram_size + 4..wram_size + 8].copy_from_slice(&0u32.to_le_bytes());
+
+        assert_eq!(emulator.core.load_save_file(&file).err(), Some(BessError::BufferTooLarge { buffer: "WRAM", size: 0x8000, max: 0x2000 }));
+        assert_eq!(emulator.save_state(), before);
+    }
+
+    #[test]
+    fn rejects_state_for_another_game() {
+        let mut emulator = blargg_emulator();
+        let before = emulator.save_state();
+
+        let mut file = fixture("dmg.s0");
+        let global_checksum = core_offset(&file) - BLOCK_HEADER_LEN - 1;
+        file[global_checksum] ^= 0xFF;
+
+        assert_eq!(emulator.core.load_save_file(&file).err(), Some(BessError::WrongCartridge));
+        assert_eq!(emulator.save_state(), before);
+    }
+
+    #[test]
+    fn rejects_malformed_files() {
+        let file = fixture("dmg.s0");
+        assert_eq!(read_blocks(&file[..file.len() - 1]).err(), Some(BessError::MissingFooter));
+        assert_eq!(read_blocks(b"BESS").err(), Some(BessError::MissingFooter));
+
+        let mut bad_offset = file.clone();
+        let footer = bad_offset.len() - FOOTER_LEN;
+        bad_offset[footer..footer + 4].copy_from_slice(&0xFFFFFFu32.to_le_bytes());
+        assert_eq!(read_blocks(&bad_offset).err(), Some(BessError::InvalidOffset(0xFFFFFF)));
+
+        let mut bad_core = file.clone();
+        let core = core_offset(&bad_core);
+        bad_core[core..core + 2].copy_from_slice(&2u16.to_le_bytes());
+        let mut emulator = blargg_emulator();
+        assert_eq!(emulator.core.load_save_file(&bad_core).err(), Some(BessError::UnsupportedVersion(2)));
+    }
+
+    #[test]
+    fn own_files_round_trip() {
+        let mut live = blargg_emulator();
+        frame_hashes(&mut live, 30);
+        let file = live.save_file();
+
+        let blocks = read_blocks(&file).unwrap();
+        assert_eq!(find_block(&blocks, "CORE").unwrap().data.len(), 0xD0);
+
+        let mut emulator = blargg_emulator();
+        emulator.load_save_file(file).unwrap();
+        assert_eq!(frame_hashes(&mut emulator, 5), frame_hashes(&mut live, 5));
+    }
+
+    #[test]
+    fn own_files_keep_the_apu_registers() {
+        let mut live = blargg_emulator();
+        frame_hashes(&mut live, 30);
+        // a playing channel 2 with write-only bits set, NR24 triggers but that isn't stored
+        for (addr, val) in [(0xFF26, 0x80), (0xFF25, 0x22), (0xFF16, 0x85), (0xFF17, 0xF0), (0xFF18, 0x34), (0xFF19, 0x87), (0xFF30, 0x5A)] {
+            live.core.bus.write(addr, val);
+        }
+        let file = live.save_file();
+        let core = find_block(&read_blocks(&file).unwrap(), "CORE").unwrap().data.to_vec();
+        assert_eq!(&core[0x18 + 0x16..0x18 + 0x1A], &[0x85, 0xF0, 0x34, 0x87]);
+        assert_eq!(core[0x18 + 0x26], 0xF2);
+
+        let mut emulator = blargg_emulator();
+        emulator.load_save_file(file).unwrap();
+        for addr in 0xFF10..=0xFF3F {
+            assert_eq!(emulator.core.bus.read(addr), live.core.bus.read(addr), "0x{:04X}", addr);
+        }
+    }
+}
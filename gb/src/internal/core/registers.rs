@@ -8,7 +8,7 @@ pub enum Register {
 #[derive(PartialEq, Eq, Clone, Copy)]
 pub enum Flag { Z, N, H, C }
 
-#[derive(PartialEq, Eq)]
+#[derive(PartialEq, Eq, Clone, Copy)]
 pub struct Registers {
     a: u8,
     b: u8,
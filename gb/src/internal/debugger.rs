@@ -0,0 +1,428 @@
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::collections::VecDeque;
+use crate::internal::snapshot::Snapshot;
+
+// a bank only narrows down addresses in ROM, where it's the bank mapped over them when PC gets there
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct BankedAddr {
+    pub addr: u16,
+    pub bank: Option<u16>
+}
+
+impl fmt::Display for BankedAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.bank {
+            Some(bank) => write!(f, "{:02X}:{:04X}", bank, self.addr),
+            None => write!(f, "{:04X}", self.addr)
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum WatchKind {
+    Read, Write, ReadWrite
+}
+
+impl WatchKind {
+    fn covers(self, access: Access) -> bool {
+        self == WatchKind::ReadWrite || (self == WatchKind::Read) == (access == Access::Read)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Access {
+    Read, Write
+}
+
+// a watched address being accessed, by the instruction at pc or by the OAM DMA while it ran
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct WatchHit {
+    pub pc: u16,
+    pub addr: u16,
+    pub value: u8,
+    pub access: Access,
+    pub dma: bool
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let access = if self.access == Access::Read { "read" } else { "write" };
+        write!(f, "{} of {:02X} at {:04X} by {}", access, self.value, self.addr, if self.dma { "the DMA".to_string() } else { format!("{:04X}", self.pc) })
+    }
+}
+
+// kept sorted by start so a lookup can stop at the first range past the address. the PPU's own fetches are never
+// seen, the OAM DMA's only once watch_dma is on
+#[derive(Clone, Default)]
+pub struct Watchpoints {
+    ranges: Vec<(RangeInclusive<u16>, WatchKind)>,
+    pub dma: bool
+}
+
+impl Watchpoints {
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    pub fn add(&mut self, range: RangeInclusive<u16>, kind: WatchKind) {
+        if !self.ranges.contains(&(range.clone(), kind)) {
+            let at = self.ranges.partition_point(|(set, _)| set.start() <= range.start());
+            self.ranges.insert(at, (range, kind));
+        }
+    }
+
+    pub fn remove(&mut self, range: RangeInclusive<u16>, kind: WatchKind) -> bool {
+        let len = self.ranges.len();
+        self.ranges.retain(|set| *set != (range.clone(), kind));
+        self.ranges.len() != len
+    }
+
+    pub fn watches(&self, addr: u16, access: Access, dma: bool) -> bool {
+        (!dma || self.dma) && self.ranges.iter().take_while(|(range, _)| *range.start() <= addr).any(|(range, kind)| addr <= *range.end() && kind.covers(access))
+    }
+}
+
+// why a run stopped before it was done
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BreakReason {
+    Breakpoint(BankedAddr),
+    Watchpoint(WatchHit),
+    Locked { pc: u16, opcode: u8 }, // only stops the run it happened in, see CPU::is_locked
+    NoFrame { m_cycles: u32 }, // the PPU didn't finish a frame in that long, the next run waits as long again
+    Step // a step or step over got where it was going
+}
+
+impl fmt::Display for BreakReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BreakReason::Breakpoint(breakpoint) => write!(f, "breakpoint at {}", breakpoint),
+            BreakReason::Watchpoint(hit) => write!(f, "watchpoint, {}", hit),
+            BreakReason::Locked { pc, opcode } => write!(f, "locked up on opcode {:02X} at {:04X}", opcode, pc),
+            BreakReason::NoFrame { m_cycles } => write!(f, "no frame finished in {} M-cycles", m_cycles),
+            BreakReason::Step => write!(f, "step")
+        }
+    }
+}
+
+// ring of the last PCs instructions were fetched from, always on since recording is a couple of stores. 0 entries
+// turns it off
+#[derive(Clone)]
+pub struct PcHistory {
+    entries: Vec<BankedAddr>,
+    next: usize,
+    wrapped: bool
+}
+
+impl Default for PcHistory {
+    fn default() -> Self {
+        PcHistory::new(256)
+    }
+}
+
+impl PcHistory {
+    pub fn new(len: usize) -> PcHistory {
+        PcHistory { entries: vec![BankedAddr { addr: 0, bank: None }; len], next: 0, wrapped: false }
+    }
+
+    pub fn record(&mut self, at: BankedAddr) {
+        if let Some(entry) = self.entries.get_mut(self.next) {
+            *entry = at;
+            self.next += 1;
+            if self.next == self.entries.len() {
+                self.next = 0;
+                self.wrapped = true;
+            }
+        }
+    }
+
+    // oldest first
+    pub fn to_vec(&self) -> Vec<BankedAddr> {
+        let mut pcs = if self.wrapped { self.entries[self.next..].to_vec() } else { vec![] };
+        pcs.extend_from_slice(&self.entries[..self.next]);
+        pcs
+    }
+}
+
+impl fmt::Display for PcHistory {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let pcs: Vec<String> = self.to_vec().iter().map(|pc| pc.to_string()).collect();
+        write!(f, "recent PCs, oldest first: {}", pcs.join(" "))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CallKind {
+    Call, Rst, Interrupt
+}
+
+// sp is where the return address was pushed
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct CallFrame {
+    pub return_addr: u16,
+    pub call_target: u16,
+    pub bank: Option<u16>, // of call_target
+    pub kind: CallKind,
+    pub sp: u16
+}
+
+const MAX_FRAMES: usize = 1024;
+
+// the stack as the calls and returns seen so far build it, innermost last. games that move SP themselves or return
+// somewhere else only lose the frames they skipped over, and a new call drops any frame it was pushed on top of
+#[derive(Clone, Default)]
+pub struct CallStack {
+    frames: Vec<CallFrame>
+}
+
+impl CallStack {
+    pub fn called(&mut self, frame: CallFrame) {
+        while self.frames.last().is_some_and(|last| last.sp <= frame.sp) {
+            self.frames.pop();
+        }
+        if self.frames.len() == MAX_FRAMES {
+            self.frames.remove(0);
+        }
+        self.frames.push(frame);
+    }
+
+    // a RET that popped its address from sp, a PUSH and RET jump pops from under the frame and leaves it
+    pub fn returned(&mut self, sp: u16) {
+        while self.frames.last().is_some_and(|last| last.sp <= sp) {
+            self.frames.pop();
+        }
+    }
+
+    pub fn frames(&self) -> &[CallFrame] {
+        &self.frames
+    }
+}
+
+const CHECKPOINT_INTERVAL: u64 = 1000;
+const CHECKPOINTS: usize = 16;
+
+// the machine as it was at an instruction boundary, with what the debugger had seen by then
+#[derive(Clone)]
+pub struct Checkpoint {
+    pub steps: u64,
+    pub snapshot: Snapshot,
+    pub history: PcHistory,
+    pub calls: Option<CallStack>
+}
+
+// counts the instruction boundaries the CPU gets to and keeps a checkpoint every CHECKPOINT_INTERVAL of them. stepping
+// back restores the last checkpoint before where it's going and runs forward from there, which only comes out the same
+// if nothing from outside changed in between. held buttons are the only outside input that slips into a snapshot, so
+// pressing or releasing one drops the checkpoints and starts again from there
+#[derive(Default)]
+pub struct Rewind {
+    pub steps: u64,
+    checkpoints: VecDeque<Checkpoint>,
+    buttons: u8
+}
+
+impl Rewind {
+    pub fn new(buttons: u8) -> Rewind {
+        Rewind { buttons, ..Rewind::default() }
+    }
+
+    // with the buttons held when the CPU got to the boundary, true if a checkpoint is due
+    pub fn passed_boundary(&mut self, buttons: u8) -> bool {
+        self.steps += 1;
+        if buttons != self.buttons {
+            self.buttons = buttons;
+            self.clear();
+        }
+        self.checkpoints.is_empty() || self.steps % CHECKPOINT_INTERVAL == 0
+    }
+
+    pub fn push(&mut self, snapshot: Snapshot, history: PcHistory, calls: Option<CallStack>) {
+        if self.checkpoints.len() == CHECKPOINTS {
+            self.checkpoints.pop_front();
+        }
+        self.checkpoints.push_back(Checkpoint { steps: self.steps, snapshot, history, calls });
+    }
+
+    // the last checkpoint at or before steps, the ones after are dropped since running forward makes them again
+    pub fn back_to(&mut self, steps: u64) -> Option<Checkpoint> {
+        let checkpoint = self.checkpoints.iter().rev().find(|checkpoint| checkpoint.steps <= steps)?.clone();
+        while self.checkpoints.back().is_some_and(|last| last.steps > checkpoint.steps) {
+            self.checkpoints.pop_back();
+        }
+        self.steps = steps;
+        Some(checkpoint)
+    }
+
+    // the state changed some other way than running, there's nothing to go back through
+    pub fn clear(&mut self) {
+        self.checkpoints.clear();
+    }
+}
+
+// checked by the CPU between instructions, never part of a snapshot
+#[derive(Default)]
+pub struct Debugger {
+    pub history: PcHistory,
+    pub calls: Option<CallStack>, // only kept once asked for
+    pub rewind: Option<Rewind>, // same
+    breakpoints: Vec<BankedAddr>,
+    stopped: Option<BreakReason>, // cleared once the CPU carries on
+    watch_hit: Option<WatchHit>, // stops the CPU once the instruction that made it is done
+    resuming: bool // the CPU is at the breakpoint it stopped at, which doesn't stop it again on the way out
+}
+
+impl Debugger {
+    pub fn add_breakpoint(&mut self, breakpoint: BankedAddr) {
+        if !self.breakpoints.contains(&breakpoint) {
+            self.breakpoints.push(breakpoint);
+        }
+    }
+
+    pub fn remove_breakpoint(&mut self, breakpoint: BankedAddr) -> bool {
+        let len = self.breakpoints.len();
+        self.breakpoints.retain(|&set| set != breakpoint);
+        self.breakpoints.len() != len
+    }
+
+    pub fn breakpoints(&self) -> &[BankedAddr] {
+        &self.breakpoints
+    }
+
+    // empty while calls aren't tracked
+    pub fn call_stack(&self) -> &[CallFrame] {
+        self.calls.as_ref().map_or(&[], |calls| calls.frames())
+    }
+
+    pub fn track_calls(&mut self, enabled: bool) {
+        if enabled != self.calls.is_some() {
+            self.calls = enabled.then(CallStack::default);
+        }
+    }
+
+    pub fn is_armed(&self) -> bool {
+        !self.breakpoints.is_empty()
+    }
+
+    pub fn stopped(&self) -> Option<BreakReason> {
+        self.stopped
+    }
+
+    pub fn stop(&mut self, reason: BreakReason) {
+        self.stopped = Some(reason);
+    }
+
+    pub fn note_watch_hit(&mut self, hit: WatchHit) {
+        self.watch_hit.get_or_insert(hit);
+    }
+
+    // at the end of an M-cycle that finished an instruction
+    pub fn finish_instruction(&mut self) {
+        if let Some(hit) = self.watch_hit.take() {
+            self.stopped = Some(BreakReason::Watchpoint(hit));
+        }
+    }
+
+    // called before running again. stopped between instructions, the one at PC runs even if it has a breakpoint
+    pub fn resume(&mut self, at_instruction_boundary: bool) {
+        if self.stopped.take().is_some() && at_instruction_boundary {
+            self.resuming = true;
+        }
+    }
+
+    // with the instruction at pc about to be fetched, bank being what's mapped there if it's ROM
+    pub fn check(&mut self, pc: u16, bank: Option<u16>) -> bool {
+        if std::mem::take(&mut self.resuming) {
+            return false;
+        }
+        let hit = self.breakpoints.iter().find(|breakpoint| breakpoint.addr == pc && breakpoint.bank.is_none_or(|want| bank == Some(want)));
+        if let Some(&breakpoint) = hit {
+            self.stopped = Some(BreakReason::Breakpoint(breakpoint));
+        }
+        hit.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn banked_breakpoints_only_hit_in_their_bank() {
+        let mut debugger = Debugger::default();
+        debugger.add_breakpoint(BankedAddr { addr: 0x4000, bank: Some(2) });
+        debugger.add_breakpoint(BankedAddr { addr: 0xC000, bank: None });
+        assert!(!debugger.check(0x4000, Some(1)));
+        assert!(debugger.check(0x4000, Some(2)));
+        assert_eq!(debugger.stopped().unwrap().to_string(), "breakpoint at 02:4000");
+
+        // resuming steps off it, the next visit stops again
+        debugger.resume(true);
+        assert_eq!(debugger.stopped(), None);
+        assert!(!debugger.check(0x4000, Some(2)));
+        assert!(debugger.check(0xC000, None));
+
+        assert!(debugger.remove_breakpoint(BankedAddr { addr: 0xC000, bank: None }));
+        assert!(!debugger.remove_breakpoint(BankedAddr { addr: 0xC000, bank: None }));
+        debugger.resume(true);
+        assert!(!debugger.check(0x4000, Some(2)));
+        assert!(!debugger.check(0xC000, None));
+    }
+
+    #[test]
+    fn history_keeps_the_latest_pcs_in_order() {
+        let at = |addr| BankedAddr { addr, bank: None };
+        let mut history = PcHistory::new(3);
+        history.record(at(0x100));
+        history.record(at(0x101));
+        assert_eq!(history.to_vec(), [at(0x100), at(0x101)]);
+        history.record(at(0x102));
+        history.record(at(0x103));
+        assert_eq!(history.to_vec(), [at(0x101), at(0x102), at(0x103)]);
+        assert_eq!(history.to_string(), "recent PCs, oldest first: 0101 0102 0103");
+
+        let mut off = PcHistory::new(0);
+        off.record(at(0x100));
+        assert_eq!(off.to_vec(), []);
+    }
+
+    #[test]
+    fn call_stack_resyncs_with_sp() {
+        let frame = |return_addr, sp| CallFrame { return_addr, call_target: 0x200, bank: Some(0), kind: CallKind::Call, sp };
+        let mut calls = CallStack::default();
+        calls.called(frame(0x153, 0xFFFC));
+        calls.called(frame(0x203, 0xFFFA));
+        // PUSH then RET, from under the innermost frame
+        calls.returned(0xFFF8);
+        assert_eq!(calls.frames().len(), 2);
+        calls.returned(0xFFFA);
+        assert_eq!(calls.frames(), [frame(0x153, 0xFFFC)]);
+
+        // SP reset under it, the next call replaces what's left
+        calls.called(frame(0x20A, 0xFFFC));
+        assert_eq!(calls.frames(), [frame(0x20A, 0xFFFC)]);
+        calls.returned(0xFFFE);
+        assert_eq!(calls.frames(), []);
+    }
+
+    #[test]
+    fn watchpoints_match_their_range_and_kind() {
+        let mut watchpoints = Watchpoints::default();
+        watchpoints.add(0xC100..=0xC1FF, WatchKind::Write);
+        watchpoints.add(0xC000..=0xC0FF, WatchKind::Read);
+        watchpoints.add(0xFE00..=0xFE9F, WatchKind::ReadWrite);
+        assert!(watchpoints.watches(0xC0FF, Access::Read, false));
+        assert!(!watchpoints.watches(0xC0FF, Access::Write, false));
+        assert!(watchpoints.watches(0xC100, Access::Write, false));
+        assert!(!watchpoints.watches(0xC200, Access::Write, false));
+        assert!(watchpoints.watches(0xFE00, Access::Read, false));
+
+        // the DMA only counts when asked for
+        assert!(!watchpoints.watches(0xFE00, Access::Write, true));
+        watchpoints.dma = true;
+        assert!(watchpoints.watches(0xFE00, Access::Write, true));
+
+        assert!(watchpoints.remove(0xC000..=0xC0FF, WatchKind::Read));
+        assert!(!watchpoints.remove(0xC000..=0xC0FF, WatchKind::Write));
+        assert!(!watchpoints.watches(0xC000, Access::Read, false));
+    }
+}
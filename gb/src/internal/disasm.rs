@@ -0,0 +1,211 @@
+use std::fmt;
+
+const R: [&str; 8] = ["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+const RP: [&str; 4] = ["BC", "DE", "HL", "SP"];
+const RP2: [&str; 4] = ["BC", "DE", "HL", "AF"]; // PUSH and POP
+const CC: [&str; 4] = ["NZ", "Z", "NC", "C"];
+const ALU: [&str; 8] = ["ADD A, ", "ADC A, ", "SUB ", "SBC A, ", "AND ", "XOR ", "OR ", "CP "];
+const ROT: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SWAP", "SRL"];
+const X0_Z7: [&str; 8] = ["RLCA", "RRCA", "RLA", "RRA", "DAA", "CPL", "SCF", "CCF"];
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct DisasmLine {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    pub text: String
+}
+
+// the address, the bytes padded to the longest instruction and the mnemonic
+impl fmt::Display for DisasmLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes: Vec<String> = self.bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+        write!(f, "{:04X}  {:<8}  {}", self.addr, bytes.join(" "), self.text)
+    }
+}
+
+// bytes an opcode takes with its operands, CB counts its second byte
+pub fn instruction_len(opcode: u8) -> u16 {
+    match opcode {
+        0x01 | 0x11 | 0x21 | 0x31 | 0x08 | 0xC2 | 0xC3 | 0xC4 | 0xCA | 0xCC | 0xCD | 0xD2 | 0xD4 | 0xDA | 0xDC | 0xEA | 0xFA => 3,
+        0x06 | 0x0E | 0x16 | 0x1E | 0x26 | 0x2E | 0x36 | 0x3E | 0x10 | 0x18 | 0x20 | 0x28 | 0x30 | 0x38 | 0xC6 | 0xCE | 0xD6 | 0xDE
+            | 0xE6 | 0xEE | 0xF6 | 0xFE | 0xE0 | 0xF0 | 0xE8 | 0xF8 | 0xCB => 2,
+        _ => 1
+    }
+}
+
+// signed offsets as SP+$08 or SP-$08
+fn signed(e: u8) -> String {
+    let e = e as i8;
+    if e < 0 { format!("-${:02X}", -(e as i16)) } else { format!("+${:02X}", e) }
+}
+
+fn decode(addr: u16, bytes: &[u8]) -> String {
+    let op = bytes[0];
+    let (x, y, z) = ((op >> 6) as usize, ((op >> 3) & 7) as usize, (op & 7) as usize);
+    let (p, q) = (y >> 1, y & 1);
+    let n = || bytes[1];
+    let nn = || ((bytes[2] as u16) << 8) | bytes[1] as u16;
+    let target = || addr.wrapping_add(2).wrapping_add_signed(bytes[1] as i8 as i16); // of JR, from the next instruction
+    match (x, z) {
+        (0, 0) => match y {
+            0 => "NOP".to_string(),
+            1 => format!("LD (${:04X}), SP", nn()),
+            2 => "STOP".to_string(),
+            3 => format!("JR ${:04X}", target()),
+            _ => format!("JR {}, ${:04X}", CC[y - 4], target())
+        },
+        (0, 1) if q == 0 => format!("LD {}, ${:04X}", RP[p], nn()),
+        (0, 1) => format!("ADD HL, {}", RP[p]),
+        (0, 2) => {
+            let at = ["(BC)", "(DE)", "(HL+)", "(HL-)"][p];
+            if q == 0 { format!("LD {}, A", at) } else { format!("LD A, {}", at) }
+        },
+        (0, 3) => format!("{} {}", if q == 0 { "INC" } else { "DEC" }, RP[p]),
+        (0, 4) => format!("INC {}", R[y]),
+        (0, 5) => format!("DEC {}", R[y]),
+        (0, 6) => format!("LD {}, ${:02X}", R[y], n()),
+        (0, _) => X0_Z7[y].to_string(),
+        (1, 6) if y == 6 => "HALT".to_string(),
+        (1, _) => format!("LD {}, {}", R[y], R[z]),
+        (2, _) => format!("{}{}", ALU[y], R[z]),
+        (_, 0) => match y {
+            4 => format!("LDH (${:04X}), A", 0xFF00 | n() as u16),
+            5 => format!("ADD SP, {}", signed(n())),
+            6 => format!("LDH A, (${:04X})", 0xFF00 | n() as u16),
+            7 => format!("LD HL, SP{}", signed(n())),
+            _ => format!("RET {}", CC[y])
+        },
+        (_, 1) if q == 0 => format!("POP {}", RP2[p]),
+        (_, 1) => ["RET", "RETI", "JP HL", "LD SP, HL"][p].to_string(),
+        (_, 2) => match y {
+            4 => "LDH (C), A".to_string(),
+            5 => format!("LD (${:04X}), A", nn()),
+            6 => "LDH A, (C)".to_string(),
+            7 => format!("LD A, (${:04X})", nn()),
+            _ => format!("JP {}, ${:04X}", CC[y], nn())
+        },
+        (_, 3) => match y {
+            0 => format!("JP ${:04X}", nn()),
+            1 => {
+                let cb = bytes[1];
+                let (x, y, z) = ((cb >> 6) as usize, ((cb >> 3) & 7) as usize, (cb & 7) as usize);
+                match x {
+                    0 => format!("{} {}", ROT[y], R[z]),
+                    _ => format!("{} {}, {}", ["", "BIT", "RES", "SET"][x], y, R[z])
+                }
+            },
+            6 => "DI".to_string(),
+            7 => "EI".to_string(),
+            _ => format!("DB ${:02X}", op)
+        },
+        (_, 4) if y < 4 => format!("CALL {}, ${:04X}", CC[y], nn()),
+        (_, 5) if q == 0 => format!("PUSH {}", RP2[p]),
+        (_, 5) if p == 0 => format!("CALL ${:04X}", nn()),
+        (_, 6) => format!("{}${:02X}", ALU[y], n()),
+        (_, 7) => format!("RST ${:02X}", y * 8),
+        _ => format!("DB ${:02X}", op) // the opcodes the SM83 doesn't have
+    }
+}
+
+// `count` instructions from addr, with read giving None for bytes that can't be reached, like past the end of a ROM
+// bank. an instruction whose operands can't all be read becomes a DB of its opcode and ends the listing
+pub fn disassemble(read: impl Fn(u16) -> Option<u8>, addr: u16, count: usize) -> Vec<DisasmLine> {
+    let mut lines = vec![];
+    let mut addr = addr;
+    for _ in 0..count {
+        let Some(opcode) = read(addr) else {
+            break
+        };
+        let bytes: Option<Vec<u8>> = (0..instruction_len(opcode)).map(|offset| read(addr.wrapping_add(offset))).collect();
+        let Some(bytes) = bytes else {
+            lines.push(DisasmLine { addr, bytes: vec![opcode], text: format!("DB ${:02X}", opcode) });
+            break
+        };
+        lines.push(DisasmLine { addr, text: decode(addr, &bytes), bytes });
+        addr = addr.wrapping_add(instruction_len(opcode));
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // every opcode at 0xC000 followed by 34 12, so n is $34, nn is $1234 and JR lands on $C036
+    const PRIMARY: [&str; 64 + 64] = [
+        "NOP", "LD BC, $1234", "LD (BC), A", "INC BC", "INC B", "DEC B", "LD B, $34", "RLCA",
+        "LD ($1234), SP", "ADD HL, BC", "LD A, (BC)", "DEC BC", "INC C", "DEC C", "LD C, $34", "RRCA",
+        "STOP", "LD DE, $1234", "LD (DE), A", "INC DE", "INC D", "DEC D", "LD D, $34", "RLA",
+        "JR $C036", "ADD HL, DE", "LD A, (DE)", "DEC DE", "INC E", "DEC E", "LD E, $34", "RRA",
+        "JR NZ, $C036", "LD HL, $1234", "LD (HL+), A", "INC HL", "INC H", "DEC H", "LD H, $34", "DAA",
+        "JR Z, $C036", "ADD HL, HL", "LD A, (HL+)", "DEC HL", "INC L", "DEC L", "LD L, $34", "CPL",
+        "JR NC, $C036", "LD SP, $1234", "LD (HL-), A", "INC SP", "INC (HL)", "DEC (HL)", "LD (HL), $34", "SCF",
+        "JR C, $C036", "ADD HL, SP", "LD A, (HL-)", "DEC SP", "INC A", "DEC A", "LD A, $34", "CCF",
+        "RET NZ", "POP BC", "JP NZ, $1234", "JP $1234", "CALL NZ, $1234", "PUSH BC", "ADD A, $34", "RST $00",
+        "RET Z", "RET", "JP Z, $1234", "SWAP H", "CALL Z, $1234", "CALL $1234", "ADC A, $34", "RST $08",
+        "RET NC", "POP DE", "JP NC, $1234", "DB $D3", "CALL NC, $1234", "PUSH DE", "SUB $34", "RST $10",
+        "RET C", "RETI", "JP C, $1234", "DB $DB", "CALL C, $1234", "DB $DD", "SBC A, $34", "RST $18",
+        "LDH ($FF34), A", "POP HL", "LDH (C), A", "DB $E3", "DB $E4", "PUSH HL", "AND $34", "RST $20",
+        "ADD SP, +$34", "JP HL", "LD ($1234), A", "DB $EB", "DB $EC", "DB $ED", "XOR $34", "RST $28",
+        "LDH A, ($FF34)", "POP AF", "LDH A, (C)", "DI", "DB $F4", "PUSH AF", "OR $34", "RST $30",
+        "LD HL, SP+$34", "LD SP, HL", "LD A, ($1234)", "EI", "DB $FC", "DB $FD", "CP $34", "RST $38"
+    ];
+    const LENGTHS: [&str; 16] = [
+        "1311112131111121", "2311112121111121", "2311112121111121", "2311112121111121",
+        "1111111111111111", "1111111111111111", "1111111111111111", "1111111111111111",
+        "1111111111111111", "1111111111111111", "1111111111111111", "1111111111111111",
+        "1133312111323321", "1131312111313121", "2111112121311121", "2111112121311121"
+    ];
+
+    fn listing(bytes: &[u8]) -> DisasmLine {
+        let mut memory = bytes.to_vec();
+        memory.resize(4, 0x00);
+        disassemble(|addr| memory.get((addr - 0xC000) as usize).copied(), 0xC000, 1).remove(0)
+    }
+
+    #[test]
+    fn every_opcode_has_its_mnemonic_and_length() {
+        for op in 0..=0xFF_u8 {
+            let line = listing(&[op, 0x34, 0x12]);
+            let expected = match op {
+                0x40..=0x7F if op == 0x76 => "HALT".to_string(),
+                0x40..=0x7F => format!("LD {}, {}", R[(op as usize >> 3) & 7], R[op as usize & 7]),
+                0x80..=0xBF => format!("{}{}", ALU[(op as usize >> 3) & 7], R[op as usize & 7]),
+                0xC0..=0xFF => PRIMARY[op as usize - 0x80].to_string(),
+                _ => PRIMARY[op as usize].to_string()
+            };
+            assert_eq!(line.text, expected, "opcode {:02X}", op);
+            let len = LENGTHS[op as usize >> 4].as_bytes()[op as usize & 0xF] - b'0';
+            assert_eq!(line.bytes.len(), len as usize, "opcode {:02X}", op);
+        }
+    }
+
+    #[test]
+    fn prefixed_opcodes_and_jump_targets() {
+        let cb = |op: u8| listing(&[0xCB, op]).text;
+        assert_eq!(cb(0x00), "RLC B");
+        assert_eq!(cb(0x1E), "RR (HL)");
+        assert_eq!(cb(0x37), "SWAP A");
+        assert_eq!(cb(0x3F), "SRL A");
+        assert_eq!(cb(0x46), "BIT 0, (HL)");
+        assert_eq!(cb(0x7F), "BIT 7, A");
+        assert_eq!(cb(0x88), "RES 1, B");
+        assert_eq!(cb(0xFD), "SET 7, L");
+
+        // backwards, and SP offsets below zero
+        assert_eq!(listing(&[0x18, 0xFE]).text, "JR $C000");
+        assert_eq!(listing(&[0x38, 0x80]).text, "JR C, $BF82");
+        assert_eq!(listing(&[0xE8, 0xFD]).text, "ADD SP, -$03");
+        assert_eq!(listing(&[0xF8, 0x80]).text, "LD HL, SP-$80");
+        assert_eq!(listing(&[0x3E, 0x42]).to_string(), "C000  3E 42     LD A, $42");
+    }
+
+    #[test]
+    fn listing_stops_where_the_bytes_run_out() {
+        let bytes = [0x00, 0x3E, 0x01, 0xC3, 0x00];
+        let lines = disassemble(|addr| bytes.get(addr as usize).copied(), 0x0000, 10);
+        let texts: Vec<_> = lines.iter().map(|line| line.text.as_str()).collect();
+        assert_eq!(texts, ["NOP", "LD A, $01", "DB $C3"]);
+        assert_eq!(lines[2].bytes, [0xC3]);
+    }
+}
@@ -0,0 +1,256 @@
+use wasm_bindgen::prelude::*;
+
+const SELECT_DPAD: u8 = 1 << 4; // P14, the d-pad is read while it's low
+const SELECT_ACTION: u8 = 1 << 5; // P15, same for A, B, Select and Start
+
+// bits of the button state set_buttons takes, the action buttons in the order FF00 reads them and the d-pad above
+#[wasm_bindgen]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Button {
+    A = 0,
+    B = 1,
+    Select = 2,
+    Start = 3,
+    Right = 4,
+    Left = 5,
+    Up = 6,
+    Down = 7
+}
+
+impl Button {
+    // the codes render and the other frame calls used to take for the one button that could be held
+    fn from_keypress(keypress: i8) -> Option<Button> {
+        match keypress {
+            1 => Some(Button::Up),
+            2 => Some(Button::Left),
+            3 => Some(Button::Down),
+            4 => Some(Button::Right),
+            5 => Some(Button::A),
+            6 => Some(Button::B),
+            7 => Some(Button::Start),
+            8 => Some(Button::Select),
+            _ => None
+        }
+    }
+}
+
+// the buttons are what the frontend holds down right now, they're never part of a snapshot
+#[derive(Clone, Default)]
+pub struct Joypad {
+    select: u8, // P14 and P15 as last written
+    held: u8, // pressed and released one at a time, a bit per Button
+    keypress: u8, // the single button of the old keypress code, replaced on every frame call
+    turbo: [Option<u8>; 8], // period in frames of every Button, pressed for the first half of it
+    turbo_frames: [u32; 8], // frames the button has been down for, turbo starts over with every press
+    turbo_released: u8, // held buttons turbo lets go of for this frame
+    playback: Option<u8>, // buttons of a movie frame, what's held is ignored while it's set
+    lines: u8, // P10-P13 pulled low by the last change, a bit per line
+    interrupt: bool // a line went low, taken by Memory on the next M-cycle
+}
+
+impl Joypad {
+    // with both lines low the two groups are read at once, a line is low if a button of either is pressed
+    pub fn read(&self) -> u8 {
+        0xC0 | self.select | (!self.lines() & 0x0F)
+    }
+
+    // selecting a group with a button held pulls its line low like a press does
+    pub fn write(&mut self, val: u8) {
+        self.select = val & (SELECT_DPAD | SELECT_ACTION);
+        self.update_lines();
+    }
+
+    // for snapshots, the lines start from what's selected without requesting anything
+    pub fn restore_select(&mut self, val: u8) {
+        self.select = val & (SELECT_DPAD | SELECT_ACTION);
+        self.lines = self.lines();
+    }
+
+    fn update_lines(&mut self) {
+        let lines = self.lines();
+        if lines & !std::mem::replace(&mut self.lines, lines) != 0 {
+            self.interrupt = true;
+        }
+    }
+
+    pub fn take_interrupt(&mut self) -> bool {
+        std::mem::take(&mut self.interrupt)
+    }
+
+    fn lines(&self) -> u8 {
+        let pressed = self.buttons();
+        let mut lines = 0;
+        if self.select & SELECT_DPAD == 0 {
+            lines |= pressed >> 4;
+        }
+        if self.select & SELECT_ACTION == 0 {
+            lines |= pressed & 0x0F;
+        }
+        lines
+    }
+
+    // what STOP checks for, a selected button pressed
+    pub fn line_low(&self) -> bool {
+        self.lines() != 0
+    }
+
+    pub fn select(&self) -> u8 {
+        self.select
+    }
+
+    pub fn press(&mut self, button: Button) {
+        self.held |= 1 << button as u8;
+        self.update_lines();
+    }
+
+    pub fn release(&mut self, button: Button) {
+        self.held &= !(1 << button as u8);
+        self.update_lines();
+    }
+
+    pub fn set_buttons(&mut self, buttons: u8) {
+        self.held = buttons;
+        self.update_lines();
+    }
+
+    pub fn buttons(&self) -> u8 {
+        self.playback.unwrap_or((self.held | self.keypress) & !self.turbo_released)
+    }
+
+    // a period under 2 frames would never let go, so it turns turbo off like None does
+    pub fn set_turbo(&mut self, button: Button, period: Option<u8>) {
+        self.turbo[button as usize] = period.filter(|&period| period > 1);
+        self.turbo_frames[button as usize] = 0;
+        self.turbo_released &= !(1 << button as u8);
+        self.update_lines();
+    }
+
+    // called before every frame, the phase only depends on how long the button is held so loading a state doesn't
+    // move it
+    pub fn advance_turbo(&mut self) {
+        let down = self.held | self.keypress;
+        for (i, (period, frames)) in self.turbo.iter().zip(self.turbo_frames.iter_mut()).enumerate() {
+            let Some(period) = *period else {
+                continue;
+            };
+            let bit = 1 << i;
+            if down & bit == 0 {
+                *frames = 0;
+                self.turbo_released &= !bit;
+                continue;
+            }
+            if *frames % period as u32 >= (period as u32).div_ceil(2) {
+                self.turbo_released |= bit;
+            } else {
+                self.turbo_released &= !bit;
+            }
+            *frames += 1;
+        }
+        self.update_lines();
+    }
+
+    pub fn set_playback(&mut self, buttons: Option<u8>) {
+        self.playback = buttons;
+        self.update_lines();
+    }
+
+    // -1 or any other code that isn't a button leaves only what's held
+    pub fn set_keypress(&mut self, keypress: i8) {
+        self.keypress = Button::from_keypress(keypress).map_or(0, |button| 1 << button as u8);
+        self.update_lines();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn select_lines_pick_the_group() {
+        let mut joypad = Joypad::default();
+        joypad.press(Button::Right);
+        joypad.press(Button::A);
+        joypad.press(Button::Start);
+
+        joypad.write(0x20);
+        assert_eq!(joypad.read(), 0xEE);
+        joypad.write(0x10);
+        assert_eq!(joypad.read(), 0xD6);
+        // both groups at once, Right and A share a line
+        joypad.write(0x00);
+        assert_eq!(joypad.read(), 0xC6);
+        joypad.write(0x30);
+        assert_eq!(joypad.read(), 0xFF);
+
+        joypad.release(Button::A);
+        joypad.write(0x10);
+        assert_eq!(joypad.read(), 0xD7);
+    }
+
+    #[test]
+    fn keypress_code_adds_to_what_is_held() {
+        let mut joypad = Joypad::default();
+        joypad.set_buttons(1 << Button::Down as u8);
+        joypad.set_keypress(2);
+        joypad.write(0x20);
+        assert_eq!(joypad.read(), 0xE5);
+
+        joypad.set_keypress(-1);
+        assert_eq!(joypad.read(), 0xE7);
+        assert_eq!(joypad.buttons(), 1 << Button::Down as u8);
+    }
+
+    #[test]
+    fn lines_going_low_request_the_interrupt() {
+        let mut joypad = Joypad::default();
+        joypad.write(0x30);
+        joypad.press(Button::A);
+        // nothing selected, so no line moved
+        assert!(!joypad.take_interrupt());
+
+        joypad.write(0x10);
+        assert!(joypad.take_interrupt());
+        // B shares nothing with what's already low
+        joypad.press(Button::B);
+        assert!(joypad.take_interrupt());
+        joypad.release(Button::A);
+        joypad.write(0x00);
+        assert!(!joypad.take_interrupt());
+
+        // Left pulls the line B already holds low
+        joypad.press(Button::Left);
+        assert!(!joypad.take_interrupt());
+        joypad.press(Button::Up);
+        assert!(joypad.take_interrupt());
+
+        joypad.write(0x30);
+        joypad.restore_select(0x10);
+        assert!(!joypad.take_interrupt());
+    }
+
+    #[test]
+    fn turbo_follows_the_frames_held() {
+        let mut joypad = Joypad::default();
+        joypad.set_turbo(Button::A, Some(2));
+        joypad.set_turbo(Button::B, Some(3));
+        joypad.press(Button::A);
+        joypad.press(Button::B);
+        let mut frames = vec![];
+        for frame in 0..6 {
+            if frame == 4 {
+                // a new press starts A over after a frame up, B keeps counting through it
+                joypad.release(Button::A);
+                joypad.advance_turbo();
+                joypad.press(Button::A);
+            }
+            joypad.advance_turbo();
+            frames.push(joypad.buttons());
+        }
+        assert_eq!(frames, [0b11, 0b10, 0b01, 0b10, 0b01, 0b10]);
+
+        joypad.release(Button::B);
+        joypad.advance_turbo();
+        joypad.set_turbo(Button::A, None);
+        assert_eq!(joypad.buttons(), 0b01);
+    }
+}
@@ -0,0 +1,109 @@
+use wasm_bindgen::prelude::*;
+use crate::internal::joypad::Button;
+use crate::internal::snapshot::{StateWriter, StateReader, StateError};
+
+const KEYMAP_MAGIC: &[u8; 4] = b"GBKM";
+const KEYMAP_VERSION: u8 = 1;
+const BUTTONS: [Button; 8] = [Button::A, Button::B, Button::Select, Button::Start, Button::Right, Button::Left, Button::Up, Button::Down];
+
+// host key codes to buttons, the codes are whatever the frontend gets for a key, KeyboardEvent.code in a browser.
+// a key presses one button, a button can have any number of keys
+#[wasm_bindgen]
+#[derive(Clone, PartialEq, Debug)]
+pub struct KeyMap {
+    bindings: Vec<(String, Button)>
+}
+
+#[wasm_bindgen]
+impl KeyMap {
+    // arrows for the d-pad, Q and W for A and B, Enter for Start and Escape for Select
+    pub fn new() -> KeyMap {
+        let defaults = [
+            ("ArrowUp", Button::Up), ("ArrowLeft", Button::Left), ("ArrowDown", Button::Down), ("ArrowRight", Button::Right),
+            ("KeyQ", Button::A), ("KeyW", Button::B), ("Enter", Button::Start), ("Escape", Button::Select)
+        ];
+        KeyMap { bindings: defaults.iter().map(|&(code, button)| (code.to_string(), button)).collect() }
+    }
+
+    pub fn empty() -> KeyMap {
+        KeyMap { bindings: vec![] }
+    }
+
+    // replaces whatever the key pressed before
+    pub fn set_binding(&mut self, key_code: &str, button: Button) {
+        match self.bindings.iter_mut().find(|(code, _)| code == key_code) {
+            Some(binding) => binding.1 = button,
+            None => self.bindings.push((key_code.to_string(), button))
+        }
+    }
+
+    pub fn remove_binding(&mut self, key_code: &str) {
+        self.bindings.retain(|(code, _)| code != key_code);
+    }
+
+    pub fn button(&self, key_code: &str) -> Option<Button> {
+        self.bindings.iter().find(|(code, _)| code == key_code).map(|&(_, button)| button)
+    }
+
+    // every key bound to the button, in the order they were bound
+    pub fn keys(&self, button: Button) -> Vec<String> {
+        self.bindings.iter().filter(|&&(_, bound)| bound == button).map(|(code, _)| code.clone()).collect()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = StateWriter::default();
+        w.bytes(KEYMAP_MAGIC);
+        w.u8(KEYMAP_VERSION);
+        w.u16(self.bindings.len() as u16);
+        for (code, button) in &self.bindings {
+            w.u8(*button as u8);
+            w.vec(code.as_bytes());
+        }
+        w.buf
+    }
+
+    // None for anything that isn't a key map this build can read
+    pub fn from_bytes(bytes: &[u8]) -> Option<KeyMap> {
+        KeyMap::read(bytes).ok()
+    }
+}
+
+impl KeyMap {
+    fn read(bytes: &[u8]) -> Result<KeyMap, StateError> {
+        let mut r = StateReader::new(bytes);
+        if r.bytes(KEYMAP_MAGIC.len())? != KEYMAP_MAGIC || r.u8()? != KEYMAP_VERSION {
+            return Err(StateError::InvalidMagic);
+        }
+        let mut map = KeyMap::empty();
+        for _ in 0..r.u16()? {
+            let button = *BUTTONS.get(r.u8()? as usize).ok_or(StateError::InvalidData("unknown button"))?;
+            let code = String::from_utf8(r.vec()?).map_err(|_| StateError::InvalidData("key code isn't UTF-8"))?;
+            map.set_binding(&code, button);
+        }
+        Ok(map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bindings_replace_and_round_trip() {
+        let mut map = KeyMap::new();
+        assert_eq!(map.button("KeyQ"), Some(Button::A));
+        assert_eq!(map.button("KeyX"), None);
+
+        map.set_binding("KeyX", Button::A);
+        map.set_binding("KeyQ", Button::B);
+        map.remove_binding("Escape");
+        assert_eq!(map.keys(Button::A), ["KeyX"]);
+        assert_eq!(map.keys(Button::B), ["KeyQ", "KeyW"]);
+        assert_eq!(map.button("Escape"), None);
+
+        assert_eq!(KeyMap::from_bytes(&map.to_bytes()), Some(map.clone()));
+        let bytes = map.to_bytes();
+        assert_eq!(KeyMap::from_bytes(&bytes[..bytes.len() - 1]), None);
+        assert_eq!(KeyMap::from_bytes(b"GBSN"), None);
+    }
+}
@@ -0,0 +1,403 @@
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::rc::Rc;
+use crate::internal::serial::SerialDevice;
+
+// every frame is a kind and the byte that goes with it
+const FRAME_LEN: usize = 2;
+const TRANSFER: u8 = 0x01; // sent by the side on the internal clock as its transfer starts
+const REPLY: u8 = 0x02; // sent back by the side on the external clock once it clocked the transfer in
+
+const DEFAULT_IN_FLIGHT: usize = 4;
+const DEFAULT_MAX_WAIT: u32 = 8 * 1048; // M-cycles, about 8 ms
+
+// moves the raw bytes of the link protocol to the other emulator, neither call may block
+pub trait Transport {
+    // appends whatever arrived since the last call, false once the other side is gone
+    fn receive(&mut self, into: &mut VecDeque<u8>) -> bool;
+
+    // false once the other side is gone
+    fn send(&mut self, bytes: &[u8]) -> bool;
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use tcp::TcpTransport;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod tcp {
+    use std::collections::VecDeque;
+    use std::io::{self, ErrorKind, Read, Write};
+    use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+    use super::Transport;
+
+    pub struct TcpTransport {
+        stream: TcpStream,
+        unsent: Vec<u8> // whatever the socket didn't take yet
+    }
+
+    impl TcpTransport {
+        pub fn connect(addr: impl ToSocketAddrs) -> io::Result<TcpTransport> {
+            TcpTransport::from_stream(TcpStream::connect(addr)?)
+        }
+
+        // waits for the other side to connect
+        pub fn accept(listener: &TcpListener) -> io::Result<TcpTransport> {
+            TcpTransport::from_stream(listener.accept()?.0)
+        }
+
+        pub fn from_stream(stream: TcpStream) -> io::Result<TcpTransport> {
+            stream.set_nonblocking(true)?;
+            stream.set_nodelay(true)?;
+            Ok(TcpTransport { stream, unsent: vec![] })
+        }
+
+        fn flush(&mut self) -> bool {
+            while !self.unsent.is_empty() {
+                match self.stream.write(&self.unsent) {
+                    Ok(0) => return false,
+                    Ok(n) => { self.unsent.drain(..n); },
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => return true,
+                    Err(err) if err.kind() == ErrorKind::Interrupted => (),
+                    Err(_) => return false
+                }
+            }
+            true
+        }
+    }
+
+    impl Transport for TcpTransport {
+        fn receive(&mut self, into: &mut VecDeque<u8>) -> bool {
+            if !self.flush() {
+                return false;
+            }
+            let mut buffer = [0; 64];
+            loop {
+                match self.stream.read(&mut buffer) {
+                    Ok(0) => return false,
+                    Ok(n) => into.extend(&buffer[..n]),
+                    Err(err) if err.kind() == ErrorKind::WouldBlock => return true,
+                    Err(err) if err.kind() == ErrorKind::Interrupted => (),
+                    Err(_) => return false
+                }
+            }
+        }
+
+        fn send(&mut self, bytes: &[u8]) -> bool {
+            self.unsent.extend_from_slice(bytes);
+            self.flush()
+        }
+    }
+}
+
+// for frontends that move the bytes themselves, over a WebSocket for instance
+#[derive(Default)]
+pub struct LinkPipe {
+    pub incoming: VecDeque<u8>,
+    pub outgoing: Vec<u8>,
+    pub closed: bool
+}
+
+impl Transport for Rc<RefCell<LinkPipe>> {
+    fn receive(&mut self, into: &mut VecDeque<u8>) -> bool {
+        let mut pipe = self.borrow_mut();
+        into.extend(pipe.incoming.drain(..));
+        !pipe.closed
+    }
+
+    fn send(&mut self, bytes: &[u8]) -> bool {
+        let mut pipe = self.borrow_mut();
+        pipe.outgoing.extend_from_slice(bytes);
+        !pipe.closed
+    }
+}
+
+// one end of a link cable to another emulator. the side on the internal clock sends its byte as the transfer starts
+// and holds its clock until the other side's byte comes back, for at most max_wait M-cycles before the transfer
+// finishes with 0xFF. the side on the external clock clocks each byte in as it arrives and answers with its own,
+// bytes that arrive before it's ready are kept, up to in_flight of them. once the other side is gone every transfer
+// finishes with 0xFF so the game notices instead of waiting forever
+pub struct LinkCable<T: Transport> {
+    transport: T,
+    connected: Rc<Cell<bool>>,
+    pub in_flight: usize,
+    pub max_wait: u32,
+
+    received: VecDeque<u8>, // until a whole frame is there
+    transfers: VecDeque<u8>, // from the other side's internal clock, waiting for ours to be ready
+    replies: VecDeque<u8>,
+    late: usize, // replies still on their way for transfers that gave up waiting, thrown away as they arrive
+
+    in_byte: u8, // shifted in a bit at a time, most significant first
+    out_byte: u8,
+    bits: u8, // of the byte in progress
+    answering: bool, // clocking in a transfer of the other side
+    waited: Option<u32> // M-cycles our clock has been held for a reply, None when there's nothing to wait for
+}
+
+impl<T: Transport> LinkCable<T> {
+    pub fn new(transport: T) -> LinkCable<T> {
+        LinkCable {
+            transport,
+            connected: Rc::new(Cell::new(true)),
+            in_flight: DEFAULT_IN_FLIGHT,
+            max_wait: DEFAULT_MAX_WAIT,
+            received: VecDeque::new(),
+            transfers: VecDeque::new(),
+            replies: VecDeque::new(),
+            late: 0,
+            in_byte: 0xFF,
+            out_byte: 0xFF,
+            bits: 0,
+            answering: false,
+            waited: None
+        }
+    }
+
+    // stays readable after the cable is attached to an emulator
+    pub fn status(&self) -> LinkStatus {
+        LinkStatus(self.connected.clone())
+    }
+
+    fn poll(&mut self) {
+        if !self.connected.get() {
+            return;
+        }
+        if !self.transport.receive(&mut self.received) {
+            self.connected.set(false);
+        }
+        while self.received.len() >= FRAME_LEN {
+            let (kind, byte) = (self.received[0], self.received[1]);
+            self.received.drain(..FRAME_LEN);
+            match kind {
+                TRANSFER => {
+                    if self.transfers.len() == self.in_flight {
+                        self.transfers.pop_front();
+                    }
+                    self.transfers.push_back(byte);
+                },
+                REPLY if self.late > 0 => self.late -= 1,
+                REPLY => self.replies.push_back(byte),
+                // not something this protocol sends, there's no telling where the next frame starts
+                _ => {
+                    self.connected.set(false);
+                    self.received.clear();
+                }
+            }
+        }
+    }
+
+    fn send(&mut self, kind: u8, byte: u8) {
+        if self.connected.get() && !self.transport.send(&[kind, byte]) {
+            self.connected.set(false);
+        }
+    }
+
+    fn start_byte(&mut self, in_byte: u8) {
+        self.in_byte = in_byte;
+        self.bits = 0;
+    }
+}
+
+impl<T: Transport> SerialDevice for LinkCable<T> {
+    fn exchange_bit(&mut self, out_bit: bool) -> bool {
+        let in_bit = self.in_byte & 0x80 != 0;
+        self.in_byte = (self.in_byte << 1) | 0x1;
+        self.out_byte = (self.out_byte << 1) | out_bit as u8;
+        self.bits += 1;
+        if self.bits == 8 {
+            self.bits = 0;
+            if std::mem::take(&mut self.answering) {
+                self.send(REPLY, self.out_byte);
+            }
+        }
+        in_bit
+    }
+
+    // each byte of the other side is clocked in a bit per M-cycle, faster than the 8192 Hz it was sent at
+    fn external_clock(&mut self) -> bool {
+        self.poll();
+        if self.answering {
+            return true;
+        }
+        if let Some(byte) = self.transfers.pop_front() {
+            self.start_byte(byte);
+        } else if !self.connected.get() {
+            self.start_byte(0xFF);
+        } else {
+            return false;
+        }
+        self.answering = true;
+        true
+    }
+
+    fn transfer_started(&mut self, out: u8) {
+        self.poll();
+        // a restart in the middle of waiting means the reply to that one will never be read
+        if self.waited.is_some() {
+            self.late += 1;
+        }
+        self.send(TRANSFER, out);
+        self.start_byte(0xFF);
+        self.answering = false;
+        self.waited = Some(0);
+    }
+
+    fn hold_clock(&mut self) -> bool {
+        let Some(waited) = self.waited else {
+            return false;
+        };
+        self.poll();
+        if let Some(byte) = self.replies.pop_front() {
+            self.start_byte(byte);
+        } else if !self.connected.get() {
+            self.start_byte(0xFF);
+        } else if waited >= self.max_wait {
+            self.late += 1;
+            self.start_byte(0xFF);
+        } else {
+            self.waited = Some(waited + 1);
+            return true;
+        }
+        self.waited = None;
+        false
+    }
+}
+
+#[derive(Clone)]
+pub struct LinkStatus(Rc<Cell<bool>>);
+
+impl LinkStatus {
+    // false for good once the other side dropped or sent something that isn't the link protocol
+    pub fn connected(&self) -> bool {
+        self.0.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::internal::serial::Serial;
+
+    fn pipes() -> (Rc<RefCell<LinkPipe>>, Rc<RefCell<LinkPipe>>) {
+        (Rc::new(RefCell::new(LinkPipe::default())), Rc::new(RefCell::new(LinkPipe::default())))
+    }
+
+    // what the other side sent so far, arriving all at once
+    fn deliver(from: &Rc<RefCell<LinkPipe>>, to: &Rc<RefCell<LinkPipe>>) {
+        let bytes = std::mem::take(&mut from.borrow_mut().outgoing);
+        to.borrow_mut().incoming.extend(bytes);
+    }
+
+    fn start(serial: &mut Serial, cable: &mut impl SerialDevice, sb: u8, sc: u8) {
+        serial.write_registers(0xFF01, sb);
+        if let Some(out) = serial.write_registers(0xFF02, sc) {
+            cable.transfer_started(out);
+        }
+    }
+
+    // M-cycles until the interrupt of each side, the bytes are passed on every `latency` M-cycles
+    fn exchange(latency: u32, sb: [u8; 2]) -> ([u8; 2], [Option<u32>; 2]) {
+        let (a, b) = pipes();
+        let mut cables = [LinkCable::new(a.clone()), LinkCable::new(b.clone())];
+        let mut serials = [Serial::default(), Serial::default()];
+        start(&mut serials[1], &mut cables[1], sb[1], 0x80);
+        start(&mut serials[0], &mut cables[0], sb[0], 0x81);
+
+        let mut finished = [None; 2];
+        for cycle in 1..=20_000 {
+            for side in 0..2 {
+                serials[side].update(&mut cables[side], cycle % 128 == 0);
+                if serials[side].take_interrupt() {
+                    finished[side].get_or_insert(cycle);
+                }
+            }
+            if cycle % latency == 0 {
+                deliver(&a, &b);
+                deliver(&b, &a);
+            }
+        }
+        ([serials[0].read_registers(0xFF01), serials[1].read_registers(0xFF01)], finished)
+    }
+
+    #[test]
+    fn both_sides_end_up_with_the_other_byte() {
+        let (sb, finished) = exchange(1, [0x12, 0x34]);
+        assert_eq!(sb, [0x34, 0x12]);
+        assert!(finished.iter().all(|cycle| cycle.is_some()));
+    }
+
+    #[test]
+    fn internal_clock_waits_out_the_latency() {
+        let (sb, finished) = exchange(1000, [0x12, 0x34]);
+        assert_eq!(sb, [0x34, 0x12]);
+        // the reply is back on the second delivery, the 8 bits shift on the next 8 falls of the clock after that
+        assert_eq!(finished[0], Some(2048 + 7 * 128));
+        // too slow for max_wait
+        let (sb, _) = exchange(DEFAULT_MAX_WAIT + 1, [0x12, 0x34]);
+        assert_eq!(sb, [0xFF, 0x12]);
+    }
+
+    #[test]
+    fn drop_finishes_the_pending_transfer_with_ff() {
+        let (a, _) = pipes();
+        let mut cable = LinkCable::new(a.clone());
+        let status = cable.status();
+        let mut serial = Serial::default();
+        start(&mut serial, &mut cable, 0x12, 0x81);
+        for cycle in 1..=1000 {
+            serial.update(&mut cable, cycle % 128 == 0);
+        }
+        assert!(status.connected());
+
+        a.borrow_mut().closed = true;
+        let mut cycle = 1000;
+        while !serial.take_interrupt() {
+            cycle += 1;
+            serial.update(&mut cable, cycle % 128 == 0);
+        }
+        assert_eq!(cycle, 1024 + 7 * 128);
+        assert_eq!(serial.read_registers(0xFF01), 0xFF);
+        assert!(!status.connected());
+
+        // the external clock doesn't wait for a partner that's gone either
+        start(&mut serial, &mut cable, 0x12, 0x80);
+        for _ in 0..8 {
+            serial.update(&mut cable, false);
+        }
+        assert!(serial.take_interrupt());
+    }
+
+    #[test]
+    fn garbage_drops_the_link() {
+        let (a, _) = pipes();
+        let mut cable = LinkCable::new(a.clone());
+        a.borrow_mut().incoming.extend([0x7F, 0x00]);
+        cable.external_clock();
+        assert!(!cable.status().connected());
+    }
+
+    #[test]
+    fn tcp_carries_a_transfer() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut internal = LinkCable::new(TcpTransport::connect(listener.local_addr().unwrap()).unwrap());
+        let mut external = LinkCable::new(TcpTransport::accept(&listener).unwrap());
+        internal.max_wait = u32::MAX;
+        let mut serials = [Serial::default(), Serial::default()];
+        start(&mut serials[1], &mut external, 0x34, 0x80);
+        start(&mut serials[0], &mut internal, 0x12, 0x81);
+
+        let mut finished = [false; 2];
+        let started = std::time::Instant::now();
+        for cycle in 1.. {
+            if finished.iter().all(|&done| done) {
+                break;
+            }
+            assert!(started.elapsed().as_secs() < 5, "transfer never finished");
+            serials[0].update(&mut internal, cycle % 128 == 0);
+            serials[1].update(&mut external, false);
+            finished[0] |= serials[0].take_interrupt();
+            finished[1] |= serials[1].take_interrupt();
+        }
+        assert_eq!((serials[0].read_registers(0xFF01), serials[1].read_registers(0xFF01)), (0x34, 0x12));
+    }
+}
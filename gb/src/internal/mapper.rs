@@ -0,0 +1,388 @@
+pub const MBC_TYPE: usize = 0x0147;
+pub const RAM_SIZE: usize = 0x0149;
+
+const NINTENDO_LOGO: [u8; 48] = [0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+                                 0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+                                 0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E];
+
+// the registers of every mapper as snapshots keep them, whichever kind it is. mappers leave what they don't have at 0
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct MapperState {
+    pub ram_enabled: bool,
+    pub advanced_banking: bool,
+    pub rom_bank: u8,
+    pub rom_bank_high: u8,
+    pub ram_bank: u8
+}
+
+// the cartridge's side of 0000-7FFF and A000-BFFF. the mapper owns the ROM, the cartridge RAM stays with Memory
+// (save files and BESS reach it there) and is handed in. writes to either area only ever come here
+pub trait Mapper {
+    fn read_rom(&self, addr: u16) -> u8;
+    fn read_ram(&self, sram: &[u8], addr: u16) -> u8;
+    fn write(&mut self, sram: &mut [u8], addr: u16, val: u8);
+    // register writes for a BESS file's MBC block, None without an MBC
+    fn bess_block(&self) -> Option<Vec<u8>>;
+    // the bank at 4000-7FFF before it's wrapped to the size of the ROM
+    fn current_rom_bank(&self) -> u16;
+    // same for 0000-3FFF, only the MBC1 ever moves it
+    fn rom_bank0(&self) -> u16 {
+        0
+    }
+    fn rom(&self) -> &[u8];
+    fn rom_mut(&mut self) -> &mut [u8];
+    fn state(&self) -> MapperState;
+    fn restore_state(&mut self, state: &MapperState);
+}
+
+// the mapper the header asks for, MBC1 carts with a second logo 16 banks in are multicarts
+// whether for_cartridge has a mapper for the cartridge type in the header
+pub fn supported(mbc_type: u8) -> bool {
+    matches!(mbc_type, 0x00..=0x03 | 0x0F..=0x13 | 0x19..=0x1E)
+}
+
+pub fn for_cartridge(mut rom: Vec<u8>) -> Box<dyn Mapper> {
+    match rom[MBC_TYPE] {
+        0x00 => {
+            rom.resize(0x10000, 0x00);
+            Box::new(NoMbc { rom })
+        },
+        0x01..=0x03 => {
+            let mut multicart = false;
+            let mut logo_ptr = (NINTENDO_LOGO.len() - 1) as i8;
+            for i in (0x4000..0x8000).rev() {
+                if logo_ptr < 0 {
+                    multicart = true;
+                    break
+                }
+
+                let bank_ten_ptr = ((0x10 as u32) << 14) | (i & 0x3FFF);
+                if bank_ten_ptr >= rom.len() as u32 { break } // out of bounds
+                if rom[bank_ten_ptr as usize] == NINTENDO_LOGO[logo_ptr as usize] {
+                    logo_ptr -= 1;
+                } else {
+                    logo_ptr = (NINTENDO_LOGO.len() - 1) as i8;
+                }
+            }
+            Box::new(Mbc1 { rom, multicart, ..Mbc1::default() })
+        },
+        0x0F..=0x13 => Box::new(Mbc3 { rom, ..Mbc3::default() }),
+        0x19..=0x1E => Box::new(Mbc5 { rom, ..Mbc5::default() }),
+        _ => panic!("MBC NOT IMPLEMENTED YET! 0x{:02X}", rom[MBC_TYPE])
+    }
+}
+
+// bank 0 is the first 16 KiB of the ROM, banks past its end wrap around (ROM sizes are powers of 2)
+fn banked(rom: &[u8], bank: u32, addr: u16) -> u8 {
+    rom[((bank << 14) as usize | (addr & 0x3FFF) as usize) & (rom.len() - 1)]
+}
+
+// no ROM banking and, with the ROM padded out to 64 KiB, nothing but 0s at A000-BFFF
+#[derive(Default)]
+pub struct NoMbc {
+    rom: Vec<u8>
+}
+
+impl Mapper for NoMbc {
+    fn read_rom(&self, addr: u16) -> u8 {
+        self.rom[addr as usize]
+    }
+
+    fn read_ram(&self, _sram: &[u8], addr: u16) -> u8 {
+        self.rom[addr as usize]
+    }
+
+    fn write(&mut self, _sram: &mut [u8], _addr: u16, _val: u8) {}
+
+    fn bess_block(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn current_rom_bank(&self) -> u16 {
+        1
+    }
+
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn rom_mut(&mut self) -> &mut [u8] {
+        &mut self.rom
+    }
+
+    fn state(&self) -> MapperState {
+        MapperState::default()
+    }
+
+    fn restore_state(&mut self, _state: &MapperState) {}
+}
+
+#[derive(Default)]
+pub struct Mbc1 {
+    rom: Vec<u8>,
+    multicart: bool, // MBC1M, the upper bits pick one of the games 16 banks apart and the lower bank has a bit less
+    ram_enabled: bool,
+    advanced_banking: bool, // the upper bits bank 0000-3FFF and RAM too
+    rom_bank: u8, // 5 bits, 0 is bank 1
+    ram_bank: u8 // 2 bits above the ROM bank or the RAM bank
+}
+
+impl Mbc1 {
+    fn upper_bank(&self) -> u32 {
+        (self.ram_bank as u32) << if self.multicart { 4 } else { 5 }
+    }
+}
+
+impl Mapper for Mbc1 {
+    fn read_rom(&self, addr: u16) -> u8 {
+        let bank = if addr < 0x4000 { self.rom_bank0() } else { self.current_rom_bank() };
+        banked(&self.rom, bank as u32, addr)
+    }
+
+    fn read_ram(&self, sram: &[u8], addr: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF
+        }
+        let mut offset = 0;
+        if self.advanced_banking && self.rom[RAM_SIZE] == 0x03 { // 32 KiB RAM carts only
+            offset = self.ram_bank as usize * 0x2000;
+        }
+        sram[offset + (addr & 0x1FFF) as usize]
+    }
+
+    fn write(&mut self, sram: &mut [u8], addr: u16, val: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_enabled = val & 0xF == 0xA,
+            0x2000..=0x3FFF => self.rom_bank = val & 0x1F,
+            0x4000..=0x5FFF => self.ram_bank = val & 0x3,
+            0x6000..=0x7FFF => self.advanced_banking = val & 0x1 == 1,
+            0xA000..=0xBFFF => {
+                if self.ram_enabled {
+                    let mut offset = 0;
+                    if self.advanced_banking && self.rom[RAM_SIZE] == 0x03 { // 32 KiB RAM carts only
+                        offset = self.ram_bank as usize * 0x2000;
+                    }
+                    sram[offset + (addr & 0x1FFF) as usize] = val;
+                }
+            },
+            _ => unreachable!("should not have recieved values outside of this region.")
+        }
+    }
+
+    fn bess_block(&self) -> Option<Vec<u8>> {
+        Some(vec![0x00, 0x00, if self.ram_enabled { 0x0A } else { 0x00 }, 0x00, 0x20, self.rom_bank, 0x00, 0x40, self.ram_bank, 0x00, 0x60, self.advanced_banking as u8])
+    }
+
+    fn current_rom_bank(&self) -> u16 {
+        let lower = self.rom_bank.max(1) as u32; // the 0 check sees all 5 bits, even on a multicart
+        (self.upper_bank() | if self.multicart { lower & 0x0F } else { lower }) as u16
+    }
+
+    fn rom_bank0(&self) -> u16 {
+        if self.advanced_banking { self.upper_bank() as u16 } else { 0 }
+    }
+
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn rom_mut(&mut self) -> &mut [u8] {
+        &mut self.rom
+    }
+
+    fn state(&self) -> MapperState {
+        MapperState { ram_enabled: self.ram_enabled, advanced_banking: self.advanced_banking, rom_bank: self.rom_bank, rom_bank_high: 0, ram_bank: self.ram_bank }
+    }
+
+    fn restore_state(&mut self, state: &MapperState) {
+        self.ram_enabled = state.ram_enabled;
+        self.advanced_banking = state.advanced_banking;
+        self.rom_bank = state.rom_bank;
+        self.ram_bank = state.ram_bank;
+    }
+}
+
+#[derive(Default)]
+pub struct Mbc3 {
+    rom: Vec<u8>,
+    ram_enabled: bool,
+    rom_bank: u8, // 7 bits, writing 0 gets 1
+    ram_bank: u8 // 0-3, the RTC registers past that aren't there yet
+}
+
+impl Mapper for Mbc3 {
+    fn read_rom(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x3FFF => self.rom[(addr & 0x3FFF) as usize],
+            _ => banked(&self.rom, self.rom_bank as u32, addr)
+        }
+    }
+
+    fn read_ram(&self, sram: &[u8], addr: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF
+        }
+        if self.ram_bank > 0x03 {
+            unreachable!("did not implemenent RTC stuff yet.")
+        }
+        let offset = ((self.ram_bank as u32) << 13) | ((addr as u32) & 0x1FFF);
+        sram[(offset as usize) & (sram.len() - 1)]
+    }
+
+    fn write(&mut self, sram: &mut [u8], addr: u16, val: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_enabled = val & 0xF == 0x0A,
+            0x2000..=0x3FFF => self.rom_bank = if val & 0x7F == 0x00 { 0x01 } else { val & 0x7F },
+            0x4000..=0x5FFF => self.ram_bank = val,
+            0x6000..=0x7FFF => (), // Latch Clock Data (Write Only)
+            0xA000..=0xBFFF => {
+                if self.ram_enabled {
+                    let offset = ((self.ram_bank as u32) << 13) | ((addr as u32) & 0x1FFF);
+                    let sram_len = sram.len() - 1;
+                    sram[(offset as usize) & sram_len] = val;
+                }
+            }
+
+            _ => panic!("should not have recieved values outside of this region.")
+        }
+    }
+
+    // latch key not implemented as well as RTC register
+    fn bess_block(&self) -> Option<Vec<u8>> {
+        Some(vec![0x00, 0x00, if self.ram_enabled { 0x0A } else { 0x00 }, 0x00, 0x20, if self.rom_bank == 0x01 { 0x00 } else { self.rom_bank }, 0x00, 0x40, self.ram_bank])
+    }
+
+    fn current_rom_bank(&self) -> u16 {
+        self.rom_bank as u16
+    }
+
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn rom_mut(&mut self) -> &mut [u8] {
+        &mut self.rom
+    }
+
+    fn state(&self) -> MapperState {
+        MapperState { ram_enabled: self.ram_enabled, rom_bank: self.rom_bank, ram_bank: self.ram_bank, ..MapperState::default() }
+    }
+
+    fn restore_state(&mut self, state: &MapperState) {
+        self.ram_enabled = state.ram_enabled;
+        self.rom_bank = state.rom_bank;
+        self.ram_bank = state.ram_bank;
+    }
+}
+
+/* DOESNT PASS MOONEYE MBC5 */
+#[derive(Default)]
+pub struct Mbc5 {
+    rom: Vec<u8>,
+    ram_enabled: bool,
+    rom_bank: u8, // the low 8 bits, 0 is bank 0
+    rom_bank_high: u8, // bit 8
+    ram_bank: u8
+}
+
+impl Mapper for Mbc5 {
+    fn read_rom(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x3FFF => self.rom[(addr & 0x3FFF) as usize],
+            _ => banked(&self.rom, self.current_rom_bank() as u32, addr)
+        }
+    }
+
+    fn read_ram(&self, sram: &[u8], addr: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF
+        }
+        let offset = ((self.ram_bank as u32) << 13) | ((addr as u32) & 0x1FFF);
+        sram[(offset as usize) & (sram.len() - 1)]
+    }
+
+    fn write(&mut self, sram: &mut [u8], addr: u16, val: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram_enabled = val & 0x0F == 0x0A,
+            0x2000..=0x2FFF => self.rom_bank = val,
+            0x3000..=0x3FFF => self.rom_bank_high = val & 0x01,
+            0x4000..=0x5FFF => self.ram_bank = val & 0x0F,
+            0xA000..=0xBFFF => {
+                if self.ram_enabled {
+                    let offset = ((self.ram_bank as u32) << 13) | ((addr as u32) & 0x1FFF);
+                    sram[offset as usize] = val;
+                }
+            }
+            0x6000..=0x7FFF => (), // region not mapped in MBC5
+
+            _ => panic!("should not have recieved values outside of this region.")
+        }
+    }
+
+    fn bess_block(&self) -> Option<Vec<u8>> {
+        Some(vec![0x00, 0x00, if self.ram_enabled { 0x0A } else { 0x00 }, 0x00, 0x20, self.rom_bank, 0x00, 0x30, self.rom_bank_high, 0x00, 0x40, self.ram_bank])
+    }
+
+    fn current_rom_bank(&self) -> u16 {
+        ((self.rom_bank_high as u16) << 8) | self.rom_bank as u16
+    }
+
+    fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    fn rom_mut(&mut self) -> &mut [u8] {
+        &mut self.rom
+    }
+
+    fn state(&self) -> MapperState {
+        MapperState { ram_enabled: self.ram_enabled, advanced_banking: false, rom_bank: self.rom_bank, rom_bank_high: self.rom_bank_high, ram_bank: self.ram_bank }
+    }
+
+    fn restore_state(&mut self, state: &MapperState) {
+        self.ram_enabled = state.ram_enabled;
+        self.rom_bank = state.rom_bank;
+        self.rom_bank_high = state.rom_bank_high;
+        self.ram_bank = state.ram_bank;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // every byte is the number of its bank
+    fn numbered_rom(mbc: u8, banks: usize) -> Vec<u8> {
+        let mut rom: Vec<u8> = (0..banks * 0x4000).map(|i| (i / 0x4000) as u8).collect();
+        rom[MBC_TYPE] = mbc;
+        rom
+    }
+
+    #[test]
+    fn mbc1_banks_wrap_and_bank_0_reads_as_1() {
+        let mut mbc = for_cartridge(numbered_rom(0x01, 8));
+        let mut sram = vec![0; 0x2000];
+        assert_eq!((mbc.read_rom(0x0000), mbc.read_rom(0x4000)), (0, 1));
+        mbc.write(&mut sram, 0x2000, 0x00);
+        assert_eq!(mbc.read_rom(0x4000), 1);
+        mbc.write(&mut sram, 0x2000, 0x0B);
+        assert_eq!((mbc.read_rom(0x4000), mbc.current_rom_bank()), (3, 0x0B));
+        assert_eq!(mbc.read_ram(&sram, 0xA000), 0xFF);
+    }
+
+    #[test]
+    fn mbc1m_takes_4_bits_of_the_lower_bank() {
+        let mut rom = numbered_rom(0x01, 64);
+        rom[0x40104..0x40134].copy_from_slice(&NINTENDO_LOGO); // the game in banks 0x10-0x1F
+        let mut mbc = for_cartridge(rom);
+        let mut sram = vec![0; 0x2000];
+
+        mbc.write(&mut sram, 0x4000, 0x01);
+        mbc.write(&mut sram, 0x2000, 0x12); // bit 4 of it is dropped
+        assert_eq!(mbc.read_rom(0x4000), 0x12);
+        mbc.write(&mut sram, 0x2000, 0x10); // still not 0 to the MBC
+        assert_eq!(mbc.read_rom(0x4000), 0x10);
+        mbc.write(&mut sram, 0x6000, 0x01);
+        assert_eq!((mbc.read_rom(0x0000), mbc.rom_bank0()), (0x10, 0x10));
+    }
+}
@@ -1,64 +1,294 @@
-use crate::internal::ppu::{PPU, Display};
-use crate::internal::timer::Timer;
-use crate::internal::apu::APU;
-use crate::{u32_to_little_endian, console_log, log};
+use std::cell::{Cell, RefCell};
+use wasm_bindgen::prelude::*;
+use crate::internal::ppu::{PPU, Display, PpuMode, OamBugAccess, MapViewport, OamEntry};
+use crate::internal::timer::{Timer, TimerState};
+use crate::internal::apu::{APU, ApuSnapshot, AudioQuality};
+use crate::internal::sgb::{self, Sgb};
+use crate::internal::serial::{Serial, SerialDevice, Disconnected};
+use crate::internal::joypad::Joypad;
+use crate::internal::snapshot::{StateWriter, StateReader, StateError};
+use crate::internal::bess::BufferLimits;
+use crate::internal::debugger::{Watchpoints, WatchHit, Access};
+use crate::internal::mapper::{self, Mapper, MapperState, NoMbc, MBC_TYPE, RAM_SIZE};
+use crate::u32_to_little_endian;
 
-const MBC_TYPE: usize = 0x0147;
-const RAM_SIZE: usize = 0x0149;
+pub const RGBA_FRAME_LEN: usize = 160 * 144 * 4;
+pub const DMG_GREEN: [[u8; 3]; 4] = [[0x9B, 0xBC, 0x0F], [0x8B, 0xAC, 0x0F], [0x30, 0x62, 0x30], [0x0F, 0x38, 0x0F]];
 
-#[derive(PartialEq)]
-enum BankingMode {
-    SIMPLE, ADVANCED
+// post processing for the RGBA output, Blend2 averages every frame with the one before it like the DMG's slow
+// LCD does, which games rely on to make flickering objects look transparent
+#[wasm_bindgen]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FrameBlend {
+    Off, Blend2
 }
 
-#[derive(PartialEq, Debug)]
-enum MemoryBank {
-    MBCNONE, MBC1, MBC1M, MBC3, MBC5
+// bits of FF00-FF7F that aren't there and read as 1, on top of whatever reading the register gave. the APU masks its
+// own registers, and the registers only the CGB has read 0xFF on the DMG whatever their mask
+const IO_READ_MASKS: [u8; 0x80] = [
+    0xC0, 0x00, 0x7E, 0xFF, 0x00, 0x00, 0x00, 0xF8, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xE0, // joypad, serial, timer, IF
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // APU
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // wave RAM
+    0x00, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0xFF, // PPU, then the CGB's
+    0xFE, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+    0xF8, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF // SVBK
+];
+
+// what made an access a BusObserver sees
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BusSource {
+    Cpu, OamDma
+}
+
+// sees every read and write the CPU and the OAM DMA make as they happen, peek and poke aren't accesses and neither
+// are CPU accesses a DMA keeps off the bus. it's called in the middle of an M-cycle, so it should be quick
+pub trait BusObserver {
+    fn on_read(&mut self, _addr: u16, _val: u8, _source: BusSource) {}
+    fn on_write(&mut self, _addr: u16, _val: u8, _source: BusSource) {}
+}
+
+// what WRAM, HRAM and VRAM hold at power on. the chips come up with garbage on hardware, which some homebrew only
+// trips over there. Random makes up the same garbage for the same seed, so runs stay reproducible
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum PowerOnRam {
+    #[default]
+    Zeros,
+    Pattern55AA,
+    Random { seed: u64 }
+}
+
+impl PowerOnRam {
+    // one splitmix64 stream over all of them in turn
+    fn fill(&self, rams: [&mut [u8]; 3]) {
+        let mut state = match *self {
+            PowerOnRam::Random { seed } => seed,
+            _ => 0
+        };
+        for ram in rams {
+            for (i, byte) in ram.iter_mut().enumerate() {
+                *byte = match self {
+                    PowerOnRam::Zeros => 0x00,
+                    PowerOnRam::Pattern55AA => if i % 2 == 0 { 0x55 } else { 0xAA },
+                    PowerOnRam::Random { .. } => {
+                        state = state.wrapping_add(0x9E3779B97F4A7C15);
+                        let mut z = state;
+                        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+                        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+                        (z ^ (z >> 31)) as u8
+                    }
+                }
+            }
+        }
+    }
+
+    fn write_state(&self, w: &mut StateWriter) {
+        match *self {
+            PowerOnRam::Zeros => { w.u8(0); w.u64(0) },
+            PowerOnRam::Pattern55AA => { w.u8(1); w.u64(0) },
+            PowerOnRam::Random { seed } => { w.u8(2); w.u64(seed) }
+        }
+    }
+
+    fn read_state(r: &mut StateReader) -> Result<PowerOnRam, StateError> {
+        let (kind, seed) = (r.u8()?, r.u64()?);
+        match kind {
+            0 => Ok(PowerOnRam::Zeros),
+            1 => Ok(PowerOnRam::Pattern55AA),
+            2 => Ok(PowerOnRam::Random { seed }),
+            _ => Err(StateError::InvalidData("power on RAM out of range"))
+        }
+    }
+}
+
+// what's emulated, only WRAM banking tells the CGB apart so far
+#[wasm_bindgen]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Model {
+    Dmg, Cgb
+}
+
+// the console a boot ROM of len bytes is for. the CGB's is mapped at 0x0000-0x00FF and 0x0200-0x08FF, dumps come
+// with or without the 0x100 bytes in between
+pub fn boot_rom_model(len: usize) -> Option<Model> {
+    match len {
+        0x100 => Some(Model::Dmg),
+        0x800 | 0x900 => Some(Model::Cgb),
+        _ => None
+    }
+}
+
+const CARTRIDGE_HEADER_END: usize = 0x0150;
+const SGB_FLAG: usize = 0x0146;
+const OLD_LICENSEE: usize = 0x014B;
+
+// one OAM DMA transfer, counted in M-cycles from the FF46 write: 1 to set up, then a byte per M-cycle for 160
+#[derive(Clone, Copy, Default)]
+struct OamDma {
+    source: u16,
+    elapsed: u8
+}
+
+impl OamDma {
+    fn write_state(dma: &Option<OamDma>, w: &mut StateWriter) {
+        let state = dma.unwrap_or_default();
+        w.bool(dma.is_some());
+        w.u16(state.source);
+        w.u8(state.elapsed);
+    }
+
+    fn read_state(r: &mut StateReader) -> Result<Option<OamDma>, StateError> {
+        let active = r.bool()?;
+        let dma = OamDma { source: r.u16()?, elapsed: r.u8()? };
+        Ok(if active { Some(dma) } else { None })
+    }
+}
+
+// everything in Memory that changes while a cartridge runs, copied by value
+#[derive(Clone)]
+pub struct MemorySnapshot {
+    wram: [u8; 0x8000],
+    svbk: u8,
+    hram: [u8; 0x7F],
+    pub(crate) sram: Vec<u8>,
+    mapper: MapperState,
+    IE: u8,
+    IF: u8,
+    boot_rom_mapped: bool,
+    joyp: u8,
+    ppu: PPU,
+    timer: Timer,
+    dma_register: u8,
+    oam_dma: Option<OamDma>,
+    oam_dma_restart: Option<OamDma>,
+    sgb: Sgb,
+    apu: ApuSnapshot,
+    serial: Serial,
+    power_on_ram: PowerOnRam
+}
+
+// what the CPU's accesses go to. Flat is for instruction tests: a plain 64 KiB of RAM, no cartridge, PPU, timer or
+// IO registers behind it and nothing ticking, only IE and IF stay fields for the interrupt logic to look at
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum BusConfig {
+    Hardware, Flat
 }
 
 pub struct Memory {
     // testing
-    pub flat_ram: bool,
+    pub config: BusConfig,
+    pub access_lockout: bool, // VRAM and OAM can't be touched while the PPU reads them, the flat bus has no PPU regardless
+    pub oam_bug: bool,
+    oam_bug_access: Cell<Option<OamBugAccess>>, // reads don't take &mut self, the PPU gets it at the end of the M-cycle
+    pub watchpoints: Watchpoints, // never part of a snapshot
+    watch_hit: Cell<Option<WatchHit>>, // same as oam_bug_access, the CPU takes it every M-cycle
+    observer: Option<RefCell<Box<dyn BusObserver>>>, // a RefCell for the same reason, never part of a snapshot
+    flat_memory: Vec<u8>, // the flat bus' address space, allocated on first write
+    flat_writes: Vec<(u16, u8)>, // every write to it since the last take_flat_writes
 
     // used for save files
     pub bess_buffer_offsets: Vec<u8>, 
 
-    rom_chip: Vec<u8>,
-    wram: [u8; 0x2000],
+    mapper: Box<dyn Mapper>, // with the ROM
+    wram: [u8; 0x8000], // 8 banks of 4 KiB, the DMG only has the first 2
+    model: Model,
+    svbk: u8,
     hram: [u8; 0x7F],
     pub sram: Vec<u8>, // resize to fit all banks of cartridge (if any)
 
-    boot_rom: [u8; 0x100],
-    mbc_ram_enabled: bool,
-
-    memory_bank: MemoryBank,
-    banking_mode: BankingMode,
-    rom_bank_number: u8,
-    mbc5_rom_bank_number_top_bit: u8,
-    ram_rom_bank_number: u8,
+    boot_rom: Vec<u8>, // laid out like it's mapped, the CGB's with the header window left in
+    boot_rom_mapped: bool, // over the cartridge until FF50 is written, only a reset maps it again
+    power_on_ram: PowerOnRam, // what the RAM is filled with at power on, a reset fills it the same way
 
     pub IE: u8,
     pub IF: u8,
 
-    pub keypress: i8,
-    joyp: u8,
+    pub joypad: Joypad,
 
+    palette: [[u8; 3]; 4], // RGB for shades 0 (lightest) to 3, only used for RGBA output
+    frame_blend: FrameBlend,
     ppu: PPU,
-    //apu: APU,
-    pub timer: Timer
+    apu: APU,
+    serial: Serial,
+    serial_device: Box<dyn SerialDevice>,
+    serial_output: Vec<u8>, // every byte sent on the internal clock since take_serial_output, left out of snapshots
+    pub timer: Timer,
+
+    dma_register: u8,
+    oam_dma: Option<OamDma>,
+    oam_dma_restart: Option<OamDma>, // written while another transfer runs, which keeps going until this one is set up
+
+    sgb_supported: bool, // the header asks for SGB functions, the joypad port only listens for packets if it does
+    sgb: Sgb
 }
 
 impl Memory {
-    const NINTENDO_LOGO: [u8; 48] = [0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
-                                      0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
-                                      0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E];
+    // mapped from here on, the CPU has to start at 0 for it to run. the length has been checked with boot_rom_model, a
+    // CGB boot ROM without the 0x100 bytes under the cartridge header gets them back as filler
+    pub fn mount_bootrom(&mut self, boot_rom: &[u8]) {
+        self.boot_rom = boot_rom.to_vec();
+        if boot_rom.len() == 0x800 {
+            self.boot_rom.splice(0x100..0x100, [0x00; 0x100]);
+        }
+        self.boot_rom_mapped = true;
+    }
+
+    // fills the RAM the way the console powering on would
+    pub fn power_on(&mut self) {
+        self.power_on_ram.fill([&mut self.wram, &mut self.ppu.vram, &mut self.hram]);
+    }
+
+    pub fn power_on_ram(&self) -> PowerOnRam {
+        self.power_on_ram
+    }
+
+    // only takes effect at the next power on
+    pub fn set_power_on_ram(&mut self, ram: PowerOnRam) {
+        self.power_on_ram = ram;
+    }
+
+    // only set while nothing is loaded, what's in WRAM and how it's banked is left to the next cartridge
+    pub fn set_model(&mut self, model: Model) {
+        self.model = model;
+    }
 
-    pub fn load_cartridge(&mut self, bytes: Vec<u8>) {
-        self.rom_chip = bytes;
+    // C000-CFFF and its echo are always bank 0, D000-DFFF is bank 1 on the DMG and the one SVBK picks on the CGB,
+    // where picking 0 gets 1 too
+    fn wram_offset(&self, addr: u16) -> usize {
+        let addr = (addr & 0x1FFF) as usize;
+        let bank = match self.model {
+            Model::Dmg => 1,
+            Model::Cgb => self.svbk.max(1) as usize
+        };
+        if addr < 0x1000 { addr } else { bank * 0x1000 + addr - 0x1000 }
+    }
+
+    fn wram_len(&self) -> usize {
+        match self.model {
+            Model::Dmg => 0x2000,
+            Model::Cgb => 0x8000
+        }
+    }
+
+    pub fn rom(&self) -> &[u8] {
+        self.mapper.rom()
+    }
+
+    // leaves everything as it was when the header is cut short or names a mapper there's no support for
+    pub fn load_cartridge(&mut self, bytes: Vec<u8>) -> Result<(), String> {
+        if bytes.len() < CARTRIDGE_HEADER_END {
+            return Err(format!("cartridge is {} bytes, shorter than its header", bytes.len()));
+        }
+        if !mapper::supported(bytes[MBC_TYPE]) {
+            return Err(format!("cartridge type 0x{:02X} is not supported", bytes[MBC_TYPE]));
+        }
+        self.sgb_supported = bytes[SGB_FLAG] == 0x03 && bytes[OLD_LICENSEE] == 0x33;
+        self.sgb = Sgb::default();
 
         self.sram.resize(0x2000, 0x00); // some cartridges "use MBC" but actually dont so just initializing 16 KiB by default
 
-        match self.rom_chip[RAM_SIZE] {
+        match bytes[RAM_SIZE] {
             0x00 => (), // No RAM
             0x01 => (), // Unused
             0x02 => self.sram.resize(0x2000, 0x00), // 1 bank
@@ -68,297 +298,341 @@ impl Memory {
             _ => ()
         }
 
-        match self.rom_chip[MBC_TYPE] {
-            0x00 => {
-                self.memory_bank = MemoryBank::MBCNONE;
-                self.rom_chip.resize(0x10000, 0x00);
-            },
-            0x01..=0x03 => {
-                self.memory_bank = MemoryBank::MBC1;
-
-                // checks if MBC1M instead
-                let mut logo_ptr = (Memory::NINTENDO_LOGO.len() - 1) as i8;
-                for i in (0x4000..0x8000).rev() {
-                    if logo_ptr < 0 {
-                        self.memory_bank = MemoryBank::MBC1M;
-                        panic!("not implemented MBC1M yet!");
-                    }
+        self.mapper = mapper::for_cartridge(bytes);
+        Ok(())
+    }
 
-                    let bank_ten_ptr = ((0x10 as u32) << 14) | (i & 0x3FFF);
-                    if bank_ten_ptr >= self.rom_chip.len() as u32 { break } // out of bounds
-                    if self.rom_chip[bank_ten_ptr as usize] == Memory::NINTENDO_LOGO[logo_ptr as usize] {
-                        logo_ptr -= 1;
-                    } else {
-                        logo_ptr = (Memory::NINTENDO_LOGO.len() - 1) as i8;
-                    }
-                }
-            },
-            0x0F..=0x13 => self.memory_bank = MemoryBank::MBC3,
-            0x19..=0x1E => self.memory_bank = MemoryBank::MBC5,
-            _ => panic!("MBC NOT IMPLEMENTED YET! 0x{:02X}", self.rom_chip[MBC_TYPE])
-        };
+    // the IO registers as the DMG boot ROM leaves them, with DIV's internal counter where it ends up. audio is put
+    // back the way a BESS file is so nothing is triggered
+    pub fn initialize_io(&mut self) {
+        self.boot_rom_mapped = false;
+        self.joypad.write(0xCF);
+        self.serial.write_registers(0xFF01, 0x00);
+        self.serial.write_registers(0xFF02, 0x7E);
+        self.timer.restore_state(&TimerState { div: 0xABCC, ..TimerState::from_registers(0xAB, 0x00, 0x00, 0xF8) });
+        self.IF = 0x01;
+        self.IE = 0x00;
+        let apu = [
+            0x80, 0xBF, 0xF3, 0xFF, 0xBF, 0xFF, 0x3F, 0x00, 0xFF, 0xBF, 0x7F, 0xFF, 0x9F, 0xFF, 0xBF, 0xFF,
+            0xFF, 0x00, 0x00, 0xBF, 0x77, 0xF3, 0xF1
+        ];
+        for (i, &val) in apu.iter().enumerate() {
+            self.apu.restore_bess_register(0xFF10 + i as u16, val);
+        }
+        self.ppu.restore_control(0x91);
+        for (addr, val) in [(0xFF42, 0x00), (0xFF43, 0x00), (0xFF45, 0x00), (0xFF47, 0xFC), (0xFF4A, 0x00), (0xFF4B, 0x00)] {
+            self.ppu.write_registers(addr, val);
+        }
+        self.dma_register = 0xFF;
+    }
+
+    // byte 014D, which the boot ROM checks and leaves its mark of in F
+    pub fn header_checksum(&self) -> u8 {
+        self.rom().get(0x14D).copied().unwrap_or(0x00)
     }
 
     pub fn get_rom_info(&self) -> Vec<u8> {
         let mut info = vec![];
-        info.extend_from_slice(&self.rom_chip[0x134..=0x143]); // title
-        info.extend_from_slice(&self.rom_chip[0x14E..=0x14F]); // global checksum
+        info.extend_from_slice(&self.rom()[0x134..=0x143]); // title
+        info.extend_from_slice(&self.rom()[0x14E..=0x14F]); // global checksum
         info
     }
 
+    #[cfg(test)]
+    pub fn take_flat_writes(&mut self) -> Vec<(u16, u8)> {
+        std::mem::take(&mut self.flat_writes)
+    }
+
     pub fn read(&self, addr: u16) -> u8 {
+        if self.config == BusConfig::Flat {
+            return if self.flat_memory.is_empty() { 0x00 } else { self.flat_memory[addr as usize] };
+        }
+        if self.oam_dma_running() && addr < 0xFF00 { // the DMA owns the bus, only IO and HRAM can be reached
+            return 0xFF;
+        }
+        self.note_oam_bug(addr, OamBugAccess::Read);
+        let val = self.bus_read(addr);
+        if !self.watchpoints.is_empty() {
+            self.note_watch(addr, val, Access::Read, false);
+        }
+        if let Some(observer) = &self.observer {
+            observer.borrow_mut().on_read(addr, val, BusSource::Cpu);
+        }
+        val
+    }
+
+    // what's there, without counting as a read: no OAM bug or watchpoint, the bus as if no DMA held it and VRAM and
+    // OAM as if the PPU didn't have them. registers come out the way they'd read, FF00 included
+    pub fn peek(&self, addr: u16) -> u8 {
+        if self.config == BusConfig::Flat {
+            return self.read(addr);
+        }
+        self.bus_read_with(addr, false)
+    }
+
+    // for hexdumps, wraps around at FFFF
+    pub fn peek_range(&self, addr: u16, len: usize) -> Vec<u8> {
+        (0..len).map(|i| self.peek(addr.wrapping_add(i as u16))).collect()
+    }
+
+    // changes what peek finds at addr and nothing else: past the PPU and a DMA like peek, into the ROM bank mapped
+    // there (MBC registers are left alone), and IO registers take the value without what writing it does, so FF46
+    // doesn't start a transfer and FF04 sets DIV instead of resetting it. no watchpoint sees it
+    pub fn poke(&mut self, addr: u16, val: u8) {
+        if self.config == BusConfig::Flat {
+            if self.flat_memory.is_empty() {
+                self.flat_memory = vec![0x00; 0x10000];
+            }
+            self.flat_memory[addr as usize] = val;
+            return
+        }
         match addr {
+            0x0000..=0x00FF | 0x0200..=0x08FF if self.boot_rom_mapped && (addr as usize) < self.boot_rom.len() => self.boot_rom[addr as usize] = val,
             0x0000..=0x7FFF => {
-                if self.memory_bank == MemoryBank::MBC1 {
-                    return self.mbc1_read(addr);
-                } else if self.memory_bank == MemoryBank::MBC3 {
-                    return self.mbc3_read(addr);
-                } else if self.memory_bank == MemoryBank::MBC5 {
-                    return self.mbc5_read(addr);
-                };
-                self.rom_chip[addr as usize]
-            },
-            0xA000..=0xBFFF => {
-                if self.memory_bank == MemoryBank::MBC1 {
-                    return self.mbc1_read(addr);
-                } else if self.memory_bank == MemoryBank::MBC3 {
-                    return self.mbc3_read(addr);
-                } else if self.memory_bank == MemoryBank::MBC5 {
-                    return self.mbc5_read(addr);
-                };
-                self.rom_chip[addr as usize]
+                let offset = self.rom_bank(addr).unwrap_or(0) as usize * 0x4000 + (addr & 0x3FFF) as usize;
+                if let Some(byte) = self.mapper.rom_mut().get_mut(offset) {
+                    *byte = val;
+                }
             },
-            0x8000..=0x9FFF => self.ppu.read_vram(addr - 0x8000),
-            0xC000..=0xDFFF => self.wram[(addr - 0xC000) as usize], // 4 KiB Work RAM (WRAM)
-            0xFE00..=0xFE9F => self.ppu.read_oam(addr - 0xFE00),
+            0xA000..=0xBFFF => self.mapper.write(&mut self.sram, addr, val), // only RAM is behind it, disabled it takes nothing
+            0x8000..=0x9FFF => self.ppu.vram[(addr - 0x8000) as usize] = val,
+            0xC000..=0xFDFF => self.wram[self.wram_offset(addr)] = val,
+            0xFE00..=0xFE9F => self.ppu.oam[(addr - 0xFE00) as usize] = val,
+            0xFF00 => self.joypad.restore_select(val),
+            0xFF01..=0xFF02 => self.serial.poke_registers(addr, val),
+            0xFF04..=0xFF07 => self.timer.poke_registers(addr, val),
+            0xFF0F => self.IF = val & 0x1F,
+            0xFF10..=0xFF3F => self.apu.restore_bess_register(addr, val),
+            0xFF46 => self.dma_register = val,
+            0xFF40..=0xFF4B => self.ppu.poke_registers(addr, val),
+            0xFF50 => self.boot_rom_mapped = val & 0x01 == 0 && !self.boot_rom.is_empty(),
+            0xFF70 if self.model == Model::Cgb => self.svbk = val & 0x07,
+            0xFF80..=0xFFFE => self.hram[(addr - 0xFF80) as usize] = val,
+            0xFFFF => self.IE = val,
+            _ => ()
+        }
+    }
+
+    // a byte of the ROM as if bank were mapped over 4000-7FFF, bank 0 below it. None outside the ROM window or past
+    // the end of the ROM
+    pub fn rom_bank_byte(&self, bank: u16, addr: u16) -> Option<u8> {
+        let offset = match addr {
+            0x0000..=0x3FFF => addr as usize,
+            0x4000..=0x7FFF => bank as usize * 0x4000 + (addr & 0x3FFF) as usize,
+            _ => return None
+        };
+        self.rom().get(offset).copied()
+    }
+
+    // the ROM bank reads of addr go to right now, None outside ROM
+    pub fn rom_bank(&self, addr: u16) -> Option<u16> {
+        let banks = (self.rom().len() / 0x4000).max(1) as u16;
+        let bank = match addr {
+            0x8000.. => return None,
+            0x0000..=0x3FFF => self.mapper.rom_bank0(),
+            _ => self.mapper.current_rom_bank()
+        };
+        Some(bank & (banks - 1)) // ROM sizes are powers of 2
+    }
+
+    fn bus_read(&self, addr: u16) -> u8 {
+        self.bus_read_with(addr, self.access_lockout)
+    }
+
+    // locked is whether the PPU keeps VRAM and OAM to itself while it reads them
+    fn bus_read_with(&self, addr: u16, locked: bool) -> u8 {
+        match addr {
+            0x0000..=0x00FF | 0x0200..=0x08FF if self.boot_rom_mapped && (addr as usize) < self.boot_rom.len() => self.boot_rom[addr as usize],
+            0x0000..=0x7FFF => self.mapper.read_rom(addr),
+            0xA000..=0xBFFF => self.mapper.read_ram(&self.sram, addr),
+            0x8000..=0x9FFF => if locked { self.ppu.read_vram(addr - 0x8000) } else { self.ppu.vram[(addr - 0x8000) as usize] },
+            0xC000..=0xFDFF => self.wram[self.wram_offset(addr)], // Work RAM (WRAM) and its echo
+            0xFE00..=0xFE9F => if locked { self.ppu.read_oam(addr - 0xFE00) } else { self.ppu.oam[(addr - 0xFE00) as usize] },
+            0xFEA0..=0xFEFF => if locked { self.ppu.read_unusable() } else { 0x00 }, // not usable, OAM bug included
+            0xFF00..=0xFF7F => self.read_io(addr) | IO_READ_MASKS[(addr - 0xFF00) as usize],
+            0xFF80..=0xFFFE => self.hram[(addr - 0xFF80) as usize], // High RAM (HRAM)
+            0xFFFF => self.IE
+        }
+    }
+
+    fn read_io(&self, addr: u16) -> u8 {
+        match addr {
             0xFF00 => {
-                if self.keypress != -1 {
-                    let mut buttons_pressed = 0xF;
-    
-                    if ((self.joyp >> 4) & 0x1 == 0) && ((self.joyp >> 5) & 0x1 == 1) { // DPAD
-                        buttons_pressed = match self.keypress {
-                            1 => buttons_pressed & !(1 << 2), // UP
-                            2 => buttons_pressed & !(1 << 1), // LEFT
-                            3 => buttons_pressed & !(1 << 3), // DOWN
-                            4 => buttons_pressed & !(1 << 0), // RIGHT 
-                            _ => 0xF
-                        };
-                    } else if ((self.joyp >> 5) & 0x1 == 0) && ((self.joyp >> 4) & 0x1 == 1) { // SELECT
-                        buttons_pressed = match self.keypress {
-                            5 => buttons_pressed & !(1 << 0), // A
-                            6 => buttons_pressed & !(1 << 1), // B
-                            7 => buttons_pressed & !(1 << 3), // START
-                            8 => buttons_pressed & !(1 << 2), // SELECT 
-                            _ => 0xF
-                        };
-                    }
-                    return buttons_pressed
+                // the other joypads of an SGB multiplayer setup have nothing pressed, with both lines high the
+                // port tells which one is selected
+                if self.sgb_supported && self.sgb.player != 0 {
+                    return if self.joypad.select() == 0x30 { 0xFF - self.sgb.player } else { 0xCF | self.joypad.select() };
                 }
-                return 0xFF;
+                self.joypad.read()
             }
+            0xFF01..=0xFF02 => self.serial.read_registers(addr),
             0xFF04..=0xFF07 => self.timer.read_registers(addr),
             0xFF0F => self.IF,
-            //0xFF10..=0xFF3F => self.apu.read_registers(addr),
+            0xFF10..=0xFF3F => self.apu.read_registers(addr),
+            0xFF46 => self.dma_register,
             0xFF40..=0xFF4B => self.ppu.read_registers(addr),
-            0xFF50 => 0x01,
-            0xFF80..=0xFFFE => self.hram[(addr - 0xFF80) as usize], // High RAM (HRAM)
-            0xFFFF => self.IE,
-
-            _ => 0xFF
+            0xFF50 => !self.boot_rom_mapped as u8,
+            0xFF70 if self.model == Model::Cgb => self.svbk,
+            _ => 0xFF // nothing there on the DMG
         }
     }
 
     pub fn write(&mut self, addr: u16, val: u8) {
+        if self.config == BusConfig::Flat {
+            if self.flat_memory.is_empty() {
+                self.flat_memory = vec![0x00; 0x10000];
+            }
+            self.flat_memory[addr as usize] = val;
+            self.flat_writes.push((addr, val));
+            return
+        }
+        if self.oam_dma_running() && addr < 0xFF00 {
+            return
+        }
+        self.note_oam_bug(addr, OamBugAccess::Write);
+        if !self.watchpoints.is_empty() {
+            self.note_watch(addr, val, Access::Write, false);
+        }
+        if let Some(observer) = &self.observer {
+            observer.borrow_mut().on_write(addr, val, BusSource::Cpu);
+        }
+
         match addr {
-            0x0000..=0x7FFF => {
-                if self.memory_bank == MemoryBank::MBC1 {
-                    self.mbc1_write(addr, val)
-                } else if self.memory_bank == MemoryBank::MBC3 {
-                    self.mbc3_write(addr, val)
-                } else if self.memory_bank == MemoryBank::MBC5 {
-                    self.mbc5_write(addr, val)
+            0x0000..=0x7FFF | 0xA000..=0xBFFF => self.mapper.write(&mut self.sram, addr, val),
+            0x8000..=0x9FFF => if self.access_lockout { self.ppu.write_vram(addr - 0x8000, val) } else { self.ppu.vram[(addr - 0x8000) as usize] = val }, // 8 KiB Video RAM (VRAM)
+            0xC000..=0xFDFF => self.wram[self.wram_offset(addr)] = val, // Work RAM (WRAM) and its echo
+            0xFE00..=0xFE9F => if self.access_lockout { self.ppu.write_oam(addr - 0xFE00, val) } else { self.ppu.oam[(addr - 0xFE00) as usize] = val }, // Object attribute memory (OAM)
+            0xFEA0..=0xFEFF => (), // not usable, writes go nowhere
+            0xFF00 => {
+                self.joypad.write(val);
+                if self.sgb_supported && self.sgb.write_joypad(val) == Some(sgb::PAL_TRN) {
+                    self.sgb.palette_transfer(&self.ppu.vram_transfer());
                 }
             },
-            0xA000..=0xBFFF => {
-                if self.memory_bank == MemoryBank::MBC1 {
-                    self.mbc1_write(addr, val)
-                } else if self.memory_bank == MemoryBank::MBC3 {
-                    self.mbc3_write(addr, val)
-                } else if self.memory_bank == MemoryBank::MBC5 {
-                    self.mbc5_write(addr, val)
+            0xFF01..=0xFF02 => {
+                if let Some(out) = self.serial.write_registers(addr, val) {
+                    self.serial_output.push(out);
+                    self.serial_device.transfer_started(out);
                 }
             },
-            0x8000..=0x9FFF => self.ppu.write_vram(addr - 0x8000, val), // 8 KiB Video RAM (VRAM)
-            0xC000..=0xDFFF => self.wram[(addr - 0xC000) as usize] = val, // 4 KiB Work RAM (WRAM)
-            0xFE00..=0xFE9F => self.ppu.write_oam(addr - 0xFE00, val), // Object attribute memory (OAM)
-            0xFF00 => self.joyp = val,
             0xFF04..=0xFF07 => self.timer.write_registers(addr, val),
-            0xFF0F => self.IF = val,
-            //0xFF10..=0xFF3F => self.apu.write_registers(addr, val),
-            0xFF46 => self.oam_dma_transfer((val as u16) << 8),
+            0xFF0F => self.IF = val & 0x1F, // only 5 interrupts are wired, the other bits read back as 1
+            0xFF10..=0xFF3F => self.apu.write_registers(addr, val),
+            0xFF46 => self.start_oam_dma(val),
             0xFF40..=0xFF4B => self.ppu.write_registers(addr, val),
-            0xFF50 => (),
+            0xFF50 => if val != 0 { self.boot_rom_mapped = false },
+            0xFF70 if self.model == Model::Cgb => self.svbk = val & 0x07,
             0xFF80..=0xFFFE => self.hram[(addr - 0xFF80) as usize] = val, // High RAM (HRAM)
-            0xFFFF => self.IE = val,
+            0xFFFF => self.IE = val, // all 8 bits are kept although only the low 5 enable anything
 
             _ => ()
         }
     }
 
-    fn oam_dma_transfer(&mut self, source: u16) {
-        for i in 0..0xA0 {
-            self.ppu.oam[i] = self.read(source + (i as u16))
+    fn note_watch(&self, addr: u16, value: u8, access: Access, dma: bool) {
+        if self.watch_hit.get().is_none() && self.watchpoints.watches(addr, access, dma) {
+            self.watch_hit.set(Some(WatchHit { pc: 0, addr, value, access, dma }));
         }
     }
 
-    fn mbc1_read(&self, addr: u16) -> u8 {
-        match addr {
-            0x0000..=0x3FFF => {
-                let offset = if self.banking_mode == BankingMode::ADVANCED { ((self.ram_rom_bank_number as u32) << 19) | ((addr as u32) & 0x3FFF) } else { addr as u32 };
-                return self.rom_chip[(offset as usize) & (self.rom_chip.len() - 1)];
-            },
-            0x4000..=0x7FFF => {
-                let translated_bank_number = if self.rom_bank_number == 0x00 { 0x01 } else { self.rom_bank_number };
-                let offset = ((self.ram_rom_bank_number as u32) << 19) | ((translated_bank_number as u32) << 14) | ((addr as u32) & 0x3FFF);
-                return self.rom_chip[(offset as usize) & (self.rom_chip.len() - 1)];
-            },
-            0xA000..=0xBFFF => {
-                if self.mbc_ram_enabled {
-                    let mut offset = 0;
-                    if self.banking_mode == BankingMode::ADVANCED && self.rom_chip[RAM_SIZE] == 0x03 { // 32 KiB RAM carts only
-                        offset = (self.ram_rom_bank_number as u16) * 0x2000;
-                    }
-                    return self.sram[(offset + (addr & 0x1FFF)) as usize];
-                }
-                return 0xFF
-            },
-
-            _ => unreachable!("should not have recieved values outside of this region.")
-        }
+    pub fn set_observer(&mut self, observer: Box<dyn BusObserver>) {
+        self.observer = Some(RefCell::new(observer));
     }
 
-    fn mbc1_write(&mut self, addr: u16, val: u8) {
-        match addr {
-            0x0000..=0x1FFF => self.mbc_ram_enabled = val & 0xF == 0xA,
-            0x2000..=0x3FFF => self.rom_bank_number = val & 0x1F,
-            0x4000..=0x5FFF => self.ram_rom_bank_number = val & 0x3,
-            0x6000..=0x7FFF => self.banking_mode = if val & 0x1 == 1 { BankingMode::ADVANCED } else { BankingMode::SIMPLE },
-            0xA000..=0xBFFF => {
-                if self.mbc_ram_enabled {
-                    let mut offset = 0;
-                    if self.banking_mode == BankingMode::ADVANCED && self.rom_chip[RAM_SIZE] == 0x03 { // 32 KiB RAM carts only
-                        offset = (self.ram_rom_bank_number as u16) * 0x2000;
-                    }
-                    self.sram[(offset + (addr & 0x1FFF)) as usize] = val;
-                }
-            },
-            _ => unreachable!("should not have recieved values outside of this region.")
-        }
+    pub fn take_observer(&mut self) -> Option<Box<dyn BusObserver>> {
+        self.observer.take().map(RefCell::into_inner)
     }
 
-    fn mbc3_read(&self, addr: u16) -> u8 {
-        match addr {
-            0x0000..=0x3FFF => self.rom_chip[(addr & 0x3FFF) as usize],
-            0x4000..=0x7FFF => {
-                let offset = ((self.rom_bank_number as u32) << 14) | ((addr as u32) & 0x3FFF);
-                return self.rom_chip[(offset as usize) & (self.rom_chip.len() - 1)];
-            },
-            0xA000..=0xBFFF => {
-                if self.mbc_ram_enabled {
-                    if self.ram_rom_bank_number > 0x03 {
-                        unreachable!("did not implemenent RTC stuff yet.")
-                    }
+    // the first watched access since the last call, the CPU fills in PC
+    pub fn take_watch_hit(&self) -> Option<WatchHit> {
+        self.watch_hit.take()
+    }
 
-                    let offset = ((self.ram_rom_bank_number as u32) << 13) | ((addr as u32) & 0x1FFF);
-                    return self.sram[(offset as usize) & (self.sram.len() - 1)];
-                }
-                return 0xFF
-            },
+    // called by the CPU with the value a 16 bit register had before it was incremented or decremented
+    pub fn inc_dec_oam_bug(&self, addr: u16) {
+        self.note_oam_bug(addr, OamBugAccess::IncDec);
+    }
 
-            _ => unreachable!("should not have recieved values outside of this region.")
+    // all of an instruction's accesses in one M-cycle add up to a single corruption
+    fn note_oam_bug(&self, addr: u16, access: OamBugAccess) {
+        if !self.oam_bug || !(0xFE00..=0xFEFF).contains(&addr) {
+            return
         }
+        let merged = match (self.oam_bug_access.get(), access) {
+            (None, access) => access,
+            (Some(OamBugAccess::Read), OamBugAccess::IncDec) => OamBugAccess::ReadIncDec,
+            (Some(previous), OamBugAccess::Read) => previous,
+            (Some(_), _) => OamBugAccess::Write
+        };
+        self.oam_bug_access.set(Some(merged));
     }
 
-    fn mbc3_write(&mut self, addr: u16, val: u8) {
-        match addr {
-            0x0000..=0x1FFF => {
-                if val & 0xF == 0x0A {
-                    self.mbc_ram_enabled = true;
-                } else {
-                    self.mbc_ram_enabled = false;
-                }
-            },
-            0x2000..=0x3FFF => self.rom_bank_number = if val & 0x7F == 0x00 { 0x01 } else { val & 0x7F },
-            0x4000..=0x5FFF => self.ram_rom_bank_number = val,
-            0x6000..=0x7FFF => (), // Latch Clock Data (Write Only)
-            0xA000..=0xBFFF => {
-                if self.mbc_ram_enabled {
-                    let offset = ((self.ram_rom_bank_number as u32) << 13) | ((addr as u32) & 0x1FFF);
-                    let sram_len = self.sram.len() - 1;
-                    self.sram[(offset as usize) & sram_len] = val;
-                }
-            }
-
-            _ => panic!("should not have recieved values outside of this region.")
+    fn start_oam_dma(&mut self, val: u8) {
+        self.dma_register = val;
+        let dma = OamDma { source: (val as u16) << 8, elapsed: 0 };
+        if self.oam_dma.is_some() {
+            self.oam_dma_restart = Some(dma);
+        } else {
+            self.oam_dma = Some(dma);
         }
     }
 
-    /* DOESNT PASS MOONEYE MBC5 */
-    fn mbc5_read(&self, addr: u16) -> u8 {
-        match addr {
-            0x0000..=0x3FFF => self.rom_chip[(addr & 0x3FFF) as usize],
-            0x4000..=0x7FFF => {
-                let offset = ((self.mbc5_rom_bank_number_top_bit as u32) << 22) | ((self.rom_bank_number as u32) << 14) | ((addr as u32) & 0x3FFF); // 23 bits long?
-                return self.rom_chip[(offset as usize) & (self.rom_chip.len() - 1)];
-            },
-            0xA000..=0xBFFF => {
-                if self.mbc_ram_enabled {
-                    let offset = ((self.ram_rom_bank_number as u32) << 13) | ((addr as u32) & 0x1FFF);
-                    return self.sram[(offset as usize) & (self.sram.len() - 1)];
-                }
-                return 0xFF
-            },
-
+    fn oam_dma_running(&self) -> bool {
+        matches!(self.oam_dma, Some(dma) if dma.elapsed >= 2)
+    }
 
-            _ => panic!("should not have recieved values outside of this region.")
+    // the DMA's own view of the bus: it doesn't see the PPU's locks, and every source past WRAM reads its echo,
+    // so FE00-FFFF is more WRAM rather than OAM, IO and HRAM
+    fn dma_read(&self, addr: u16) -> u8 {
+        match addr {
+            0x8000..=0x9FFF => self.ppu.vram[(addr - 0x8000) as usize],
+            0xC000..=0xFFFF => self.wram[self.wram_offset(addr)],
+            _ => self.bus_read(addr)
         }
     }
 
-    /* DOESNT PASS MOONEYE MBC5 */
-    fn mbc5_write(&mut self, addr: u16, val: u8) {
-        match addr {
-            0x0000..=0x1FFF => self.mbc_ram_enabled = if val & 0x0F == 0x0A { true } else { false },
-            0x2000..=0x2FFF => self.rom_bank_number = val,
-            0x3000..=0x3FFF => self.mbc5_rom_bank_number_top_bit = val & 0x01,
-            0x4000..=0x5FFF => self.ram_rom_bank_number = val & 0x0F,
-            0xA000..=0xBFFF => {
-                if self.mbc_ram_enabled {
-                    let offset = ((self.ram_rom_bank_number as u32) << 13) | ((addr as u32) & 0x1FFF);
-                    self.sram[offset as usize] = val;
+    fn step_oam_dma(&mut self) {
+        if let Some(restart) = self.oam_dma_restart {
+            if restart.elapsed == 1 {
+                self.oam_dma = self.oam_dma_restart.take();
+            } else {
+                self.oam_dma_restart = Some(OamDma { elapsed: restart.elapsed + 1, ..restart });
+            }
+        }
+
+        if let Some(mut dma) = self.oam_dma {
+            dma.elapsed += 1;
+            if dma.elapsed > 161 {
+                self.oam_dma = None;
+                return
+            }
+            if dma.elapsed >= 2 {
+                let i = (dma.elapsed - 2) as u16;
+                self.ppu.oam[i as usize] = self.dma_read(dma.source + i);
+                if !self.watchpoints.is_empty() {
+                    self.note_watch(dma.source + i, self.ppu.oam[i as usize], Access::Read, true);
+                    self.note_watch(0xFE00 + i, self.ppu.oam[i as usize], Access::Write, true);
+                }
+                if let Some(observer) = &self.observer {
+                    let mut observer = observer.borrow_mut();
+                    observer.on_read(dma.source + i, self.ppu.oam[i as usize], BusSource::OamDma);
+                    observer.on_write(0xFE00 + i, self.ppu.oam[i as usize], BusSource::OamDma);
                 }
             }
-            0x6000..=0x7FFF => (), // region not mapped in MBC5
-        
-            _ => panic!("should not have recieved values outside of this region.")
+            self.oam_dma = Some(dma);
         }
     }
 
     pub fn create_bess_mbc_block(&self) -> Option<Vec<u8>> {
-        match self.memory_bank {
-            MemoryBank::MBCNONE => None,
-            MemoryBank::MBC1 => Some(vec![0x00, 0x00, if self.mbc_ram_enabled { 0x0A } else { 0x00 }, 0x00, 0x20, self.rom_bank_number, 0x00, 0x40, self.ram_rom_bank_number, 0x00, 0x60, if self.banking_mode == BankingMode::ADVANCED { 1 } else { 0 }]),
-            MemoryBank::MBC3 => Some(vec![0x00, 0x00, if self.mbc_ram_enabled { 0x0A } else { 0x00 }, 0x00, 0x20, if self.rom_bank_number == 0x01 { 0x00 } else { self.rom_bank_number }, 0x00, 0x40, self.ram_rom_bank_number]), // latch key not implemented as well as RTC register
-            MemoryBank::MBC5 => Some(vec![0x00, 0x00, if self.mbc_ram_enabled { 0x0A } else { 0x00 }, 0x00, 0x20, self.rom_bank_number, 0x00, 0x30, self.mbc5_rom_bank_number_top_bit, 0x00, 0x40, self.ram_rom_bank_number]),
-            _ => unreachable!()
-        }
+        self.mapper.bess_block()
     }
 
     pub fn aggregate_buffers(&mut self) -> Vec<u8> {
         let mut buffers = vec![];
 
-        self.bess_buffer_offsets.extend(u32_to_little_endian(self.wram.len() as u32)); // size of wram
+        self.bess_buffer_offsets.extend(u32_to_little_endian(self.wram_len() as u32)); // size of wram
         self.bess_buffer_offsets.extend(u32_to_little_endian(buffers.len() as u32)); // offset of wram
-        buffers.extend(self.wram);
+        buffers.extend(&self.wram[..self.wram_len()]);
 
         self.bess_buffer_offsets.extend(u32_to_little_endian(self.ppu.vram.len() as u32)); // size of vram
         self.bess_buffer_offsets.extend(u32_to_little_endian(buffers.len() as u32)); // offset of vram
@@ -379,42 +653,267 @@ impl Memory {
         buffers
     }
 
+    pub fn bess_buffer_limits(&self) -> BufferLimits {
+        BufferLimits { wram: self.wram_len(), vram: self.ppu.vram.len(), sram: self.sram.len(), oam: self.ppu.oam.len(), hram: self.hram.len() }
+    }
+
+    // counterpart of aggregate_buffers, sizes have already been checked against bess_buffer_limits
+    pub fn restore_bess_buffers(&mut self, wram: &[u8], vram: &[u8], sram: &[u8], oam: &[u8], hram: &[u8]) {
+        self.wram[..wram.len()].copy_from_slice(wram);
+        self.ppu.vram[..vram.len()].copy_from_slice(vram);
+        self.sram[..sram.len()].copy_from_slice(sram);
+        self.ppu.oam[..oam.len()].copy_from_slice(oam);
+        self.hram[..hram.len()].copy_from_slice(hram);
+    }
+
     pub fn update_requested_interrupts(&mut self) {
-        let mut requests: u8 = 0x0;
+        let mut requests: u8 = self.ppu.interrupt_requests; // VBLANK and STAT
+        self.ppu.interrupt_requests = 0;
 
-        if !self.ppu.vblank_irq_triggered && (self.ppu.read_registers(0xFF41) & 0x3 == 1) { // VBLANK interrupt
-            requests |= 0b00000001;
-            self.ppu.vblank_irq_triggered = true;
+        if self.timer.take_interrupt() {
+            requests |= 0b00000100; // TIMER interrupt
         }
+        if self.serial.take_interrupt() {
+            requests |= 0b00001000; // SERIAL interrupt
+        }
+        if self.joypad.take_interrupt() {
+            requests |= 0b00010000; // JOYPAD interrupt
+        }
+
+        self.IF |= requests;
+    }
 
-        if !self.ppu.stat_irq_triggered {
-            if ((self.ppu.read_registers(0xFF41) >> 6) & 0x1 == 1) && (self.ppu.read_registers(0xFF41) >> 2 & 0x1 == 1) { requests |= 0b00000010; } // STAT interrupt (LY == LYC)
-            if ((self.ppu.read_registers(0xFF41) >> 5) & 0x1 == 1) && (self.ppu.read_registers(0xFF41) & 0x3 == 2) { requests |= 0b00000010 }; // STAT interrupt (OAM)
-            if ((self.ppu.read_registers(0xFF41) >> 4) & 0x1 == 1) && (self.ppu.read_registers(0xFF41) & 0x3 == 1) { requests |= 0b00000010 }; // STAT interrupt (VBLANK)
-            if ((self.ppu.read_registers(0xFF41) >> 3) & 0x1 == 1) && (self.ppu.read_registers(0xFF41) & 0x3 == 0) { requests |= 0b00000010 }; // STAT interrupt (HBLANK)
-            self.ppu.stat_irq_triggered = true;
+    // 1 M-cycle, every component's update steps 4 T-cycles. STOP stops the system clock, which leaves the timer and
+    // everything counting off DIV where it is
+    pub fn update_components(&mut self, cpu_stopped: bool) {
+        if self.config == BusConfig::Flat {
+            return
+        }
+        if let Some(access) = self.oam_bug_access.take() {
+            self.ppu.trigger_oam_bug(access);
+        }
+        self.step_oam_dma();
+        self.ppu.update();
+        if !cpu_stopped {
+            self.timer.update();
         }
+        self.apu.update(self.timer.take_div_apu_edge());
+        self.serial.update(self.serial_device.as_mut(), self.timer.take_serial_edge());
+    }
 
-        if self.timer.tima_irq > 0 { // starts at 2 to delay 1 cycle
-            self.timer.tima_irq -= 1;
-            if self.timer.tima_irq == 0 { 
-                requests |= 0b00000100; // TIMER interrupt
-            }
+    pub fn snapshot(&self) -> MemorySnapshot {
+        MemorySnapshot {
+            wram: self.wram,
+            svbk: self.svbk,
+            hram: self.hram,
+            sram: self.sram.clone(),
+            mapper: self.mapper.state(),
+            IE: self.IE,
+            IF: self.IF,
+            boot_rom_mapped: self.boot_rom_mapped,
+            joyp: self.joypad.select(),
+            ppu: self.ppu.clone(),
+            timer: self.timer.clone(),
+            dma_register: self.dma_register,
+            oam_dma: self.oam_dma,
+            oam_dma_restart: self.oam_dma_restart,
+            sgb: self.sgb.clone(),
+            apu: self.apu.snapshot(),
+            serial: self.serial.clone(),
+            power_on_ram: self.power_on_ram
         }
+    }
 
-        self.IF |= requests | 0xE0;
+    // overwrites an earlier snapshot in place instead of allocating a new one
+    pub fn snapshot_into(&self, snapshot: &mut MemorySnapshot) {
+        snapshot.wram = self.wram;
+        snapshot.svbk = self.svbk;
+        snapshot.hram = self.hram;
+        snapshot.sram.clone_from(&self.sram);
+        snapshot.mapper = self.mapper.state();
+        snapshot.IE = self.IE;
+        snapshot.IF = self.IF;
+        snapshot.boot_rom_mapped = self.boot_rom_mapped;
+        snapshot.joyp = self.joypad.select();
+        snapshot.ppu.clone_from(&self.ppu);
+        snapshot.timer.clone_from(&self.timer);
+        snapshot.dma_register = self.dma_register;
+        snapshot.oam_dma = self.oam_dma;
+        snapshot.oam_dma_restart = self.oam_dma_restart;
+        snapshot.sgb.clone_from(&self.sgb);
+        self.apu.snapshot_into(&mut snapshot.apu);
+        snapshot.serial.clone_from(&self.serial);
+        snapshot.power_on_ram = self.power_on_ram;
     }
 
-    pub fn update_components(&mut self) { // 1 M-cycle
-        self.ppu.update();
-        self.timer.update();
-        // self.apu.update(((self.timer.sysclock >> 12) & 0x1) as u8); // bit 4 of DIV register
+    pub fn restore(&mut self, snapshot: &MemorySnapshot) {
+        self.wram = snapshot.wram;
+        self.svbk = snapshot.svbk;
+        self.hram = snapshot.hram;
+        self.sram.clone_from(&snapshot.sram);
+        self.mapper.restore_state(&snapshot.mapper);
+        self.IE = snapshot.IE;
+        self.IF = snapshot.IF;
+        self.boot_rom_mapped = snapshot.boot_rom_mapped;
+        self.joypad.restore_select(snapshot.joyp);
+        self.ppu.clone_from(&snapshot.ppu);
+        self.timer.clone_from(&snapshot.timer);
+        self.dma_register = snapshot.dma_register;
+        self.oam_dma = snapshot.oam_dma;
+        self.oam_dma_restart = snapshot.oam_dma_restart;
+        self.sgb.clone_from(&snapshot.sgb);
+        self.apu.restore(&snapshot.apu);
+        self.serial.clone_from(&snapshot.serial);
+        self.power_on_ram = snapshot.power_on_ram;
+    }
+
+    pub fn set_ppu_mode(&mut self, mode: PpuMode) {
+        self.ppu.render_mode = mode;
+    }
+
+    pub fn ppu_mode(&self) -> PpuMode {
+        self.ppu.render_mode
+    }
+
+    // the device stays plugged in across snapshots, only the transfer in progress is part of one
+    pub fn attach_serial(&mut self, device: Box<dyn SerialDevice>) {
+        self.serial_device = device;
+    }
+
+    pub fn detach_serial(&mut self) -> Box<dyn SerialDevice> {
+        std::mem::replace(&mut self.serial_device, Box::new(Disconnected))
+    }
+
+    pub fn take_serial_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.serial_output)
+    }
+
+    pub fn bess_apu_registers(&self) -> [u8; 0x30] {
+        self.apu.bess_registers()
     }
 
+    // a write to the mapper's registers from a BESS MBC block, which isn't an access the CPU made
+    pub fn restore_mbc_write(&mut self, addr: u16, val: u8) {
+        self.mapper.write(&mut self.sram, addr, val);
+    }
+
+    #[allow(dead_code)] // by value for callers that want their own copy, everything in the crate borrows it
     pub fn get_display(&self) -> Display {
         self.ppu.lcd
     }
 
+    pub fn get_display_ref(&self) -> &Display {
+        &self.ppu.lcd
+    }
+
+    // out has to hold the 160 * 144 shade bytes
+    // false with out left alone unless it's exactly the size of the display
+    pub fn copy_display_into(&self, out: &mut [u8]) -> bool {
+        if out.len() != self.ppu.lcd.len() {
+            return false;
+        }
+        out.copy_from_slice(&self.ppu.lcd);
+        true
+    }
+
+    pub fn set_palette(&mut self, palette: [[u8; 3]; 4]) {
+        self.palette = palette;
+    }
+
+    pub fn set_frame_blend(&mut self, blend: FrameBlend) {
+        self.frame_blend = blend;
+    }
+
+    pub fn frame_blend(&self) -> FrameBlend {
+        self.frame_blend
+    }
+
+    pub fn set_sample_rate(&mut self, hz: u32) {
+        self.apu.set_sample_rate(hz);
+    }
+
+    pub fn set_audio_quality(&mut self, quality: AudioQuality) {
+        self.apu.set_audio_quality(quality);
+    }
+
+    pub fn set_high_pass(&mut self, enabled: bool) {
+        self.apu.set_high_pass(enabled);
+    }
+
+    pub fn drain_audio(&mut self, out: &mut Vec<i16>) {
+        self.apu.drain_audio(out);
+    }
+
+    pub fn set_channel_enabled(&mut self, channel: u8, on: bool) {
+        self.apu.set_channel_enabled(channel, on);
+    }
+
+    pub fn channel_levels(&self) -> [u8; 4] {
+        self.apu.channel_levels()
+    }
+
+    pub fn audio_overruns(&self) -> u32 {
+        self.apu.audio_overruns()
+    }
+
+    pub fn audio_frames_produced(&self) -> u64 {
+        self.apu.audio_frames_produced()
+    }
+
+    // expands the current frame into out, false with out left alone unless it holds RGBA_FRAME_LEN bytes
+    pub fn render_rgba(&self, out: &mut [u8]) -> bool {
+        if out.len() != RGBA_FRAME_LEN {
+            return false;
+        }
+        let previous = match self.frame_blend {
+            FrameBlend::Blend2 => self.ppu.previous_frame.as_deref(),
+            FrameBlend::Off => None
+        };
+
+        // once an SGB game set up its palettes they replace the plain one, per 8x8 cell of the screen
+        let sgb = if self.sgb_supported && self.sgb.colorized { Some(&self.sgb) } else { None };
+        let color = |i: usize, shade: u8| match sgb {
+            Some(sgb) => sgb.color(i % 160, i / 160, shade),
+            None => self.palette[(shade & 0x3) as usize]
+        };
+
+        for (i, (pixel, &shade)) in out.chunks_exact_mut(4).zip(self.ppu.lcd.iter()).enumerate() {
+            let [r, g, b] = color(i, shade);
+            pixel.copy_from_slice(&[r, g, b, 0xFF]);
+            if let Some(previous) = previous {
+                let before = color(i, previous[i]);
+                for (channel, before) in pixel.iter_mut().zip(before) {
+                    *channel = ((*channel as u16 + before as u16) / 2) as u8;
+                }
+            }
+        }
+        true
+    }
+
+    pub fn debug_render_tiles(&self) -> Vec<u8> {
+        self.ppu.debug_render_tiles()
+    }
+
+    pub fn debug_render_bg_map(&self, which: u8) -> Vec<u8> {
+        self.ppu.debug_render_bg_map(which)
+    }
+
+    pub fn debug_map_viewport(&self) -> MapViewport {
+        self.ppu.debug_map_viewport()
+    }
+
+    pub fn debug_timer(&self) -> TimerState {
+        self.timer.state()
+    }
+
+    pub fn debug_oam(&self) -> [OamEntry; 40] {
+        self.ppu.debug_oam()
+    }
+
+    pub fn debug_oam_scan(&self, ly: u8) -> Vec<u8> {
+        self.ppu.debug_oam_scan(ly)
+    }
+
     pub fn get_debug_panel(&mut self) -> [usize; 144 * 3] {
         let old = self.ppu.debug_panel;
         self.ppu.debug_panel = [0; 144 * 3];
@@ -428,31 +927,848 @@ impl Memory {
         }
         return false;
     }
+
+    // true once for every frame completed since the last call, whether it was drawn or the LCD was off for it
+    pub fn take_frame_ready(&mut self) -> bool {
+        return std::mem::take(&mut self.ppu.frame_ready);
+    }
+}
+
+impl MemorySnapshot {
+    pub fn write_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.wram[..0x2000]);
+        w.bytes(&self.hram);
+        w.vec(&self.sram);
+        w.bool(self.mapper.ram_enabled);
+        w.bool(self.mapper.advanced_banking);
+        w.u8(self.mapper.rom_bank);
+        w.u8(self.mapper.rom_bank_high);
+        w.u8(self.mapper.ram_bank);
+        w.u8(self.IE);
+        w.u8(self.IF);
+        w.bool(self.boot_rom_mapped);
+        w.u8(self.joyp);
+        self.ppu.write_state(w);
+        self.timer.write_state(w);
+        self.ppu.write_render_state(w);
+        w.u8(self.dma_register);
+        OamDma::write_state(&self.oam_dma, w);
+        OamDma::write_state(&self.oam_dma_restart, w);
+        self.ppu.write_window_state(w);
+        self.sgb.write_state(w);
+        self.apu.write_state(w);
+        self.serial.write_state(w);
+        w.u8(self.svbk);
+        w.bytes(&self.wram[0x2000..]);
+        self.power_on_ram.write_state(w);
+    }
+
+    pub fn read_state(r: &mut StateReader) -> Result<MemorySnapshot, StateError> {
+        let mut wram = [0x0; 0x8000];
+        r.fill(&mut wram[..0x2000])?;
+        let mut hram = [0x0; 0x7F];
+        r.fill(&mut hram)?;
+        let sram = r.vec()?;
+
+        let mut snapshot = MemorySnapshot {
+            wram,
+            svbk: 0,
+            hram,
+            sram,
+            mapper: MapperState { ram_enabled: r.bool()?, advanced_banking: r.bool()?, rom_bank: r.u8()?, rom_bank_high: r.u8()?, ram_bank: r.u8()? },
+            IE: r.u8()?,
+            IF: r.u8()?,
+            boot_rom_mapped: r.bool()?,
+            joyp: r.u8()?,
+            ppu: PPU::default(),
+            timer: Timer::default(),
+            dma_register: 0xFF,
+            oam_dma: None,
+            oam_dma_restart: None,
+            sgb: Sgb::default(),
+            apu: ApuSnapshot::default(),
+            serial: Serial::default(),
+            power_on_ram: PowerOnRam::Zeros
+        };
+        snapshot.ppu.read_state(r)?;
+        snapshot.timer.read_state(r)?;
+        snapshot.ppu.read_render_state(r)?;
+        snapshot.dma_register = r.u8()?;
+        snapshot.oam_dma = OamDma::read_state(r)?;
+        snapshot.oam_dma_restart = OamDma::read_state(r)?;
+        snapshot.ppu.read_window_state(r)?;
+        snapshot.sgb.read_state(r)?;
+        snapshot.apu.read_state(r)?;
+        snapshot.serial.read_state(r)?;
+        snapshot.svbk = r.u8()?;
+        r.fill(&mut snapshot.wram[0x2000..])?;
+        snapshot.power_on_ram = PowerOnRam::read_state(r)?;
+        Ok(snapshot)
+    }
 }
 
 impl Default for Memory {
     fn default() -> Self {
         Self {
-            rom_chip: vec![],
-            banking_mode: BankingMode::SIMPLE,
-            memory_bank: MemoryBank::MBCNONE,
-            mbc_ram_enabled: false,
-            boot_rom: [0x0; 0x100],
+            mapper: Box::new(NoMbc::default()),
+            boot_rom: vec![],
+            boot_rom_mapped: false,
+            power_on_ram: PowerOnRam::Zeros,
             ppu: PPU::default(),
             IE: 0x0,
             IF: 0x0,
-            joyp: 0x0,
-            keypress: -1,
+            joypad: Joypad::default(),
+            palette: DMG_GREEN,
+            frame_blend: FrameBlend::Off,
             timer: Timer::default(),
-            flat_ram: false,
-            ram_rom_bank_number: 0x00,
-            rom_bank_number: 0x00,
+            config: BusConfig::Hardware,
+            access_lockout: true,
+            oam_bug: false,
+            oam_bug_access: Cell::new(None),
+            watchpoints: Watchpoints::default(),
+            watch_hit: Cell::new(None),
+            observer: None,
+            flat_memory: vec![],
+            flat_writes: vec![],
             hram: [0x0; 0x7F],
-            wram: [0x0; 0x2000],
+            wram: [0x0; 0x8000],
+            model: Model::Dmg,
+            svbk: 0,
             sram: vec![],
-            //apu: APU::default(),
+            apu: APU::default(),
+            serial: Serial::default(),
+            serial_device: Box::new(Disconnected),
+            serial_output: vec![],
             bess_buffer_offsets: vec![],
-            mbc5_rom_bank_number_top_bit: 0,
+            dma_register: 0xFF,
+            oam_dma: None,
+            oam_dma_restart: None,
+            sgb_supported: false,
+            sgb: Sgb::default()
+        }
+    }
+}   
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // steps one M-cycle at a time and records LY and the STAT mode whenever the given interrupt is requested
+    fn interrupts(memory: &mut Memory, flag: u8, m_cycles: usize) -> Vec<(u8, u8)> {
+        let mut requests = vec![];
+        for _ in 0..m_cycles {
+            memory.update_components(false);
+            memory.update_requested_interrupts();
+            if memory.IF & flag != 0 {
+                requests.push((memory.read(0xFF44), memory.read(0xFF41) & 0x3));
+                memory.IF &= !flag;
+            }
+        }
+        requests
+    }
+
+    fn stat_interrupts(memory: &mut Memory, m_cycles: usize) -> Vec<(u8, u8)> {
+        interrupts(memory, 0b00000010, m_cycles)
+    }
+
+    const FRAME_M_CYCLES: usize = 70224 / 4;
+
+    fn lcd_on(stat: u8) -> Memory {
+        let mut memory = Memory::default();
+        memory.write(0xFF40, 0x91);
+        memory.write(0xFF41, stat);
+        stat_interrupts(&mut memory, FRAME_M_CYCLES); // whatever turning the sources on requested doesn't count
+        memory.IF = 0;
+        memory
+    }
+
+    #[test]
+    fn hblank_stat_interrupt_fires_every_visible_line() {
+        let mut memory = lcd_on(1 << 3);
+        let requests = stat_interrupts(&mut memory, FRAME_M_CYCLES);
+        assert_eq!(requests.len(), 144);
+        assert!(requests.iter().all(|&(_, mode)| mode == 0));
+    }
+
+    #[test]
+    fn stat_sources_that_stay_high_block_each_other() {
+        // hblank runs straight into the next line's OAM scan, so only line 0's OAM scan gets its own interrupt
+        let mut memory = lcd_on((1 << 3) | (1 << 5));
+        let requests = stat_interrupts(&mut memory, FRAME_M_CYCLES);
+        assert_eq!(requests.len(), 145);
+        assert_eq!(requests.iter().filter(|&&(_, mode)| mode == 2).count(), 1);
+    }
+
+    #[test]
+    fn lyc_match_during_hblank_is_blocked() {
+        // LY moves on to 50 on the same dot hblank ends, so the line never drops, and the match staying high
+        // for the rest of line 50 blocks that line's hblank too
+        let mut memory = lcd_on((1 << 3) | (1 << 6));
+        memory.write(0xFF45, 50);
+        let requests = stat_interrupts(&mut memory, FRAME_M_CYCLES);
+        assert_eq!(requests.len(), 143);
+        assert!(!requests.iter().any(|&(ly, _)| ly == 50));
+
+        // with hblank off the match gets through, and it stays high for the whole line
+        memory.write(0xFF41, 1 << 6);
+        stat_interrupts(&mut memory, FRAME_M_CYCLES);
+        assert_eq!(stat_interrupts(&mut memory, FRAME_M_CYCLES), [(50, 2)]);
+    }
+
+    fn run_until(memory: &mut Memory, ly: u8, mode: u8) {
+        while memory.read(0xFF44) != ly || memory.read(0xFF41) & 0x3 != mode {
+            memory.update_components(false);
         }
+        memory.update_requested_interrupts();
+        memory.IF = 0;
+    }
+
+    #[test]
+    fn stat_writes_briefly_enable_every_source() {
+        let mut memory = lcd_on(0);
+        memory.write(0xFF45, 0xFF); // no LY match anywhere
+
+        run_until(&mut memory, 145, 1);
+        memory.write(0xFF41, 0);
+        assert_eq!(stat_interrupts(&mut memory, 1), [(145, 1)]);
+
+        run_until(&mut memory, 10, 3);
+        memory.write(0xFF41, 0);
+        assert_eq!(stat_interrupts(&mut memory, 1), []);
+
+        // with hblank already holding the line high the write has nothing to raise
+        run_until(&mut memory, 10, 0);
+        memory.write(0xFF41, 1 << 3);
+        stat_interrupts(&mut memory, 1);
+        memory.write(0xFF41, 1 << 3);
+        assert_eq!(stat_interrupts(&mut memory, 1), []);
+
+        memory.write(0xFF40, 0x11);
+        memory.write(0xFF41, 0);
+        assert_eq!(stat_interrupts(&mut memory, 1), []);
+    }
+
+    #[test]
+    fn line_153_reads_as_0_after_its_first_m_cycle() {
+        let mut memory = lcd_on(0);
+        let mut lines = vec![];
+        for _ in 0..FRAME_M_CYCLES {
+            memory.update_components(false);
+            lines.push((memory.read(0xFF44), memory.read(0xFF41) & 0x3));
+        }
+        assert_eq!(lines.iter().filter(|&&(ly, _)| ly == 153).count(), 1);
+        assert_eq!(lines.iter().filter(|&&line| line == (0, 1)).count(), 113);
+
+        // LYC = 0 fires early in line 153 and the match carries over into line 0 without firing again
+        memory.write(0xFF41, 1 << 6);
+        memory.write(0xFF45, 0);
+        stat_interrupts(&mut memory, FRAME_M_CYCLES);
+        assert_eq!(stat_interrupts(&mut memory, FRAME_M_CYCLES * 2), [(0, 1); 2]);
+
+        memory.write(0xFF45, 153);
+        stat_interrupts(&mut memory, FRAME_M_CYCLES);
+        assert_eq!(stat_interrupts(&mut memory, FRAME_M_CYCLES * 2), [(153, 1); 2]);
+    }
+
+    #[test]
+    fn lyc_written_mid_line_matches_straight_away() {
+        let mut memory = lcd_on(1 << 6);
+        memory.write(0xFF45, 0xFF);
+        run_until(&mut memory, 10, 0);
+
+        // a STAT handler moving LYC onto the line it's on, then onto a later one for a second interrupt
+        memory.write(0xFF45, 10);
+        assert_eq!(memory.read(0xFF41) & 0x4, 0x4);
+        assert_eq!(stat_interrupts(&mut memory, 1), [(10, 0)]);
+        memory.write(0xFF45, 20);
+        assert_eq!(memory.read(0xFF41) & 0x4, 0);
+        assert_eq!(stat_interrupts(&mut memory, FRAME_M_CYCLES), [(20, 2)]);
+
+        // moving it away and back while still on the line is a new rising edge
+        run_until(&mut memory, 20, 0);
+        memory.write(0xFF45, 0xFF);
+        stat_interrupts(&mut memory, 1);
+        memory.write(0xFF45, 20);
+        assert_eq!(stat_interrupts(&mut memory, 1).len(), 1);
+    }
+
+    #[test]
+    fn vblank_interrupt_fires_once_when_ly_reaches_144() {
+        let mut memory = lcd_on(0);
+        assert_eq!(interrupts(&mut memory, 0b00000001, FRAME_M_CYCLES * 3), [(144, 1); 3]);
+    }
+
+    #[test]
+    fn vram_and_oam_are_locked_while_the_ppu_reads_them() {
+        let mut memory = lcd_on(0);
+        memory.ppu.vram[0] = 0x42;
+        memory.ppu.oam[0] = 0x24;
+
+        for _ in 0..FRAME_M_CYCLES {
+            let (vram, oam) = (memory.read(0x8000), memory.read(0xFE00));
+            memory.update_components(false);
+            let mode = memory.read(0xFF41) & 0x3;
+            assert_eq!(vram, if mode == 3 { 0xFF } else { 0x42 }, "LY {} mode {}", memory.read(0xFF44), mode);
+            assert_eq!(oam, if mode == 2 || mode == 3 { 0xFF } else { 0x24 }, "LY {} mode {}", memory.read(0xFF44), mode);
+        }
+    }
+
+    #[test]
+    fn writes_during_mode_3_are_dropped_unless_lockout_is_off() {
+        let mut memory = lcd_on(0);
+        while memory.read(0xFF41) & 0x3 != 3 {
+            memory.update_components(false);
+        }
+        memory.write(0x8000, 0x42);
+        memory.write(0xFE00, 0x24);
+        assert_eq!((memory.ppu.vram[0], memory.ppu.oam[0]), (0x00, 0x00));
+
+        memory.access_lockout = false;
+        memory.write(0x8000, 0x42);
+        memory.write(0xFE00, 0x24);
+        assert_eq!((memory.read(0x8000), memory.read(0xFE00)), (0x42, 0x24));
+    }
+
+    #[test]
+    fn if_reads_its_unwired_bits_as_set_and_ie_keeps_all_of_them() {
+        let mut memory = Memory::default();
+        assert_eq!(memory.read(0xFF0F), 0xE0);
+        memory.write(0xFF0F, 0xFF);
+        assert_eq!((memory.IF, memory.read(0xFF0F)), (0x1F, 0xFF));
+        memory.write(0xFF0F, 0x04);
+        assert_eq!(memory.read(0xFF0F), 0xE4);
+        memory.poke(0xFF0F, 0xE2);
+        assert_eq!(memory.IF, 0x02);
+
+        memory.write(0xFFFF, 0xE1);
+        assert_eq!(memory.read(0xFFFF), 0xE1);
+        memory.write(0xFFFF, 0x00);
+        assert_eq!(memory.read(0xFFFF), 0x00);
+    }
+
+    #[test]
+    fn peek_and_poke_get_past_the_locks_unnoticed() {
+        use crate::internal::debugger::WatchKind;
+        let mut memory = lcd_on(0);
+        memory.watchpoints.add(0x8000..=0xFFFF, WatchKind::ReadWrite);
+        while memory.read(0xFF41) & 0x3 != 3 {
+            memory.update_components(false);
+        }
+        memory.take_watch_hit();
+
+        memory.poke(0x8000, 0x42);
+        memory.poke(0xFE00, 0x24);
+        assert_eq!((memory.read(0x8000), memory.peek(0x8000)), (0xFF, 0x42));
+        assert_eq!((memory.read(0xFE00), memory.peek(0xFE00)), (0xFF, 0x24));
+        memory.take_watch_hit();
+
+        memory.poke(0xFF46, 0xC0);
+        memory.poke(0xFF04, 0x12);
+        assert_eq!(memory.peek(0xFF04), 0x12);
+        memory.poke(0xFFFE, 0x05);
+        memory.poke(0xFFFF, 0x1F);
+        assert_eq!(memory.peek_range(0xFFFE, 2), [0x05, 0x1F]);
+        assert_eq!(memory.peek(0xFF46), 0xC0);
+        assert!(memory.oam_dma.is_none());
+        assert!(memory.take_watch_hit().is_none());
+    }
+
+    fn memory_with_dma_sources() -> Memory {
+        let mut memory = Memory::default();
+        for i in 0..0xA0 {
+            memory.write(0xC000 + i, i as u8);
+            memory.write(0xD000 + i, 0x80 | i as u8);
+        }
+        memory.write(0xFF80, 0x42);
+        memory
+    }
+
+    fn run(memory: &mut Memory, m_cycles: usize) {
+        for _ in 0..m_cycles {
+            memory.update_components(false);
+        }
+    }
+
+    #[test]
+    fn flat_bus_is_plain_ram_where_nothing_ticks() {
+        let mut memory = Memory { config: BusConfig::Flat, ..Memory::default() };
+        for (addr, val) in [(0x2000, 0x05), (0xFF04, 0x12), (0xFF40, 0x91), (0xFF46, 0xC0), (0xFF50, 0x01)] {
+            memory.write(addr, val);
+        }
+        run(&mut memory, FRAME_M_CYCLES);
+        memory.update_requested_interrupts();
+
+        assert_eq!(memory.peek_range(0xFF40, 7), [0x91, 0x00, 0x00, 0x00, 0x00, 0x00, 0xC0]); // LY never moved
+        assert_eq!((memory.read(0x2000), memory.read(0xFF04), memory.read(0xFF50)), (0x05, 0x12, 0x01));
+        assert_eq!((memory.timer.state().div, memory.oam_dma.is_none(), memory.IF & 0x1F), (0, true, 0));
+        assert_eq!(memory.take_flat_writes().len(), 5);
     }
-}   
\ No newline at end of file
+
+    #[test]
+    fn echo_ram_mirrors_wram() {
+        let mut memory = Memory::default();
+        memory.write(0xC123, 0x42);
+        memory.write(0xFDFF, 0x24);
+        assert_eq!((memory.read(0xE123), memory.read(0xDDFF)), (0x42, 0x24));
+
+        // and so does a DMA from there
+        memory.write(0xFF46, 0xE1);
+        run(&mut memory, 162);
+        assert_eq!(memory.ppu.oam[0x23], 0x42);
+    }
+
+    #[test]
+    fn svbk_banks_the_upper_half_of_wram_on_the_cgb_only() {
+        let mut memory = Memory::default();
+        memory.set_model(Model::Cgb);
+        for bank in 1..8 {
+            memory.write(0xFF70, 0xF8 | bank);
+            memory.write(0xD000, 0x10 | bank);
+        }
+        memory.write(0xC000, 0x42);
+        for bank in 1..8 {
+            memory.write(0xFF70, bank);
+            assert_eq!((memory.read(0xFF70), memory.read(0xD000), memory.read(0xF000)), (0xF8 | bank, 0x10 | bank, 0x10 | bank));
+            assert_eq!(memory.read(0xC000), 0x42);
+        }
+        memory.write(0xFF70, 0);
+        assert_eq!(memory.read(0xFF70), 0xF8);
+        memory.write(0xD000, 0x24);
+        memory.write(0xFF70, 1);
+        assert_eq!(memory.read(0xD000), 0x24); // 0 picks bank 1 too
+        assert_eq!(memory.bess_buffer_limits().wram, 0x8000);
+
+        let mut memory = Memory::default();
+        memory.write(0xD000, 0x42);
+        memory.write(0xFF70, 0x03);
+        assert_eq!((memory.read(0xFF70), memory.read(0xD000)), (0xFF, 0x42));
+        assert_eq!(memory.bess_buffer_limits().wram, 0x2000);
+    }
+
+    #[test]
+    fn cgb_boot_rom_leaves_the_header_to_the_cartridge() {
+        let mut rom = vec![0x11; 0x8000];
+        rom[MBC_TYPE] = 0x00;
+        rom[RAM_SIZE] = 0x00;
+        let boot_rom: Vec<u8> = (0..0x800).map(|i| (i >> 8) as u8 | 0x80).collect();
+        for (len, boot_rom) in [(0x900, [&boot_rom[..0x100], &[0xEE; 0x100], &boot_rom[0x100..]].concat()), (0x800, boot_rom.clone())] {
+            assert_eq!(boot_rom_model(len), Some(Model::Cgb));
+            let mut memory = Memory::default();
+            memory.load_cartridge(rom.clone()).unwrap();
+            memory.mount_bootrom(&boot_rom);
+            let read = [0x0000, 0x00FF, 0x0100, 0x01FF, 0x0200, 0x08FF, 0x0900].map(|addr| memory.read(addr));
+            assert_eq!(read, [0x80, 0x80, 0x11, 0x11, 0x81, 0x87, 0x11], "{:#X} bytes", len);
+        }
+        assert_eq!(boot_rom_model(0x100), Some(Model::Dmg));
+        assert_eq!(boot_rom_model(0x200), None);
+    }
+
+    #[test]
+    fn oam_dma_copies_a_byte_per_m_cycle_and_owns_the_bus() {
+        let mut memory = memory_with_dma_sources();
+        memory.write(0xFF46, 0xC0);
+        assert_eq!(memory.read(0xFF46), 0xC0);
+
+        run(&mut memory, 1); // setup, nothing copied or blocked yet
+        assert_eq!(memory.read(0xC000), 0x00);
+        assert_eq!(memory.ppu.oam[0], 0x00);
+
+        run(&mut memory, 1);
+        assert_eq!(memory.ppu.oam[..2], [0x00, 0x00]);
+        run(&mut memory, 1);
+        assert_eq!(memory.ppu.oam[..2], [0x00, 0x01]);
+        assert_eq!(memory.read(0xC005), 0xFF);
+        assert_eq!(memory.read(0xFE00), 0xFF);
+        assert_eq!(memory.read(0xFF80), 0x42);
+        memory.write(0xC005, 0xEE); // dropped
+        memory.write(0xFF81, 0x24);
+        assert_eq!(memory.read(0xFF81), 0x24);
+
+        run(&mut memory, 158);
+        assert_eq!(memory.ppu.oam[0x9F], 0x9F);
+        assert_eq!(memory.read(0xC005), 0xFF); // the last byte's M-cycle still belongs to the DMA
+        run(&mut memory, 1);
+        assert_eq!(memory.read(0xC005), 0x05);
+        assert_eq!(memory.read(0xFE9F), 0x9F);
+    }
+
+    #[test]
+    fn oam_dma_sources_past_wram_and_in_locked_vram() {
+        let mut memory = lcd_on(0);
+        for i in 0..0xA0 {
+            memory.write(0xD100 + i, i as u8);
+            memory.ppu.vram[0x1000 + i as usize] = !i as u8;
+        }
+        memory.write(0xFF46, 0xF1);
+        run(&mut memory, 162);
+        assert_eq!(memory.ppu.oam, std::array::from_fn(|i| i as u8));
+
+        while memory.read(0xFF41) & 0x3 != 3 {
+            memory.update_components(false);
+        }
+        memory.write(0xFF46, 0x90);
+        run(&mut memory, 162);
+        assert_eq!(memory.ppu.oam, std::array::from_fn(|i| !i as u8));
+    }
+
+    #[test]
+    fn oam_dma_from_rom_reads_the_bank_mapped_at_each_byte() {
+        // the CPU's writes are kept off the bus while the DMA runs, so the bank is switched straight on the mapper
+        let mut rom = vec![0; 0x10000];
+        rom[MBC_TYPE] = 0x01;
+        rom[0x148] = 0x01;
+        rom[0x4000..0x40A0].fill(0x11);
+        rom[0x8000..0x80A0].fill(0x22);
+        let mut memory = Memory::default();
+        memory.load_cartridge(rom).unwrap();
+
+        memory.write(0xFF46, 0x40);
+        run(&mut memory, 81); // bytes 0 to 79
+        memory.mapper.write(&mut memory.sram, 0x2000, 0x02);
+        run(&mut memory, 81);
+        assert_eq!(memory.ppu.oam[..80], [0x11; 80]);
+        assert_eq!(memory.ppu.oam[80..], [0x22; 80]);
+    }
+
+    #[test]
+    fn oam_dma_restart_takes_over_after_its_setup() {
+        let mut memory = memory_with_dma_sources();
+        memory.write(0xFF46, 0xC0);
+        run(&mut memory, 51); // bytes 0 to 49
+
+        memory.write(0xFF46, 0xD0);
+        run(&mut memory, 1); // the old transfer keeps going while the new one sets up
+        assert_eq!(memory.ppu.oam[50], 50);
+        assert_eq!(memory.read(0xC000), 0xFF);
+
+        run(&mut memory, 1);
+        assert_eq!(memory.ppu.oam[0], 0x80);
+        assert_eq!(memory.ppu.oam[51], 0x00);
+        run(&mut memory, 160);
+        assert!(memory.ppu.oam.iter().enumerate().all(|(i, &byte)| byte == 0x80 | i as u8));
+        assert_eq!(memory.read(0xC000), 0x00);
+    }
+
+    #[test]
+    fn unusable_area_reads_ff_only_while_the_ppu_has_oam() {
+        let mut memory = lcd_on(0);
+        memory.write(0xFEA0, 0x42);
+        for (mode, expected) in [(2, 0xFF), (3, 0xFF), (0, 0x00), (1, 0x00)] {
+            while memory.read(0xFF41) & 0x3 != mode {
+                memory.update_components(false);
+            }
+            run(&mut memory, 1);
+            assert_eq!((memory.read(0xFEA0), memory.read(0xFEFF)), (expected, expected), "mode {}", mode);
+        }
+        memory.write(0xFF40, 0x00);
+        assert_eq!(memory.read(0xFEA0), 0x00);
+
+        // a DMA from FE00 on copies the WRAM that would be echoed there instead
+        for i in 0..0xA0 {
+            memory.write(0xDE00 + i, i as u8);
+        }
+        memory.write(0xFF46, 0xFE);
+        run(&mut memory, 162);
+        assert_eq!(memory.ppu.oam, std::array::from_fn(|i| i as u8));
+    }
+
+    #[test]
+    fn oam_bug_only_hits_during_the_oam_scan_when_enabled() {
+        let mut memory = lcd_on(0);
+        for i in 0..0xA0 {
+            memory.ppu.oam[i] = i as u8;
+        }
+        let before = memory.ppu.oam;
+
+        // 5 M-cycles into a line the OAM scan is reading row 5
+        let run_to_row_5 = |memory: &mut Memory| {
+            while memory.read(0xFF41) & 0x3 != 0 {
+                memory.update_components(false);
+            }
+            while memory.read(0xFF41) & 0x3 != 2 {
+                memory.update_components(false);
+            }
+            run(memory, 5);
+        };
+
+        run_to_row_5(&mut memory);
+        memory.read(0xFE00);
+        memory.update_components(false);
+        assert_eq!(memory.ppu.oam, before);
+
+        memory.oam_bug = true;
+        run_to_row_5(&mut memory);
+        memory.write(0xFE00, 0x00);
+        memory.update_components(false);
+        assert_eq!(memory.ppu.oam[42..48], before[34..40]);
+        assert_ne!(memory.ppu.oam[40..42], before[40..42]);
+
+        // hblank is fine
+        let corrupted = memory.ppu.oam;
+        while memory.read(0xFF41) & 0x3 != 0 {
+            memory.update_components(false);
+        }
+        memory.inc_dec_oam_bug(0xFE10);
+        memory.update_components(false);
+        assert_eq!(memory.ppu.oam, corrupted);
+    }
+
+    fn next_frame(memory: &mut Memory) {
+        while !memory.is_frame_rendered() {
+            memory.update_components(false);
+        }
+    }
+
+    #[test]
+    fn blended_frames_average_with_the_one_before() {
+        let mut memory = Memory::default();
+        memory.set_palette([[0xFF; 3], [0xAA; 3], [0x55; 3], [0x00; 3]]);
+        memory.set_frame_blend(FrameBlend::Blend2);
+        memory.write(0xFF40, 0x91);
+        let mut frame = vec![0; RGBA_FRAME_LEN];
+
+        next_frame(&mut memory); // the first frame after switching on is never shown
+        memory.write(0xFF47, 0xFF);
+        next_frame(&mut memory);
+        assert!(!memory.render_rgba(&mut frame[4..]));
+        assert!(frame.iter().all(|&channel| channel == 0));
+        assert!(memory.render_rgba(&mut frame));
+        assert!(frame.chunks_exact(4).all(|pixel| pixel == [0x7F, 0x7F, 0x7F, 0xFF]));
+
+        memory.set_frame_blend(FrameBlend::Off);
+        memory.render_rgba(&mut frame);
+        assert!(frame.chunks_exact(4).all(|pixel| pixel == [0x00, 0x00, 0x00, 0xFF]));
+
+        // nothing from before the LCD went off bleeds into the frames after it
+        memory.set_frame_blend(FrameBlend::Blend2);
+        memory.write(0xFF40, 0x11);
+        memory.write(0xFF40, 0x91);
+        next_frame(&mut memory);
+        memory.render_rgba(&mut frame);
+        assert!(frame.chunks_exact(4).all(|pixel| pixel == [0xFF, 0xFF, 0xFF, 0xFF]));
+        next_frame(&mut memory);
+        memory.render_rgba(&mut frame);
+        assert!(frame.chunks_exact(4).all(|pixel| pixel == [0x7F, 0x7F, 0x7F, 0xFF]));
+    }
+
+    #[test]
+    fn rgba_frames_use_the_palette() {
+        let mut memory = Memory::default();
+        memory.ppu.lcd[..4].copy_from_slice(&[0, 1, 2, 3]);
+        let mut frame = vec![0; RGBA_FRAME_LEN];
+
+        memory.render_rgba(&mut frame);
+        assert_eq!(frame[..16], [0x9B, 0xBC, 0x0F, 0xFF, 0x8B, 0xAC, 0x0F, 0xFF, 0x30, 0x62, 0x30, 0xFF, 0x0F, 0x38, 0x0F, 0xFF]);
+        assert_eq!(frame[16..20], [0x9B, 0xBC, 0x0F, 0xFF]);
+
+        memory.set_palette([[0xFF; 3], [0xAA; 3], [0x55; 3], [0x00; 3]]);
+        memory.render_rgba(&mut frame);
+        assert_eq!(frame[..16], [0xFF, 0xFF, 0xFF, 0xFF, 0xAA, 0xAA, 0xAA, 0xFF, 0x55, 0x55, 0x55, 0xFF, 0x00, 0x00, 0x00, 0xFF]);
+    }
+
+    fn sgb_cartridge() -> Memory {
+        let mut rom = vec![0x00; 0x8000];
+        rom[SGB_FLAG] = 0x03;
+        rom[OLD_LICENSEE] = 0x33;
+        let mut memory = Memory::default();
+        memory.load_cartridge(rom).unwrap();
+        memory
+    }
+
+    // reset pulse, 128 bits LSB first, then the 0 stop bit, with both lines back high in between
+    fn send_packet(memory: &mut Memory, packet: [u8; 16]) {
+        memory.write(0xFF00, 0x00);
+        memory.write(0xFF00, 0x30);
+        for bit in (0..128).map(|i| (packet[i / 8] >> (i % 8)) & 0x1).chain([0]) {
+            memory.write(0xFF00, if bit == 1 { 0x10 } else { 0x20 });
+            memory.write(0xFF00, 0x30);
+        }
+    }
+
+    #[test]
+    fn sgb_palettes_color_screen_regions() {
+        const WHITE: [u8; 2] = [0xFF, 0x7F];
+        const RED: [u8; 2] = [0x1F, 0x00];
+        const BLUE: [u8; 2] = [0x00, 0x7C];
+        let mut pal01 = [0x01, WHITE[0], WHITE[1], 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        for i in 0..3 {
+            pal01[3 + i * 2..5 + i * 2].copy_from_slice(&RED);
+            pal01[9 + i * 2..11 + i * 2].copy_from_slice(&BLUE);
+        }
+        // one block covering the right half of the screen, inside only so its border goes with it
+        let attr_blk = [0x21, 1, 0x1, 0x01, 10, 0, 19, 17, 0, 0, 0, 0, 0, 0, 0, 0];
+
+        let mut memory = sgb_cartridge();
+        memory.ppu.lcd.fill(1);
+        memory.ppu.lcd[160] = 0;
+        let mut frame = vec![0; RGBA_FRAME_LEN];
+        send_packet(&mut memory, pal01);
+        send_packet(&mut memory, attr_blk);
+        memory.render_rgba(&mut frame);
+
+        let pixel = |frame: &[u8], x: usize, y: usize| frame[(y * 160 + x) * 4..(y * 160 + x) * 4 + 4].to_vec();
+        assert_eq!(pixel(&frame, 0, 0), [0xFF, 0x00, 0x00, 0xFF]);
+        assert_eq!(pixel(&frame, 79, 143), [0xFF, 0x00, 0x00, 0xFF]);
+        assert_eq!(pixel(&frame, 80, 0), [0x00, 0x00, 0xFF, 0xFF]);
+        assert_eq!(pixel(&frame, 159, 143), [0x00, 0x00, 0xFF, 0xFF]);
+        assert_eq!(pixel(&frame, 0, 1), [0xFF, 0xFF, 0xFF, 0xFF]);
+
+        // plain DMG cartridges never see the packets
+        let mut memory = Memory::default();
+        memory.load_cartridge(vec![0x00; 0x8000]).unwrap();
+        memory.ppu.lcd.fill(1);
+        send_packet(&mut memory, pal01);
+        memory.render_rgba(&mut frame);
+        assert_eq!(pixel(&frame, 0, 0), [0x8B, 0xAC, 0x0F, 0xFF]);
+    }
+
+    #[test]
+    fn sgb_pal_set_picks_transferred_palettes() {
+        let mut memory = sgb_cartridge();
+        memory.write(0xFF40, 0x10);
+        // system palette 1 is white and three greens
+        for (i, byte) in [0xFF, 0x7F, 0xE0, 0x03, 0xE0, 0x03, 0xE0, 0x03].into_iter().enumerate() {
+            memory.write(0x8008 + i as u16, byte);
+        }
+        send_packet(&mut memory, [0x59, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        send_packet(&mut memory, [0x51, 1, 0, 1, 0, 1, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+        memory.ppu.lcd.fill(2);
+        let mut frame = vec![0; RGBA_FRAME_LEN];
+        memory.render_rgba(&mut frame);
+        assert_eq!(frame[..4], [0x00, 0xFF, 0x00, 0xFF]);
+
+        // MLT_REQ for two players, every P15 pulse switches to the other joypad
+        send_packet(&mut memory, [0x89, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(memory.read(0xFF00) & 0xF, 0xF);
+        memory.write(0xFF00, 0x10);
+        memory.write(0xFF00, 0x30);
+        assert_eq!(memory.read(0xFF00) & 0xF, 0xE);
+        memory.write(0xFF00, 0x10);
+        memory.write(0xFF00, 0x30);
+        assert_eq!(memory.read(0xFF00) & 0xF, 0xF);
+    }
+
+    #[test]
+    fn apu_registers_read_back_through_their_masks() {
+        const EXPECTED: [u8; 0x30] = [
+            0x80, 0x3F, 0x00, 0xFF, 0xBF, 0xFF, 0x3F, 0x00, 0xFF, 0xBF, 0x7F, 0xFF, 0x9F, 0xFF, 0xBF, 0xFF,
+            0xFF, 0x00, 0x00, 0xBF, 0x00, 0x00, 0xF0, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00
+        ];
+        let mut memory = Memory::default();
+        memory.write(0xFF26, 0x80);
+        for addr in (0xFF10..=0xFF3F).filter(|&addr| addr != 0xFF26) {
+            memory.write(addr, 0x00);
+        }
+        let read_back: Vec<u8> = (0xFF10..=0xFF3F).map(|addr| memory.read(addr)).collect();
+        assert_eq!(read_back, EXPECTED);
+
+        // wave RAM is plain memory while channel 3 is off
+        memory.write(0xFF30, 0x12);
+        memory.write(0xFF3F, 0xFE);
+        assert_eq!((memory.read(0xFF30), memory.read(0xFF3F)), (0x12, 0xFE));
+    }
+
+    // M-cycles until a channel 2 with a length of 2 runs out, optionally writing DIV first
+    fn ch2_length_expiry(div_write_at: Option<usize>) -> usize {
+        let mut memory = Memory::default();
+        memory.write(0xFF26, 0x80);
+        memory.write(0xFF16, 0x3E);
+        memory.write(0xFF17, 0xF0);
+        memory.write(0xFF19, 0xC0);
+        for m_cycles in 0.. {
+            if Some(m_cycles) == div_write_at {
+                memory.write(0xFF04, 0x00);
+            }
+            if memory.read(0xFF26) & 0x2 == 0 {
+                return m_cycles;
+            }
+            memory.update_components(false);
+        }
+        unreachable!()
+    }
+
+    #[test]
+    fn frame_sequencer_follows_div() {
+        // DIV bit 4 falls every 2048 M-cycles, the length is clocked on steps 0 and 2
+        assert_eq!(ch2_length_expiry(None), 3 * 2048);
+        // resetting DIV while the bit is set clocks step 0 there and then, and restarts the count
+        assert_eq!(ch2_length_expiry(Some(1536)), 1536 + 2 * 2048);
+        // while it's clear the reset only pushes the next step back
+        assert_eq!(ch2_length_expiry(Some(512)), 512 + 3 * 2048);
+    }
+
+    #[test]
+    fn serial_transfer_requests_its_interrupt_after_8_bits() {
+        let mut memory = Memory::default();
+        memory.write(0xFF01, 0x00);
+        memory.write(0xFF02, 0x81);
+        let mut requested = vec![];
+        for cycle in 1..=2048 {
+            memory.update_components(false);
+            memory.update_requested_interrupts();
+            if memory.IF & 0x8 != 0 {
+                requested.push(cycle);
+                memory.IF &= !0x8;
+            }
+        }
+        // 8 bits of 128 M-cycles, only once
+        assert_eq!(requested, [1024]);
+        assert_eq!((memory.read(0xFF01), memory.read(0xFF02)), (0xFF, 0x7F));
+    }
+
+    // M-cycle of the serial interrupt for a transfer started `start` M-cycles in, with DIV reset at `div_reset`
+    fn serial_interrupt_cycle(start: u32, div_reset: Option<u32>) -> u32 {
+        let mut memory = Memory::default();
+        for cycle in 1.. {
+            if cycle == start {
+                memory.write(0xFF02, 0x81);
+            }
+            if Some(cycle) == div_reset {
+                memory.write(0xFF04, 0x00);
+            }
+            memory.update_components(false);
+            memory.update_requested_interrupts();
+            if memory.IF & 0x8 != 0 {
+                return cycle;
+            }
+        }
+        unreachable!()
+    }
+
+    #[test]
+    fn serial_clock_follows_div() {
+        // the bits shift when DIV's 8192 Hz bit falls, however far into the first one the transfer started
+        assert_eq!(serial_interrupt_cycle(1, None), 1024);
+        assert_eq!(serial_interrupt_cycle(100, None), 1024);
+        assert_eq!(serial_interrupt_cycle(129, None), 1024 + 128);
+        // resetting DIV while the bit is set shifts the third bit there and then, the rest follow 127 M-cycles later
+        assert_eq!(serial_interrupt_cycle(1, Some(200)), 200 + 127 + 5 * 128);
+        // while it's clear the reset only pushes the third bit back
+        assert_eq!(serial_interrupt_cycle(1, Some(300)), 300 + 127 + 5 * 128);
+    }
+
+    #[test]
+    fn joyp_reads_every_select_combination() {
+        use crate::internal::joypad::Button;
+        let buttons = [
+            (vec![], [0xFF, 0xEF, 0xDF, 0xCF]),
+            (vec![Button::Down, Button::Left], [0xFF, 0xE5, 0xDF, 0xC5]),
+            (vec![Button::Start, Button::B], [0xFF, 0xEF, 0xD5, 0xC5]),
+            (vec![Button::Up, Button::Select], [0xFF, 0xEB, 0xDB, 0xCB]),
+            (vec![Button::Right, Button::B], [0xFF, 0xEE, 0xDD, 0xCC]),
+            (vec![Button::A, Button::B, Button::Select, Button::Start, Button::Right], [0xFF, 0xEE, 0xD0, 0xC0])
+        ];
+        for (held, expected) in buttons {
+            let mut memory = Memory::default();
+            for &button in &held {
+                memory.joypad.press(button);
+            }
+            // neither group, the d-pad, the action buttons, both. the low nibble and the unused bits are read-only
+            for (written, expected) in [0x3F, 0x2F, 0x1F, 0x00].into_iter().zip(expected) {
+                memory.write(0xFF00, written);
+                assert_eq!(memory.read(0xFF00), expected, "{:?} with 0x{:02X} written", held, written);
+            }
+        }
+    }
+}
@@ -1,5 +1,19 @@
 pub mod memory;
+pub mod mapper;
 pub mod core;
 pub mod ppu;
 pub mod timer;
-pub mod apu;
\ No newline at end of file
+pub mod serial;
+pub mod joypad;
+pub mod keymap;
+pub mod movie;
+pub mod trace;
+pub mod disasm;
+pub mod debugger;
+pub mod profiler;
+#[cfg(feature = "link")]
+pub mod link;
+pub mod apu;
+pub mod sgb;
+pub mod snapshot;
+pub mod bess;
\ No newline at end of file
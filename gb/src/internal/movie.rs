@@ -0,0 +1,73 @@
+use std::fmt;
+use crate::internal::snapshot::{Snapshot, StateWriter, StateReader, StateError};
+
+const MOVIE_MAGIC: &[u8; 4] = b"GBMV";
+const MOVIE_VERSION: u16 = 1;
+const ROM_INFO_LEN: usize = 18; // see Memory::get_rom_info
+const BOOT_ROM: u8 = 1 << 0;
+
+#[derive(PartialEq, Debug)]
+pub enum MovieError {
+    InvalidMagic,
+    NewerVersion(u16),
+    Truncated,
+    NeedsBootRom,
+    WrongCartridge,
+    InvalidState(StateError)
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MovieError::InvalidMagic => write!(f, "not a movie"),
+            MovieError::NewerVersion(version) => write!(f, "movie was made by a newer emulator (version {}, this build reads up to {})", version, MOVIE_VERSION),
+            MovieError::Truncated => write!(f, "movie is truncated"),
+            MovieError::NeedsBootRom => write!(f, "movie was recorded with a boot ROM, mount one to play it"),
+            MovieError::WrongCartridge => write!(f, "movie was recorded with a different game"),
+            MovieError::InvalidState(err) => write!(f, "movie starts from a broken state: {}", err)
+        }
+    }
+}
+
+impl std::error::Error for MovieError {}
+
+// the buttons held through every frame from where the recording started, which plays the game back exactly the
+// same way since nothing else from outside goes into a frame
+#[derive(Clone)]
+pub struct Movie {
+    pub rom_info: Vec<u8>,
+    pub boot_rom: bool, // one was mounted while recording, so the start can still have it mapped
+    pub start: Snapshot,
+    pub frames: Vec<u8> // a bit per Button
+}
+
+impl Movie {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = StateWriter::default();
+        w.bytes(MOVIE_MAGIC);
+        w.u16(MOVIE_VERSION);
+        w.bytes(&self.rom_info);
+        w.u8(if self.boot_rom { BOOT_ROM } else { 0 });
+        w.vec(&self.start.to_bytes());
+        w.vec(&self.frames);
+        w.buf
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Movie, MovieError> {
+        if bytes.len() < MOVIE_MAGIC.len() || bytes[..MOVIE_MAGIC.len()] != *MOVIE_MAGIC {
+            return Err(MovieError::InvalidMagic);
+        }
+        let truncated = |_| MovieError::Truncated;
+        let mut r = StateReader::new(&bytes[MOVIE_MAGIC.len()..]);
+        let version = r.u16().map_err(truncated)?;
+        if version > MOVIE_VERSION {
+            return Err(MovieError::NewerVersion(version));
+        }
+        let rom_info = r.bytes(ROM_INFO_LEN).map_err(truncated)?.to_vec();
+        let boot_rom = r.u8().map_err(truncated)? & BOOT_ROM != 0;
+        let start = r.vec().map_err(truncated)?;
+        let frames = r.vec().map_err(truncated)?;
+        let start = Snapshot::from_bytes(&start).map_err(MovieError::InvalidState)?;
+        Ok(Movie { rom_info, boot_rom, start, frames })
+    }
+}
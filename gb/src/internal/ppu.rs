@@ -1,3 +1,7 @@
+use wasm_bindgen::prelude::*;
+use crate::internal::snapshot::{StateWriter, StateReader, StateError};
+use crate::internal::sgb::VRAM_TRANSFER_LEN;
+
 const LCD_ENABLED: u8 = 7;
 const WINDOW_TILE_MAP: u8 = 6;
 const WINDOW_ENABLED: u8 = 5;
@@ -14,14 +18,76 @@ pub enum Mode {
 
 pub type Display = [u8; 23040];
 
+// 64 bit FNV-1a over the shade bytes, stable across runs and platforms so tests can keep tables of known frames
+pub fn hash_display(display: &[u8]) -> u64 {
+    display.iter().fold(0xCBF29CE484222325, |hash, &shade| (hash ^ shade as u64).wrapping_mul(0x100000001B3))
+}
+
+// where the screen and window currently sit on the bg maps, for drawing on top of debug_render_bg_map
+#[wasm_bindgen]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct MapViewport {
+    pub scx: u8,
+    pub scy: u8,
+    pub wx: u8,
+    pub wy: u8,
+    pub bg_map: u8, // 0 for 9800, 1 for 9C00
+    pub window_map: u8,
+    pub window_enabled: bool
+}
+
+// an OAM entry with its flags split out, on_screen says whether any of it lands inside the 160x144 screen
+// with the current object size
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct OamEntry {
+    pub y: u8,
+    pub x: u8,
+    pub tile: u8,
+    pub palette: u8,
+    pub x_flip: bool,
+    pub y_flip: bool,
+    pub bg_priority: bool,
+    pub on_screen: bool
+}
+
+impl OamEntry {
+    pub fn to_bytes(self) -> [u8; 8] {
+        [self.y, self.x, self.tile, self.palette, self.x_flip as u8, self.y_flip as u8, self.bg_priority as u8, self.on_screen as u8]
+    }
+}
+
+pub const TILE_SHEET_WIDTH: usize = 16 * 8;
+pub const TILE_SHEET_HEIGHT: usize = 24 * 8;
+
+// Fifo steps mode 3 one dot at a time so mid-scanline register writes show up on the right pixel, Scanline
+// steps 2 dots at a time and pushes pixels in pairs, which is cheaper but coarser
+#[wasm_bindgen]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PpuMode {
+    Scanline, Fifo
+}
+
+// ways the CPU can touch FE00-FEFF that set off the DMG's OAM corruption bug, a 16 bit inc/dec of a register
+// holding such an address corrupts like a write
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum OamBugAccess {
+    Read, Write, IncDec, ReadIncDec
+}
+
+#[derive(Clone)]
 pub struct PPU {
     pub lcd: Display,
     pub oam: [u8; 0xA0],
     pub vram: [u8; 0x2000],
-    pub vblank_irq_triggered: bool,
-    pub stat_irq_triggered: bool,
+    pub interrupt_requests: u8, // IF bits raised since the bus last collected them, which it does every M-cycle
+    vblank_line: bool,
+    stat_line: bool,
     pub rendered_frame: bool,
+    pub frame_ready: bool, // like rendered_frame but left for the frontend to collect, next_frame consumes the other one
+    pub previous_frame: Option<Box<Display>>, // the completed frame before the last one, for frame blending
+    last_frame: Option<Box<Display>>,
     pub debug_panel: [usize; 144 * 3],
+    pub render_mode: PpuMode,
     control: u8,
     stat: u8,
     ly: u8,
@@ -38,6 +104,8 @@ pub struct PPU {
     window_in_frame: bool,
     window_line_counter: usize,
     rendered_window_on_scanline: bool,
+    enable_line: bool,
+    enable_frame: bool,
     tick_state: TickState,
 
     sprite_fifo: Vec<ObjectPixel>,
@@ -45,6 +113,7 @@ pub struct PPU {
     sprite_buffer: Vec<Object>,
 }
 
+#[derive(Clone)]
 struct TickState {
     is_fetching_window: bool,
     fetcher_x: usize,
@@ -57,10 +126,18 @@ struct TickState {
 
     oam_ptr: usize,
     bg_fetcher_step: u8,
-    sprite_fetcher_step: u8
+    sprite_fetcher_step: u8,
+
+    // only used by the fifo renderer
+    fetcher_dot: bool,
+    pixels_to_discard: u8,
+    wx_166_stall: u8, // dots the window fetch at WX = 166 has held up the last pixel for
+
+    // dot at which the scanline renderer enters hblank, the fifo renderer gets there on its own
+    mode_3_end: usize
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Default)]
 struct Object {
     y_pos: u8,
     x_pos: u8,
@@ -68,6 +145,7 @@ struct Object {
     sprite_flags: u8
 }
 
+#[derive(Clone, Copy)]
 struct ObjectPixel {
     color_id: u8,
     flags: u8,
@@ -97,25 +175,34 @@ impl PPU {
     pub fn write_registers(&mut self, addr: u16, val: u8) {
         match addr {
             0xFF40 => {
+                let was_enabled = (self.control >> LCD_ENABLED) & 0x1 == 1;
                 self.control = val;
-                if self.control >> 7 & 0x1 == 0 { // if LCD is switched off
-                    self.stat &= 0b11111100; // reset stat mode to 0
-                    self.ly = 0; // reset ly to 0
-                    self.lcd = [0x03; 23040]; // white out background
+                match (was_enabled, (self.control >> LCD_ENABLED) & 0x1 == 1) {
+                    (true, false) => self.switch_off(),
+                    (false, true) => self.switch_on(),
+                    _ => ()
                 }
                 return
             },
-            0xFF41 => self.stat = (val & 0x78) | (self.stat & 0x07), // bottom 3 bits are read only
+            0xFF41 => {
+                // DMG bug (CGB fixed it, this only emulates the DMG): for the cycle of the write every source is
+                // enabled, so any mode but 3 or an LY match raises the line unless it's already high
+                if (self.control >> LCD_ENABLED) & 0x1 == 1 && PPU::stat_line(self.stat | 0x78) {
+                    if !self.stat_line {
+                        self.interrupt_requests |= 0b00000010;
+                    }
+                    self.stat_line = true;
+                }
+                self.stat = (val & 0x78) | (self.stat & 0x07) // bottom 3 bits are read only
+            },
             0xFF42 => self.scy = val,
             0xFF43 => self.scx = val,
             0xFF44 => (), // Read only.
             0xFF45 => {
+                // compared right away, the line is then looked at on the PPU's next dot like after any LY change,
+                // so handlers moving LYC onto the current line get their interrupt
                 self.lyc = val;
-                if self.ly == self.lyc {
-                    self.stat |= 1 << 2;
-                } else {
-                    self.stat &= !(1 << 2);
-                }
+                self.compare_lyc();
             },
             0xFF47 => self.bgp = val,
             0xFF48 => self.obp0 = val,
@@ -127,6 +214,68 @@ impl PPU {
         };
     }
 
+    // the PPU stops on the spot, reads back LY = 0 and mode 0 and the screen goes white
+    fn switch_off(&mut self) {
+        self.stat &= 0b11111100;
+        self.ly = 0;
+        self.compare_lyc();
+        self.lcd = [0x0; 23040];
+        self.scanline_timeline = 0;
+        self.vblank_timeline = 0;
+        self.window_in_frame = false;
+        self.window_line_counter = 0;
+        self.rendered_window_on_scanline = false;
+        self.tick_state = TickState::default();
+        self.background_fifo.clear();
+        self.sprite_fifo.clear();
+        self.sprite_buffer.clear();
+        self.vblank_line = false;
+        self.stat_line = false;
+        self.enable_line = false;
+        self.enable_frame = false;
+        self.forget_frames();
+    }
+
+    // blending with what was on screen before the LCD went off would show a ghost of it
+    fn forget_frames(&mut self) {
+        self.previous_frame = None;
+        self.last_frame = None;
+    }
+
+    // line 0 after turning the LCD on skips the OAM scan and reports mode 0 in its place, the frame it starts
+    // isn't shown, most of it is drawn with whatever state the game was still setting up
+    fn switch_on(&mut self) {
+        // the timelines were only counting blank frames for the frontend, the first real one starts from scratch
+        self.scanline_timeline = 0;
+        self.vblank_timeline = 0;
+        self.enable_line = true;
+        self.enable_frame = true;
+        self.forget_frames();
+    }
+
+    // for states that pick up with the LCD already running (after the boot rom, loading a save file), which
+    // don't get the first frame quirks
+    pub fn restore_control(&mut self, val: u8) {
+        self.write_registers(0xFF40, val);
+        self.enable_line = false;
+        self.enable_frame = false;
+    }
+
+    // a debugger's write, the LCD still switches but STAT and LYC don't request anything
+    pub fn poke_registers(&mut self, addr: u16, val: u8) {
+        match addr {
+            0xFF40 => self.restore_control(val),
+            0xFF41 => self.restore_status(val),
+            0xFF45 => self.lyc = val,
+            _ => self.write_registers(addr, val)
+        }
+    }
+
+    // loading a save file isn't a write from the CPU, so it mustn't set off the STAT write bug
+    pub fn restore_status(&mut self, val: u8) {
+        self.stat = (val & 0x78) | (self.stat & 0x07);
+    }
+
     fn get_mode(&self) -> Mode {
         match self.stat & 0x3 {
             0 => Mode::HBLANK,
@@ -157,14 +306,9 @@ impl PPU {
     }
 
     fn detect_sprite(&mut self) -> Option<Object> {
-        // sort sprites in the order they appear in on the scanline (x pos)
-        for i in 1..self.sprite_buffer.len() {
-            while self.sprite_buffer[i - 1].x_pos > self.sprite_buffer[i].x_pos {
-                let temp = self.sprite_buffer[i - 1];
-                self.sprite_buffer[i - 1] = self.sprite_buffer[i];
-                self.sprite_buffer[i] = temp;
-            }
-        }
+        // sort sprites in the order they appear in on the scanline (x pos), stable so OAM order breaks ties.
+        // sprites fetched first win overlapping pixels since later ones only fill in transparent ones
+        self.sprite_buffer.sort_by_key(|sprite| sprite.x_pos);
 
         // grab the first valid element and pop it from the buffer (can i just always check from the front now that im sorting?)
         for i in 0..self.sprite_buffer.len() {
@@ -176,49 +320,126 @@ impl PPU {
         return None
     }
 
+    // the CPU's access lands at the end of its M-cycle but is made before the PPU steps through that M-cycle,
+    // so the locks look 4 dots ahead
+    fn vram_locked(&self) -> bool {
+        let ahead = self.scanline_timeline + 4;
+        match self.get_mode() {
+            Mode::OAMSCAN => ahead >= 80,
+            Mode::DRAW => ahead < self.tick_state.mode_3_end,
+            Mode::HBLANK => self.enable_line && ahead >= 80, // line 0 after switching the LCD on goes straight to mode 3
+            Mode::VBLANK => false
+        }
+    }
+
+    fn oam_locked(&self) -> bool {
+        let ahead = self.scanline_timeline + 4;
+        match self.get_mode() {
+            Mode::OAMSCAN => true,
+            Mode::DRAW => ahead < self.tick_state.mode_3_end,
+            Mode::HBLANK => (self.control >> LCD_ENABLED) & 0x1 == 1 && self.ly < 143 && ahead >= 456,
+            Mode::VBLANK => self.vblank_timeline + 4 >= 4560
+        }
+    }
+
     pub fn read_vram(&self, addr: u16) -> u8 {
-        if self.get_mode() != Mode::DRAW {
+        if !self.vram_locked() {
             return self.vram[addr as usize];
         }
         0xFF
     }
 
     pub fn write_vram(&mut self, addr: u16, val: u8) {
-        if self.get_mode() != Mode::DRAW {
+        if !self.vram_locked() {
             self.vram[addr as usize] = val;
         }
     }
 
     pub fn read_oam(&self, addr: u16) -> u8 {
-        if self.get_mode() != Mode::DRAW && self.get_mode() != Mode::OAMSCAN {
+        if !self.oam_locked() {
             return self.oam[addr as usize];
         }
         return 0xFF;
     }
 
+    // FEA0-FEFF, the DMG's 0x00 while OAM is free and 0xFF while the PPU has it
+    pub fn read_unusable(&self) -> u8 {
+        if self.oam_locked() { 0xFF } else { 0x00 }
+    }
+
     pub fn write_oam(&mut self, addr: u16, val: u8) {
-        if self.get_mode() != Mode::DRAW && self.get_mode() != Mode::OAMSCAN {
+        if !self.oam_locked() {
             self.oam[addr as usize] = val;
         }
     }
 
-    pub fn sprite_pixel_fetcher(&mut self) {
-        if self.tick_state.current_sprite.is_none() { self.tick_state.current_sprite = self.detect_sprite() }
+    // while the OAM scan runs it reads one 8 byte row per M-cycle, an access from the CPU in the same M-cycle
+    // mangles that row using the one before it (pan docs "OAM corruption bug")
+    pub fn trigger_oam_bug(&mut self, access: OamBugAccess) {
+        if self.get_mode() != Mode::OAMSCAN {
+            return
+        }
+
+        let row = self.tick_state.oam_ptr / 2;
+        match access {
+            OamBugAccess::Read => corrupt_oam_read(&mut self.oam, row),
+            OamBugAccess::Write | OamBugAccess::IncDec => corrupt_oam_write(&mut self.oam, row),
+            OamBugAccess::ReadIncDec => corrupt_oam_read_inc_dec(&mut self.oam, row)
+        }
+    }
+
+    // 2 bytes per row, vertically flipped objects count rows from the bottom of the full 8 or 16 pixel height
+    fn sprite_row_offset(&self, sprite: &Object, sprite_height: u16) -> u16 {
+        // shoutout to nemo for helping me with this math lol
+        let vertical_offset = ((self.ly as u16).wrapping_sub((sprite.y_pos as u16).wrapping_sub(16)) % sprite_height).wrapping_mul(2) as u16;
+        if (sprite.sprite_flags >> 6) & 0x1 == 1 {
+            return ((sprite_height - 1) * 2) - vertical_offset;
+        }
+        vertical_offset
+    }
+
+    fn sprite_height(&self) -> u16 {
+        if self.control >> SPRITE_SIZE & 0x1 == 1 { 16 } else { 8 }
+    }
+
+    // bit 0 of the tile index is ignored for 8x16 objects, the offset then runs into index | 1 for the bottom half
+    fn sprite_tile_number(sprite: &Object, sprite_height: u16) -> u8 {
+        if sprite_height == 16 { sprite.tile_number & 0b11111110 } else { sprite.tile_number }
+    }
 
-        if !self.tick_state.current_sprite.is_none() {
-            let sprite = self.tick_state.current_sprite.as_ref().unwrap();
-            let sprite_height = (if self.control >> SPRITE_SIZE & 0x1 == 1 { 16 } else { 8 }) as u16;
+    fn merge_sprite_pixels(&mut self, sprite: &Object, tile_data_low: u8, tile_data_high: u8) {
+        let horizontal_flip = sprite.sprite_flags >> 5 & 0x1 == 1;
+        let base = if sprite.x_pos < 8 { 8 - sprite.x_pos } else { 0 };
 
-            let vertical_flip = (sprite.sprite_flags >> 6) & 0x1 == 1;
-            
-            // shoutout to nemo for helping me with this math lol
-            let mut vertical_offset = ((self.ly as u16).wrapping_sub((sprite.y_pos as u16).wrapping_sub(16)) % sprite_height).wrapping_mul(2) as u16;
-            if vertical_flip {
-                vertical_offset = ((sprite_height - 1) * 2) - vertical_offset;
+        for i in (base as usize)..8 {
+            let pos = if horizontal_flip { i } else { 7 - i };
+            let fifo_index = i - base as usize; // pixels hidden past the left edge never enter the fifo
+            let pixel = ObjectPixel {
+                color_id: (((tile_data_high >> pos) & 0x1) << 1) | ((tile_data_low >> pos) & 0x1),
+                flags: sprite.sprite_flags,
+                x_pos: sprite.x_pos
+            };
+
+            // mix overlapping pixels
+            if fifo_index < self.sprite_fifo.len() {
+                if self.sprite_fifo[fifo_index].color_id == 0 && pixel.color_id != 0 {
+                    self.sprite_fifo[fifo_index] = pixel;
+                }
+            } else {
+                self.sprite_fifo.push(pixel);
             }
+        }
+    }
+
+    pub fn sprite_pixel_fetcher(&mut self) {
+        if self.tick_state.current_sprite.is_none() { self.tick_state.current_sprite = self.detect_sprite() }
+
+        if let Some(sprite) = self.tick_state.current_sprite {
+            let sprite_height = self.sprite_height();
+            let vertical_offset = self.sprite_row_offset(&sprite, sprite_height);
 
             if self.tick_state.sprite_fetcher_step < 1 {
-                self.tick_state.tile_number = sprite.tile_number;
+                self.tick_state.tile_number = PPU::sprite_tile_number(&sprite, sprite_height);
                 self.tick_state.bg_fetcher_step = 0;
                 self.tick_state.sprite_fetcher_step += 1;
             } else if self.tick_state.sprite_fetcher_step < 2 {
@@ -230,34 +451,62 @@ impl PPU {
                 self.tick_state.tile_data_high = self.vram[(tile + vertical_offset + 1) as usize];
                 self.tick_state.sprite_fetcher_step += 1;
             } else {
-                let horizontal_flip = sprite.sprite_flags >> 5 & 0x1 == 1;
-                let base = if sprite.x_pos < 8 { 8 - sprite.x_pos } else { 0 };
-
-                for i in (base as usize)..8 {
-                    let pos = if horizontal_flip { i } else { 7 - i };
-                    let pixel = ObjectPixel {
-                        color_id: (((self.tick_state.tile_data_high >> pos) & 0x1) << 1) | ((self.tick_state.tile_data_low >> pos) & 0x1), 
-                        flags: sprite.sprite_flags,
-                        x_pos: sprite.x_pos
-                    };
-
-                    // mix overlapping pixels
-                    if i + 1 <= self.sprite_fifo.len() {
-                        if self.sprite_fifo[i].color_id == 0 && pixel.color_id != 0 {
-                            self.sprite_fifo.remove(i);
-                            self.sprite_fifo.insert(i, pixel);
-                        }
-                    } else {
-                        self.sprite_fifo.push(pixel);
-                    }
-                }
-
+                self.merge_sprite_pixels(&sprite, self.tick_state.tile_data_low, self.tick_state.tile_data_high);
                 self.tick_state.current_sprite = self.detect_sprite();
                 self.tick_state.sprite_fetcher_step = 0;
             }
         }
     }
 
+    // SCX and SCY are read on every fetch rather than once per line or frame, so writes from hblank handlers
+    // move the next line and writes during mode 3 move the tiles that haven't been fetched yet
+    fn fetch_bg_tile_number(&mut self) {
+        let mut tile_map: u16 = 0x9800;
+        let tile_x;
+        let tile_y;
+
+        if self.tick_state.is_fetching_window {
+            self.rendered_window_on_scanline = true;
+            if (self.control >> WINDOW_TILE_MAP) & 0x1 == 1 {
+                tile_map = 0x9C00;
+            }
+            tile_x = self.tick_state.fetcher_x as u16 & 0x1F;
+            tile_y = (32 * (self.window_line_counter / 8)) as u16;
+        } else {
+            if (self.control >> BG_TILE_MAP) & 0x1 == 1 {
+                tile_map = 0x9C00;
+            }
+            tile_x = (self.tick_state.fetcher_x as u16 + ((self.scx as u16) / 8)) & 0x1F;
+            tile_y = 32 * ((((self.ly as u16) + (self.scy as u16)) & 0xFF) / 8);
+        }
+        self.tick_state.tile_number = self.vram[((tile_map + ((tile_x + tile_y) & 0x3FF)) - 0x8000) as usize];
+    }
+
+    // offset into vram of a bg/window tile under the current addressing mode
+    fn bg_tile_address(&self, tile_number: u8) -> usize {
+        let tile;
+
+        if (self.control >> TILE_ADDRESSING) & 0x1 == 1 {
+            tile = 0x8000 + (tile_number as u16 * 16)
+        } else {
+            tile = (0x9000 as u16).wrapping_add_signed((tile_number as i8 as i16) * 16);
+        }
+
+        (tile - 0x8000) as usize
+    }
+
+    // vram index of the low byte of the current row of the fetched tile
+    fn bg_tile_data_index(&self) -> usize {
+        let offset = if self.tick_state.is_fetching_window { 2 * (self.window_line_counter % 8) } else { 2 * ((self.ly as usize + self.scy as usize) % 8) };
+        self.bg_tile_address(self.tick_state.tile_number) + offset
+    }
+
+    fn push_bg_pixels(&mut self) {
+        for i in 0..8 {
+            self.background_fifo.push((((self.tick_state.tile_data_high >> (7 - i)) & 0x1) << 1) | ((self.tick_state.tile_data_low >> (7 - i)) & 0x1));
+        }
+    }
+
     pub fn background_pixel_fetcher(&mut self) {
         if (self.control >> BG_OR_WINDOW_ENABLED) & 0x1 == 0 { // clear background with white pixels, sprites unaffected.
             if self.background_fifo.len() <= 8 {
@@ -269,58 +518,20 @@ impl PPU {
         }
 
         if self.tick_state.bg_fetcher_step < 1 {
-            let mut tile_map: u16 = 0x9800;
-            let tile_x;
-            let tile_y;
-
-            if self.tick_state.is_fetching_window {
-                self.rendered_window_on_scanline = true;
-                if (self.control >> WINDOW_TILE_MAP) & 0x1 == 1 {
-                    tile_map = 0x9C00;
-                }
-                tile_x = self.tick_state.fetcher_x as u16 & 0x1F;
-                tile_y = (32 * (self.window_line_counter / 8)) as u16;
-            } else {
-                if (self.control >> BG_TILE_MAP) & 0x1 == 1 {
-                    tile_map = 0x9C00;
-                }
-                tile_x = (self.tick_state.fetcher_x as u16 + ((self.scx as u16) / 8)) & 0x1F;
-                tile_y = 32 * ((((self.ly as u16) + (self.scy as u16)) & 0xFF) / 8);
-            }
-            self.tick_state.tile_number = self.vram[((tile_map + ((tile_x + tile_y) & 0x3FF)) - 0x8000) as usize];
+            self.fetch_bg_tile_number();
             self.tick_state.bg_fetcher_step += 1;
         } else if self.tick_state.bg_fetcher_step < 2 {
-            let offset = if self.tick_state.is_fetching_window { (2 * (self.window_line_counter % 8)) as u16 } else { 2 * ((self.ly as u16 + self.scy as u16) % 8) };
-            let tile;
-
-            if (self.control >> TILE_ADDRESSING) & 0x1 == 1 {
-                tile = 0x8000 + (self.tick_state.tile_number as u16 * 16)
-            } else {
-                tile = (0x9000 as u16).wrapping_add_signed((self.tick_state.tile_number as i8 as i16) * 16);
-            }
-
-            self.tick_state.tile_data_low = self.vram[((tile + offset) - 0x8000) as usize];
+            self.tick_state.tile_data_low = self.vram[self.bg_tile_data_index()];
             self.tick_state.bg_fetcher_step += 1;
         } else if self.tick_state.bg_fetcher_step < 3 {
-            let offset = if self.tick_state.is_fetching_window { (2 * (self.window_line_counter % 8)) as u16 } else { 2 * ((self.ly as u16 + self.scy as u16) % 8) };
-            let tile;
-
-            if (self.control >> TILE_ADDRESSING) & 0x1 == 1 {
-                tile = 0x8000 + (self.tick_state.tile_number as u16 * 16)
-            } else {
-                tile = (0x9000 as u16).wrapping_add_signed((self.tick_state.tile_number as i8 as i16) * 16);
-            }
-
-            self.tick_state.tile_data_high = self.vram[((tile + offset + 1) - 0x8000) as usize];
+            self.tick_state.tile_data_high = self.vram[self.bg_tile_data_index() + 1];
             self.tick_state.bg_fetcher_step += 1;
         } else if self.tick_state.new_scanline {
             self.tick_state.new_scanline = false;
             self.tick_state.bg_fetcher_step = 0;
         } else {
             if self.background_fifo.len() <= 8 {
-                for i in 0..8 {
-                    self.background_fifo.push((((self.tick_state.tile_data_high >> (7 - i)) & 0x1) << 1) | ((self.tick_state.tile_data_low >> (7 - i)) & 0x1));
-                }
+                self.push_bg_pixels();
                 self.tick_state.bg_fetcher_step = 0;
             }
             self.tick_state.fetcher_x += 1;
@@ -336,207 +547,1470 @@ impl PPU {
         panic!("invalid pallete number!");
     }
 
-    fn tick(&mut self) { // 2 dots
-        self.scanline_timeline += 2;
+    // shade of the pixel at the current LCD position, palettes are applied as the pixel leaves the fifos
+    fn mix_pixel(&self, bg_color_id: u8, sprite: Option<ObjectPixel>) -> u8 {
+        let bg_color_value = (self.bgp >> (bg_color_id * 2)) & 0x3;
 
-        if self.wy == self.ly {
-            self.window_in_frame = true;
+        if let Some(sprite) = sprite {
+            let sprite_color_value = self.get_object_color((sprite.flags >> 4) & 0x1, sprite.color_id);
+
+            if sprite.color_id == 0x00 { // sprite is transparent so background is visible
+                return bg_color_value;
+            } else if (sprite.flags >> 7) & 0x1 == 1 && bg_color_id != 0 { // background has priority and isn't transparent
+                return bg_color_value;
+            }
+            return sprite_color_value; // otherwise just default to showing the sprite
         }
+        bg_color_value
+    }
 
-        match self.get_mode() {
-            Mode::OAMSCAN => {
-                if self.sprite_buffer.len() < 10 {
-                    let base_ptr = 4 * self.tick_state.oam_ptr;
-
-                    let y_pos = self.oam[base_ptr];
-                    let x_pos = self.oam[base_ptr + 1];
-                    let mut tile_number = self.oam[base_ptr + 2];
-                    let sprite_flags = self.oam[base_ptr + 3];
-
-                    let mut sprite_height: u8 = 8;
-                    if (self.control >> SPRITE_SIZE) & 0x1 == 1 {
-                        tile_number &= 0b11111110; // bit 0 of tile index for 8x16 objects should be ignored
-                        sprite_height = 16;
-                    }
+    fn window_triggered(&self) -> bool {
+        // WX = 166 would start the window past the last pixel
+        !self.tick_state.is_fetching_window && self.window_in_frame && ((self.control >> WINDOW_ENABLED) & 0x1 == 1) && self.wx < 166 && self.wx <= self.tick_state.scanline_x as u8 + 7
+    }
 
-                    if x_pos > 0 && self.ly + 16 >= y_pos && self.ly + 16 < y_pos + sprite_height {
-                        self.sprite_buffer.push(Object {
-                            y_pos,
-                            x_pos,
-                            tile_number,
-                            sprite_flags
-                        })
-                    }
-                }
+    // window starting at WX < 7 is shifted left by the pixels that would be off screen. at WX = 0 it starts
+    // while the fine scroll is still being thrown away and loses those pixels too, so it jitters with SCX
+    fn window_pixels_off_screen(&self) -> u8 {
+        if self.wx == 0 { 7 + self.scx % 8 } else { 7 - self.wx }
+    }
+
+    // whether the window starts somewhere on the current line, at WX = 166 none of it is shown but the fetch
+    // still holds up the last pixel
+    fn window_on_line(&self) -> bool {
+        self.window_in_frame && ((self.control >> WINDOW_ENABLED) & 0x1 == 1) && self.wx <= 166
+    }
 
-                if self.tick_state.oam_ptr < 39 {
-                    self.tick_state.oam_ptr += 1;
-                } else {
-                    self.update_mode(Mode::DRAW);
-                    self.tick_state.oam_ptr = 0;
+    // 172 dots plus the fine scroll discard, 6 when the window starts and 6 to 11 per object depending on how far
+    // into a background tile it starts, only the first object in each tile waits for the background fetch
+    fn mode_3_length(&self) -> usize {
+        let fine_scroll = (self.scx % 8) as usize;
+        let mut length = 172 + fine_scroll;
+        if self.window_on_line() {
+            length += 6;
+        }
+
+        if (self.control >> SPRITES_ENABLED) & 0x1 == 1 {
+            let mut fetched_tiles = vec![];
+            for sprite in self.sprite_buffer.iter().filter(|sprite| sprite.x_pos < 168) { // objects past the right edge are never fetched
+                let column = (sprite.x_pos as usize + fine_scroll) / 8;
+                if !fetched_tiles.contains(&column) {
+                    fetched_tiles.push(column);
+                    length += 5usize.saturating_sub((sprite.x_pos as usize + fine_scroll) % 8);
                 }
-            },
-            Mode::DRAW => {
-                if (self.control >> SPRITES_ENABLED) & 0x1 == 1 { self.sprite_pixel_fetcher() }
-
-                let sprite_fetching = !self.tick_state.current_sprite.is_none();
-
-                if !sprite_fetching { self.background_pixel_fetcher() }
-
-                if !sprite_fetching {
-                    for _ in 0..2 { // draws 1 pixel per dot
-                        if self.background_fifo.len() > 8 {
-                            if (self.tick_state.scanline_x == 0) && !self.rendered_window_on_scanline { // at the start of each scanline discard SCX mod 8 pixels from FIFO and push the rest to LCD ** A BIT INACCURATE EACH REMOVAL SHOULD BE A CYCLE
-                                for _ in 0..(self.scx % 8) {
-                                    self.background_fifo.remove(0);
-                                }
-                            }
-
-                            let bg_color_id = self.background_fifo.remove(0);
-                            let bg_color_value = (self.bgp >> (bg_color_id * 2)) & 0x3;
-
-                            if self.sprite_fifo.len() > 0 {
-                                let sprite = self.sprite_fifo.remove(0);
-                                let sprite_color_value = self.get_object_color((sprite.flags >> 4) & 0x1, sprite.color_id);    
-
-                                if sprite.color_id == 0x00 { // sprite is transparent so background is visible
-                                    self.lcd[(self.ly as usize * 160) + self.tick_state.scanline_x] = bg_color_value;
-                                } else if (sprite.flags >> 7) & 0x1 == 1 && bg_color_id != 0 { // background has priority and isn't transparent
-                                    self.lcd[(self.ly as usize * 160) + self.tick_state.scanline_x] = bg_color_value;
-                                } else { // otherwise just default to showing the sprite
-                                    self.lcd[(self.ly as usize * 160) + self.tick_state.scanline_x] = sprite_color_value;
-                                }
-                            } else {
-                                self.lcd[(self.ly as usize * 160) + self.tick_state.scanline_x] = bg_color_value;
-                            }
-
-                            self.tick_state.scanline_x += 1;
-                        }
+                length += 6;
+            }
+        }
+        length
+    }
 
-                        /* Encountered window for the first time on a scanline */
-                        if !self.tick_state.is_fetching_window && self.window_in_frame && ((self.control >> WINDOW_ENABLED) & 0x1 == 1) && self.wx <= self.tick_state.scanline_x as u8 + 7 {
-                            self.tick_state.is_fetching_window = true;
-                            self.tick_state.bg_fetcher_step = 0;
-                            self.tick_state.fetcher_x = 0;
-                            self.background_fifo.clear();
-                            break;
-                        }
+    fn oam_scan_step(&mut self) {
+        if self.sprite_buffer.len() < 10 { // only the first 10 entries in OAM order covering the line are selected
+            let base_ptr = 4 * self.tick_state.oam_ptr;
+
+            let y_pos = self.oam[base_ptr];
+            let x_pos = self.oam[base_ptr + 1];
+            let tile_number = self.oam[base_ptr + 2];
+            let sprite_flags = self.oam[base_ptr + 3];
+
+            // entries with x = 0 are off screen but still take up one of the 10 slots
+            if object_covers_line(y_pos, self.ly, self.sprite_height()) {
+                self.sprite_buffer.push(Object {
+                    y_pos,
+                    x_pos,
+                    tile_number,
+                    sprite_flags
+                })
+            }
+        }
+
+        if self.tick_state.oam_ptr < 39 {
+            self.tick_state.oam_ptr += 1;
+        } else {
+            self.start_drawing();
+        }
+    }
+
+    fn start_drawing(&mut self) {
+        self.update_mode(Mode::DRAW);
+        self.tick_state.oam_ptr = 0;
+        self.tick_state.pixels_to_discard = self.scx % 8; // fine scroll is latched when drawing starts
+        self.tick_state.mode_3_end = self.scanline_timeline + self.mode_3_length();
+    }
+
+    // mode 3 of the scanline renderer, nothing here stalls like the hardware does so the pixels don't decide when
+    // hblank starts, a line that isn't done by the time mode 3 should end gets finished off at once
+    fn draw_pixels(&mut self) {
+        if self.tick_state.scanline_x <= 159 {
+            self.draw_pixel_pair();
+        }
+
+        if self.scanline_timeline >= self.tick_state.mode_3_end {
+            while self.tick_state.scanline_x <= 159 {
+                self.draw_pixel_pair();
+            }
+            self.update_mode(Mode::HBLANK);
+        }
+    }
+
+    // fetches on every call and pushes 2 pixels out
+    fn draw_pixel_pair(&mut self) {
+        if (self.control >> SPRITES_ENABLED) & 0x1 == 1 { self.sprite_pixel_fetcher() }
+
+        let sprite_fetching = !self.tick_state.current_sprite.is_none();
+
+        if !sprite_fetching { self.background_pixel_fetcher() }
 
-                        if self.tick_state.scanline_x > 159 {
-                            self.update_mode(Mode::HBLANK);
-                            break
+        if !sprite_fetching {
+            for _ in 0..2 { // draws 1 pixel per dot
+                if self.background_fifo.len() > 8 {
+                    if self.tick_state.scanline_x == 0 { // at the start of each scanline discard SCX mod 8 pixels from FIFO and push the rest to LCD ** A BIT INACCURATE EACH REMOVAL SHOULD BE A CYCLE
+                        let discarded = if self.tick_state.is_fetching_window {
+                            self.window_pixels_off_screen()
+                        } else if !self.rendered_window_on_scanline {
+                            self.scx % 8
+                        } else {
+                            0
+                        };
+                        for _ in 0..discarded {
+                            self.background_fifo.remove(0);
                         }
                     }
+
+                    let bg_color_id = self.background_fifo.remove(0);
+                    let sprite = if self.sprite_fifo.len() > 0 { Some(self.sprite_fifo.remove(0)) } else { None };
+                    self.lcd[(self.ly as usize * 160) + self.tick_state.scanline_x] = self.mix_pixel(bg_color_id, sprite);
+
+                    self.tick_state.scanline_x += 1;
                 }
-            },
-            Mode::HBLANK => {
-                self.tick_state = TickState::default();
-                self.background_fifo.clear();
-                self.sprite_fifo.clear();
-                self.sprite_buffer.clear();
-
-                if self.scanline_timeline == 456 { // 456 dots per scanline
-                    if self.rendered_window_on_scanline {
-                        self.window_line_counter += 1;
-                        self.rendered_window_on_scanline = false;
-                    }
 
-                    self.stat_irq_triggered = false;
-                    self.ly += 1;
-                    if self.ly == self.lyc {
-                        self.stat |= 1 << 2;
-                    } else {
-                        self.stat &= !(1 << 2);
-                    }
-                    if self.ly > 143 {
-                        self.update_mode(Mode::VBLANK);
-                    } else {
-                        self.update_mode(Mode::OAMSCAN);
-                    }
+                /* Encountered window for the first time on a scanline */
+                if self.window_triggered() {
+                    self.tick_state.is_fetching_window = true;
+                    self.tick_state.bg_fetcher_step = 0;
+                    self.tick_state.fetcher_x = 0;
+                    self.background_fifo.clear();
+                    break;
                 }
-            },
-            Mode::VBLANK => {
-                self.window_line_counter = 0;
-                self.vblank_timeline += 2;
-                if self.vblank_timeline == 4560 { // 4560 dots per vblank
-                    self.rendered_frame = true;
-                    self.vblank_timeline = 0;
-                    self.ly = 0;
-                    self.vblank_irq_triggered = false;
-                    self.window_in_frame = false;
-                    self.update_mode(Mode::OAMSCAN);
-                } else if self.vblank_timeline % 456 == 0 {
-                    self.ly += 1;
-                    if self.ly == self.lyc {
-                        self.stat |= 1 << 2;
-                    } else {
-                        self.stat &= !(1 << 2);
-                    }
+
+                if self.tick_state.scanline_x > 159 {
+                    break
                 }
             }
         }
+    }
 
-        if self.scanline_timeline == 456 {
-            self.scanline_timeline = 0;
+    // one dot of the background/window fetcher, get tile -> data low -> data high take 2 dots each and the
+    // push is retried every dot until the fifo is empty, the next fetch starts on the same dot as the push
+    fn bg_fetcher_dot(&mut self) {
+        if self.tick_state.bg_fetcher_step == 3 {
+            if !self.background_fifo.is_empty() {
+                return
+            }
+            self.push_bg_pixels();
+            self.tick_state.fetcher_x += 1;
+            self.tick_state.bg_fetcher_step = 0;
+        }
+
+        self.tick_state.fetcher_dot = !self.tick_state.fetcher_dot;
+        if self.tick_state.fetcher_dot {
+            return
+        }
+
+        match self.tick_state.bg_fetcher_step {
+            0 => self.fetch_bg_tile_number(),
+            1 => self.tick_state.tile_data_low = self.vram[self.bg_tile_data_index()],
+            _ => {
+                self.tick_state.tile_data_high = self.vram[self.bg_tile_data_index() + 1];
+                if self.tick_state.new_scanline { // the first fetch of every line is thrown away
+                    self.tick_state.new_scanline = false;
+                    self.tick_state.bg_fetcher_step = 0;
+                    return
+                }
+            }
         }
+        self.tick_state.bg_fetcher_step += 1;
     }
 
-    pub fn update(&mut self) {
-        if (self.control >> LCD_ENABLED) & 0x1 == 1 {
-            self.tick();
-            self.tick();
+    // encountered window for the first time on a scanline, the fetcher starts over on the window tiles
+    fn start_window(&mut self) {
+        self.tick_state.is_fetching_window = true;
+        self.tick_state.bg_fetcher_step = 0;
+        self.tick_state.fetcher_dot = false;
+        self.tick_state.fetcher_x = 0;
+        self.background_fifo.clear();
+        self.tick_state.pixels_to_discard = if self.tick_state.scanline_x == 0 { self.window_pixels_off_screen() } else { 0 };
+    }
+
+    fn output_pixel(&mut self) {
+        if self.background_fifo.is_empty() {
+            return
+        }
+
+        let bg_color_id = self.background_fifo.remove(0);
+        if self.tick_state.pixels_to_discard > 0 {
+            self.tick_state.pixels_to_discard -= 1;
+            return
+        }
+
+        // background and window are drawn as color 0 while disabled, the fetcher keeps running
+        let bg_color_id = if (self.control >> BG_OR_WINDOW_ENABLED) & 0x1 == 0 { 0 } else { bg_color_id };
+        let sprite = if self.sprite_fifo.is_empty() { None } else { Some(self.sprite_fifo.remove(0)) };
+        self.lcd[(self.ly as usize * 160) + self.tick_state.scanline_x] = self.mix_pixel(bg_color_id, sprite);
+        self.tick_state.scanline_x += 1;
+
+        if self.tick_state.scanline_x > 159 {
+            self.update_mode(Mode::HBLANK);
         }
     }
-}
 
-impl Default for PPU {
-    fn default() -> Self {
-        Self {
-            lcd: [0; 23040],
-            debug_panel: [0; 144 * 3],
-            vram: [0x0; 0x2000],
-            oam: [0x0; 0xA0],
-            ly: 0,
-            lyc: 0,
-            control: 0x0,
-            stat: 0x0,
-            scy: 0,
-            scx: 0,
-            wy: 0,
-            wx: 0,
-            bgp: 0x0,
-            obp0: 0x0,
-            obp1: 0x0,
-            tick_state: TickState::default(),
-            scanline_timeline: 0,
-            vblank_timeline: 0,
-            vblank_irq_triggered: false,
-            stat_irq_triggered: false,
-            sprite_buffer: vec![],
-            background_fifo: vec![],
-            sprite_fifo: vec![],
-            window_in_frame: false,
-            window_line_counter: 0,
-            rendered_window_on_scanline: false,
-            rendered_frame: false,
+    // mode 3 of the fifo renderer, one dot at a time so register writes land on the pixel they would on hardware
+    fn draw_dot(&mut self) {
+        if self.tick_state.current_sprite.is_none() && (self.control >> SPRITES_ENABLED) & 0x1 == 1 {
+            self.tick_state.current_sprite = self.detect_sprite();
+        }
+
+        // pixel output stalls while an object is pending, the background fetcher first finishes the tile
+        // it's working on and the object fetch takes 6 dots from the dot it does, which is 6 to 11 dots in total
+        if let Some(sprite) = self.tick_state.current_sprite {
+            if self.tick_state.bg_fetcher_step < 3 || self.background_fifo.is_empty() {
+                self.bg_fetcher_dot();
+                if self.tick_state.bg_fetcher_step < 3 || self.background_fifo.is_empty() {
+                    return
+                }
+            }
+
+            self.tick_state.sprite_fetcher_step += 1;
+            if self.tick_state.sprite_fetcher_step == 6 {
+                let sprite_height = self.sprite_height();
+                let tile = PPU::sprite_tile_number(&sprite, sprite_height) as u16 * 16 + self.sprite_row_offset(&sprite, sprite_height);
+                self.merge_sprite_pixels(&sprite, self.vram[tile as usize], self.vram[tile as usize + 1]);
+                self.tick_state.sprite_fetcher_step = 0;
+                self.tick_state.current_sprite = None;
+            }
+            return
+        }
+
+        if self.window_triggered() {
+            self.start_window(); // the window fetch begins on this same dot
+        } else if self.wx == 166 && self.window_on_line() && self.tick_state.scanline_x == 159 && self.tick_state.wx_166_stall < 6 {
+            self.tick_state.wx_166_stall += 1;
+            return
         }
+        self.bg_fetcher_dot();
+        self.output_pixel();
     }
-}
 
-impl Default for TickState {
-    fn default() -> Self {
-        Self {
-            oam_ptr: 0,
-            fetcher_x: 0,
-            scanline_x: 0,
-            bg_fetcher_step: 0,
-            sprite_fetcher_step: 0,
-            tile_data_high: 0,
-            tile_data_low: 0,
-            tile_number: 0,
-            new_scanline: true,
-            current_sprite: None,
-            is_fetching_window: false,
+    fn hblank_step(&mut self) {
+        self.tick_state = TickState::default();
+        self.background_fifo.clear();
+        self.sprite_fifo.clear();
+        self.sprite_buffer.clear();
+
+        if self.enable_line && self.scanline_timeline == 80 {
+            self.enable_line = false;
+            self.start_drawing();
+            return
+        }
+
+        if self.scanline_timeline == 456 { // 456 dots per scanline
+            if self.rendered_window_on_scanline {
+                self.window_line_counter += 1;
+                self.rendered_window_on_scanline = false;
+            }
+
+            self.ly += 1;
+            self.compare_lyc();
+            if self.ly > 143 {
+                self.update_mode(Mode::VBLANK);
+            } else {
+                self.update_mode(Mode::OAMSCAN);
+            }
+        }
+    }
+
+    fn compare_lyc(&mut self) {
+        if self.ly == self.lyc {
+            self.stat |= 1 << 2;
+        } else {
+            self.stat &= !(1 << 2);
         }
     }
-}
\ No newline at end of file
+
+    // the interrupts fire on the rising edge of their lines, the STAT sources are OR'd into one line so a source
+    // that is still high blocks the next one from firing
+    fn update_interrupt_lines(&mut self) {
+        let vblank_line = self.get_mode() == Mode::VBLANK;
+        if vblank_line && !self.vblank_line {
+            self.interrupt_requests |= 0b00000001;
+        }
+        self.vblank_line = vblank_line;
+
+        let stat_line = PPU::stat_line(self.stat);
+        if stat_line && !self.stat_line {
+            self.interrupt_requests |= 0b00000010;
+        }
+        self.stat_line = stat_line;
+    }
+
+    fn stat_line(stat: u8) -> bool {
+        ((stat >> 6) & 0x1 == 1 && (stat >> 2) & 0x1 == 1) // LY == LYC
+            || ((stat >> 5) & 0x1 == 1 && stat & 0x3 == 2) // OAM
+            || ((stat >> 4) & 0x1 == 1 && stat & 0x3 == 1) // VBLANK
+            || ((stat >> 3) & 0x1 == 1 && stat & 0x3 == 0) // HBLANK
+    }
+
+    fn vblank_step(&mut self, dots: usize) {
+        self.window_line_counter = 0;
+        self.vblank_timeline += dots;
+        if self.vblank_timeline == 4560 { // 4560 dots per vblank
+            self.rendered_frame = true;
+            self.frame_ready = true;
+            if self.enable_frame {
+                self.enable_frame = false;
+                self.lcd = [0x0; 23040];
+            }
+            self.previous_frame = self.last_frame.replace(Box::new(self.lcd));
+            self.vblank_timeline = 0;
+            self.window_in_frame = false;
+            self.update_mode(Mode::OAMSCAN); // LY is already 0 and compared, LYC = 0 doesn't fire a second time
+        } else if self.vblank_timeline % 456 == 0 {
+            self.ly += 1;
+            self.compare_lyc();
+        } else if self.vblank_timeline == 456 * 9 + 4 {
+            // line 153 only reads as 153 for its first 4 dots, then as 0 for the rest of it. the coincidence flag
+            // drops for a moment in between before matching against 0
+            self.ly = 0;
+            self.stat &= !(1 << 2);
+        } else if self.vblank_timeline == 456 * 9 + 8 {
+            self.compare_lyc();
+        }
+    }
+
+    fn tick(&mut self) { // 2 dots
+        self.scanline_timeline += 2;
+
+        if self.wy == self.ly {
+            self.window_in_frame = true;
+        }
+
+        match self.get_mode() {
+            Mode::OAMSCAN => self.oam_scan_step(),
+            Mode::DRAW => self.draw_pixels(),
+            Mode::HBLANK => self.hblank_step(),
+            Mode::VBLANK => self.vblank_step(2)
+        }
+
+        if self.scanline_timeline == 456 {
+            self.scanline_timeline = 0;
+        }
+        self.update_interrupt_lines();
+    }
+
+    fn dot(&mut self) {
+        self.scanline_timeline += 1;
+
+        if self.wy == self.ly {
+            self.window_in_frame = true;
+        }
+
+        match self.get_mode() {
+            Mode::OAMSCAN => if self.scanline_timeline % 2 == 0 { self.oam_scan_step() }, // 2 dots per OAM entry
+            Mode::DRAW => self.draw_dot(),
+            Mode::HBLANK => self.hblank_step(),
+            Mode::VBLANK => self.vblank_step(1)
+        }
+
+        if self.scanline_timeline == 456 {
+            self.scanline_timeline = 0;
+        }
+        self.update_interrupt_lines();
+    }
+
+    // frames still go by at the usual rate while the LCD is off so the frontend keeps getting (blank) ones, the
+    // timelines count dots and lines of those since nothing else uses them then
+    fn off_dots(&mut self, dots: usize) {
+        self.scanline_timeline += dots;
+        if self.scanline_timeline == 456 {
+            self.scanline_timeline = 0;
+            self.vblank_timeline += 1;
+            if self.vblank_timeline == 154 {
+                self.vblank_timeline = 0;
+                self.rendered_frame = true;
+                self.frame_ready = true;
+            }
+        }
+    }
+
+    // one M-cycle, 4 dots
+    pub fn update(&mut self) {
+        if (self.control >> LCD_ENABLED) & 0x1 == 0 {
+            self.off_dots(4);
+            return
+        }
+
+        match self.render_mode {
+            PpuMode::Scanline => {
+                self.tick();
+                self.tick();
+            },
+            PpuMode::Fifo => for _ in 0..4 { self.dot() }
+        }
+    }
+
+    // all 384 tiles of 8000-97FF in order, 16 to a row, as raw color ids so the sheet doesn't depend on LCDC or the
+    // palettes and can be pulled at any point without touching the renderer
+    // the 256 bg tiles in tile number order, which is what an SGB VRAM transfer sees as long as the map shows
+    // them in order across the screen, and every game sets it up that way
+    pub fn vram_transfer(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(VRAM_TRANSFER_LEN);
+        for tile_number in 0..=255 {
+            let tile = self.bg_tile_address(tile_number);
+            data.extend_from_slice(&self.vram[tile..tile + 16]);
+        }
+        data
+    }
+
+    pub fn debug_render_tiles(&self) -> Vec<u8> {
+        let mut sheet = vec![0; TILE_SHEET_WIDTH * TILE_SHEET_HEIGHT];
+        for (i, pixel) in sheet.iter_mut().enumerate() {
+            let (x, y) = (i % TILE_SHEET_WIDTH, i / TILE_SHEET_WIDTH);
+            let tile = (y / 8) * 16 + x / 8;
+            *pixel = tile_color_id(&self.vram, tile * 16, x % 8, y % 8);
+        }
+        sheet
+    }
+
+    // one of the two 32x32 maps (0 for 9800, anything else for 9C00) as 256x256 shades through the current
+    // addressing mode and BGP, the viewport wraps around the edges just like the screen does
+    pub fn debug_render_bg_map(&self, which: u8) -> Vec<u8> {
+        let map = if which == 0 { 0x1800 } else { 0x1C00 };
+        let mut image = vec![0; 256 * 256];
+        for (i, pixel) in image.iter_mut().enumerate() {
+            let (x, y) = (i % 256, i / 256);
+            let tile_number = self.vram[map + (y / 8) * 32 + x / 8];
+            let color_id = tile_color_id(&self.vram, self.bg_tile_address(tile_number), x % 8, y % 8);
+            *pixel = (self.bgp >> (2 * color_id)) & 0x3;
+        }
+        image
+    }
+
+    pub fn debug_map_viewport(&self) -> MapViewport {
+        MapViewport {
+            scx: self.scx,
+            scy: self.scy,
+            wx: self.wx,
+            wy: self.wy,
+            bg_map: (self.control >> BG_TILE_MAP) & 0x1,
+            window_map: (self.control >> WINDOW_TILE_MAP) & 0x1,
+            window_enabled: (self.control >> WINDOW_ENABLED) & 0x1 == 1
+        }
+    }
+
+    pub fn debug_oam(&self) -> [OamEntry; 40] {
+        let sprite_height = self.sprite_height();
+        std::array::from_fn(|i| {
+            let [y, x, tile, flags] = [self.oam[4 * i], self.oam[4 * i + 1], self.oam[4 * i + 2], self.oam[4 * i + 3]];
+            OamEntry {
+                y,
+                x,
+                tile,
+                palette: (flags >> 4) & 0x1,
+                x_flip: (flags >> 5) & 0x1 == 1,
+                y_flip: (flags >> 6) & 0x1 == 1,
+                bg_priority: (flags >> 7) & 0x1 == 1,
+                on_screen: x > 0 && x < 168 && y as u16 + sprite_height > 16 && y < 160
+            }
+        })
+    }
+
+    // indices of the entries the OAM scan would pick for line ly out of the current OAM, in OAM order
+    pub fn debug_oam_scan(&self, ly: u8) -> Vec<u8> {
+        let sprite_height = self.sprite_height();
+        (0..40).filter(|&i| object_covers_line(self.oam[4 * i as usize], ly, sprite_height)).take(10).collect()
+    }
+
+    pub fn write_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.lcd);
+        w.bytes(&self.oam);
+        w.bytes(&self.vram);
+        w.bool(self.vblank_line);
+        w.bool(self.stat_line);
+        w.bool(self.rendered_frame);
+        for register in [self.control, self.stat, self.ly, self.lyc, self.scy, self.scx, self.wy, self.wx, self.bgp, self.obp0, self.obp1] {
+            w.u8(register);
+        }
+        w.u16(self.scanline_timeline as u16);
+        w.u16(self.vblank_timeline as u16);
+        w.bool(self.window_in_frame);
+        w.u8(self.window_line_counter as u8);
+        w.bool(self.rendered_window_on_scanline);
+
+        let state = &self.tick_state;
+        w.bool(state.is_fetching_window);
+        w.u8(state.fetcher_x as u8);
+        w.u8(state.scanline_x as u8);
+        w.u8(state.tile_number);
+        w.u8(state.tile_data_low);
+        w.u8(state.tile_data_high);
+        w.bool(state.current_sprite.is_some());
+        state.current_sprite.unwrap_or_default().write_state(w);
+        w.bool(state.new_scanline);
+        w.u8(state.oam_ptr as u8);
+        w.u8(state.bg_fetcher_step);
+        w.u8(state.sprite_fetcher_step);
+
+        w.u8(self.sprite_fifo.len() as u8);
+        for pixel in &self.sprite_fifo {
+            w.u8(pixel.color_id);
+            w.u8(pixel.flags);
+            w.u8(pixel.x_pos);
+        }
+        w.vec(&self.background_fifo);
+        w.u8(self.sprite_buffer.len() as u8);
+        for sprite in &self.sprite_buffer {
+            sprite.write_state(w);
+        }
+    }
+
+    pub fn read_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.fill(&mut self.lcd)?;
+        r.fill(&mut self.oam)?;
+        r.fill(&mut self.vram)?;
+        self.vblank_line = r.bool()?;
+        self.stat_line = r.bool()?;
+        self.rendered_frame = r.bool()?;
+        for register in [&mut self.control, &mut self.stat, &mut self.ly, &mut self.lyc, &mut self.scy, &mut self.scx,
+                         &mut self.wy, &mut self.wx, &mut self.bgp, &mut self.obp0, &mut self.obp1] {
+            *register = r.u8()?;
+        }
+        self.scanline_timeline = r.u16()? as usize;
+        self.vblank_timeline = r.u16()? as usize;
+        self.window_in_frame = r.bool()?;
+        self.window_line_counter = r.u8()? as usize;
+        self.rendered_window_on_scanline = r.bool()?;
+
+        let state = &mut self.tick_state;
+        state.is_fetching_window = r.bool()?;
+        state.fetcher_x = r.u8()? as usize;
+        state.scanline_x = r.u8()? as usize;
+        state.tile_number = r.u8()?;
+        state.tile_data_low = r.u8()?;
+        state.tile_data_high = r.u8()?;
+        let has_sprite = r.bool()?;
+        let sprite = Object::read_state(r)?;
+        state.current_sprite = if has_sprite { Some(sprite) } else { None };
+        state.new_scanline = r.bool()?;
+        state.oam_ptr = r.u8()? as usize;
+        state.bg_fetcher_step = r.u8()?;
+        state.sprite_fetcher_step = r.u8()?;
+
+        self.sprite_fifo.clear();
+        for _ in 0..r.u8()? {
+            self.sprite_fifo.push(ObjectPixel { color_id: r.u8()?, flags: r.u8()?, x_pos: r.u8()? });
+        }
+        self.background_fifo = r.vec()?;
+        self.sprite_buffer.clear();
+        for _ in 0..r.u8()? {
+            self.sprite_buffer.push(Object::read_state(r)?);
+        }
+        self.debug_panel = [0; 144 * 3];
+        self.forget_frames();
+        Ok(())
+    }
+
+    // added in snapshot version 3 (mode_3_end in 4, the enable flags in 5), written after everything else so older states only need these appended
+    pub fn write_render_state(&self, w: &mut StateWriter) {
+        w.bool(self.render_mode == PpuMode::Fifo);
+        w.bool(self.tick_state.fetcher_dot);
+        w.u8(self.tick_state.pixels_to_discard);
+        w.u16(self.tick_state.mode_3_end as u16);
+        w.bool(self.enable_line);
+        w.bool(self.enable_frame);
+    }
+
+    pub fn read_render_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.render_mode = if r.bool()? { PpuMode::Fifo } else { PpuMode::Scanline };
+        self.tick_state.fetcher_dot = r.bool()?;
+        self.tick_state.pixels_to_discard = r.u8()?;
+        self.tick_state.mode_3_end = r.u16()? as usize;
+        self.enable_line = r.bool()?;
+        self.enable_frame = r.bool()?;
+        Ok(())
+    }
+
+    // added in snapshot version 7, after the OAM DMA state
+    pub fn write_window_state(&self, w: &mut StateWriter) {
+        w.u8(self.tick_state.wx_166_stall);
+    }
+
+    pub fn read_window_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.tick_state.wx_166_stall = r.u8()?;
+        Ok(())
+    }
+}
+
+fn object_covers_line(y_pos: u8, ly: u8, sprite_height: u16) -> bool {
+    let line = ly as u16 + 16;
+    line >= y_pos as u16 && line < y_pos as u16 + sprite_height
+}
+
+// color id (0-3, before any palette) of one pixel of the tile starting at tile_addr, an offset into vram
+fn tile_color_id(vram: &[u8; 0x2000], tile_addr: usize, x: usize, y: usize) -> u8 {
+    let low = vram[tile_addr + y * 2];
+    let high = vram[tile_addr + y * 2 + 1];
+    (((high >> (7 - x)) & 0x1) << 1) | ((low >> (7 - x)) & 0x1)
+}
+
+fn oam_word(oam: &[u8; 0xA0], row: usize, word: usize) -> u16 {
+    u16::from_le_bytes([oam[row * 8 + word * 2], oam[row * 8 + word * 2 + 1]])
+}
+
+fn set_oam_word(oam: &mut [u8; 0xA0], row: usize, word: usize, val: u16) {
+    oam[row * 8 + word * 2..row * 8 + word * 2 + 2].copy_from_slice(&val.to_le_bytes());
+}
+
+// the first word of the row is mixed with the first and third words of the row before it, the other three are
+// copied over from there. row 0 has nothing before it and is left alone
+fn corrupt_oam_row(oam: &mut [u8; 0xA0], row: usize, mix: fn(u16, u16, u16) -> u16) {
+    if row == 0 {
+        return
+    }
+    let (a, b, c) = (oam_word(oam, row, 0), oam_word(oam, row - 1, 0), oam_word(oam, row - 1, 2));
+    set_oam_word(oam, row, 0, mix(a, b, c));
+    oam.copy_within((row - 1) * 8 + 2..row * 8, row * 8 + 2);
+}
+
+fn corrupt_oam_write(oam: &mut [u8; 0xA0], row: usize) {
+    corrupt_oam_row(oam, row, |a, b, c| ((a ^ c) & (b ^ c)) ^ c);
+}
+
+fn corrupt_oam_read(oam: &mut [u8; 0xA0], row: usize) {
+    corrupt_oam_row(oam, row, |a, b, c| b | (a & c));
+}
+
+// a read in the same M-cycle as an inc/dec first mangles the row before and copies it over the current row and
+// the one two before, except near either end of OAM, and then corrupts like a plain read
+fn corrupt_oam_read_inc_dec(oam: &mut [u8; 0xA0], row: usize) {
+    if (4..19).contains(&row) {
+        let (a, b, c, d) = (oam_word(oam, row - 2, 0), oam_word(oam, row - 1, 0), oam_word(oam, row, 0), oam_word(oam, row - 1, 2));
+        set_oam_word(oam, row - 1, 0, (b & (a | c | d)) | (a & c & d));
+        oam.copy_within((row - 1) * 8..row * 8, row * 8);
+        oam.copy_within((row - 1) * 8..row * 8, (row - 2) * 8);
+    }
+    corrupt_oam_read(oam, row);
+}
+
+impl Object {
+    fn write_state(&self, w: &mut StateWriter) {
+        w.bytes(&[self.y_pos, self.x_pos, self.tile_number, self.sprite_flags]);
+    }
+
+    fn read_state(r: &mut StateReader) -> Result<Object, StateError> {
+        Ok(Object { y_pos: r.u8()?, x_pos: r.u8()?, tile_number: r.u8()?, sprite_flags: r.u8()? })
+    }
+}
+
+impl Default for PPU {
+    fn default() -> Self {
+        Self {
+            lcd: [0; 23040],
+            debug_panel: [0; 144 * 3],
+            vram: [0x0; 0x2000],
+            oam: [0x0; 0xA0],
+            ly: 0,
+            lyc: 0,
+            control: 0x0,
+            stat: 0x0,
+            scy: 0,
+            scx: 0,
+            wy: 0,
+            wx: 0,
+            bgp: 0x0,
+            obp0: 0x0,
+            obp1: 0x0,
+            tick_state: TickState::default(),
+            scanline_timeline: 0,
+            vblank_timeline: 0,
+            interrupt_requests: 0,
+            vblank_line: false,
+            stat_line: false,
+            sprite_buffer: vec![],
+            background_fifo: vec![],
+            sprite_fifo: vec![],
+            window_in_frame: false,
+            window_line_counter: 0,
+            rendered_window_on_scanline: false,
+            rendered_frame: false,
+            frame_ready: false,
+            previous_frame: None,
+            last_frame: None,
+            enable_line: false,
+            enable_frame: false,
+            render_mode: PpuMode::Fifo,
+        }
+    }
+}
+
+impl Default for TickState {
+    fn default() -> Self {
+        Self {
+            oam_ptr: 0,
+            fetcher_x: 0,
+            scanline_x: 0,
+            bg_fetcher_step: 0,
+            sprite_fetcher_step: 0,
+            tile_data_high: 0,
+            tile_data_low: 0,
+            tile_number: 0,
+            new_scanline: true,
+            current_sprite: None,
+            is_fetching_window: false,
+            fetcher_dot: false,
+            pixels_to_discard: 0,
+            wx_166_stall: 0,
+            mode_3_end: 0,
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: u8 = 0;
+    const LIGHT: u8 = 1;
+    const BLACK: u8 = 3;
+
+    // tile 1 is solid black, tile 2 only has its leftmost column set to light grey, tile 3 is solid light grey
+    fn ppu_with_tiles() -> PPU {
+        let mut ppu = PPU::default();
+        for row in 0..8 {
+            ppu.vram[0x10 + row * 2] = 0xFF;
+            ppu.vram[0x10 + row * 2 + 1] = 0xFF;
+            ppu.vram[0x20 + row * 2] = 0x80;
+            ppu.vram[0x30 + row * 2] = 0xFF;
+        }
+        ppu.bgp = 0b11100100;
+        ppu
+    }
+
+    fn fill_map(ppu: &mut PPU, map: usize, tile: u8) {
+        ppu.vram[map - 0x8000..map - 0x8000 + 0x400].fill(tile);
+    }
+
+    fn run_frame(ppu: &mut PPU) {
+        while !ppu.rendered_frame {
+            ppu.update();
+        }
+        ppu.rendered_frame = false;
+    }
+
+    // the first frame after power on starts in the middle of nowhere, so tests look at the second one
+    fn render(ppu: &mut PPU, control: u8) {
+        ppu.write_registers(0xFF40, control);
+        run_frame(ppu);
+        run_frame(ppu);
+    }
+
+    fn run_until_line(ppu: &mut PPU, ly: u8) {
+        while ppu.ly != ly || ppu.get_mode() != Mode::OAMSCAN {
+            ppu.update();
+        }
+    }
+
+    fn pixel(ppu: &PPU, x: usize, y: usize) -> u8 {
+        ppu.lcd[y * 160 + x]
+    }
+
+    #[test]
+    fn tile_sheet_ignores_addressing_mode() {
+        let mut ppu = ppu_with_tiles();
+        ppu.vram[0x17FF] = 0x01; // bottom right pixel of tile 383 has its high bit set
+        ppu.write_registers(0xFF40, 1 << LCD_ENABLED);
+        run_frame(&mut ppu);
+
+        let sheet = ppu.debug_render_tiles();
+        let at = |x: usize, y: usize| sheet[y * TILE_SHEET_WIDTH + x];
+        assert_eq!(sheet.len(), TILE_SHEET_WIDTH * TILE_SHEET_HEIGHT);
+        assert!((0..8).all(|y| (8..16).all(|x| at(x, y) == BLACK)));
+        assert!((0..8).all(|y| at(16, y) == LIGHT && (17..24).all(|x| at(x, y) == 0)));
+        assert!((0..8).all(|y| (24..32).all(|x| at(x, y) == LIGHT)));
+        assert_eq!(at(127, 191), 2);
+        assert_eq!(sheet.iter().filter(|&&id| id != 0).count(), 64 + 8 + 64 + 1);
+
+        let scanline_timeline = ppu.scanline_timeline;
+        ppu.write_registers(0xFF40, (1 << LCD_ENABLED) | (1 << TILE_ADDRESSING));
+        assert_eq!(ppu.debug_render_tiles(), sheet);
+        assert_eq!(ppu.scanline_timeline, scanline_timeline);
+    }
+
+    #[test]
+    fn bg_map_follows_addressing_mode_and_palette() {
+        let mut ppu = ppu_with_tiles();
+        fill_map(&mut ppu, 0x9800, 1);
+        fill_map(&mut ppu, 0x9C00, 3);
+        ppu.vram[0x1800 + 32 + 2] = 2; // tile row 1, column 2
+        ppu.scx = 250;
+        ppu.wy = 40;
+        ppu.write_registers(0xFF40, (1 << LCD_ENABLED) | (1 << TILE_ADDRESSING) | (1 << BG_TILE_MAP));
+
+        let map = ppu.debug_render_bg_map(0);
+        assert_eq!(map.len(), 256 * 256);
+        assert_eq!(map[0], BLACK);
+        assert_eq!(map[8 * 256 + 16], LIGHT);
+        assert_eq!(map[8 * 256 + 17], 0);
+        assert!(ppu.debug_render_bg_map(1).iter().all(|&shade| shade == LIGHT));
+
+        // 8800 addressing puts tile 1 at 9010, which is empty
+        ppu.write_registers(0xFF40, 1 << LCD_ENABLED);
+        assert!(ppu.debug_render_bg_map(0).iter().all(|&shade| shade == 0));
+        ppu.bgp = 0b11100111;
+        assert!(ppu.debug_render_bg_map(0).iter().all(|&shade| shade == BLACK));
+
+        let viewport = ppu.debug_map_viewport();
+        assert_eq!((viewport.scx, viewport.scy, viewport.wx, viewport.wy), (250, 0, 0, 40));
+        assert_eq!((viewport.bg_map, viewport.window_map, viewport.window_enabled), (0, 0, false));
+    }
+
+    const WINDOW: u8 = (1 << LCD_ENABLED) | (1 << WINDOW_TILE_MAP) | (1 << WINDOW_ENABLED) | (1 << TILE_ADDRESSING) | (1 << BG_OR_WINDOW_ENABLED);
+
+    #[test]
+    fn window_starts_at_wx_minus_7_and_wy() {
+        for mode in [PpuMode::Scanline, PpuMode::Fifo] {
+            let mut ppu = ppu_with_tiles();
+            ppu.render_mode = mode;
+            fill_map(&mut ppu, 0x9C00, 1);
+            ppu.wx = 87;
+            ppu.wy = 72;
+            render(&mut ppu, WINDOW);
+
+            assert_eq!(pixel(&ppu, 100, 71), WHITE);
+            assert_eq!(pixel(&ppu, 79, 72), WHITE);
+            assert_eq!(pixel(&ppu, 80, 72), BLACK);
+            assert_eq!(pixel(&ppu, 159, 143), BLACK);
+
+            // same frame with the window disabled only shows the background
+            render(&mut ppu, WINDOW & !(1 << WINDOW_ENABLED));
+            assert!(ppu.lcd.iter().all(|&shade| shade == WHITE));
+        }
+    }
+
+    #[test]
+    fn window_ignores_fine_scroll() {
+        for mode in [PpuMode::Scanline, PpuMode::Fifo] {
+            let mut ppu = ppu_with_tiles();
+            ppu.render_mode = mode;
+            fill_map(&mut ppu, 0x9800, 2);
+            fill_map(&mut ppu, 0x9C00, 2);
+            ppu.scx = 3;
+            ppu.wx = 87;
+            render(&mut ppu, WINDOW);
+
+            // background columns are scrolled by SCX, the window's start at WX - 7
+            assert_eq!(pixel(&ppu, 5, 10), LIGHT);
+            assert_eq!(pixel(&ppu, 8, 10), WHITE);
+            assert_eq!(pixel(&ppu, 77, 10), LIGHT);
+            assert_eq!(pixel(&ppu, 79, 10), WHITE);
+            for x in 80..160 {
+                assert_eq!(pixel(&ppu, x, 10), if (x - 80) % 8 == 0 { LIGHT } else { WHITE }, "x = {}", x);
+            }
+        }
+    }
+
+    #[test]
+    fn window_with_wx_below_7_is_shifted_left() {
+        for mode in [PpuMode::Scanline, PpuMode::Fifo] {
+            let mut ppu = ppu_with_tiles();
+            ppu.render_mode = mode;
+            fill_map(&mut ppu, 0x9C00, 2);
+            ppu.wx = 3;
+            render(&mut ppu, WINDOW);
+
+            for x in 0..160 {
+                assert_eq!(pixel(&ppu, x, 20), if x % 8 == 4 { LIGHT } else { WHITE }, "x = {}", x);
+            }
+        }
+    }
+
+    #[test]
+    fn window_at_wx_166_is_hidden() {
+        for mode in [PpuMode::Scanline, PpuMode::Fifo] {
+            let mut ppu = ppu_with_tiles();
+            ppu.render_mode = mode;
+            fill_map(&mut ppu, 0x9C00, 1);
+            ppu.wx = 166;
+            render(&mut ppu, WINDOW);
+            assert!(ppu.lcd.iter().all(|&shade| shade == WHITE));
+        }
+    }
+
+    #[test]
+    fn window_at_wx_0_moves_with_fine_scroll() {
+        for mode in [PpuMode::Scanline, PpuMode::Fifo] {
+            let mut ppu = ppu_with_tiles();
+            ppu.render_mode = mode;
+            fill_map(&mut ppu, 0x9C00, 2);
+            ppu.wx = 0;
+            render(&mut ppu, WINDOW);
+            for x in 0..160 {
+                assert_eq!(pixel(&ppu, x, 20), if x % 8 == 1 { LIGHT } else { WHITE }, "x = {}", x);
+            }
+
+            ppu.scx = 3;
+            render(&mut ppu, WINDOW);
+            for x in 0..160 {
+                assert_eq!(pixel(&ppu, x, 20), if x % 8 == 6 { LIGHT } else { WHITE }, "{:?} x = {}", mode, x);
+            }
+        }
+    }
+
+    #[test]
+    fn window_toggled_across_wx_values() {
+        for mode in [PpuMode::Scanline, PpuMode::Fifo] {
+            let mut ppu = ppu_with_tiles();
+            ppu.render_mode = mode;
+            // even window map rows are black, odd ones light grey
+            for row in 0..32 {
+                let start = 0x1C00 + row * 32;
+                ppu.vram[start..start + 32].fill(if row % 2 == 0 { 1 } else { 3 });
+            }
+            ppu.wx = 166;
+            render(&mut ppu, WINDOW);
+            let expected = |dots: usize| if mode == PpuMode::Scanline { dots + dots % 2 } else { dots };
+            assert_eq!(mode_3_dots(&mut ppu, 4), expected(172 + 6), "{:?}", mode);
+
+            // nothing is drawn at WX = 166 so the line counter doesn't move until WX = 0
+            run_until_line(&mut ppu, 8);
+            ppu.write_registers(0xFF4B, 0);
+            run_until_line(&mut ppu, 16);
+            ppu.write_registers(0xFF4B, 7);
+            run_frame(&mut ppu);
+
+            assert!((0..8).all(|y| line(&ppu, y, 0..160) == [WHITE; 160]), "{:?}", mode);
+            assert!((8..16).all(|y| line(&ppu, y, 0..160) == [BLACK; 160]), "{:?}", mode);
+            assert_eq!(line(&ppu, 16, 0..160), [LIGHT; 160]);
+            assert_eq!(line(&ppu, 24, 0..160), [BLACK; 160]);
+
+            // moving WY onto a line that already went by doesn't start the window late
+            ppu.wy = 100;
+            run_frame(&mut ppu);
+            run_until_line(&mut ppu, 50);
+            ppu.write_registers(0xFF4A, 30);
+            run_frame(&mut ppu);
+            assert!(ppu.lcd.iter().all(|&shade| shade == WHITE), "{:?}", mode);
+            run_frame(&mut ppu);
+            assert_eq!(line(&ppu, 29, 0..160), [WHITE; 160]);
+            assert_eq!(line(&ppu, 30, 0..160), [BLACK; 160]);
+        }
+    }
+
+    #[test]
+    fn window_line_counter_survives_disabling_window() {
+        for mode in [PpuMode::Scanline, PpuMode::Fifo] {
+            let mut ppu = ppu_with_tiles();
+            ppu.render_mode = mode;
+            // even window map rows are black, odd ones light grey
+            for row in 0..32 {
+                let start = 0x1C00 + row * 32;
+                ppu.vram[start..start + 32].fill(if row % 2 == 0 { 1 } else { 3 });
+            }
+            ppu.wx = 7;
+            render(&mut ppu, WINDOW);
+
+            run_until_line(&mut ppu, 8);
+            ppu.write_registers(0xFF40, WINDOW & !(1 << WINDOW_ENABLED));
+            run_until_line(&mut ppu, 16);
+            ppu.write_registers(0xFF40, WINDOW);
+            run_frame(&mut ppu);
+
+            assert_eq!(pixel(&ppu, 40, 7), BLACK);
+            assert_eq!(pixel(&ppu, 40, 8), WHITE);
+            assert_eq!(pixel(&ppu, 40, 15), WHITE);
+            assert_eq!(pixel(&ppu, 40, 16), LIGHT); // continues with the second window row, not the third
+            assert_eq!(pixel(&ppu, 40, 24), BLACK);
+        }
+    }
+
+    const OBJECTS: u8 = (1 << LCD_ENABLED) | (1 << TILE_ADDRESSING) | (1 << SPRITES_ENABLED) | (1 << BG_OR_WINDOW_ENABLED);
+
+    // tiles 4 and 5 form a tall sprite, row r lights up column r % 8, black in the top half and light grey in the bottom
+    fn ppu_with_tall_sprite() -> PPU {
+        let mut ppu = ppu_with_tiles();
+        for row in 0..8 {
+            ppu.vram[0x40 + row * 2] = 0x80 >> row;
+            ppu.vram[0x40 + row * 2 + 1] = 0x80 >> row;
+            ppu.vram[0x50 + row * 2] = 0x80 >> row;
+        }
+        ppu.obp0 = 0b11100100;
+        ppu
+    }
+
+    fn set_object(ppu: &mut PPU, index: usize, y: u8, x: u8, tile: u8, flags: u8) {
+        ppu.oam[index * 4..index * 4 + 4].copy_from_slice(&[y, x, tile, flags]);
+    }
+
+    // expected shade of column x of the sprite drawn at the top left of the screen on line y
+    fn tall_sprite_shade(row: usize, x: usize) -> u8 {
+        if x != row % 8 {
+            WHITE
+        } else if row < 8 {
+            BLACK
+        } else {
+            LIGHT
+        }
+    }
+
+    #[test]
+    fn oam_inspector_decodes_entries_and_scan() {
+        let mut ppu = PPU::default();
+        set_object(&mut ppu, 0, 16, 8, 5, 0b11110000);
+        set_object(&mut ppu, 1, 1, 8, 0, 0x00); // only reaches line 0 as 8x16
+        set_object(&mut ppu, 2, 16, 0, 0, 0x00); // x = 0 is hidden but still scanned
+        for i in 3..15 {
+            set_object(&mut ppu, i, 20, 50, 0, 0x00);
+        }
+
+        let oam = ppu.debug_oam();
+        assert_eq!(oam[0], OamEntry { y: 16, x: 8, tile: 5, palette: 1, x_flip: true, y_flip: true, bg_priority: true, on_screen: true });
+        assert!(!oam[1].on_screen && !oam[2].on_screen && !oam[20].on_screen);
+        assert_eq!(ppu.debug_oam_scan(0), vec![0, 2]);
+        assert_eq!(ppu.debug_oam_scan(4), vec![0, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+
+        ppu.control |= 1 << SPRITE_SIZE;
+        assert!(ppu.debug_oam()[1].on_screen);
+        assert_eq!(ppu.debug_oam_scan(0), vec![0, 1, 2]);
+        assert_eq!(ppu.debug_oam_scan(200), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn tall_sprites_cover_two_tiles() {
+        for mode in [PpuMode::Scanline, PpuMode::Fifo] {
+            let mut ppu = ppu_with_tall_sprite();
+            ppu.render_mode = mode;
+            set_object(&mut ppu, 0, 16, 8, 5, 0x00); // low bit of the index is ignored
+            set_object(&mut ppu, 1, 255, 40, 4, 0x00); // the y range check must not overflow
+            render(&mut ppu, OBJECTS | (1 << SPRITE_SIZE));
+
+            for y in 0..24 {
+                for x in 0..8 {
+                    let expected = if y < 16 { tall_sprite_shade(y, x) } else { WHITE };
+                    assert_eq!(pixel(&ppu, x, y), expected, "x = {}, y = {}", x, y);
+                }
+            }
+
+            // in 8x8 mode the same entry only covers the tile it names
+            render(&mut ppu, OBJECTS);
+            for y in 0..16 {
+                let expected = if y < 8 { if y == 0 { LIGHT } else { WHITE } } else { WHITE };
+                assert_eq!(pixel(&ppu, 0, y), expected, "y = {}", y);
+            }
+        }
+    }
+
+    #[test]
+    fn vertical_flip_mirrors_full_height() {
+        for mode in [PpuMode::Scanline, PpuMode::Fifo] {
+            let mut ppu = ppu_with_tall_sprite();
+            ppu.render_mode = mode;
+            set_object(&mut ppu, 0, 16, 8, 4, 1 << 6);
+            render(&mut ppu, OBJECTS | (1 << SPRITE_SIZE));
+
+            for y in 0..16 {
+                for x in 0..8 {
+                    assert_eq!(pixel(&ppu, x, y), tall_sprite_shade(15 - y, x), "x = {}, y = {}", x, y);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn tall_sprites_clip_at_top_of_screen() {
+        for mode in [PpuMode::Scanline, PpuMode::Fifo] {
+            let mut ppu = ppu_with_tall_sprite();
+            ppu.render_mode = mode;
+            set_object(&mut ppu, 0, 8, 8, 4, 0x00);
+            render(&mut ppu, OBJECTS | (1 << SPRITE_SIZE));
+
+            for y in 0..16 {
+                for x in 0..8 {
+                    let expected = if y < 8 { tall_sprite_shade(y + 8, x) } else { WHITE };
+                    assert_eq!(pixel(&ppu, x, y), expected, "x = {}, y = {}", x, y);
+                }
+            }
+        }
+    }
+
+    const DARK: u8 = 2;
+
+    // tile 6 is solid dark grey, tile 7 has a transparent left half and a light grey right half
+    fn ppu_with_sprite_tiles() -> PPU {
+        let mut ppu = ppu_with_tiles();
+        for row in 0..8 {
+            ppu.vram[0x60 + row * 2 + 1] = 0xFF;
+            ppu.vram[0x70 + row * 2] = 0x0F;
+        }
+        ppu.obp0 = 0b11100100;
+        ppu
+    }
+
+    fn line(ppu: &PPU, y: usize, xs: std::ops::Range<usize>) -> Vec<u8> {
+        xs.map(|x| pixel(ppu, x, y)).collect()
+    }
+
+    #[test]
+    fn overlapping_sprites_prefer_smaller_x_then_oam_index() {
+        for mode in [PpuMode::Scanline, PpuMode::Fifo] {
+            let mut ppu = ppu_with_sprite_tiles();
+            ppu.render_mode = mode;
+            set_object(&mut ppu, 0, 16, 24, 1, 0x00); // black, covers 16..24
+            set_object(&mut ppu, 1, 16, 20, 7, 0x00); // transparent then light grey, covers 12..20
+            set_object(&mut ppu, 2, 16, 20, 6, 0x00); // dark grey, same x as entry 1 so it loses every opaque pixel to it
+            set_object(&mut ppu, 3, 16, 50, 1, 0x00); // black with a later transparent entry on top at a smaller x
+            set_object(&mut ppu, 4, 16, 46, 7, 0x00);
+            render(&mut ppu, OBJECTS);
+
+            let expected = [[WHITE; 4], [DARK; 4], [LIGHT; 4], [BLACK; 4], [WHITE; 4]].concat();
+            assert_eq!(line(&ppu, 0, 8..28), expected);
+            assert_eq!(line(&ppu, 0, 38..50), [[WHITE; 4], [LIGHT; 4], [BLACK; 4]].concat());
+        }
+    }
+
+    #[test]
+    fn sprites_clipped_by_left_edge_mix_in_place() {
+        for mode in [PpuMode::Scanline, PpuMode::Fifo] {
+            let mut ppu = ppu_with_sprite_tiles();
+            ppu.render_mode = mode;
+            // all three are fetched at the start of the line, in reverse OAM order
+            set_object(&mut ppu, 0, 16, 8, 1, 0x00); // black, covers 0..8
+            set_object(&mut ppu, 1, 16, 6, 6, 0x00); // dark grey, covers -2..6
+            set_object(&mut ppu, 2, 16, 4, 7, 0x00); // light grey right half covers 0..4
+            render(&mut ppu, OBJECTS);
+
+            assert_eq!(line(&ppu, 0, 0..10), [LIGHT, LIGHT, LIGHT, LIGHT, DARK, DARK, BLACK, BLACK, WHITE, WHITE]);
+        }
+    }
+
+    #[test]
+    fn bg_priority_hides_sprite_behind_non_zero_colors() {
+        for mode in [PpuMode::Scanline, PpuMode::Fifo] {
+            let mut ppu = ppu_with_sprite_tiles();
+            ppu.render_mode = mode;
+            fill_map(&mut ppu, 0x9800, 2); // background color 1 on every x % 8 == 0
+            set_object(&mut ppu, 0, 16, 16, 1, 1 << 7); // black behind the background
+            set_object(&mut ppu, 1, 16, 16, 6, 0x00); // dark grey, loses to entry 0 even where entry 0 is hidden
+            set_object(&mut ppu, 2, 16, 40, 6, 0x00);
+            render(&mut ppu, OBJECTS);
+
+            assert_eq!(line(&ppu, 0, 8..16), [LIGHT, BLACK, BLACK, BLACK, BLACK, BLACK, BLACK, BLACK]);
+            assert_eq!(line(&ppu, 0, 32..40), [DARK; 8]); // sprites without the attribute draw over every color
+        }
+    }
+
+    #[test]
+    fn only_first_ten_sprites_on_a_line_are_drawn() {
+        for mode in [PpuMode::Scanline, PpuMode::Fifo] {
+            let mut ppu = ppu_with_sprite_tiles();
+            ppu.render_mode = mode;
+            // x order is the reverse of OAM order, entries 10 and 11 land on the first two tiles of the line
+            for i in 0..12 {
+                set_object(&mut ppu, i, 16, 8 + 8 * (11 - i as u8), 1, 0x00);
+            }
+            render(&mut ppu, OBJECTS);
+
+            assert_eq!(line(&ppu, 0, 0..16), [WHITE; 16]);
+            assert_eq!(line(&ppu, 0, 16..96), [BLACK; 80]);
+        }
+    }
+
+    #[test]
+    fn hidden_sprites_still_use_up_the_line() {
+        for mode in [PpuMode::Scanline, PpuMode::Fifo] {
+            let mut ppu = ppu_with_sprite_tiles();
+            ppu.render_mode = mode;
+            set_object(&mut ppu, 0, 16, 0, 1, 0x00); // x = 0 is never visible but is still selected
+            for i in 1..11 {
+                set_object(&mut ppu, i, 16, 8 + 8 * i as u8, 1, 0x00);
+            }
+            render(&mut ppu, OBJECTS);
+
+            assert_eq!(line(&ppu, 0, 8..80), [BLACK; 72]);
+            assert_eq!(line(&ppu, 0, 80..88), [WHITE; 8]);
+        }
+    }
+
+    #[test]
+    fn sprite_limit_uses_tall_sprite_height() {
+        for mode in [PpuMode::Scanline, PpuMode::Fifo] {
+            let mut ppu = ppu_with_sprite_tiles();
+            ppu.render_mode = mode;
+            for i in 0..10 {
+                set_object(&mut ppu, i, 16, 8 + 8 * i as u8, 6, 0x00); // lines 0..8, or 0..16 when tall
+            }
+            set_object(&mut ppu, 10, 28, 128, 6, 0x00); // lines 12..20, or 12..28 when tall
+
+            render(&mut ppu, OBJECTS);
+            assert_eq!(line(&ppu, 12, 120..128), [DARK; 8]);
+
+            render(&mut ppu, OBJECTS | (1 << SPRITE_SIZE));
+            assert_eq!(line(&ppu, 12, 120..128), [WHITE; 8]);
+            assert_eq!(line(&ppu, 16, 120..128), [DARK; 8]);
+        }
+    }
+
+    // steps the fifo renderer dot by dot until pixel x of line ly is the next one drawn
+    fn run_until_pixel(ppu: &mut PPU, ly: u8, x: usize) {
+        while ppu.ly != ly || ppu.get_mode() != Mode::DRAW || ppu.tick_state.scanline_x != x {
+            ppu.dot();
+        }
+    }
+
+    fn step(ppu: &mut PPU) {
+        match ppu.render_mode {
+            PpuMode::Scanline => ppu.tick(),
+            PpuMode::Fifo => ppu.dot()
+        }
+    }
+
+    fn mode_3_dots(ppu: &mut PPU, ly: u8) -> usize {
+        run_until_line(ppu, ly);
+        while ppu.get_mode() != Mode::DRAW {
+            step(ppu);
+        }
+        let start = ppu.scanline_timeline;
+        while ppu.get_mode() == Mode::DRAW {
+            step(ppu);
+        }
+        ppu.scanline_timeline - start
+    }
+
+    #[test]
+    fn fifo_mode_3_lasts_172_dots_plus_penalties() {
+        let mut ppu = ppu_with_sprite_tiles();
+        render(&mut ppu, OBJECTS);
+        assert_eq!(mode_3_dots(&mut ppu, 10), 172);
+
+        ppu.scx = 3; // one dot per discarded pixel
+        assert_eq!(mode_3_dots(&mut ppu, 20), 175);
+
+        // an object starting on a tile boundary waits for the whole next background fetch
+        ppu.scx = 0;
+        set_object(&mut ppu, 0, 16 + 30, 40, 1, 0x00);
+        assert_eq!(mode_3_dots(&mut ppu, 30), 172 + 11);
+
+        // one starting 5 pixels into a tile only pays for its own fetch
+        set_object(&mut ppu, 0, 16 + 40, 45, 1, 0x00);
+        assert_eq!(mode_3_dots(&mut ppu, 40), 172 + 6);
+
+        set_object(&mut ppu, 0, 16 + 50, 8, 1, 0x00);
+        assert_eq!(mode_3_dots(&mut ppu, 50), 172 + 11);
+    }
+
+    #[test]
+    fn mode_3_length_follows_scroll_objects_and_window() {
+        for mode in [PpuMode::Scanline, PpuMode::Fifo] {
+            let mut ppu = ppu_with_sprite_tiles();
+            ppu.render_mode = mode;
+            ppu.wy = 100;
+            ppu.wx = 7 + 40;
+            render(&mut ppu, OBJECTS | WINDOW);
+            let expected = |dots: usize| if mode == PpuMode::Scanline { dots + dots % 2 } else { dots }; // 2 dots at a time
+
+            ppu.scx = 5;
+            assert_eq!(mode_3_dots(&mut ppu, 10), expected(177), "{:?}", mode);
+            ppu.scx = 0;
+
+            // the second object in the same tile doesn't wait for the background fetch again
+            set_object(&mut ppu, 0, 16 + 20, 40, 1, 0x00);
+            set_object(&mut ppu, 1, 16 + 20, 42, 1, 0x00);
+            assert_eq!(mode_3_dots(&mut ppu, 20), expected(172 + 11 + 6), "{:?}", mode);
+
+            set_object(&mut ppu, 2, 16 + 30, 90, 1, 0x00);
+            set_object(&mut ppu, 3, 16 + 30, 168, 1, 0x00); // past the right edge
+            assert_eq!(mode_3_dots(&mut ppu, 30), expected(172 + 6 + 3), "{:?}", mode);
+
+            assert_eq!(mode_3_dots(&mut ppu, 110), expected(172 + 6), "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn switching_lcd_off_stops_and_blanks_the_screen() {
+        for mode in [PpuMode::Scanline, PpuMode::Fifo] {
+            let mut ppu = ppu_with_tiles();
+            ppu.render_mode = mode;
+            fill_map(&mut ppu, 0x9800, 1);
+            ppu.write_registers(0xFF41, 0b01111000); // every STAT source
+            render(&mut ppu, OBJECTS);
+            run_until_line(&mut ppu, 50);
+
+            ppu.write_registers(0xFF40, OBJECTS & !(1 << LCD_ENABLED));
+            assert_eq!(ppu.read_registers(0xFF44), 0);
+            assert_eq!(ppu.read_registers(0xFF41) & 0x3, 0);
+            assert_eq!(ppu.lcd, [WHITE; 23040]);
+
+            // frames keep coming at the usual rate, without interrupts or anything changing
+            ppu.interrupt_requests = 0;
+            for _ in 0..(70224 / 4) - 1 {
+                ppu.update();
+            }
+            assert!(!ppu.rendered_frame);
+            ppu.update();
+            assert!(ppu.rendered_frame, "{:?}", mode);
+            assert_eq!(ppu.read_registers(0xFF44), 0);
+            assert_eq!(ppu.interrupt_requests, 0);
+        }
+    }
+
+    #[test]
+    fn first_frame_after_switching_lcd_on_is_skipped() {
+        for mode in [PpuMode::Scanline, PpuMode::Fifo] {
+            let mut ppu = ppu_with_tiles();
+            ppu.render_mode = mode;
+            fill_map(&mut ppu, 0x9800, 1);
+            ppu.write_registers(0xFF40, OBJECTS);
+
+            // line 0 reports mode 0 where the OAM scan would be
+            for _ in 0..20 {
+                assert_eq!(ppu.read_registers(0xFF41) & 0x3, 0);
+                ppu.update();
+            }
+            assert_eq!(ppu.read_registers(0xFF41) & 0x3, 3);
+
+            run_frame(&mut ppu);
+            assert_eq!(ppu.lcd, [WHITE; 23040], "{:?}", mode);
+            run_frame(&mut ppu);
+            assert_eq!(ppu.lcd, [BLACK; 23040], "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn switching_lcd_back_on_mid_line_starts_a_whole_frame() {
+        for mode in [PpuMode::Scanline, PpuMode::Fifo] {
+            let mut ppu = ppu_with_tiles();
+            ppu.render_mode = mode;
+            fill_map(&mut ppu, 0x9800, 1);
+            render(&mut ppu, OBJECTS);
+            run_until_line(&mut ppu, 70);
+
+            // off for part of a line, the frame it was counting while off is dropped
+            ppu.write_registers(0xFF40, OBJECTS & !(1 << LCD_ENABLED));
+            for _ in 0..37 {
+                ppu.update();
+            }
+            ppu.write_registers(0xFF40, OBJECTS);
+
+            let mut updates = 1;
+            ppu.update();
+            while !ppu.rendered_frame {
+                updates += 1;
+                ppu.update();
+            }
+            ppu.rendered_frame = false;
+            assert_eq!(updates, 70224 / 4, "{:?}", mode);
+            assert_eq!(ppu.lcd, [WHITE; 23040], "{:?}", mode);
+            run_frame(&mut ppu);
+            assert_eq!(ppu.lcd, [BLACK; 23040], "{:?}", mode);
+        }
+    }
+
+    // every word starts out as its row in the high byte and its index in the low one
+    fn numbered_oam() -> [u8; 0xA0] {
+        let mut oam = [0; 0xA0];
+        for row in 0..20 {
+            for word in 0..4 {
+                set_oam_word(&mut oam, row, word, ((row as u16) << 8) | word as u16);
+            }
+        }
+        oam
+    }
+
+    fn oam_row(oam: &[u8; 0xA0], row: usize) -> [u16; 4] {
+        [0, 1, 2, 3].map(|word| oam_word(oam, row, word))
+    }
+
+    #[test]
+    fn oam_bug_write_and_read_patterns() {
+        let mut oam = numbered_oam();
+        set_oam_word(&mut oam, 4, 0, 0xF0F0);
+        set_oam_word(&mut oam, 3, 0, 0xCCCC);
+        set_oam_word(&mut oam, 3, 2, 0xAAAA);
+        let before = oam;
+
+        let mut written = before;
+        corrupt_oam_write(&mut written, 4);
+        assert_eq!(oam_row(&written, 4), [0xE8E8, 0x0301, 0xAAAA, 0x0303]);
+
+        let mut read = before;
+        corrupt_oam_read(&mut read, 4);
+        assert_eq!(oam_row(&read, 4), [0xECEC, 0x0301, 0xAAAA, 0x0303]);
+
+        for row in (0..20).filter(|&row| row != 4) {
+            assert_eq!(oam_row(&written, row), oam_row(&before, row));
+            assert_eq!(oam_row(&read, row), oam_row(&before, row));
+        }
+
+        corrupt_oam_write(&mut oam, 0);
+        corrupt_oam_read(&mut oam, 0);
+        assert_eq!(oam, before);
+    }
+
+    #[test]
+    fn oam_bug_read_during_inc_dec_pattern() {
+        let mut oam = numbered_oam();
+        set_oam_word(&mut oam, 3, 0, 0x00F0);
+        set_oam_word(&mut oam, 4, 0, 0xFFFF);
+        set_oam_word(&mut oam, 5, 0, 0x0F00);
+        set_oam_word(&mut oam, 4, 2, 0x000F);
+        corrupt_oam_read_inc_dec(&mut oam, 5);
+
+        // the mangled row before is copied to both sides, then the plain read corruption leaves it that way
+        let expected = [0x0FFF, 0x0401, 0x000F, 0x0403];
+        for row in 3..=5 {
+            assert_eq!(oam_row(&oam, row), expected, "row {}", row);
+        }
+        assert_eq!(oam_row(&oam, 2), [0x0200, 0x0201, 0x0202, 0x0203]);
+        assert_eq!(oam_row(&oam, 6), [0x0600, 0x0601, 0x0602, 0x0603]);
+
+        // within the first four rows only the read corruption happens
+        let mut near_start = numbered_oam();
+        let mut read = numbered_oam();
+        corrupt_oam_read_inc_dec(&mut near_start, 3);
+        corrupt_oam_read(&mut read, 3);
+        assert_eq!(near_start, read);
+    }
+
+    #[test]
+    fn fifo_palette_write_lands_on_exact_pixel() {
+        let mut ppu = ppu_with_tiles();
+        fill_map(&mut ppu, 0x9800, 1);
+        render(&mut ppu, OBJECTS);
+
+        run_until_pixel(&mut ppu, 10, 83);
+        ppu.write_registers(0xFF47, 0x00);
+        run_frame(&mut ppu);
+
+        assert_eq!(line(&ppu, 10, 0..83), [BLACK; 83]);
+        assert_eq!(line(&ppu, 10, 83..160), [WHITE; 77]);
+        assert_eq!(line(&ppu, 11, 0..160), [WHITE; 160]);
+    }
+
+    #[test]
+    fn fifo_scroll_write_applies_to_following_tiles() {
+        let mut ppu = ppu_with_tiles();
+        for column in 0..32 {
+            ppu.vram[0x1800 + column] = (column % 2) as u8; // 8 pixel stripes, white first
+        }
+        render(&mut ppu, OBJECTS);
+
+        run_until_pixel(&mut ppu, 0, 80);
+        ppu.write_registers(0xFF43, 8);
+        run_frame(&mut ppu);
+
+        let stripe = |x: usize, shift: usize| if ((x / 8) + shift) % 2 == 0 { WHITE } else { BLACK };
+        for x in 0..80 {
+            assert_eq!(pixel(&ppu, x, 0), stripe(x, 0), "x = {}", x);
+        }
+        for x in 96..160 {
+            assert_eq!(pixel(&ppu, x, 0), stripe(x, 1), "x = {}", x);
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use std::fmt;
+use crate::internal::debugger::BankedAddr;
+
+const TABLE_LEN: usize = 0x10000;
+
+#[derive(Clone, Copy, Default)]
+struct Count {
+    instructions: u64,
+    cycles: u64
+}
+
+// what ran at one address in one bank while the profiler was on
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ProfileEntry {
+    pub at: BankedAddr,
+    pub instructions: u64,
+    pub cycles: u64 // M-cycles, from the fetch until the next one
+}
+
+impl fmt::Display for ProfileEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}  {} instructions  {} cycles", self.at, self.instructions, self.cycles)
+    }
+}
+
+// counts per PC, a table for the whole address space for every bank that shows up. the first one is for the
+// addresses a bank doesn't narrow down, the rest are ROM banks 0 and up. tables are only made once something runs there
+#[derive(Default)]
+pub struct Profiler {
+    tables: Vec<Option<Box<[Count]>>>,
+    running: bool,
+    slot: usize, // where the instruction running now was fetched from
+    addr: u16
+}
+
+impl Profiler {
+    pub fn start(&mut self) {
+        self.running = true;
+    }
+
+    // keeps the counts for report
+    pub fn stop(&mut self) {
+        self.running = false;
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    pub fn reset(&mut self) {
+        self.tables = Vec::new();
+    }
+
+    pub fn executed(&mut self, at: BankedAddr) {
+        self.slot = at.bank.map_or(0, |bank| bank as usize + 1);
+        self.addr = at.addr;
+        if self.tables.len() <= self.slot {
+            self.tables.resize(self.slot + 1, None);
+        }
+        let table = self.tables[self.slot].get_or_insert_with(|| vec![Count::default(); TABLE_LEN].into_boxed_slice());
+        table[at.addr as usize].instructions += 1;
+    }
+
+    // an interrupt dispatch counts for the instruction it came after
+    pub fn cycle(&mut self) {
+        if let Some(table) = self.tables.get_mut(self.slot).and_then(|table| table.as_deref_mut()) {
+            table[self.addr as usize].cycles += 1;
+        }
+    }
+
+    // the top_n addresses that took the most cycles, most first
+    pub fn report(&self, top_n: usize) -> Vec<ProfileEntry> {
+        let mut entries: Vec<ProfileEntry> = self.tables.iter().enumerate()
+            .filter_map(|(slot, table)| Some((slot, table.as_deref()?)))
+            .flat_map(|(slot, table)| table.iter().enumerate()
+                .filter(|(_, count)| count.instructions > 0 || count.cycles > 0)
+                .map(move |(addr, count)| ProfileEntry {
+                    at: BankedAddr { addr: addr as u16, bank: slot.checked_sub(1).map(|bank| bank as u16) },
+                    instructions: count.instructions,
+                    cycles: count.cycles
+                }))
+            .collect();
+        entries.sort_by(|a, b| b.cycles.cmp(&a.cycles).then(b.instructions.cmp(&a.instructions)));
+        entries.truncate(top_n);
+        entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(profiler: &mut Profiler, at: BankedAddr, cycles: u64) {
+        profiler.executed(at);
+        for _ in 0..cycles {
+            profiler.cycle();
+        }
+    }
+
+    #[test]
+    fn reports_the_most_cycles_first_apart_by_bank() {
+        let mut profiler = Profiler::default();
+        let banked = |bank| BankedAddr { addr: 0x4000, bank: Some(bank) };
+        let hram = BankedAddr { addr: 0xFF80, bank: None };
+        for _ in 0..3 {
+            run(&mut profiler, banked(1), 2);
+            run(&mut profiler, banked(2), 1);
+        }
+        run(&mut profiler, hram, 4);
+
+        assert_eq!(profiler.report(2), [
+            ProfileEntry { at: banked(1), instructions: 3, cycles: 6 },
+            ProfileEntry { at: hram, instructions: 1, cycles: 4 }
+        ]);
+        assert_eq!(profiler.report(10).len(), 3);
+        assert_eq!(profiler.report(10)[2].to_string(), "02:4000  3 instructions  3 cycles");
+
+        profiler.reset();
+        profiler.cycle();
+        assert!(profiler.report(10).is_empty());
+    }
+}
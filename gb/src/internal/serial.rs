@@ -0,0 +1,252 @@
+use crate::internal::snapshot::{StateWriter, StateReader, StateError};
+
+const SC_START: u8 = 1 << 7;
+const SC_INTERNAL_CLOCK: u8 = 1 << 0;
+const SC_UNUSED: u8 = 0x7E; // always read back set
+
+// whatever is plugged into the link port, the timing of a transfer stays with Serial
+pub trait SerialDevice {
+    // called once per bit, most significant first, with the bit shifted out of SB. returns the one shifted in
+    fn exchange_bit(&mut self, out_bit: bool) -> bool;
+
+    // asked every M-cycle while a transfer waits on the external clock, true clocks one bit in that cycle
+    fn external_clock(&mut self) -> bool {
+        false
+    }
+
+    // a transfer on the internal clock was started with this byte in SB
+    fn transfer_started(&mut self, _out: u8) {}
+
+    // asked every M-cycle of a transfer on the internal clock, true holds the clock for that cycle. nothing on a real
+    // cable can do this, it's for devices that have to wait for the other side over a network
+    fn hold_clock(&mut self) -> bool {
+        false
+    }
+}
+
+// nothing plugged in: bits shifted out are lost and 1s come back in, an external clock never arrives so only
+// transfers on the internal clock ever finish
+pub struct Disconnected;
+
+impl SerialDevice for Disconnected {
+    fn exchange_bit(&mut self, _out_bit: bool) -> bool {
+        true
+    }
+}
+
+#[derive(Clone)]
+pub struct Serial {
+    sb: u8,
+    sc: u8,
+    bits_left: u8, // of the transfer in progress, 0 while there's none
+    interrupt: bool // requested by the last bit, taken by Memory in the same M-cycle
+}
+
+impl Serial {
+    pub fn read_registers(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF01 => self.sb,
+            0xFF02 => self.sc | SC_UNUSED,
+            _ => panic!("recieved invalid address")
+        }
+    }
+
+    // setting the start bit starts over and clearing it aborts, even in the middle of a transfer, while SB can be
+    // written at any time and the bits left shift out of whatever it holds. returns the byte going out when this
+    // starts a transfer on the internal clock, which is how test roms print
+    pub fn write_registers(&mut self, addr: u16, val: u8) -> Option<u8> {
+        match addr {
+            0xFF01 => self.sb = val,
+            0xFF02 => {
+                self.sc = val & (SC_START | SC_INTERNAL_CLOCK);
+                self.bits_left = if val & SC_START != 0 { 8 } else { 0 };
+                if self.sc == SC_START | SC_INTERNAL_CLOCK {
+                    return Some(self.sb);
+                }
+            },
+            _ => panic!("recieved invalid address")
+        }
+        None
+    }
+
+    // a debugger's write, SC doesn't start or stop a transfer
+    pub fn poke_registers(&mut self, addr: u16, val: u8) {
+        match addr {
+            0xFF01 => self.sb = val,
+            0xFF02 => self.sc = val & (SC_START | SC_INTERNAL_CLOCK),
+            _ => panic!("recieved invalid address")
+        }
+    }
+
+    // one M-cycle. the internal clock is the 8192 Hz bit of the system clock shared with DIV, a bit shifts every time
+    // it falls, so a transfer takes 4096 T-cycles less however far into the first bit DIV already was
+    pub fn update(&mut self, device: &mut dyn SerialDevice, clock_fell: bool) {
+        if self.bits_left == 0 {
+            return;
+        }
+        if self.sc & SC_INTERNAL_CLOCK == 0 {
+            if !device.external_clock() {
+                return;
+            }
+        } else {
+            if device.hold_clock() || !clock_fell {
+                return;
+            }
+        }
+        let in_bit = device.exchange_bit(self.sb & 0x80 != 0);
+        self.sb = (self.sb << 1) | in_bit as u8;
+        self.bits_left -= 1;
+        if self.bits_left == 0 {
+            self.sc &= !SC_START;
+            self.interrupt = true;
+        }
+    }
+
+    pub fn take_interrupt(&mut self) -> bool {
+        std::mem::take(&mut self.interrupt)
+    }
+
+    pub fn write_state(&self, w: &mut StateWriter) {
+        w.u8(self.sb);
+        w.u8(self.sc);
+        w.u8(self.bits_left);
+    }
+
+    pub fn read_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.sb = r.u8()?;
+        self.sc = r.u8()?;
+        self.bits_left = r.u8()?;
+        if self.bits_left > 8 {
+            return Err(StateError::InvalidData("serial transfer out of range"));
+        }
+        Ok(())
+    }
+}
+
+impl Default for Serial {
+    fn default() -> Self {
+        Self { sb: 0x00, sc: 0x00, bits_left: 0, interrupt: false }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // M-cycles between falls of the internal clock
+    const BIT_M_CYCLES: u32 = 128;
+
+    // the system clock starts at 0, so the clock falls on every multiple of BIT_M_CYCLES
+    fn run(serial: &mut Serial, device: &mut dyn SerialDevice, from: u32, to: u32) {
+        for cycle in from..=to {
+            serial.update(device, cycle % BIT_M_CYCLES == 0);
+        }
+    }
+
+    #[test]
+    fn internal_clock_shifts_in_ones_over_8_bits() {
+        let mut serial = Serial::default();
+        serial.write_registers(0xFF01, 0x42);
+        assert_eq!(serial.write_registers(0xFF02, 0x81), Some(0x42));
+        assert_eq!(serial.read_registers(0xFF02), 0xFF);
+
+        // 7 bits in, and the M-cycle before the last one
+        for cycle in 1..BIT_M_CYCLES * 8 {
+            serial.update(&mut Disconnected, cycle % BIT_M_CYCLES == 0);
+            assert!(!serial.take_interrupt());
+        }
+        assert_eq!(serial.read_registers(0xFF01), 0x7F);
+        assert_eq!(serial.read_registers(0xFF02), 0xFF);
+
+        serial.update(&mut Disconnected, true);
+        assert!(serial.take_interrupt());
+        assert_eq!(serial.read_registers(0xFF01), 0xFF);
+        assert_eq!(serial.read_registers(0xFF02), 0x7F);
+    }
+
+    #[test]
+    fn external_clock_never_finishes_without_a_partner() {
+        let mut serial = Serial::default();
+        serial.write_registers(0xFF01, 0x42);
+        assert_eq!(serial.write_registers(0xFF02, 0x80), None);
+        run(&mut serial, &mut Disconnected, 1, BIT_M_CYCLES * 16);
+        assert!(!serial.take_interrupt());
+        assert_eq!((serial.read_registers(0xFF01), serial.read_registers(0xFF02)), (0x42, 0xFE));
+    }
+
+    #[test]
+    fn writes_in_the_middle_of_a_transfer() {
+        // SB takes the write and carries on shifting from there
+        let mut serial = Serial::default();
+        serial.write_registers(0xFF01, 0x00);
+        serial.write_registers(0xFF02, 0x81);
+        run(&mut serial, &mut Disconnected, 1, BIT_M_CYCLES * 4);
+        assert_eq!(serial.read_registers(0xFF01), 0x0F);
+        serial.write_registers(0xFF01, 0x00);
+        run(&mut serial, &mut Disconnected, BIT_M_CYCLES * 4 + 1, BIT_M_CYCLES * 8);
+        assert!(serial.take_interrupt());
+        assert_eq!(serial.read_registers(0xFF01), 0x0F);
+
+        // clearing the start bit stops it where it is
+        serial.write_registers(0xFF01, 0x00);
+        serial.write_registers(0xFF02, 0x81);
+        run(&mut serial, &mut Disconnected, 1, BIT_M_CYCLES * 3);
+        serial.write_registers(0xFF02, 0x01);
+        run(&mut serial, &mut Disconnected, BIT_M_CYCLES * 3 + 1, BIT_M_CYCLES * 16);
+        assert!(!serial.take_interrupt());
+        assert_eq!(serial.read_registers(0xFF01), 0x07);
+    }
+
+    // answers every byte with the one it got before, clocking a bit every other M-cycle when asked to
+    struct Echo {
+        received: Vec<bool>,
+        previous: u8,
+        clocking: bool,
+        tick: bool
+    }
+
+    impl SerialDevice for Echo {
+        fn exchange_bit(&mut self, out_bit: bool) -> bool {
+            let in_bit = self.previous & (0x80 >> (self.received.len() % 8)) != 0;
+            self.received.push(out_bit);
+            if self.received.len() % 8 == 0 {
+                self.previous = self.received[self.received.len() - 8..].iter().fold(0, |byte, &bit| (byte << 1) | bit as u8);
+            }
+            in_bit
+        }
+
+        fn external_clock(&mut self) -> bool {
+            self.tick = !self.tick;
+            self.clocking && self.tick
+        }
+    }
+
+    #[test]
+    fn device_swaps_bits_most_significant_first() {
+        let mut echo = Echo { received: vec![], previous: 0xA5, clocking: false, tick: false };
+        let mut serial = Serial::default();
+        serial.write_registers(0xFF01, 0x3C);
+        serial.write_registers(0xFF02, 0x81);
+        run(&mut serial, &mut echo, 1, BIT_M_CYCLES * 8);
+        assert!(serial.take_interrupt());
+        assert_eq!(serial.read_registers(0xFF01), 0xA5);
+        assert_eq!(echo.previous, 0x3C);
+    }
+
+    #[test]
+    fn device_can_drive_the_external_clock() {
+        let mut echo = Echo { received: vec![], previous: 0x0F, clocking: true, tick: true };
+        let mut serial = Serial::default();
+        serial.write_registers(0xFF01, 0xF0);
+        serial.write_registers(0xFF02, 0x80);
+        for _ in 0..15 {
+            // the internal clock has nothing to do with it
+            serial.update(&mut echo, true);
+            assert!(!serial.take_interrupt());
+        }
+        serial.update(&mut echo, false);
+        assert!(serial.take_interrupt());
+        assert_eq!((serial.read_registers(0xFF01), serial.read_registers(0xFF02)), (0x0F, 0x7E));
+        assert_eq!(echo.previous, 0xF0);
+    }
+}
@@ -0,0 +1,235 @@
+use crate::internal::snapshot::{StateWriter, StateReader, StateError};
+
+// https://gbdev.io/pandocs/SGB_Command_Packet.html
+const PACKET_LEN: usize = 16;
+const PACKET_BITS: u8 = PACKET_LEN as u8 * 8;
+const SYSTEM_PALETTES: usize = 512;
+pub const VRAM_TRANSFER_LEN: usize = 0x1000;
+
+// attributes are kept per 8x8 cell of the screen
+const ATTR_WIDTH: usize = 20;
+const ATTR_HEIGHT: usize = 18;
+
+const PAL01: u8 = 0x00;
+const PAL23: u8 = 0x01;
+const PAL03: u8 = 0x02;
+const PAL12: u8 = 0x03;
+const ATTR_BLK: u8 = 0x04;
+const PAL_SET: u8 = 0x0A;
+pub const PAL_TRN: u8 = 0x0B;
+const MLT_REQ: u8 = 0x11;
+
+// the part of the Super Game Boy a DMG cartridge talks to: command packets pulsed through P14/P15 and the
+// palettes they set up, borders and the other commands are ignored
+#[derive(Clone)]
+pub struct Sgb {
+    pins: u8, // P14/P15 as last written, a bit is only taken after both lines went back high
+    receiving: bool,
+    bits: u8, // of the packet in progress, PACKET_BITS means only the stop bit is left
+    packet: [u8; PACKET_LEN],
+    command: Vec<u8>, // packets received so far of a command that spans several
+
+    palettes: [[u16; 4]; 4], // BGR555, color 0 is shared by all four
+    system_palettes: Vec<[u16; 4]>, // filled by PAL_TRN, picked from by PAL_SET
+    attributes: [u8; ATTR_WIDTH * ATTR_HEIGHT],
+    pub colorized: bool, // set by the first palette command, the RGBA output keeps the plain palette until then
+
+    players: u8,
+    pub player: u8 // joypad selected by the last P15 pulse while MLT_REQ enabled more than one
+}
+
+impl Sgb {
+    // returns the command once its last packet arrived so anything that needs VRAM can be run by the caller
+    pub fn write_joypad(&mut self, val: u8) -> Option<u8> {
+        let pins = val & 0x30;
+        let previous = std::mem::replace(&mut self.pins, pins);
+        match pins {
+            0x00 => {
+                self.receiving = true;
+                self.bits = 0;
+                self.packet = [0; PACKET_LEN];
+                None
+            },
+            0x10 | 0x20 if previous == 0x30 && self.receiving => self.receive_bit(pins == 0x10),
+            0x30 => {
+                if previous & 0x20 == 0 && !self.receiving && self.players > 1 {
+                    self.player = (self.player + 1) % self.players;
+                }
+                None
+            },
+            _ => None
+        }
+    }
+
+    fn receive_bit(&mut self, bit: bool) -> Option<u8> {
+        if self.bits < PACKET_BITS {
+            let (byte, shift) = ((self.bits / 8) as usize, self.bits % 8);
+            self.packet[byte] |= (bit as u8) << shift;
+            self.bits += 1;
+            return None;
+        }
+
+        // the stop bit is always 0, anything else throws the packet away
+        self.receiving = false;
+        if bit {
+            return None;
+        }
+        self.command.extend_from_slice(&self.packet);
+        let packets = (self.command[0] & 0x7).max(1) as usize;
+        if self.command.len() < packets * PACKET_LEN {
+            return None;
+        }
+
+        let command = std::mem::take(&mut self.command);
+        self.run_command(&command);
+        Some(command[0] >> 3)
+    }
+
+    fn run_command(&mut self, command: &[u8]) {
+        let color = |i: usize| (command[i] as u16) | ((command[i + 1] as u16) << 8);
+        match command[0] >> 3 {
+            id @ (PAL01 | PAL23 | PAL03 | PAL12) => {
+                let (first, second) = match id {
+                    PAL01 => (0, 1),
+                    PAL23 => (2, 3),
+                    PAL03 => (0, 3),
+                    _ => (1, 2)
+                };
+                for i in 0..3 {
+                    self.palettes[first][i + 1] = color(3 + i * 2);
+                    self.palettes[second][i + 1] = color(9 + i * 2);
+                }
+                self.share_color_0(color(1));
+            },
+            ATTR_BLK => self.attribute_blocks(command),
+            PAL_SET => {
+                // the attribute file in byte 9 needs ATTR_TRN, which isn't supported, so it's ignored
+                for palette in 0..4 {
+                    let system_palette = color(1 + palette * 2) as usize % SYSTEM_PALETTES;
+                    self.palettes[palette] = self.system_palettes[system_palette];
+                }
+                self.share_color_0(self.palettes[0][0]);
+            },
+            MLT_REQ => {
+                self.players = match command[1] & 0x3 {
+                    1 => 2,
+                    3 => 4,
+                    _ => 1
+                };
+                self.player = 0;
+            },
+            _ => ()
+        }
+    }
+
+    fn share_color_0(&mut self, color: u16) {
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color;
+        }
+        self.colorized = true;
+    }
+
+    // up to 18 rectangles of cells, each can color its inside, border and outside with a palette of its own
+    fn attribute_blocks(&mut self, command: &[u8]) {
+        let sets = (command[1] & 0x1F) as usize;
+        for set in command[2..].chunks_exact(6).take(sets) {
+            let control = set[0] & 0x7;
+            let (inside, mut border, outside) = (set[1] & 0x3, (set[1] >> 2) & 0x3, (set[1] >> 4) & 0x3);
+            // with only one of inside/outside given, the border goes with it
+            let border_set = match control {
+                0x1 => { border = inside; true },
+                0x4 => { border = outside; true },
+                _ => control & 0x2 != 0
+            };
+            let (x1, y1, x2, y2) = (set[2] as usize & 0x1F, set[3] as usize & 0x1F, set[4] as usize & 0x1F, set[5] as usize & 0x1F);
+
+            for y in 0..ATTR_HEIGHT {
+                for x in 0..ATTR_WIDTH {
+                    let within = (x1..=x2).contains(&x) && (y1..=y2).contains(&y);
+                    let palette = if within && (x == x1 || x == x2 || y == y1 || y == y2) {
+                        border_set.then_some(border)
+                    } else if within {
+                        (control & 0x1 != 0).then_some(inside)
+                    } else {
+                        (control & 0x4 != 0).then_some(outside)
+                    };
+                    if let Some(palette) = palette {
+                        self.attributes[y * ATTR_WIDTH + x] = palette;
+                    }
+                }
+            }
+        }
+    }
+
+    // data is what the cartridge put on screen for the transfer, see PPU::vram_transfer
+    pub fn palette_transfer(&mut self, data: &[u8]) {
+        for (palette, colors) in self.system_palettes.iter_mut().zip(data.chunks_exact(8)) {
+            for (i, color) in palette.iter_mut().enumerate() {
+                *color = (colors[i * 2] as u16) | ((colors[i * 2 + 1] as u16) << 8);
+            }
+        }
+    }
+
+    // RGB for a shade at a pixel of the 160x144 screen
+    pub fn color(&self, x: usize, y: usize, shade: u8) -> [u8; 3] {
+        let palette = self.attributes[(y / 8) * ATTR_WIDTH + x / 8] as usize;
+        let color = self.palettes[palette][(shade & 0x3) as usize];
+        let expand = |c: u16| ((c << 3) | (c >> 2)) as u8;
+        [expand(color & 0x1F), expand((color >> 5) & 0x1F), expand((color >> 10) & 0x1F)]
+    }
+
+    pub fn write_state(&self, w: &mut StateWriter) {
+        w.u8(self.pins);
+        w.bool(self.receiving);
+        w.u8(self.bits);
+        w.bytes(&self.packet);
+        w.vec(&self.command);
+        for color in self.palettes.iter().chain(self.system_palettes.iter()).flatten() {
+            w.u16(*color);
+        }
+        w.bytes(&self.attributes);
+        w.bool(self.colorized);
+        w.u8(self.players);
+        w.u8(self.player);
+    }
+
+    pub fn read_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.pins = r.u8()?;
+        self.receiving = r.bool()?;
+        self.bits = r.u8()?;
+        r.fill(&mut self.packet)?;
+        self.command = r.vec()?;
+        for color in self.palettes.iter_mut().chain(self.system_palettes.iter_mut()).flatten() {
+            *color = r.u16()?;
+        }
+        r.fill(&mut self.attributes)?;
+        self.colorized = r.bool()?;
+        self.players = r.u8()?;
+        self.player = r.u8()?;
+        if self.bits > PACKET_BITS || self.command.len() >= 7 * PACKET_LEN || self.attributes.iter().any(|&palette| palette > 3) {
+            return Err(StateError::InvalidData("SGB packet or attribute out of range"));
+        }
+        if ![1, 2, 4].contains(&self.players) || self.player >= self.players {
+            return Err(StateError::InvalidData("SGB joypad out of range"));
+        }
+        Ok(())
+    }
+}
+
+impl Default for Sgb {
+    fn default() -> Self {
+        Self {
+            pins: 0x30,
+            receiving: false,
+            bits: 0,
+            packet: [0; PACKET_LEN],
+            command: vec![],
+            palettes: [[0; 4]; 4],
+            system_palettes: vec![[0; 4]; SYSTEM_PALETTES],
+            attributes: [0; ATTR_WIDTH * ATTR_HEIGHT],
+            colorized: false,
+            players: 1,
+            player: 0
+        }
+    }
+}
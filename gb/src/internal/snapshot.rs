@@ -0,0 +1,665 @@
+use std::fmt;
+use crate::internal::core::component::{CpuSnapshot, CpuState};
+use crate::internal::memory::MemorySnapshot;
+
+const SNAPSHOT_MAGIC: &[u8; 4] = b"GBSN";
+const COMPRESSED_MAGIC: &[u8; 4] = b"GBSZ"; // same header, followed by the inflated body length and a zlib stream of the body
+const SNAPSHOT_VERSION: u16 = 17;
+
+// Describes the body layout written by the current version. Whenever a component's write_state/read_state
+// changes, bump SNAPSHOT_VERSION, update this description and add a migration from the previous version. A field
+// that means something new but whose old values still read right keeps the layout, and so the version.
+const SNAPSHOT_SCHEMA: &str = "cpu[regs afbcdehl, pc, sp, ime, ei_delay, halted, halt_bug, stopped, instr?, dispatch[interrupt, step]?], \
+                               memory[wram, hram, sram, mbc, ie, if, boot_rom_mapped, joyp], \
+                               ppu[lcd, oam, vram, irq flags, regs, timelines, window, fetcher, fifos], \
+                               timer[overflow, sysclock, tma, unused x2, tima, tac, freq], \
+                               ppu_render[fifo_mode, fetcher_dot, pixels_to_discard, mode_3_end, lcd_enable_line, lcd_enable_frame], \
+                               oam_dma[register, transfer?, restart?], \
+                               ppu_window[wx_166_stall], \
+                               sgb[pins, receiving, bits, packet, command, palettes, system_palettes, attributes, colorized, players, player], \
+                               apu[frame_sequencer_step, registers, power, square x2[status, length, period, timer, envelope, sweep?], \
+                                   wave[status, length, level, period, timer, position, sample, ram], \
+                                   noise[status, length, nr43, timer, lfsr, envelope], output[phase, sum, count, history, capacitors]], \
+                               apu_wave[since_fetch], \
+                               apu_output[rising], \
+                               serial[sb, sc, bits_left], \
+                               cgb_wram[svbk, banks 2-7], \
+                               power_on_ram[kind, seed]";
+const SNAPSHOT_SCHEMA_HASH: u32 = fnv1a(SNAPSHOT_SCHEMA.as_bytes());
+
+// magic + version
+const V1_HEADER_LEN: usize = 6;
+// magic + version + schema hash
+const HEADER_LEN: usize = 10;
+
+#[derive(PartialEq, Debug)]
+pub enum StateError {
+    InvalidMagic,
+    UnsupportedVersion(u16),
+    NewerVersion(u16),
+    SchemaMismatch(u32),
+    Truncated,
+    InvalidData(&'static str),
+    CompressionUnsupported
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::InvalidMagic => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(version) => write!(f, "save state version {} is no longer supported", version),
+            StateError::NewerVersion(version) => write!(f, "save state was made by a newer emulator (version {}, this build reads up to {})", version, SNAPSHOT_VERSION),
+            StateError::SchemaMismatch(hash) => write!(f, "save state layout 0x{:08X} does not match this build (0x{:08X})", hash, SNAPSHOT_SCHEMA_HASH),
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::InvalidData(reason) => write!(f, "save state is corrupted: {}", reason),
+            StateError::CompressionUnsupported => write!(f, "save state is compressed but this build was made without compression support")
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+const fn fnv1a(bytes: &[u8]) -> u32 {
+    let mut hash: u32 = 0x811C9DC5;
+    let mut i = 0;
+    while i < bytes.len() {
+        hash = (hash ^ bytes[i] as u32).wrapping_mul(0x01000193);
+        i += 1;
+    }
+    hash
+}
+
+// what's after the header, a state that doesn't even have all of its header is cut short
+fn body(bytes: &[u8]) -> Result<&[u8], StateError> {
+    bytes.get(HEADER_LEN..).ok_or(StateError::Truncated)
+}
+
+// v1 had no schema hash in its header, the body is unchanged
+fn migrate_v1_to_v2(bytes: &[u8]) -> Result<Vec<u8>, StateError> {
+    let mut w = StateWriter::default();
+    w.bytes(SNAPSHOT_MAGIC);
+    w.u16(2);
+    w.u32(SNAPSHOT_SCHEMA_HASH);
+    w.bytes(bytes.get(V1_HEADER_LEN..).ok_or(StateError::Truncated)?);
+    Ok(w.buf)
+}
+
+// v2 states were all drawn by the scanline renderer, which doesn't use the fifo renderer's extra state
+fn migrate_v2_to_v3(bytes: &[u8]) -> Result<Vec<u8>, StateError> {
+    let mut w = StateWriter::default();
+    w.bytes(SNAPSHOT_MAGIC);
+    w.u16(3);
+    w.u32(SNAPSHOT_SCHEMA_HASH);
+    w.bytes(body(bytes)?);
+    w.bool(false);
+    w.bool(false);
+    w.u8(0);
+    Ok(w.buf)
+}
+
+// v3 states entered hblank as soon as the scanline renderer pushed its last pixel, 0 keeps doing that for the
+// line in progress
+fn migrate_v3_to_v4(bytes: &[u8]) -> Result<Vec<u8>, StateError> {
+    let mut w = StateWriter::default();
+    w.bytes(SNAPSHOT_MAGIC);
+    w.u16(4);
+    w.u32(SNAPSHOT_SCHEMA_HASH);
+    w.bytes(body(bytes)?);
+    w.u16(0);
+    Ok(w.buf)
+}
+
+// v4 had no special first frame after turning the LCD on
+fn migrate_v4_to_v5(bytes: &[u8]) -> Result<Vec<u8>, StateError> {
+    let mut w = StateWriter::default();
+    w.bytes(SNAPSHOT_MAGIC);
+    w.u16(5);
+    w.u32(SNAPSHOT_SCHEMA_HASH);
+    w.bytes(body(bytes)?);
+    w.bool(false);
+    w.bool(false);
+    Ok(w.buf)
+}
+
+// v5 copied OAM DMA transfers in one go, so none can be in progress
+fn migrate_v5_to_v6(bytes: &[u8]) -> Result<Vec<u8>, StateError> {
+    let mut w = StateWriter::default();
+    w.bytes(SNAPSHOT_MAGIC);
+    w.u16(6);
+    w.u32(SNAPSHOT_SCHEMA_HASH);
+    w.bytes(body(bytes)?);
+    w.u8(0xFF);
+    for _ in 0..2 {
+        w.bool(false);
+        w.u16(0);
+        w.u8(0);
+    }
+    Ok(w.buf)
+}
+
+// v6 states never stall for a window at WX = 166
+fn migrate_v6_to_v7(bytes: &[u8]) -> Result<Vec<u8>, StateError> {
+    let mut w = StateWriter::default();
+    w.bytes(SNAPSHOT_MAGIC);
+    w.u16(7);
+    w.u32(SNAPSHOT_SCHEMA_HASH);
+    w.bytes(body(bytes)?);
+    w.u8(0);
+    Ok(w.buf)
+}
+
+// v7 never listened for SGB packets, so there's nothing received and no palettes yet
+fn migrate_v7_to_v8(bytes: &[u8]) -> Result<Vec<u8>, StateError> {
+    let mut w = StateWriter::default();
+    w.bytes(SNAPSHOT_MAGIC);
+    w.u16(8);
+    w.u32(SNAPSHOT_SCHEMA_HASH);
+    w.bytes(body(bytes)?);
+    w.u8(0x30);
+    w.bool(false);
+    w.u8(0);
+    w.bytes(&[0; 16]);
+    w.vec(&[]);
+    w.bytes(&[0; (4 + 512) * 4 * 2]);
+    w.bytes(&[0; 20 * 18]);
+    w.bool(false);
+    w.u8(1);
+    w.u8(0);
+    Ok(w.buf)
+}
+
+// v8 had no APU state, it comes back switched off with nothing buffered in the output filters
+fn migrate_v8_to_v9(bytes: &[u8]) -> Result<Vec<u8>, StateError> {
+    let mut w = StateWriter::default();
+    w.bytes(SNAPSHOT_MAGIC);
+    w.u16(9);
+    w.u32(SNAPSHOT_SCHEMA_HASH);
+    w.bytes(body(bytes)?);
+    w.u8(0);
+    w.bytes(&[0; 0x20]);
+    w.bool(false);
+    // channel 1 with its sweep, channel 2 without, then channel 3
+    w.bytes(&[0; 16]);
+    w.bool(true);
+    w.bytes(&[0; 7]);
+    w.bytes(&[0; 16]);
+    w.bool(false);
+    w.bytes(&[0; 7]);
+    w.bytes(&[0; 28]);
+    // channel 4 with the LFSR all ones and the shortest period
+    w.bytes(&[0; 8]);
+    w.u32(8);
+    w.u16(0x7FFF);
+    w.bytes(&[0; 5]);
+    w.bytes(&[0; 36]);
+    Ok(w.buf)
+}
+
+// v9 let the CPU at wave RAM whenever channel 3 played, the next fetch puts the time since it right
+fn migrate_v9_to_v10(bytes: &[u8]) -> Result<Vec<u8>, StateError> {
+    let mut w = StateWriter::default();
+    w.bytes(SNAPSHOT_MAGIC);
+    w.u16(10);
+    w.u32(SNAPSHOT_SCHEMA_HASH);
+    w.bytes(body(bytes)?);
+    w.u16(0);
+    Ok(w.buf)
+}
+
+// v10 fed the high quality resampler every M-cycle so nothing was half way into its next input
+fn migrate_v10_to_v11(bytes: &[u8]) -> Result<Vec<u8>, StateError> {
+    let mut w = StateWriter::default();
+    w.bytes(SNAPSHOT_MAGIC);
+    w.u16(11);
+    w.u32(SNAPSHOT_SCHEMA_HASH);
+    w.bytes(body(bytes)?);
+    w.u32(0);
+    w.u32(0);
+    Ok(w.buf)
+}
+
+// v11 had no serial port, reads of SB and SC came back 0xFF but nothing was ever sent
+fn migrate_v11_to_v12(bytes: &[u8]) -> Result<Vec<u8>, StateError> {
+    let mut w = StateWriter::default();
+    w.bytes(SNAPSHOT_MAGIC);
+    w.u16(12);
+    w.u32(SNAPSHOT_SCHEMA_HASH);
+    w.bytes(body(bytes)?);
+    w.bytes(&[0x00; 3]);
+    w.u16(512);
+    Ok(w.buf)
+}
+
+// v12 counted down the T-cycles to the next serial bit, the bits now shift when DIV's 8192 Hz bit falls
+fn migrate_v12_to_v13(bytes: &[u8]) -> Result<Vec<u8>, StateError> {
+    let mut w = StateWriter::default();
+    w.bytes(SNAPSHOT_MAGIC);
+    w.u16(13);
+    w.u32(SNAPSHOT_SCHEMA_HASH);
+    let rest = body(bytes)?;
+    w.bytes(&rest[..rest.len().checked_sub(2).ok_or(StateError::Truncated)?]);
+    Ok(w.buf)
+}
+
+// v13 panicked on STOP, so no state was ever made in the middle of one. the flag goes after halt_bug
+fn migrate_v13_to_v14(bytes: &[u8]) -> Result<Vec<u8>, StateError> {
+    const STOPPED: usize = HEADER_LEN + 16; // registers, pc, sp, ime, ei_delay, halted, halt_bug
+    if bytes.len() < STOPPED {
+        return Err(StateError::Truncated);
+    }
+    let mut w = StateWriter::default();
+    w.bytes(SNAPSHOT_MAGIC);
+    w.u16(14);
+    w.u32(SNAPSHOT_SCHEMA_HASH);
+    w.bytes(&bytes[HEADER_LEN..STOPPED]);
+    w.bool(false);
+    w.bytes(&bytes[STOPPED..]);
+    Ok(w.buf)
+}
+
+// v14 kept a byte after IF that nothing read, whether the boot ROM is mapped goes there now and no boot ROM was
+// ever mapped
+fn migrate_v14_to_v15(bytes: &[u8]) -> Result<Vec<u8>, StateError> {
+    let mut body = body(bytes)?.to_vec();
+    let mut r = StateReader::new(&body);
+    CpuSnapshot::read_state(&mut r)?;
+    r.bytes(0x2000 + 0x7F)?; // wram, hram
+    r.vec()?; // sram
+    r.bytes(5 + 2)?; // mbc, ie, if
+    let boot_rom_mapped = r.ptr;
+    r.u8()?;
+    body[boot_rom_mapped] = 0;
+
+    let mut w = StateWriter::default();
+    w.bytes(SNAPSHOT_MAGIC);
+    w.u16(15);
+    w.u32(SNAPSHOT_SCHEMA_HASH);
+    w.bytes(&body);
+    Ok(w.buf)
+}
+
+// v15 had the DMG's 2 WRAM banks only, the CGB's other 6 come back empty with SVBK at 0
+fn migrate_v15_to_v16(bytes: &[u8]) -> Result<Vec<u8>, StateError> {
+    let mut w = StateWriter::default();
+    w.bytes(SNAPSHOT_MAGIC);
+    w.u16(16);
+    w.u32(SNAPSHOT_SCHEMA_HASH);
+    w.bytes(body(bytes)?);
+    w.u8(0);
+    w.bytes(&[0; 0x6000]);
+    Ok(w.buf)
+}
+
+// v16 always powered on with zeroed RAM
+fn migrate_v16_to_v17(bytes: &[u8]) -> Result<Vec<u8>, StateError> {
+    let mut w = StateWriter::default();
+    w.bytes(SNAPSHOT_MAGIC);
+    w.u16(17);
+    w.u32(SNAPSHOT_SCHEMA_HASH);
+    w.bytes(body(bytes)?);
+    w.u8(0);
+    w.u64(0);
+    Ok(w.buf)
+}
+
+// upgrades an older state one version at a time until it matches the current layout
+fn migrate(bytes: &[u8]) -> Result<Vec<u8>, StateError> {
+    let mut migrated = bytes.to_vec();
+    loop {
+        let version = match migrated.get(4..6) {
+            Some(&[low, high]) => u16::from_le_bytes([low, high]),
+            _ => return Err(StateError::Truncated)
+        };
+        migrated = match version {
+            1 => migrate_v1_to_v2(&migrated)?,
+            2 => migrate_v2_to_v3(&migrated)?,
+            3 => migrate_v3_to_v4(&migrated)?,
+            4 => migrate_v4_to_v5(&migrated)?,
+            5 => migrate_v5_to_v6(&migrated)?,
+            6 => migrate_v6_to_v7(&migrated)?,
+            7 => migrate_v7_to_v8(&migrated)?,
+            8 => migrate_v8_to_v9(&migrated)?,
+            9 => migrate_v9_to_v10(&migrated)?,
+            10 => migrate_v10_to_v11(&migrated)?,
+            11 => migrate_v11_to_v12(&migrated)?,
+            12 => migrate_v12_to_v13(&migrated)?,
+            13 => migrate_v13_to_v14(&migrated)?,
+            14 => migrate_v14_to_v15(&migrated)?,
+            15 => migrate_v15_to_v16(&migrated)?,
+            16 => migrate_v16_to_v17(&migrated)?,
+            SNAPSHOT_VERSION => return Ok(migrated),
+            _ => return Err(StateError::UnsupportedVersion(version))
+        };
+    }
+}
+
+// rebuilds the plain layout so compressed states go through the same version checks and migrations
+#[cfg(feature = "compression")]
+fn decompress(bytes: &[u8]) -> Result<Vec<u8>, StateError> {
+    let mut r = StateReader::new(bytes);
+    r.bytes(4)?;
+    let header = r.bytes(HEADER_LEN - 4)?;
+    let body_len = r.u32()? as usize;
+    let body = miniz_oxide::inflate::decompress_to_vec_zlib_with_limit(&bytes[HEADER_LEN + 4..], body_len)
+        .map_err(|_| StateError::InvalidData("compressed body does not inflate"))?;
+    if body.len() != body_len {
+        return Err(StateError::InvalidData("compressed body has the wrong length"));
+    }
+
+    let mut w = StateWriter::default();
+    w.bytes(SNAPSHOT_MAGIC);
+    w.bytes(header);
+    w.bytes(&body);
+    Ok(w.buf)
+}
+
+#[cfg(not(feature = "compression"))]
+fn decompress(_bytes: &[u8]) -> Result<Vec<u8>, StateError> {
+    Err(StateError::CompressionUnsupported)
+}
+
+// in-process copy of the whole machine (minus the cartridge ROM), no serialization involved
+#[derive(Clone)]
+pub struct Snapshot {
+    pub(crate) cpu: CpuSnapshot,
+    pub(crate) memory: MemorySnapshot
+}
+
+impl Snapshot {
+    // the registers when it was taken, mid-instruction if that's where the CPU was
+    pub fn cpu_state(&self) -> CpuState {
+        self.cpu.state()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = StateWriter::default();
+        w.bytes(SNAPSHOT_MAGIC);
+        w.u16(SNAPSHOT_VERSION);
+        w.u32(SNAPSHOT_SCHEMA_HASH);
+        self.cpu.write_state(&mut w);
+        self.memory.write_state(&mut w);
+        w.buf
+    }
+
+    // only the body is compressed so the header of an exported state stays readable
+    #[cfg(feature = "compression")]
+    pub fn to_bytes_compressed(&self) -> Vec<u8> {
+        let bytes = self.to_bytes();
+        let mut w = StateWriter::default();
+        w.bytes(COMPRESSED_MAGIC);
+        w.bytes(&bytes[4..HEADER_LEN]);
+        w.u32((bytes.len() - HEADER_LEN) as u32);
+        w.bytes(&miniz_oxide::deflate::compress_to_vec_zlib(&bytes[HEADER_LEN..], 6));
+        w.buf
+    }
+
+    // accepts both plain and compressed states
+    pub fn from_bytes(bytes: &[u8]) -> Result<Snapshot, StateError> {
+        let mut r = StateReader::new(bytes);
+        let magic = r.bytes(4)?;
+        if magic == COMPRESSED_MAGIC {
+            return Snapshot::from_bytes(&decompress(bytes)?);
+        }
+        if magic != SNAPSHOT_MAGIC {
+            return Err(StateError::InvalidMagic);
+        }
+
+        let version = r.u16()?;
+        if version > SNAPSHOT_VERSION {
+            return Err(StateError::NewerVersion(version));
+        }
+        if version < SNAPSHOT_VERSION {
+            return Snapshot::from_bytes(&migrate(bytes)?);
+        }
+
+        let schema_hash = r.u32()?;
+        if schema_hash != SNAPSHOT_SCHEMA_HASH {
+            return Err(StateError::SchemaMismatch(schema_hash));
+        }
+
+        let cpu = CpuSnapshot::read_state(&mut r)?;
+        let memory = MemorySnapshot::read_state(&mut r)?;
+        if !r.is_empty() {
+            return Err(StateError::InvalidData("trailing bytes after snapshot"));
+        }
+
+        Ok(Snapshot { cpu, memory })
+    }
+}
+
+// little endian writer used by every component for the native snapshot layout
+#[derive(Default)]
+pub struct StateWriter {
+    pub buf: Vec<u8>
+}
+
+impl StateWriter {
+    pub fn u8(&mut self, val: u8) { self.buf.push(val) }
+    pub fn bool(&mut self, val: bool) { self.buf.push(val as u8) }
+    pub fn u16(&mut self, val: u16) { self.buf.extend_from_slice(&val.to_le_bytes()) }
+    pub fn u32(&mut self, val: u32) { self.buf.extend_from_slice(&val.to_le_bytes()) }
+    pub fn u64(&mut self, val: u64) { self.buf.extend_from_slice(&val.to_le_bytes()) }
+    pub fn bytes(&mut self, val: &[u8]) { self.buf.extend_from_slice(val) }
+
+    // length prefixed, for buffers whose size depends on the cartridge
+    pub fn vec(&mut self, val: &[u8]) {
+        self.u32(val.len() as u32);
+        self.bytes(val);
+    }
+}
+
+pub struct StateReader<'a> {
+    buf: &'a [u8],
+    ptr: usize
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(buf: &'a [u8]) -> StateReader<'a> {
+        StateReader { buf, ptr: 0 }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ptr == self.buf.len()
+    }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.buf.len() - self.ptr < len {
+            return Err(StateError::Truncated);
+        }
+        let slice = &self.buf[self.ptr..self.ptr + len];
+        self.ptr += len;
+        Ok(slice)
+    }
+
+    pub fn fill(&mut self, out: &mut [u8]) -> Result<(), StateError> {
+        out.copy_from_slice(self.bytes(out.len())?);
+        Ok(())
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> { Ok(self.bytes(1)?[0]) }
+    pub fn bool(&mut self) -> Result<bool, StateError> { Ok(self.u8()? != 0) }
+    pub fn u16(&mut self) -> Result<u16, StateError> { Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap())) }
+    pub fn u32(&mut self) -> Result<u32, StateError> { Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap())) }
+    pub fn u64(&mut self) -> Result<u64, StateError> { Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap())) }
+
+    pub fn vec(&mut self) -> Result<Vec<u8>, StateError> {
+        let len = self.u32()? as usize;
+        Ok(self.bytes(len)?.to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use super::*;
+    use crate::Emulator;
+    use crate::internal::ppu::hash_display;
+
+    fn running_emulator(frames: usize) -> Emulator {
+        let mut emulator = Emulator::new();
+        emulator.load_catridge(fs::read("./tests/blargg/roms/2.gb").expect("File not found!")).unwrap();
+        for _ in 0..frames {
+            emulator.render(-1);
+        }
+        emulator
+    }
+
+    fn frame_hashes(emulator: &mut Emulator, frames: usize) -> Vec<u64> {
+        (0..frames).map(|_| hash_display(&emulator.render(-1))).collect()
+    }
+
+    #[test]
+    fn restore_replays_identical_frames() {
+        let mut emulator = running_emulator(2);
+        let snapshot = emulator.snapshot();
+        let expected = frame_hashes(&mut emulator, 25);
+        let expected_state = emulator.snapshot().to_bytes();
+
+        emulator.restore(&snapshot);
+        assert_eq!(frame_hashes(&mut emulator, 25), expected);
+        assert_eq!(emulator.snapshot().to_bytes(), expected_state);
+
+        // restoring a second time from the same snapshot must not depend on what ran in between
+        emulator.restore(&snapshot);
+        assert_eq!(frame_hashes(&mut emulator, 25), expected);
+    }
+
+    #[test]
+    fn serialized_snapshot_round_trips() {
+        let mut emulator = running_emulator(3); // lands in the middle of an instruction
+        let bytes = emulator.snapshot().to_bytes();
+        let expected = frame_hashes(&mut emulator, 25);
+        let expected_state = emulator.snapshot().to_bytes();
+
+        let snapshot = Snapshot::from_bytes(&bytes).unwrap();
+        assert_eq!(snapshot.to_bytes(), bytes);
+
+        let mut fresh = running_emulator(0);
+        fresh.restore(&snapshot);
+        assert_eq!(frame_hashes(&mut fresh, 25), expected);
+        assert_eq!(fresh.snapshot().to_bytes(), expected_state);
+    }
+
+    #[test]
+    fn rejects_malformed_bytes() {
+        let bytes = running_emulator(1).snapshot().to_bytes();
+
+        assert_eq!(Snapshot::from_bytes(b"BESS").err(), Some(StateError::InvalidMagic));
+        assert_eq!(Snapshot::from_bytes(&bytes[..bytes.len() - 1]).err(), Some(StateError::Truncated));
+
+        let mut unknown_version = bytes.clone();
+        unknown_version[4] = 0x00;
+        assert_eq!(Snapshot::from_bytes(&unknown_version).err(), Some(StateError::UnsupportedVersion(0)));
+
+        let mut wrong_schema = bytes.clone();
+        wrong_schema[6] ^= 0xFF;
+        assert!(matches!(Snapshot::from_bytes(&wrong_schema), Err(StateError::SchemaMismatch(_))));
+    }
+
+    #[test]
+    fn rejects_newer_versions_without_touching_state() {
+        let mut emulator = running_emulator(3);
+        let before = emulator.save_state();
+
+        let mut newer = before.clone();
+        newer[4..6].copy_from_slice(&(SNAPSHOT_VERSION + 1).to_le_bytes());
+        assert_eq!(emulator.load_state(&newer).err(), Some(StateError::NewerVersion(SNAPSHOT_VERSION + 1)));
+        assert_eq!(emulator.save_state(), before);
+    }
+
+    #[test]
+    #[cfg(feature = "compression")]
+    fn compressed_snapshot_round_trips() {
+        let mut emulator = running_emulator(3);
+        let snapshot = emulator.snapshot();
+        let bytes = snapshot.to_bytes();
+        let compressed = snapshot.to_bytes_compressed();
+        assert_eq!(&compressed[..4], COMPRESSED_MAGIC);
+        assert_eq!(&compressed[4..HEADER_LEN], &bytes[4..HEADER_LEN]);
+        assert!(compressed.len() < 20 * 1024, "compressed state is {} bytes", compressed.len());
+
+        assert_eq!(Snapshot::from_bytes(&compressed).unwrap().to_bytes(), bytes);
+
+        let expected = frame_hashes(&mut emulator, 10);
+        let mut fresh = running_emulator(0);
+        fresh.load_state(&compressed).unwrap();
+        assert_eq!(frame_hashes(&mut fresh, 10), expected);
+    }
+
+    #[test]
+    #[cfg(feature = "compression")]
+    fn rejects_corrupted_compressed_bytes() {
+        let compressed = running_emulator(1).snapshot().to_bytes_compressed();
+        assert_eq!(Snapshot::from_bytes(&compressed[..compressed.len() - 8]).err(), Some(StateError::InvalidData("compressed body does not inflate")));
+
+        let mut wrong_len = compressed.clone();
+        wrong_len[HEADER_LEN..HEADER_LEN + 4].copy_from_slice(&16u32.to_le_bytes());
+        assert!(matches!(Snapshot::from_bytes(&wrong_len), Err(StateError::InvalidData(_))));
+    }
+
+    #[test]
+    fn migrates_v1_fixture() {
+        let fixture = fs::read("./tests/states/v1.state").expect("File not found!");
+        assert_eq!(u16::from_le_bytes([fixture[4], fixture[5]]), 1);
+
+        let snapshot = Snapshot::from_bytes(&fixture).unwrap();
+        assert_eq!(&snapshot.to_bytes()[..6], &[b'G', b'B', b'S', b'N', 0x11, 0x00]);
+
+        let mut emulator = running_emulator(0);
+        emulator.restore(&snapshot);
+        assert_eq!(emulator.core.pc, 0xC007); // captured 3 frames into the blargg rom
+        frame_hashes(&mut emulator, 10);
+    }
+
+    #[test]
+    fn old_states_cut_short_are_truncated() {
+        for version in 1..SNAPSHOT_VERSION {
+            let header = [SNAPSHOT_MAGIC.as_slice(), &version.to_le_bytes(), &SNAPSHOT_SCHEMA_HASH.to_le_bytes()].concat();
+            // v1's header had no schema hash, the others' cut short before it too
+            let header_len = if version == 1 { V1_HEADER_LEN } else { HEADER_LEN };
+            for len in [V1_HEADER_LEN, header_len] {
+                assert_eq!(Snapshot::from_bytes(&header[..len]).err(), Some(StateError::Truncated), "v{} in {} bytes", version, len);
+            }
+        }
+        assert_eq!(migrate(b"GBSN").err(), Some(StateError::Truncated));
+    }
+
+    #[test]
+    fn v8_states_get_a_switched_off_apu() {
+        // the bytes the migrations from v8 append are what a fresh APU without an audio output writes, and then
+        // an idle serial port, the CGB's empty WRAM banks and zeroed power on RAM. the CPU's fixed fields only get
+        // the stopped flag after them, and the memory up to joyp with nothing in flight is left as it was
+        const MEMORY_TO_JOYP: usize = 2 + 0x2000 + 0x7F + 4 + 5 + 2 + 2;
+        let mut w = StateWriter::default();
+        w.bytes(&[0; 16]);
+        w.bool(false);
+        w.bytes(&[0; MEMORY_TO_JOYP]);
+        crate::internal::apu::ApuSnapshot::default().write_state(&mut w);
+        crate::internal::serial::Serial::default().write_state(&mut w);
+        w.u8(0);
+        w.bytes(&[0; 0x6000]);
+        w.u8(0);
+        w.u64(0);
+        let header = [SNAPSHOT_MAGIC.as_slice(), &8u16.to_le_bytes(), &[0; 4], &[0; 16], &[0; MEMORY_TO_JOYP]].concat();
+        assert_eq!(migrate(&header).unwrap()[HEADER_LEN..], w.buf);
+    }
+
+    #[test]
+    fn v14_states_leave_the_boot_rom_unmapped() {
+        // the byte v14 didn't read is 1 here, which is what it would be if something had been kept in it
+        let mut emulator = Emulator::new();
+        emulator.mount_bootrom(vec![0; 0x100]).unwrap();
+        emulator.load_catridge(fs::read("./tests/blargg/roms/2.gb").expect("File not found!")).unwrap();
+        assert_eq!(emulator.core.bus.read(0xFF50), 0xFE);
+
+        // without what v15 and v16 appended, the CGB's WRAM banks and the power on RAM
+        let mut v14 = emulator.save_state();
+        v14.truncate(v14.len() - (1 + 0x6000) - (1 + 8));
+        v14[4..6].copy_from_slice(&14u16.to_le_bytes());
+
+        let mut restored = running_emulator(0);
+        restored.load_state(&v14).unwrap();
+        assert_eq!(restored.core.bus.read(0xFF50), 0xFF);
+        assert_eq!(restored.core.pc, emulator.core.pc);
+    }
+}
@@ -1,13 +1,57 @@
+use wasm_bindgen::prelude::*;
+use crate::internal::snapshot::{StateWriter, StateReader, StateError};
+
+const DIV_APU_BIT: u16 = 1 << 12; // bit 4 of DIV, the upper byte of sysclock
+const SERIAL_CLOCK_BIT: u16 = 1 << 8; // 8192 Hz, the internal clock of the serial port
+const TAC_ENABLE: u8 = 1 << 2;
+const TAC_BITS: [u16; 4] = [1 << 9, 1 << 3, 1 << 5, 1 << 7]; // sysclock bit TIMA counts for 4096, 262144, 65536 and 16384 Hz
+
+// what's left of a TIMA overflow, each step lasts one M-cycle
+#[wasm_bindgen]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TimerOverflow {
+    None,
+    Pending, // TIMA reads 0, writing it cancels the reload and the interrupt
+    Reloaded // TIMA was just loaded from TMA, writes to it are lost and writes to TMA go through to it
+}
+
+// everything the timer needs to carry on from where it was, div is the whole counter FF04 shows the upper byte of
+#[wasm_bindgen]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct TimerState {
+    pub div: u16,
+    pub tima: u8,
+    pub tma: u8,
+    pub tac: u8,
+    pub overflow: TimerOverflow,
+    pub timer_bit: bool // what the edge detector last saw, see Timer::timer_bit
+}
+
+impl TimerState {
+    // for registers loaded on their own, like a BESS file's, the edge detector starts from what they select
+    pub fn from_registers(div: u8, tima: u8, tma: u8, tac: u8) -> Self {
+        let div = (div as u16) << 8;
+        Self { div, tima, tma, tac, overflow: TimerOverflow::None, timer_bit: selected_bit(div, tac) }
+    }
+}
+
+fn selected_bit(sysclock: u16, tac: u8) -> bool {
+    tac & TAC_ENABLE != 0 && sysclock & TAC_BITS[(tac & 0x3) as usize] != 0
+}
+
+#[derive(Clone)]
 pub struct Timer {
-    pub tima_irq: usize, // set if IRQ should be dispatched
+    overflow: TimerOverflow,
+    interrupt: bool, // requested by the reload, taken by Memory in the same M-cycle
 
     pub sysclock: u16,
     sysclock_cycles: usize,
     tma: u8,
-    tma_previous: Option<u8>, // used for writes and TIMA overflows in the same cycle
     tima: u8,
     tac: u8,
-    current_freq: u16 // sysclock frequency specified by TAC
+    timer_bit: bool, // the sysclock bit TAC selects ANDed with its enable, TIMA counts every time this falls
+    div_apu_edge: bool, // bit 4 of DIV fell, which clocks the APU's frame sequencer, taken in the same M-cycle
+    serial_edge: bool // same for the serial clock, which shifts a bit of a transfer on the internal clock
 }
 
 impl Timer {
@@ -21,45 +65,140 @@ impl Timer {
         }
     }
 
+    // resetting DIV or switching TAC goes through the same edge detector as counting, so either can make the
+    // selected bit fall early and count TIMA up once more
     pub fn write_registers(&mut self, addr: u16, val: u8) {
         match addr {
-            0xFF04 => self.sysclock = 0x0,
-            0xFF05 => self.tima = val,
+            0xFF04 => {
+                self.div_apu_edge |= self.sysclock & DIV_APU_BIT != 0; // resetting DIV can make the bit fall early
+                self.serial_edge |= self.sysclock & SERIAL_CLOCK_BIT != 0;
+                self.sysclock = 0x0;
+                self.detect_timer_edge();
+            },
+            0xFF05 => match self.overflow {
+                TimerOverflow::None => self.tima = val,
+                TimerOverflow::Pending => {
+                    self.tima = val;
+                    self.overflow = TimerOverflow::None;
+                },
+                TimerOverflow::Reloaded => ()
+            },
             0xFF06 => {
-                self.tma_previous.get_or_insert(self.tma);
                 self.tma = val;
+                if self.overflow == TimerOverflow::Reloaded {
+                    self.tima = val;
+                }
+            },
+            0xFF07 => {
+                self.tac = val;
+                self.detect_timer_edge();
             },
-            0xFF07 => self.tac = val,
             _ => panic!("recieved invalid address")
         };
     }
 
+    // a debugger's write, DIV takes the value instead of starting over and nothing goes through the edge detector
+    pub fn poke_registers(&mut self, addr: u16, val: u8) {
+        match addr {
+            0xFF04 => self.sysclock = ((val as u16) << 8) | (self.sysclock & 0xFF),
+            0xFF05 => self.tima = val,
+            0xFF06 => self.tma = val,
+            0xFF07 => self.tac = val,
+            _ => panic!("recieved invalid address")
+        }
+    }
+
+    pub fn state(&self) -> TimerState {
+        TimerState { div: self.sysclock, tima: self.tima, tma: self.tma, tac: self.tac, overflow: self.overflow, timer_bit: self.timer_bit }
+    }
+
+    // taken as it is, nothing counts or reloads until the next update
+    pub fn restore_state(&mut self, state: &TimerState) {
+        self.sysclock = state.div;
+        self.tima = state.tima;
+        self.tma = state.tma;
+        self.tac = state.tac;
+        self.overflow = state.overflow;
+        self.timer_bit = state.timer_bit;
+        self.interrupt = false;
+        self.div_apu_edge = false;
+        self.serial_edge = false;
+    }
+
+    // one M-cycle, the system clock counts T-cycles. an overflow from the last M-cycle is reloaded first
     pub fn update(&mut self) {
-        self.sysclock += 4;
-
-        if (self.tac >> 2 & 0x1) == 1 {
-            let bit_set_prev = self.current_freq;
-
-            self.current_freq = match self.tac & 0x3 {
-                0 => (self.sysclock >> 9) & 0x1, // 1024 (default)
-                1 => (self.sysclock >> 3) & 0x1, // 16
-                2 => (self.sysclock >> 5) & 0x1, // 64
-                3 => (self.sysclock >> 7) & 0x1, // 256
-                _ => unreachable!()
-            };
-
-            if bit_set_prev == 1 && self.current_freq == 0 {
-                let result = self.tima.overflowing_add(1);
-                if result.1 {
-                    self.tima = if self.tma_previous.is_none() { self.tma } else { self.tma_previous.unwrap() };
-                    self.tima_irq = 2;
-                } else {
-                    self.tima = result.0;
-                }
-            }
+        self.overflow = match self.overflow {
+            TimerOverflow::Pending => {
+                self.tima = self.tma;
+                self.interrupt = true;
+                TimerOverflow::Reloaded
+            },
+            _ => TimerOverflow::None
+        };
+
+        let fallen = self.sysclock & !self.sysclock.wrapping_add(4);
+        self.sysclock = self.sysclock.wrapping_add(4);
+        self.div_apu_edge |= fallen & DIV_APU_BIT != 0;
+        self.serial_edge |= fallen & SERIAL_CLOCK_BIT != 0;
+        self.detect_timer_edge();
+    }
+
+    fn detect_timer_edge(&mut self) {
+        let bit = selected_bit(self.sysclock, self.tac);
+        if std::mem::replace(&mut self.timer_bit, bit) && !bit {
+            self.increment_tima();
+        }
+    }
+
+    fn increment_tima(&mut self) {
+        let (tima, overflowed) = self.tima.overflowing_add(1);
+        self.tima = tima;
+        if overflowed {
+            self.overflow = TimerOverflow::Pending;
         }
+    }
+
+    pub fn take_interrupt(&mut self) -> bool {
+        std::mem::take(&mut self.interrupt)
+    }
+
+    pub fn take_div_apu_edge(&mut self) -> bool {
+        std::mem::take(&mut self.div_apu_edge)
+    }
 
-        self.tma_previous = None;
+    pub fn take_serial_edge(&mut self) -> bool {
+        std::mem::take(&mut self.serial_edge)
+    }
+
+    // the interrupt is always taken within the M-cycle so it's left out
+    pub fn write_state(&self, w: &mut StateWriter) {
+        let state = self.state();
+        w.u8(state.overflow as u8);
+        w.u16(state.div);
+        w.u32(self.sysclock_cycles as u32);
+        w.u8(state.tma);
+        w.bytes(&[0x0; 2]); // where older states kept the TMA a write replaced, which never outlived an M-cycle
+        w.u8(state.tima);
+        w.u8(state.tac);
+        w.u16(state.timer_bit as u16);
+    }
+
+    pub fn read_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        let overflow = match r.u8()? {
+            0 => TimerOverflow::None,
+            1 => TimerOverflow::Pending,
+            2 => TimerOverflow::Reloaded,
+            _ => return Err(StateError::InvalidData("timer overflow out of range"))
+        };
+        let div = r.u16()?;
+        self.sysclock_cycles = r.u32()? as usize;
+        let tma = r.u8()?;
+        r.bytes(2)?;
+        let (tima, tac) = (r.u8()?, r.u8()?);
+        // states from before TAC changes counted left the bit as it was whenever the timer got disabled
+        let timer_bit = r.u16()? != 0 && tac & TAC_ENABLE != 0;
+        self.restore_state(&TimerState { div, tima, tma, tac, overflow, timer_bit });
+        Ok(())
     }
 }
 
@@ -68,12 +207,129 @@ impl Default for Timer {
         Self { 
             sysclock: 0x0,
             tma: 0,
-            tma_previous: None,
             tima: 0x00,
             tac: 0x0,
-            tima_irq: 0,
+            overflow: TimerOverflow::None,
+            interrupt: false,
             sysclock_cycles: 0,
-            current_freq: 0
+            timer_bit: false,
+            div_apu_edge: false,
+            serial_edge: false
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // TAC 0x05 counts on bit 3, which sysclock sets after two M-cycles
+    fn timer_at_bit_3_high() -> Timer {
+        let mut timer = Timer::default();
+        timer.write_registers(0xFF07, 0x05);
+        timer.update();
+        timer.update();
+        assert_eq!((timer.sysclock, timer.read_registers(0xFF05)), (8, 0));
+        timer
+    }
+
+    #[test]
+    fn div_write_counts_tima_only_while_the_selected_bit_is_set() {
+        let mut timer = timer_at_bit_3_high();
+        timer.write_registers(0xFF04, 0x12);
+        assert_eq!((timer.sysclock, timer.read_registers(0xFF05)), (0, 1));
+
+        // bit 3 is clear again right after the reset
+        timer.update();
+        timer.write_registers(0xFF04, 0x00);
+        assert_eq!(timer.read_registers(0xFF05), 1);
+    }
+
+    #[test]
+    fn tac_changes_that_drop_the_selected_bit_count_tima() {
+        // switching to bit 9, which is still clear
+        let mut timer = timer_at_bit_3_high();
+        timer.write_registers(0xFF07, 0x04);
+        assert_eq!(timer.read_registers(0xFF05), 1);
+
+        // switching to another bit that's set doesn't
+        let mut timer = timer_at_bit_3_high();
+        timer.sysclock |= 1 << 5;
+        timer.write_registers(0xFF07, 0x06);
+        assert_eq!(timer.read_registers(0xFF05), 0);
+    }
+
+    #[test]
+    fn rapidly_toggling_the_timer_counts_every_disable() {
+        let mut timer = timer_at_bit_3_high();
+        for _ in 0..3 {
+            timer.write_registers(0xFF07, 0x01);
+            timer.write_registers(0xFF07, 0x05);
+        }
+        assert_eq!(timer.read_registers(0xFF05), 3);
+
+        // with the bit clear toggling counts nothing
+        let mut timer = Timer::default();
+        for _ in 0..3 {
+            timer.write_registers(0xFF07, 0x05);
+            timer.write_registers(0xFF07, 0x01);
+        }
+        assert_eq!(timer.read_registers(0xFF05), 0);
+    }
+
+    #[test]
+    fn loading_registers_on_their_own_counts_nothing() {
+        let mut timer = overflowed_timer();
+        timer.restore_state(&TimerState::from_registers(0x00, 0x12, 0x34, 0x04));
+        timer.update();
+        assert_eq!(timer.read_registers(0xFF05), 0x12);
+        assert!(!timer.take_interrupt());
+    }
+
+    // TIMA at 0xFF with TMA 0x23, stepped up to the M-cycle bit 3 falls in
+    fn overflowed_timer() -> Timer {
+        let mut timer = timer_at_bit_3_high();
+        timer.write_registers(0xFF05, 0xFF);
+        timer.write_registers(0xFF06, 0x23);
+        timer.update();
+        timer.update();
+        assert_eq!(timer.overflow, TimerOverflow::Pending);
+        timer
+    }
+
+    #[test]
+    fn overflow_reads_0_for_an_m_cycle_before_the_reload() {
+        let mut timer = overflowed_timer();
+        assert_eq!(timer.read_registers(0xFF05), 0x00);
+        assert!(!timer.take_interrupt());
+
+        timer.update();
+        assert_eq!(timer.read_registers(0xFF05), 0x23);
+        assert!(timer.take_interrupt());
+        timer.update();
+        assert!(!timer.take_interrupt());
+    }
+
+    #[test]
+    fn writing_tima_before_the_reload_cancels_it() {
+        let mut timer = overflowed_timer();
+        timer.write_registers(0xFF05, 0x80);
+        timer.update();
+        assert_eq!(timer.read_registers(0xFF05), 0x80);
+        assert!(!timer.take_interrupt());
+    }
+
+    #[test]
+    fn tima_follows_tma_in_the_reload_m_cycle() {
+        let mut timer = overflowed_timer();
+        timer.update();
+        timer.write_registers(0xFF05, 0x80);
+        assert_eq!(timer.read_registers(0xFF05), 0x23);
+        timer.write_registers(0xFF06, 0x45);
+        assert_eq!(timer.read_registers(0xFF05), 0x45);
+
+        // only for that M-cycle
+        timer.update();
+        timer.write_registers(0xFF06, 0x67);
+        assert_eq!(timer.read_registers(0xFF05), 0x45);
+    }
+}
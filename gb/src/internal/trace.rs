@@ -0,0 +1,47 @@
+use std::collections::VecDeque;
+use std::io::Write;
+
+// where the CPU's trace lines go, one per instruction in gameboy-doctor's format
+pub enum Trace {
+    Writer(Box<dyn Write>),
+    Ring(VecDeque<String>, usize) // the newest lines, dropping the oldest past the capacity
+}
+
+impl Trace {
+    // a writer that fails stops taking lines, the caller drops the trace then
+    pub fn log(&mut self, line: String) -> bool {
+        match self {
+            Trace::Writer(writer) => writeln!(writer, "{}", line).is_ok(),
+            Trace::Ring(lines, capacity) => {
+                if lines.len() == *capacity {
+                    lines.pop_front();
+                }
+                lines.push_back(line);
+                true
+            }
+        }
+    }
+
+    // what the ring holds, oldest first with a newline after every line. a writer already has everything
+    pub fn take(&mut self) -> String {
+        match self {
+            Trace::Writer(_) => String::new(),
+            Trace::Ring(lines, _) => lines.drain(..).map(|line| line + "\n").collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ring_keeps_the_newest_lines() {
+        let mut trace = Trace::Ring(VecDeque::new(), 2);
+        for line in ["a", "b", "c"] {
+            assert!(trace.log(line.to_string()));
+        }
+        assert_eq!(trace.take(), "b\nc\n");
+        assert_eq!(trace.take(), "");
+    }
+}
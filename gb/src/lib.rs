@@ -1,9 +1,65 @@
+#![allow(non_snake_case)]
+#![allow(
+    clippy::upper_case_acronyms,
+    clippy::useless_format,
+    clippy::needless_return,
+    clippy::new_without_default,
+    clippy::derivable_impls,
+    clippy::manual_is_multiple_of,
+    clippy::nonminimal_bool,
+    clippy::unnecessary_cast,
+    clippy::unnecessary_unwrap,
+    clippy::needless_late_init,
+    clippy::needless_bool,
+    clippy::needless_bool_assign,
+    clippy::collapsible_if,
+    clippy::collapsible_match,
+    clippy::len_zero,
+    clippy::int_plus_one,
+    clippy::identity_op,
+    clippy::implicit_saturating_sub,
+    clippy::manual_swap
+)]
+
 use wasm_bindgen::prelude::*;
 use crate::internal::core::component::CPU;
+use crate::internal::memory::{RGBA_FRAME_LEN, DMG_GREEN, boot_rom_model};
+use crate::internal::mapper::{self, MBC_TYPE};
+use crate::internal::ppu::hash_display;
+use crate::internal::trace::Trace;
+use crate::internal::disasm;
+use crate::internal::debugger::PcHistory;
 extern crate console_error_panic_hook;
 use std::panic;
+use std::cell::RefCell;
+use std::rc::{Rc, Weak};
+use std::ops::RangeInclusive;
+use std::fmt;
 
 mod internal;
+#[cfg(test)]
+mod test_roms;
+#[cfg(test)]
+mod test_sm83;
+
+pub use crate::internal::snapshot::{Snapshot, StateError};
+pub use crate::internal::core::component::CpuState;
+pub use crate::internal::bess::BessError;
+pub use crate::internal::ppu::{PpuMode, MapViewport, OamEntry};
+pub use crate::internal::memory::{FrameBlend, Model, BusObserver, BusSource, PowerOnRam};
+pub use crate::internal::apu::AudioQuality;
+pub use crate::internal::timer::{TimerState, TimerOverflow};
+pub use crate::internal::serial::{SerialDevice, Disconnected};
+pub use crate::internal::joypad::Button;
+pub use crate::internal::keymap::KeyMap;
+pub use crate::internal::movie::{Movie, MovieError};
+pub use crate::internal::disasm::DisasmLine;
+pub use crate::internal::profiler::ProfileEntry;
+pub use crate::internal::debugger::{BankedAddr, BreakReason, WatchKind, WatchHit, Access, CallFrame, CallKind};
+#[cfg(feature = "link")]
+pub use crate::internal::link::{LinkCable, LinkPipe, LinkStatus, Transport};
+#[cfg(all(feature = "link", not(target_arch = "wasm32")))]
+pub use crate::internal::link::TcpTransport;
 
 #[wasm_bindgen]
 extern "C" {
@@ -21,6 +77,61 @@ extern "C" {
     // Multiple arguments too!
     #[wasm_bindgen(js_namespace = console, js_name = log)]
     fn log_many(a: &str, b: &str);
+
+    // defined by the frontend, receives the recovery snapshot right before the module dies
+    #[wasm_bindgen(catch, js_namespace = window, js_name = stashRecoveryState)]
+    fn stash_recovery_state(state: Vec<u8>) -> Result<(), JsValue>;
+
+    // any object with an exchangeBit(outBit) method returning the bit shifted in, and optionally an
+    // externalClock() one, see SerialDevice
+    pub type JsSerialDevice;
+    #[wasm_bindgen(method, js_name = exchangeBit)]
+    fn exchange_bit(this: &JsSerialDevice, out_bit: bool) -> bool;
+    #[wasm_bindgen(method, catch, js_name = externalClock)]
+    fn external_clock(this: &JsSerialDevice) -> Result<bool, JsValue>;
+}
+
+impl SerialDevice for JsSerialDevice {
+    fn exchange_bit(&mut self, out_bit: bool) -> bool {
+        JsSerialDevice::exchange_bit(self, out_bit)
+    }
+
+    // a device without the method never clocks
+    fn external_clock(&mut self) -> bool {
+        JsSerialDevice::external_clock(self).unwrap_or(false)
+    }
+}
+
+type RecoverySlot = Rc<RefCell<Option<Snapshot>>>;
+
+thread_local! {
+    // recovery slot of the emulator that last enabled autosaving, read by the panic hook
+    static PANIC_RECOVERY_SLOT: RefCell<Weak<RefCell<Option<Snapshot>>>> = const { RefCell::new(Weak::new()) };
+}
+
+#[cfg(target_arch = "wasm32")]
+fn panic_hook(info: &panic::PanicHookInfo) {
+    console_error_panic_hook::hook(info);
+
+    // the slot is already borrowed if the panic happened while autosaving, there is nothing consistent to hand over then
+    let state = PANIC_RECOVERY_SLOT.with(|slot| {
+        let slot = slot.borrow().upgrade()?;
+        let snapshot = slot.try_borrow().ok()?;
+        snapshot.as_ref().map(|snapshot| snapshot.to_bytes())
+    });
+    if let Some(state) = state {
+        let _ = stash_recovery_state(state);
+    }
+}
+
+fn set_panic_hook() {
+    #[cfg(target_arch = "wasm32")]
+    {
+        static SET_HOOK: std::sync::Once = std::sync::Once::new();
+        SET_HOOK.call_once(|| panic::set_hook(Box::new(panic_hook)));
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    console_error_panic_hook::set_once();
 }
 
 #[macro_export]
@@ -35,38 +146,2222 @@ pub fn u32_to_little_endian(val: u32) -> [u8; 4] {
 }
 
 #[wasm_bindgen]
-struct Emulator {
-    core: CPU
+pub struct Emulator {
+    core: CPU,
+    autosave_interval: u32,
+    frames_until_autosave: u32,
+    recovery: RecoverySlot,
+    rgba: Vec<u8>,
+    sample_rate: u32,
+    audio_quality: AudioQuality,
+    high_pass: bool,
+    channels_enabled: [bool; 4],
+    ppu_mode: PpuMode,
+    oam_bug: bool,
+    palette: [[u8; 3]; 4],
+    frame_blend: FrameBlend,
+    audio: Vec<i16>,
+    recording: Option<Movie>,
+    playback: Option<(Movie, usize)>, // and the next frame to play
+    mid_frame: bool, // a breakpoint stopped the last frame partway, the next frame call finishes it
+    boot_rom: Option<Vec<u8>>,
+    model: Model,
+    #[cfg(feature = "link")]
+    link: Option<(Rc<RefCell<LinkPipe>>, LinkStatus)> // opened by open_link, the frontend moves the bytes
 }
 
 #[wasm_bindgen]
 impl Emulator {
     pub fn new() -> Emulator {   
-        console_error_panic_hook::set_once();
+        set_panic_hook();
         Emulator {
-            core: CPU::default()
+            core: CPU::default(),
+            autosave_interval: 0,
+            frames_until_autosave: 0,
+            recovery: Rc::new(RefCell::new(None)),
+            rgba: vec![0; RGBA_FRAME_LEN],
+            sample_rate: 0,
+            audio_quality: AudioQuality::High,
+            high_pass: true,
+            channels_enabled: [true; 4],
+            ppu_mode: PpuMode::Fifo,
+            oam_bug: false,
+            palette: DMG_GREEN,
+            frame_blend: FrameBlend::Off,
+            audio: vec![],
+            recording: None,
+            playback: None,
+            mid_frame: false,
+            boot_rom: None,
+            model: Model::Dmg,
+            #[cfg(feature = "link")]
+            link: None
+        }
+    }
+
+    // a ROM shorter than its header or with a mapper there's no support for is turned away and the game already in
+    // keeps running
+    pub fn load_catridge(&mut self, bytes: Vec<u8>) -> Result<(), String> {
+        let mut core = CPU::default();
+        core.bus.set_model(self.model);
+        core.bus.load_cartridge(bytes)?;
+        let serial_device = self.core.bus.detach_serial();
+        core.trace = self.core.trace.take();
+        core.bus.set_power_on_ram(self.core.bus.power_on_ram());
+        self.core = core;
+        self.core.bus.power_on();
+        match &self.boot_rom {
+            Some(boot_rom) => self.core.bus.mount_bootrom(boot_rom),
+            None => self.core.initialize_core()
         }
+        self.core.bus.attach_serial(serial_device);
+        self.core.bus.set_audio_quality(self.audio_quality);
+        self.core.bus.set_high_pass(self.high_pass);
+        self.core.bus.set_sample_rate(self.sample_rate);
+        for (channel, &on) in self.channels_enabled.iter().enumerate() {
+            self.core.bus.set_channel_enabled(channel as u8, on);
+        }
+        self.core.bus.set_ppu_mode(self.ppu_mode);
+        self.core.bus.oam_bug = self.oam_bug;
+        self.core.bus.set_palette(self.palette);
+        self.core.bus.set_frame_blend(self.frame_blend);
+        // an autosave, movie or frame a breakpoint stopped in of the game that was in can't be restored over this one
+        self.recording = None;
+        self.playback = None;
+        self.mid_frame = false;
+        *self.recovery.borrow_mut() = None;
+        self.frames_until_autosave = self.autosave_interval;
+        Ok(())
     }
 
-    pub fn load_catridge(&mut self, bytes: Vec<u8>) {
-        self.core = CPU::default();
+    // back to where the DMG boot ROM hands over to the game, which is where load_catridge starts it without one. only
+    // the CPU and IO registers change and the boot ROM is unmapped, RAM keeps what's in it like it does through a reset
+    pub fn skip_bootrom(&mut self) {
         self.core.initialize_core();
-        self.core.bus.load_cartridge(bytes);
     }
 
+    // runs from load_catridge and reset on instead of starting the game where the boot ROM would have left it. the
+    // model goes with the boot ROM, a DMG's is 256 bytes and a CGB's 2048 or 2304
+    pub fn mount_bootrom(&mut self, bytes: Vec<u8>) -> Result<(), String> {
+        self.model = boot_rom_model(bytes.len()).ok_or_else(|| format!("no boot ROM is {} bytes", bytes.len()))?;
+        self.boot_rom = Some(bytes);
+        Ok(())
+    }
+
+    // the console load_catridge and reset start from here on, the DMG to begin with
+    pub fn set_model(&mut self, model: Model) {
+        self.model = model;
+    }
+
+    // switches the Game Boy off and on with the same cartridge in, whose RAM keeps what the battery kept
+    pub fn reset(&mut self) {
+        let rom = self.core.bus.rom().to_vec();
+        let sram = std::mem::take(&mut self.core.bus.sram);
+        self.load_catridge(rom).expect("the cartridge was already in");
+        self.core.bus.sram = sram;
+    }
+
+    // buttons stay held until released, so several can be down at once
+    pub fn press_button(&mut self, button: Button) {
+        self.core.bus.joypad.press(button);
+    }
+
+    pub fn release_button(&mut self, button: Button) {
+        self.core.bus.joypad.release(button);
+    }
+
+    // a bit per Button, set while it's held
+    pub fn set_buttons(&mut self, buttons: u8) {
+        self.core.bus.joypad.set_buttons(buttons);
+    }
+
+    // autofire for a held button, presses it for the first half of every period frames and releases it for the
+    // rest. None turns it off
+    pub fn set_turbo(&mut self, button: Button, period: Option<u8>) {
+        self.core.bus.joypad.set_turbo(button, period);
+    }
+
+    // press_button for whatever the key is bound to, false if it isn't so the frontend can let the event through
+    pub fn key_down(&mut self, map: &KeyMap, key_code: &str) -> bool {
+        map.button(key_code).map(|button| self.press_button(button)).is_some()
+    }
+
+    pub fn key_up(&mut self, map: &KeyMap, key_code: &str) -> bool {
+        map.button(key_code).map(|button| self.release_button(button)).is_some()
+    }
+
+    // what the game sees held, including the keypress of the last frame call
+    pub fn buttons(&self) -> u8 {
+        self.core.bus.joypad.buttons()
+    }
+
+    // keypress is the old code for a single button held through this frame on top of the ones pressed with
+    // press_button, -1 adds none
     pub fn render(&mut self, keypress: i8) -> Vec<u8> {
-        self.core.next_frame(keypress).to_vec()
+        self.next_frame(keypress);
+        self.core.bus.get_display_ref().to_vec()
+    }
+
+    // same as render but expanded to RGBA in a buffer owned by the emulator, the returned pointer into wasm memory
+    // stays valid for rgba_len bytes until the next call
+    pub fn render_rgba(&mut self, keypress: i8) -> *const u8 {
+        self.next_frame(keypress);
+        self.core.bus.render_rgba(&mut self.rgba);
+        self.rgba.as_ptr()
+    }
+
+    // steps exactly n frames with only the held buttons and hashes the last one, see hash_display. a breakpoint
+    // ends it early with the frame as far as it got
+    pub fn run_frames(&mut self, n: u32) -> u64 {
+        for _ in 0..n {
+            self.next_frame(-1);
+            if self.mid_frame {
+                break;
+            }
+        }
+        hash_display(self.core.bus.get_display_ref())
+    }
+
+    pub fn run_frame(&mut self) -> u64 {
+        self.run_frames(1)
+    }
+
+    pub fn run_cycles(&mut self, keypress: i8, cycles: u32) {
+        for _ in 0..self.core.run_cycles(keypress, cycles) {
+            self.autosave();
+        }
+    }
+
+    // paces emulation off audio demand, see CPU::run_until_samples. never returns false without a sample rate set
+    pub fn run_until_samples(&mut self, keypress: i8, n: usize) -> bool {
+        let reached_frame = self.core.run_until_samples(keypress, n);
+        if reached_frame {
+            self.autosave();
+        }
+        reached_frame
+    }
+
+    // every way of running stops right before the instruction at addr, only while bank is mapped there if it's given
+    pub fn add_breakpoint(&mut self, addr: u16, bank: Option<u16>) {
+        self.core.debugger.add_breakpoint(BankedAddr { addr, bank });
+    }
+
+    pub fn remove_breakpoint(&mut self, addr: u16, bank: Option<u16>) -> bool {
+        self.core.debugger.remove_breakpoint(BankedAddr { addr, bank })
+    }
+
+    // how many of the last PCs recent_pcs keeps, 256 to start with. 0 stops keeping them
+    pub fn set_pc_history_len(&mut self, len: usize) {
+        self.core.debugger.history = PcHistory::new(len);
+    }
+
+    // a, f, b, c, d, e, h, l, sp, pc, ime and halted, 1 for the last two when they're on
+    pub fn registers(&self) -> Vec<u16> {
+        self.core.state().to_vec()
+    }
+
+    // see CPU::is_locked
+    pub fn is_locked(&self) -> bool {
+        self.core.is_locked()
+    }
+
+    // what locked the CPU up and how it got there, None while it isn't
+    pub fn lock_report(&self) -> Option<String> {
+        self.core.lock().map(|lock| format!("{}\n{}", lock, self.core.debugger.history))
+    }
+
+    // why the last run or step stopped early, None if it got to the end
+    pub fn break_reason(&self) -> Option<String> {
+        self.core.debugger.stopped().map(|reason| reason.to_string())
+    }
+
+    // step_instruction for the frontend, with the reason as text
+    pub fn step(&mut self) -> String {
+        self.step_instruction().to_string()
+    }
+
+    // continue_until_break that gives up after max_frames, None if no breakpoint was hit by then
+    pub fn continue_for(&mut self, max_frames: u32) -> Option<String> {
+        self.run_frames(max_frames);
+        self.break_reason()
+    }
+
+    // lets step_back go back up to about 15000 instructions from here on, at the cost of a snapshot every 1000
+    pub fn set_step_back(&mut self, enabled: bool) {
+        self.core.keep_checkpoints(enabled);
+    }
+
+    // see CPU::step_back, false if there's nothing kept from that far back
+    pub fn step_back(&mut self) -> bool {
+        self.core.step_back()
+    }
+
+    // counts what runs at every PC from here on, loading a cartridge throws the counts away
+    pub fn start_profiling(&mut self) {
+        self.core.profiler.start();
+    }
+
+    pub fn stop_profiling(&mut self) {
+        self.core.profiler.stop();
+    }
+
+    pub fn reset_profile(&mut self) {
+        self.core.profiler.reset();
+    }
+
+    // profile for the frontend, an entry a line
+    pub fn profile_report(&self, top_n: usize) -> String {
+        self.profile(top_n).iter().map(|entry| format!("{}\n", entry)).collect()
+    }
+
+    // set whenever a frame completes, including through render, and cleared by reading it
+    pub fn take_frame_ready(&mut self) -> bool {
+        self.core.bus.take_frame_ready()
+    }
+
+    pub fn display(&self) -> Vec<u8> {
+        self.core.bus.get_display_ref().to_vec()
+    }
+
+    // false when out isn't 160 * 144 bytes, nothing is copied then
+    pub fn copy_display_into(&self, out: &mut [u8]) -> bool {
+        self.core.bus.copy_display_into(out)
+    }
+
+    // the shades of the current frame in wasm memory, 160 * 144 bytes, so the frontend can view them without a copy.
+    // stays valid as long as the emulator does
+    pub fn display_ptr(&self) -> *const u8 {
+        self.core.bus.get_display_ref().as_ptr()
+    }
+
+    pub fn rgba_len(&self) -> usize {
+        RGBA_FRAME_LEN
+    }
+
+    // 12 bytes, RGB for shades 0 (lightest) to 3
+    pub fn set_palette(&mut self, colors: &[u8]) -> Result<(), String> {
+        if colors.len() != 12 {
+            return Err(format!("expected 12 palette bytes, got {}", colors.len()));
+        }
+        let mut palette = [[0; 3]; 4];
+        for (color, rgb) in palette.iter_mut().zip(colors.chunks_exact(3)) {
+            color.copy_from_slice(rgb);
+        }
+        self.palette = palette;
+        self.core.bus.set_palette(palette);
+        Ok(())
+    }
+
+    // stereo frames per second for the audio output, 0 (the default) turns it off. about a third of a second is
+    // buffered, after that the oldest frames are dropped and counted in audio_overruns
+    pub fn set_sample_rate(&mut self, hz: u32) {
+        self.sample_rate = hz;
+        self.core.bus.set_sample_rate(hz);
+    }
+
+    // High (the default) low-passes before resampling, Fast is cheaper but aliases
+    pub fn set_audio_quality(&mut self, quality: AudioQuality) {
+        self.audio_quality = quality;
+        self.core.bus.set_audio_quality(quality);
+    }
+
+    // the DMG's output capacitors, on by default. off gives the raw mix with the DC offset of every channel
+    pub fn set_high_pass(&mut self, enabled: bool) {
+        self.high_pass = enabled;
+        self.core.bus.set_high_pass(enabled);
+    }
+
+    // moves the samples produced since the last call to audio_ptr and returns how many there are, interleaved
+    // left and right. the pointer stays valid until the next call
+    pub fn take_audio(&mut self) -> usize {
+        self.audio.clear();
+        self.core.bus.drain_audio(&mut self.audio);
+        self.audio.len()
+    }
+
+    pub fn audio_ptr(&self) -> *const i16 {
+        self.audio.as_ptr()
+    }
+
+    pub fn audio_overruns(&self) -> u32 {
+        self.core.bus.audio_overruns()
+    }
+
+    // mutes channel 1-4 in the audio output only, the game still sees it playing
+    pub fn set_channel_enabled(&mut self, ch: u8, on: bool) -> Result<(), String> {
+        if !(1..=4).contains(&ch) {
+            return Err(format!("there is no channel {}, expected 1-4", ch));
+        }
+        self.channels_enabled[(ch - 1) as usize] = on;
+        self.core.bus.set_channel_enabled(ch - 1, on);
+        Ok(())
+    }
+
+    // four amplitudes from 0 to 15, channel 1 first
+    pub fn channel_levels(&self) -> Vec<u8> {
+        self.core.bus.channel_levels().to_vec()
+    }
+
+    // stays plugged in when another cartridge is loaded
+    pub fn attach_serial(&mut self, device: JsSerialDevice) {
+        self.attach_serial_device(Box::new(device));
+    }
+
+    pub fn detach_serial(&mut self) {
+        self.core.bus.detach_serial();
+    }
+
+    // keeps the last `lines` instructions run, logged in gameboy-doctor's format for take_trace. 0 stops tracing
+    pub fn set_trace_buffer(&mut self, lines: usize) {
+        self.core.trace = (lines > 0).then(|| Trace::Ring(std::collections::VecDeque::with_capacity(lines), lines));
+    }
+
+    // the buffered lines, oldest first, and empty when tracing to a writer
+    pub fn take_trace(&mut self) -> String {
+        self.core.trace.as_mut().map_or(String::new(), Trace::take)
+    }
+
+    // plugs in a link cable whose bytes go through push_link_bytes and take_link_bytes, the frontend sends them to the
+    // other emulator over a WebSocket or whatever else it likes
+    #[cfg(feature = "link")]
+    pub fn open_link(&mut self) {
+        let pipe = Rc::new(RefCell::new(LinkPipe::default()));
+        let cable = LinkCable::new(pipe.clone());
+        self.link = Some((pipe, cable.status()));
+        self.attach_serial_device(Box::new(cable));
+    }
+
+    // what arrived from the other emulator
+    #[cfg(feature = "link")]
+    pub fn push_link_bytes(&mut self, bytes: &[u8]) {
+        if let Some((pipe, _)) = &self.link {
+            pipe.borrow_mut().incoming.extend(bytes);
+        }
+    }
+
+    // what has to go to the other emulator
+    #[cfg(feature = "link")]
+    pub fn take_link_bytes(&mut self) -> Vec<u8> {
+        match &self.link {
+            Some((pipe, _)) => std::mem::take(&mut pipe.borrow_mut().outgoing),
+            None => vec![]
+        }
+    }
+
+    // the connection is gone, transfers from now on finish with 0xFF
+    #[cfg(feature = "link")]
+    pub fn close_link(&mut self) {
+        if let Some((pipe, _)) = &self.link {
+            pipe.borrow_mut().closed = true;
+        }
+    }
+
+    #[cfg(feature = "link")]
+    pub fn link_connected(&self) -> bool {
+        self.link.as_ref().is_some_and(|(_, status)| status.connected())
+    }
+
+    // whatever the game sent over the link cable since the last call, blargg's roms print their results this way
+    pub fn take_serial_output(&mut self) -> String {
+        String::from_utf8_lossy(&self.core.bus.take_serial_output()).into_owned()
+    }
+
+    // only affects render_rgba, render always returns the frame as the PPU drew it
+    pub fn set_frame_blend(&mut self, blend: FrameBlend) {
+        self.frame_blend = blend;
+        self.core.bus.set_frame_blend(blend);
+    }
+
+    pub fn frame_blend(&self) -> FrameBlend {
+        self.core.bus.frame_blend()
+    }
+
+    // keeps a snapshot of every Nth frame in a single recovery slot, 0 disables it
+    pub fn set_autosave_interval(&mut self, frames: u32) {
+        self.autosave_interval = frames;
+        self.frames_until_autosave = frames;
+        PANIC_RECOVERY_SLOT.with(|slot| *slot.borrow_mut() = Rc::downgrade(&self.recovery));
+    }
+
+    // the scanline renderer is faster but can't show mid-scanline register writes, fifo is the default
+    pub fn set_ppu_mode(&mut self, mode: PpuMode) {
+        self.ppu_mode = mode;
+        self.core.bus.set_ppu_mode(mode);
+    }
+
+    // the renderer drawing right now, a loaded state brings back the one it was made with until the next reset
+    pub fn ppu_mode(&self) -> PpuMode {
+        self.core.bus.ppu_mode()
+    }
+
+    // the DMG's OAM corruption bug, off by default since almost nothing needs it and it shows up as glitched sprites
+    pub fn set_oam_bug(&mut self, enabled: bool) {
+        self.oam_bug = enabled;
+        self.core.bus.oam_bug = enabled;
     }
 
     pub fn debug_panel(&mut self) -> Vec<usize> {
         self.core.bus.get_debug_panel().to_vec()
     }
 
+    // 128x192 color ids (0-3), one byte per pixel
+    pub fn debug_render_tiles(&self) -> Vec<u8> {
+        self.core.bus.debug_render_tiles()
+    }
+
+    // 256x256 shades of the 9800 (0) or 9C00 (1) map
+    pub fn debug_render_bg_map(&self, which: u8) -> Vec<u8> {
+        self.core.bus.debug_render_bg_map(which)
+    }
+
+    pub fn debug_map_viewport(&self) -> MapViewport {
+        self.core.bus.debug_map_viewport()
+    }
+
+    // a copy, changing it doesn't reach the timer. div is the whole 16 bit counter
+    pub fn debug_timer(&self) -> TimerState {
+        self.core.bus.debug_timer()
+    }
+
+    // 8 bytes per entry: y, x, tile, palette, x flip, y flip, bg priority, on screen
+    pub fn debug_oam(&self) -> Vec<u8> {
+        self.core.bus.debug_oam().iter().flat_map(|entry| entry.to_bytes()).collect()
+    }
+
+    pub fn debug_oam_scan(&self, ly: u8) -> Vec<u8> {
+        self.core.bus.debug_oam_scan(ly)
+    }
+
+    // memory as a debugger sees it, nothing is locked away and nothing notices, see Memory::peek and Memory::poke
+    pub fn peek(&self, addr: u16) -> u8 {
+        self.core.bus.peek(addr)
+    }
+
+    pub fn peek_range(&self, addr: u16, len: usize) -> Vec<u8> {
+        self.core.bus.peek_range(addr, len)
+    }
+
+    pub fn poke(&mut self, addr: u16, val: u8) {
+        self.core.bus.poke(addr, val);
+    }
+
+    // BESS only has room for the CPU between instructions, so an instruction or interrupt dispatch in progress is run
+    // to its end first. that's a few M-cycles the PPU, APU and timer go through as well, and breakpoints and
+    // watchpoints can be hit in them. a HALT or a locked up CPU is saved as it is, and saving again right away gives
+    // the same file
     pub fn save_file(&mut self) -> Vec<u8> {
         self.core.create_save_file()
     }
 
-    pub fn load_save_file(&mut self, bess_encoding: Vec<u8>) {
-        self.core.load_save_file(bess_encoding);
+    pub fn load_save_file(&mut self, bess_encoding: Vec<u8>) -> Result<(), String> {
+        self.core.load_save_file(&bess_encoding).map_err(|err| err.to_string())
+    }
+
+    // records the buttons of every frame from here on, only render, render_rgba and run_frames count as frames
+    pub fn start_recording(&mut self) {
+        self.recording = Some(Movie {
+            rom_info: self.core.bus.get_rom_info(),
+            boot_rom: self.boot_rom.is_some(),
+            start: self.snapshot(),
+            frames: vec![]
+        });
+    }
+
+    // everything recorded so far, the recording carries on. empty when nothing is being recorded
+    pub fn save_movie(&self) -> Vec<u8> {
+        self.recording.as_ref().map_or(vec![], |movie| movie.to_bytes())
+    }
+
+    pub fn stop_recording(&mut self) {
+        self.recording = None;
+    }
+
+    // the game goes back to where the movie starts and the held buttons are ignored until its last frame ran
+    pub fn play_movie(&mut self, bytes: Vec<u8>) -> Result<(), String> {
+        self.load_movie(&bytes).map_err(|err| err.to_string())
+    }
+
+    pub fn movie_playing(&self) -> bool {
+        self.playback.is_some()
+    }
+
+    pub fn export_state(&self) -> Vec<u8> {
+        self.save_state()
+    }
+
+    #[cfg(feature = "compression")]
+    pub fn export_state_compressed(&self) -> Vec<u8> {
+        self.save_state_compressed()
+    }
+
+    // error message is meant to be shown to the user as is
+    pub fn import_state(&mut self, bytes: Vec<u8>) -> Result<(), String> {
+        self.load_state(&bytes).map_err(|err| err.to_string())
+    }
+}
+
+impl Emulator {
+    // input for the frame comes from the movie being played if there is one, the recording gets whatever it ended up
+    // as after turbo. finishing a frame a breakpoint stopped keeps the input it started with
+    fn next_frame(&mut self, keypress: i8) {
+        if !self.mid_frame {
+            let joypad = &mut self.core.bus.joypad;
+            joypad.set_keypress(keypress);
+            joypad.advance_turbo();
+            if let Some((movie, frame)) = &mut self.playback {
+                let buttons = movie.frames.get(*frame).copied();
+                *frame += 1;
+                joypad.set_playback(buttons);
+                if buttons.is_none() {
+                    self.playback = None;
+                }
+            }
+            if let Some(movie) = &mut self.recording {
+                movie.frames.push(joypad.buttons());
+            }
+        }
+        self.core.next_frame();
+        self.mid_frame = self.core.debugger.stopped().is_some();
+        if !self.mid_frame {
+            self.autosave();
+        }
+    }
+
+    // runs the instruction at PC, or the interrupt dispatch about to start and then stops at the handler
+    pub fn step_instruction(&mut self) -> BreakReason {
+        self.core.step()
+    }
+
+    // runs a CALL or RST until it returns to the instruction after it, a breakpoint in between stops it there
+    pub fn step_over(&mut self) -> BreakReason {
+        self.core.step_over()
+    }
+
+    // CALLs, RSTs and interrupts not returned from yet, innermost last. nothing is kept until it's turned on, so the
+    // calls made before then are missing
+    pub fn track_calls(&mut self, enabled: bool) {
+        self.core.debugger.track_calls(enabled);
+    }
+
+    pub fn call_stack(&self) -> Vec<CallFrame> {
+        self.core.debugger.call_stack().to_vec()
+    }
+
+    pub fn cpu_state(&self) -> CpuState {
+        self.core.state()
+    }
+
+    // see CPU::set_state, an F with its low nibble set is refused
+    pub fn set_cpu_state(&mut self, state: &CpuState) -> Result<(), StateError> {
+        self.core.set_state(state)
+    }
+
+    // where the last instructions were fetched from, oldest first. also in the message of a panic in the CPU
+    pub fn recent_pcs(&self) -> Vec<BankedAddr> {
+        self.core.debugger.history.to_vec()
+    }
+
+    pub fn breakpoints(&self) -> &[BankedAddr] {
+        self.core.debugger.breakpoints()
+    }
+
+    // the top_n addresses the CPU spent the most cycles at while profiling, see ProfileEntry
+    pub fn profile(&self, top_n: usize) -> Vec<ProfileEntry> {
+        self.core.profiler.report(top_n)
+    }
+
+    // stops every way of running once the instruction that touched an address in range is done, see WatchHit
+    pub fn add_watchpoint(&mut self, range: RangeInclusive<u16>, kind: WatchKind) {
+        self.core.bus.watchpoints.add(range, kind);
+    }
+
+    pub fn remove_watchpoint(&mut self, range: RangeInclusive<u16>, kind: WatchKind) -> bool {
+        self.core.bus.watchpoints.remove(range, kind)
+    }
+
+    // whether the OAM DMA's reads and writes hit watchpoints too, off by default
+    pub fn watch_dma(&mut self, enabled: bool) {
+        self.core.bus.watchpoints.dma = enabled;
+    }
+
+    // what WRAM, VRAM and HRAM hold from the next load_catridge or reset on, zeroed by default. it goes into save
+    // states and so movies, a reset after loading one fills them the way the console it came from did
+    pub fn set_power_on_ram(&mut self, ram: PowerOnRam) {
+        self.core.bus.set_power_on_ram(ram);
+    }
+
+    // replaces the one there was, without one every access only pays for checking there isn't
+    pub fn set_observer(&mut self, observer: Box<dyn BusObserver>) {
+        self.core.bus.set_observer(observer);
+    }
+
+    pub fn take_observer(&mut self) -> Option<Box<dyn BusObserver>> {
+        self.core.bus.take_observer()
+    }
+
+    // runs whole frames until a breakpoint is hit, there's no way out if none ever is
+    pub fn continue_until_break(&mut self) -> BreakReason {
+        loop {
+            self.next_frame(-1);
+            if let Some(reason) = self.core.debugger.stopped() {
+                return reason;
+            }
+        }
+    }
+
+    pub fn load_movie(&mut self, bytes: &[u8]) -> Result<(), MovieError> {
+        let movie = Movie::from_bytes(bytes)?;
+        if movie.rom_info != self.core.bus.get_rom_info() {
+            return Err(MovieError::WrongCartridge);
+        }
+        if movie.boot_rom && self.boot_rom.is_none() {
+            return Err(MovieError::NeedsBootRom);
+        }
+        self.restore(&movie.start);
+        self.playback = Some((movie, 0));
+        Ok(())
+    }
+
+    fn autosave(&mut self) {
+        if self.autosave_interval == 0 {
+            return;
+        }
+
+        self.frames_until_autosave -= 1;
+        if self.frames_until_autosave == 0 {
+            self.frames_until_autosave = self.autosave_interval;
+
+            // reuses the buffers of the previous autosave so this stays cheap enough to run mid game
+            let mut slot = self.recovery.borrow_mut();
+            match slot.as_mut() {
+                Some(snapshot) => self.core.snapshot_into(snapshot),
+                None => *slot = Some(self.core.snapshot())
+            }
+        }
+    }
+
+    // appends the interleaved left and right samples produced since the last call
+    pub fn drain_audio(&mut self, out: &mut Vec<i16>) {
+        self.core.bus.drain_audio(out);
+    }
+
+    // every instruction run from now on goes to the writer as a line in gameboy-doctor's format, until
+    // set_trace_buffer or a write fails
+    pub fn set_trace_writer(&mut self, writer: impl std::io::Write + 'static) {
+        self.core.trace = Some(Trace::Writer(Box::new(writer)));
+    }
+
+    // count instructions from addr as the CPU would see them now, nothing is read for real so no state changes
+    pub fn disassemble(&self, addr: u16, count: usize) -> Vec<DisasmLine> {
+        disasm::disassemble(|addr| Some(self.core.bus.peek(addr)), addr, count)
+    }
+
+    // the same for a ROM bank that may not be mapped, the listing stops at the end of the bank
+    pub fn disassemble_bank(&self, bank: u16, addr: u16, count: usize) -> Vec<DisasmLine> {
+        let end = if addr < 0x4000 { 0x4000 } else { 0x8000 };
+        disasm::disassemble(|at| if at >= addr && at < end { self.core.bus.rom_bank_byte(bank, at) } else { None }, addr, count)
+    }
+
+    // same as attach_serial for devices written in rust
+    pub fn attach_serial_device(&mut self, device: Box<dyn SerialDevice>) {
+        self.core.bus.attach_serial(device);
+    }
+
+    // last autosave, at most autosave_interval frames old
+    pub fn recover(&self) -> Option<Snapshot> {
+        self.recovery.borrow().clone()
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        self.core.snapshot()
+    }
+
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.core.restore(snapshot);
+    }
+
+    pub fn save_state(&self) -> Vec<u8> {
+        self.snapshot().to_bytes()
+    }
+
+    #[cfg(feature = "compression")]
+    pub fn save_state_compressed(&self) -> Vec<u8> {
+        self.snapshot().to_bytes_compressed()
     }
-}
\ No newline at end of file
+
+    // compressed states are detected by their magic, older states are migrated, states from a newer build are rejected without touching the running game
+    pub fn load_state(&mut self, bytes: &[u8]) -> Result<(), StateError> {
+        let snapshot = Snapshot::from_bytes(bytes)?;
+        self.restore(&snapshot);
+        Ok(())
+    }
+
+    // the same as new, mount_bootrom, set_model and load_catridge in that order, with what would panic or be
+    // quietly overridden checked first
+    pub fn builder() -> EmulatorBuilder {
+        EmulatorBuilder::default()
+    }
+}
+
+#[derive(PartialEq, Debug)]
+pub enum BuildError {
+    NoCartridge,
+    CartridgeTooShort(usize),
+    UnsupportedMapper(u8),
+    BootRomSize(usize),
+    ModelMismatch { boot_rom: Model, model: Model }
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BuildError::NoCartridge => write!(f, "no cartridge was given"),
+            BuildError::CartridgeTooShort(len) => write!(f, "cartridge is {} bytes, the smallest is 32 KiB", len),
+            BuildError::UnsupportedMapper(mbc_type) => write!(f, "cartridge type 0x{:02X} is not supported", mbc_type),
+            BuildError::BootRomSize(len) => write!(f, "no boot ROM is {} bytes", len),
+            BuildError::ModelMismatch { boot_rom, model } => write!(f, "boot ROM is for the {:?} but the {:?} was asked for", boot_rom, model)
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+#[derive(Default)]
+pub struct EmulatorBuilder {
+    boot_rom: Option<Vec<u8>>,
+    cartridge: Option<Vec<u8>>,
+    model: Option<Model>
+}
+
+impl EmulatorBuilder {
+    pub fn boot_rom(mut self, bytes: Vec<u8>) -> EmulatorBuilder {
+        self.boot_rom = Some(bytes);
+        self
+    }
+
+    pub fn cartridge(mut self, bytes: Vec<u8>) -> EmulatorBuilder {
+        self.cartridge = Some(bytes);
+        self
+    }
+
+    // without it the model is the boot ROM's, or the DMG without one
+    pub fn model(mut self, model: Model) -> EmulatorBuilder {
+        self.model = Some(model);
+        self
+    }
+
+    pub fn build(self) -> Result<Emulator, BuildError> {
+        let cartridge = self.cartridge.ok_or(BuildError::NoCartridge)?;
+        if cartridge.len() < 0x8000 {
+            return Err(BuildError::CartridgeTooShort(cartridge.len()));
+        }
+        if !mapper::supported(cartridge[MBC_TYPE]) {
+            return Err(BuildError::UnsupportedMapper(cartridge[MBC_TYPE]));
+        }
+        let boot_rom_model = match &self.boot_rom {
+            Some(boot_rom) => Some(boot_rom_model(boot_rom.len()).ok_or(BuildError::BootRomSize(boot_rom.len()))?),
+            None => None
+        };
+        if let (Some(boot_rom), Some(model)) = (boot_rom_model, self.model) {
+            if boot_rom != model {
+                return Err(BuildError::ModelMismatch { boot_rom, model });
+            }
+        }
+
+        let mut emulator = Emulator::new();
+        if let Some(boot_rom) = self.boot_rom {
+            emulator.mount_bootrom(boot_rom).expect("boot ROM size was checked");
+        }
+        if let Some(model) = self.model {
+            emulator.set_model(model);
+        }
+        emulator.load_catridge(cartridge).expect("cartridge was checked");
+        Ok(emulator)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::time::{Duration, Instant};
+    use std::collections::VecDeque;
+    use super::*;
+
+    fn running_emulator() -> Emulator {
+        let mut emulator = Emulator::new();
+        emulator.load_catridge(fs::read("./tests/blargg/roms/2.gb").expect("File not found!")).unwrap();
+        emulator
+    }
+
+    #[test]
+    fn rgba_frames_match_shade_frames() {
+        let mut shades = running_emulator();
+        let mut rgba = running_emulator();
+        rgba.set_palette(&[0xFF, 0xFF, 0xFF, 0xAA, 0xAA, 0xAA, 0x55, 0x55, 0x55, 0x00, 0x00, 0x00]).unwrap();
+        assert!(rgba.set_palette(&[0xFF; 9]).is_err());
+
+        for _ in 0..20 {
+            let frame = shades.render(-1);
+            let ptr = rgba.render_rgba(-1);
+            let pixels = unsafe { std::slice::from_raw_parts(ptr, rgba.rgba_len()) };
+            for (shade, pixel) in frame.iter().zip(pixels.chunks_exact(4)) {
+                assert_eq!(pixel, [0xFF - shade * 0x55, 0xFF - shade * 0x55, 0xFF - shade * 0x55, 0xFF]);
+            }
+        }
+    }
+
+    #[test]
+    fn scx_written_every_hblank_scrolls_each_line() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x48..0x4D].copy_from_slice(&[0xF0, 0x44, 0xE0, 0x43, 0xD9]); // SCX = LY, RETI
+        rom[0x100..0x113].copy_from_slice(&[
+            0x3E, 0xE4, 0xE0, 0x47,
+            0x3E, 0x08, 0xE0, 0x41, // hblank STAT interrupt
+            0x3E, 0x02, 0xE0, 0xFF,
+            0x3E, 0x91, 0xE0, 0x40, // background on with tiles at 8000
+            0xFB, 0x18, 0xFE
+        ]);
+
+        for mode in [PpuMode::Scanline, PpuMode::Fifo] {
+            let mut emulator = Emulator::new();
+            emulator.load_catridge(rom.clone()).unwrap();
+            emulator.set_ppu_mode(mode);
+            let bus = &mut emulator.core.bus;
+            bus.access_lockout = false;
+            for row in 0..8 {
+                bus.write(0x8010 + row * 2, 0x80); // tile 1 is a black left column
+                bus.write(0x8011 + row * 2, 0x80);
+            }
+            for tile in 0..0x400 {
+                bus.write(0x9800 + tile, 1);
+            }
+
+            emulator.render(-1);
+            let frame = emulator.render(-1);
+            // each line is scrolled by the LY of the hblank before it, line 0 by the last one of the previous frame
+            for y in 0..144 {
+                let scx = if y == 0 { 143 } else { y - 1 };
+                for x in 0..160 {
+                    let shade = if (x + scx) % 8 == 0 { 3 } else { 0 };
+                    assert_eq!(frame[y * 160 + x], shade, "{:?} x = {} y = {}", mode, x, y);
+                }
+            }
+        }
+    }
+
+    // final screens of test roms once they're done, to catch anything that changes what they show
+    const KNOWN_SCREENS: [(&str, u32, u64); 1] = [
+        ("./tests/blargg/roms/2.gb", 150, 0x66812A5916480810)
+    ];
+
+    #[test]
+    fn run_frames_hash_known_screens() {
+        for (rom, frames, hash) in KNOWN_SCREENS {
+            let mut emulator = Emulator::new();
+            emulator.load_catridge(fs::read(rom).expect("File not found!")).unwrap();
+            assert_eq!(emulator.run_frames(frames), hash, "{}", rom);
+        }
+
+        let mut rendered = running_emulator();
+        let last = (0..40).map(|_| rendered.render(-1)).last().unwrap();
+        assert_eq!(running_emulator().run_frames(40), hash_display(&last));
+    }
+
+    #[test]
+    fn borrowing_the_display_skips_the_copy() {
+        let mut emulator = running_emulator();
+        emulator.render(-1);
+        // the borrowed frame is the PPU's own buffer, right there inside the bus, where a copy never is
+        let bus = &emulator.core.bus;
+        let inside_bus = |ptr: *const u8| {
+            let start = bus as *const _ as usize;
+            (start..start + std::mem::size_of_val(bus)).contains(&(ptr as usize))
+        };
+        assert!(inside_bus(bus.get_display_ref().as_ptr()));
+        assert!(!inside_bus(bus.get_display().as_ptr()));
+
+        let mut short = vec![0; 160 * 144 - 1];
+        assert!(!emulator.copy_display_into(&mut short));
+        assert!(short.iter().all(|&shade| shade == 0));
+        let mut out = vec![0; 160 * 144];
+        assert!(emulator.copy_display_into(&mut out));
+        assert_eq!(out, emulator.display());
+        assert_eq!(unsafe { std::slice::from_raw_parts(emulator.display_ptr(), 160 * 144) }, &out[..]);
+    }
+
+    #[test]
+    fn frame_after_reenabling_lcd_is_blank() {
+        let mut emulator = running_emulator();
+        emulator.run_frames(150); // the rom is done and its screen stays put
+        let screen = emulator.display();
+
+        let lcdc = emulator.core.bus.read(0xFF40);
+        emulator.core.run_cycles(-1, 1000);
+        emulator.core.bus.write(0xFF40, lcdc & 0x7F);
+        emulator.core.run_cycles(-1, 1000);
+        emulator.core.bus.write(0xFF40, lcdc);
+
+        assert!(emulator.render(-1).iter().all(|&shade| shade == 0));
+        assert_eq!(emulator.render(-1), screen);
+    }
+
+    #[test]
+    fn polled_frames_match_rendered_frames() {
+        let mut rendered = running_emulator();
+        let mut polled = running_emulator();
+        assert!(!polled.take_frame_ready());
+
+        for _ in 0..20 {
+            let frame = rendered.render(-1);
+            assert!(rendered.take_frame_ready());
+            assert!(!rendered.take_frame_ready());
+
+            // steps of 100 M-cycles overshoot the frame by less than a scanline, so only line 0 can be redrawn by then
+            while !polled.take_frame_ready() {
+                polled.run_cycles(-1, 100);
+            }
+            assert_eq!(polled.display()[160..], frame[160..]);
+        }
+    }
+
+    #[test]
+    fn recovers_autosave_after_crash() {
+        let mut emulator = running_emulator();
+        emulator.set_autosave_interval(64);
+        assert!(emulator.recover().is_none());
+
+        let mut recent_states = VecDeque::new();
+        let crash = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            for _ in 0..1000 {
+                emulator.render(-1);
+                recent_states.push_front(emulator.save_state());
+                recent_states.truncate(64);
+            }
+            panic!("simulated core crash");
+        }));
+        assert!(crash.is_err());
+
+        let recovered = emulator.recover().expect("no autosave was taken").to_bytes();
+        assert!(recent_states.contains(&recovered), "recovery snapshot is more than 64 frames old");
+    }
+
+    #[test]
+    fn swapping_cartridges_drops_the_autosave() {
+        let mut emulator = running_emulator();
+        emulator.set_autosave_interval(1);
+        emulator.render(-1);
+        assert!(emulator.recover().is_some());
+
+        emulator.load_catridge(vec![0; 0x8000]).unwrap();
+        assert!(emulator.recover().is_none());
+        emulator.render(-1);
+        assert_eq!(emulator.recover().unwrap().to_bytes(), emulator.save_state());
+    }
+
+    #[test]
+    fn autosave_reuses_its_slot() {
+        // the snapshot is written over where it is, cartridge RAM included, instead of a new one being made
+        let mut emulator = running_emulator();
+        emulator.set_autosave_interval(1);
+        emulator.render(-1);
+        let sram = |emulator: &Emulator| {
+            let slot = emulator.recovery.borrow();
+            let sram = &slot.as_ref().unwrap().memory.sram;
+            (sram.as_ptr(), sram.capacity())
+        };
+        let first = sram(&emulator);
+
+        for _ in 0..10 {
+            emulator.render(-1);
+        }
+        assert_eq!(sram(&emulator), first);
+    }
+
+    // Mealybug Tearoom screens are compared once they've had this long, every test is done well before it
+    const MEALYBUG_FRAMES: u32 = 60;
+
+    // expected | actual | mismatches, as a binary PGM so it opens anywhere without an image crate
+    fn write_diff_image(path: &std::path::Path, expected: &[u8], actual: &[u8]) {
+        let gray = |shade: u8| 0xFF - (shade & 0x3) * 0x55;
+        let mut image = b"P5 480 144 255\n".to_vec();
+        for y in 0..144 {
+            let row = y * 160..(y + 1) * 160;
+            image.extend(expected[row.clone()].iter().map(|&shade| gray(shade)));
+            image.extend(actual[row.clone()].iter().map(|&shade| gray(shade)));
+            image.extend(expected[row.clone()].iter().zip(&actual[row]).map(|(a, b)| if a == b { 0xFF } else { 0x00 }));
+        }
+        fs::write(path, image).unwrap();
+    }
+
+    // see tests/mealybug/README.md, run with cargo test -- --ignored
+    #[test]
+    #[ignore]
+    fn mealybug_tearoom_screens() {
+        let Ok(roms) = fs::read_dir("./tests/mealybug/roms") else {
+            eprintln!("no Mealybug Tearoom roms in tests/mealybug/roms, skipping");
+            return
+        };
+        let artifacts = std::path::Path::new("./target/mealybug");
+        fs::create_dir_all(artifacts).unwrap();
+
+        let mut names: Vec<_> = roms.map(|rom| rom.unwrap().path()).filter(|path| path.extension().is_some_and(|ext| ext == "gb")).collect();
+        names.sort();
+        let mut failed = vec![];
+        for rom in &names {
+            let name = rom.file_stem().unwrap().to_str().unwrap();
+            let expected = fs::read(format!("./tests/mealybug/expected/{}.shades", name)).expect("missing expected screen");
+            assert_eq!(expected.len(), 160 * 144, "{}.shades isn't a whole screen", name);
+
+            let mut emulator = Emulator::new();
+            emulator.load_catridge(fs::read(rom).unwrap()).unwrap();
+            emulator.run_frames(MEALYBUG_FRAMES);
+            let actual = emulator.display();
+
+            if actual == expected {
+                eprintln!("pass {}", name);
+            } else {
+                let diff = artifacts.join(format!("{}.pgm", name));
+                write_diff_image(&diff, &expected, &actual);
+                eprintln!("FAIL {} ({} pixels differ, see {})", name, expected.iter().zip(&actual).filter(|(a, b)| a != b).count(), diff.display());
+                failed.push(name.to_string());
+            }
+        }
+        assert!(failed.is_empty(), "{} of {} Mealybug Tearoom tests failed: {:?}", failed.len(), names.len(), failed);
+    }
+
+    // cargo test --release audio_frame_cost -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn audio_frame_cost() {
+        let frame_time = |hz: u32| {
+            let mut emulator = running_emulator();
+            emulator.set_sample_rate(hz);
+            emulator.run_frames(150);
+            let start = Instant::now();
+            for _ in 0..600 {
+                emulator.run_frames(1);
+                emulator.take_audio();
+            }
+            start.elapsed() / 600
+        };
+        // best of 10 taken in turns, a single run is easily thrown off by whatever else the machine is doing
+        let (mut off, mut on) = (Duration::MAX, Duration::MAX);
+        for _ in 0..10 {
+            off = off.min(frame_time(0));
+            on = on.min(frame_time(48000));
+        }
+        eprintln!("audio off: {:?} a frame, 48 kHz: {:?} a frame, {:+.1}%", off, on, (on.as_secs_f64() / off.as_secs_f64() - 1.0) * 100.0);
+    }
+
+    // what running every bus access on its own M-cycle costs, and the debugger on top of it
+    // cargo test --release cpu_frame_cost -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn cpu_frame_cost() {
+        let frame_time = |debugged: bool| {
+            let mut emulator = running_emulator();
+            if debugged {
+                // never hit, so every access is still checked against them
+                emulator.add_watchpoint(0xFEA0..=0xFEFF, WatchKind::ReadWrite);
+                emulator.add_breakpoint(0xFFFF, None);
+                emulator.track_calls(true);
+            }
+            emulator.run_frames(150);
+            let start = Instant::now();
+            emulator.run_frames(600);
+            start.elapsed() / 600
+        };
+        let (mut plain, mut debugged) = (Duration::MAX, Duration::MAX);
+        for _ in 0..10 {
+            plain = plain.min(frame_time(false));
+            debugged = debugged.min(frame_time(true));
+        }
+        let per_second = |frame: Duration| 17556.0 / frame.as_secs_f64() / 1e6;
+        eprintln!("{:?} a frame ({:.1}M M-cycles/s), debugged: {:?} a frame ({:.1}M M-cycles/s), {:+.1}%",
+            plain, per_second(plain), debugged, per_second(debugged), (debugged.as_secs_f64() / plain.as_secs_f64() - 1.0) * 100.0);
+    }
+
+    // what an observer that does next to nothing costs, without one it's a branch per access
+    // cargo test --release observer_cost -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn observer_cost() {
+        struct Nothing;
+        impl BusObserver for Nothing {}
+        let frame_time = |observed: bool| {
+            let mut emulator = running_emulator();
+            if observed {
+                emulator.set_observer(Box::new(Nothing));
+            }
+            emulator.run_frames(150);
+            let start = Instant::now();
+            emulator.run_frames(600);
+            start.elapsed() / 600
+        };
+        let (mut plain, mut observed) = (Duration::MAX, Duration::MAX);
+        for _ in 0..10 {
+            plain = plain.min(frame_time(false));
+            observed = observed.min(frame_time(true));
+        }
+        eprintln!("no observer: {:?} a frame, observed: {:?} a frame, {:+.1}%", plain, observed,
+            (observed.as_secs_f64() / plain.as_secs_f64() - 1.0) * 100.0);
+    }
+
+    #[test]
+    fn audio_follows_the_sample_rate() {
+        let mut emulator = Emulator::new();
+        emulator.set_sample_rate(48000);
+        emulator.load_catridge(fs::read("./tests/blargg/roms/2.gb").expect("File not found!")).unwrap();
+
+        // a quarter of a second
+        emulator.run_cycles(-1, 1 << 18);
+        assert_eq!(emulator.take_audio(), 2 * 12000);
+
+        let mut samples = vec![];
+        emulator.drain_audio(&mut samples);
+        assert!(samples.is_empty());
+        assert_eq!(emulator.audio_overruns(), 0);
+    }
+
+    #[test]
+    fn one_second_of_samples_paces_59_73_frames() {
+        let mut emulator = running_emulator();
+        emulator.run_frames(150); // past the frames the rom spends with the LCD off
+        emulator.set_sample_rate(48000);
+
+        let (mut samples, mut frames) = (vec![], 0);
+        for _ in 0..3 {
+            let mut second = 0;
+            while second < 48000 {
+                if emulator.run_until_samples(-1, 48000 - second) {
+                    frames += 1;
+                }
+                let before = samples.len();
+                emulator.drain_audio(&mut samples);
+                second += (samples.len() - before) / 2;
+            }
+        }
+        // 3 * 2^20 M-cycles over frames of 17556, 179.18 of them
+        assert_eq!(samples.len(), 3 * 2 * 48000);
+        assert!((179..=180).contains(&frames), "{} frames", frames);
+        assert_eq!(emulator.audio_overruns(), 0);
+    }
+
+    // enables the timer at 262144 Hz with TIMA and TMA at 0xF0 and interrupts off, then spins
+    fn timer_rom() -> Vec<u8> {
+        let mut rom = vec![0x00; 0x8000];
+        let program = [0x3E, 0xF0, 0xE0, 0x05, 0xE0, 0x06, 0x3E, 0x05, 0xE0, 0x07, 0x18, 0xFE];
+        rom[0x100..0x100 + program.len()].copy_from_slice(&program);
+        rom
+    }
+
+    // M-cycles until the timer interrupt is requested
+    fn cycles_to_timer_interrupt(emulator: &mut Emulator) -> u32 {
+        assert_eq!(emulator.core.bus.IF & 0x4, 0);
+        let mut cycles = 0;
+        while emulator.core.bus.IF & 0x4 == 0 {
+            emulator.run_cycles(-1, 1);
+            cycles += 1;
+        }
+        cycles
+    }
+
+    #[test]
+    fn state_saved_right_before_a_timer_overflow_interrupts_on_time() {
+        let mut emulator = Emulator::new();
+        emulator.load_catridge(timer_rom()).unwrap();
+        emulator.core.bus.IF &= !0x4;
+        let mut before = emulator.save_state();
+        while emulator.debug_timer().overflow != TimerOverflow::Pending {
+            before = emulator.save_state();
+            emulator.run_cycles(-1, 1);
+        }
+        assert_eq!(emulator.debug_timer().tima, 0x00);
+        let expected = 1 + cycles_to_timer_interrupt(&mut emulator);
+        assert_eq!(emulator.debug_timer().tima, 0xF0);
+
+        let mut resumed = Emulator::new();
+        resumed.load_catridge(timer_rom()).unwrap();
+        resumed.load_state(&before).unwrap();
+        assert_eq!(resumed.debug_timer().tima, 0xFF);
+        assert_eq!(cycles_to_timer_interrupt(&mut resumed), expected);
+        assert_eq!(expected, 2);
+    }
+
+    #[test]
+    fn debug_timer_shows_the_whole_divider() {
+        let mut emulator = Emulator::new();
+        emulator.load_catridge(timer_rom()).unwrap();
+        emulator.run_cycles(-1, 20);
+        let div = emulator.debug_timer().div;
+        emulator.run_cycles(-1, 3);
+        let timer = emulator.debug_timer();
+        assert_eq!(timer.div, div.wrapping_add(12));
+        assert_eq!((timer.div >> 8) as u8, emulator.core.bus.read(0xFF04));
+        assert_eq!(timer.tac, 0x05);
+    }
+
+    #[test]
+    fn restored_state_replays_the_same_audio() {
+        let mut emulator = running_emulator();
+        emulator.set_sample_rate(48000);
+        // the rom never touches the APU, so start a sweep, a wave, and a noise note of our own
+        let notes = [
+            (0xFF26, 0x80), (0xFF24, 0x77), (0xFF25, 0xF5),
+            (0xFF10, 0x15), (0xFF11, 0x80), (0xFF12, 0xF3), (0xFF13, 0x00), (0xFF14, 0x85),
+            (0xFF30, 0x01), (0xFF31, 0x23), (0xFF32, 0x45), (0xFF33, 0x67), (0xFF34, 0x89), (0xFF35, 0xAB),
+            (0xFF1A, 0x80), (0xFF1C, 0x20), (0xFF1D, 0x40), (0xFF1E, 0x86),
+            (0xFF21, 0xA2), (0xFF22, 0x35), (0xFF23, 0x80)
+        ];
+        for (addr, val) in notes {
+            emulator.core.bus.write(addr, val);
+        }
+        emulator.run_cycles(-1, 5000);
+        emulator.take_audio();
+
+        let state = emulator.save_state();
+        emulator.run_cycles(-1, 10 * 17556);
+        let mut expected = vec![];
+        emulator.drain_audio(&mut expected);
+
+        emulator.load_state(&state).unwrap();
+        emulator.run_cycles(-1, 10 * 17556);
+        let mut replayed = vec![];
+        emulator.drain_audio(&mut replayed);
+
+        assert!(expected.iter().any(|&sample| sample != 0));
+        assert_eq!(replayed[..2 * 4800], expected[..2 * 4800]);
+    }
+
+    #[test]
+    fn serial_output_carries_the_blargg_result() {
+        let mut emulator = running_emulator();
+        let mut output = String::new();
+        for _ in 0..300 {
+            emulator.render(-1);
+            output += &emulator.take_serial_output();
+            if output.contains("Passed") || output.contains("Failed") {
+                break;
+            }
+        }
+        assert_eq!(output.trim_end(), "02-interrupts\n\n\nPassed");
+        assert_eq!(emulator.take_serial_output(), "");
+    }
+
+    struct Recorder(Rc<RefCell<Vec<bool>>>);
+
+    impl SerialDevice for Recorder {
+        fn exchange_bit(&mut self, out_bit: bool) -> bool {
+            self.0.borrow_mut().push(out_bit);
+            true
+        }
+    }
+
+    #[test]
+    fn attached_device_sees_every_bit_sent() {
+        let bits = Rc::new(RefCell::new(vec![]));
+        let mut emulator = Emulator::new();
+        emulator.attach_serial_device(Box::new(Recorder(bits.clone())));
+        emulator.load_catridge(fs::read("./tests/blargg/roms/2.gb").expect("File not found!")).unwrap();
+        for _ in 0..60 {
+            emulator.render(-1);
+        }
+
+        let bytes: Vec<u8> = bits.borrow().chunks_exact(8).map(|byte| byte.iter().fold(0, |acc, &bit| (acc << 1) | bit as u8)).collect();
+        assert!(!bytes.is_empty());
+        assert!(emulator.take_serial_output().as_bytes().starts_with(&bytes));
+    }
+
+    #[test]
+    fn joypad_interrupt_wakes_the_cpu_from_halt() {
+        use crate::internal::core::registers::Register;
+        let mut rom = vec![0x00; 0x8000];
+        let program = [
+            0x3E, 0x10, 0xE0, 0x00, // select the action buttons
+            0x3E, 0x10, 0xE0, 0xFF, // only the joypad interrupt enabled, IME stays off
+            0xAF, 0xE0, 0x0F,
+            0x76, // HALT
+            0x06, 0x42, 0x18, 0xFE // LD B, 0x42 and spin
+        ];
+        rom[0x100..0x100 + program.len()].copy_from_slice(&program);
+        let mut emulator = Emulator::new();
+        emulator.load_catridge(rom).unwrap();
+
+        emulator.run_frames(5);
+        assert_eq!(emulator.core.registers[Register::B], 0x00);
+        // nothing on the selected lines
+        emulator.press_button(Button::Up);
+        emulator.run_frames(1);
+        assert_eq!(emulator.core.registers[Register::B], 0x00);
+
+        emulator.press_button(Button::A);
+        emulator.run_frames(1);
+        assert_eq!(emulator.core.registers[Register::B], 0x42);
+    }
+
+    #[test]
+    fn halt_with_an_interrupt_pending_reads_the_next_byte_twice() {
+        use crate::internal::core::registers::Register;
+        let mut rom = vec![0x00; 0x8000];
+        let program = [
+            0x3E, 0x04, 0xE0, 0xFF, 0xE0, 0x0F, // the timer interrupt enabled and pending, IME stays off
+            0x76, 0x04, // HALT, INC B
+            0x18, 0xFE
+        ];
+        rom[0x100..0x100 + program.len()].copy_from_slice(&program);
+        let mut emulator = Emulator::new();
+        emulator.load_catridge(rom).unwrap();
+
+        emulator.run_frames(1);
+        assert_eq!(emulator.core.registers[Register::B], 0x02);
+    }
+
+    #[test]
+    fn ei_enables_interrupts_after_the_next_instruction() {
+        use crate::internal::core::registers::Register;
+        let mut rom = vec![0x00; 0x8000];
+        rom[0x50..0x52].copy_from_slice(&[0x48, 0x76]); // the timer handler: LD C, B and HALT
+        let program = [
+            0x3E, 0x04, 0xE0, 0xFF, 0xE0, 0x0F, // the timer interrupt enabled and pending
+            0xFB, 0xF3, // EI, DI: no window at all
+            0xFB, 0x04, 0x04, // EI, then INC B twice: taken after the first
+            0x18, 0xFE
+        ];
+        rom[0x100..0x100 + program.len()].copy_from_slice(&program);
+        let mut emulator = Emulator::new();
+        emulator.load_catridge(rom).unwrap();
+
+        emulator.run_frames(1);
+        assert_eq!((emulator.core.registers[Register::B], emulator.core.registers[Register::C]), (0x01, 0x01));
+    }
+
+    #[test]
+    fn ei_then_halt_returns_to_the_halt() {
+        use crate::internal::core::registers::Register;
+        let mut rom = vec![0x00; 0x8000];
+        rom[0x50..0x52].copy_from_slice(&[0x14, 0xD9]); // the timer handler: INC D and RETI
+        let program = [
+            0x3E, 0x04, 0xE0, 0xFF, 0xE0, 0x0F, // the timer interrupt enabled and pending, the timer itself off
+            0xFB, 0x76, // EI, HALT
+            0x1C, 0x18, 0xFE // INC E and spin
+        ];
+        rom[0x100..0x100 + program.len()].copy_from_slice(&program);
+        let mut emulator = Emulator::new();
+        emulator.load_catridge(rom).unwrap();
+        let e = emulator.core.registers[Register::E];
+
+        // back from the handler it halts again, there's nothing to wake it this time
+        emulator.run_frames(1);
+        assert_eq!((emulator.core.registers[Register::D], emulator.core.registers[Register::E]), (0x01, e));
+        assert_eq!(emulator.core.pc, 0x108);
+    }
+
+    #[test]
+    fn ie_push_cancels_or_redirects_the_dispatch() {
+        use crate::internal::core::registers::Register;
+        // with SP at 0 the high byte of PC goes onto IE, the code runs from `at` so that byte is (at >> 8)
+        let dispatch = |at: usize, requested: u8| {
+            let mut rom = vec![0x00; 0x8000];
+            rom[0x00..0x04].copy_from_slice(&[0x16, 0x01, 0x18, 0xFE]); // LD D, 1 and spin for a cancelled dispatch
+            rom[0x50..0x54].copy_from_slice(&[0x16, 0x02, 0x18, 0xFE]); // and the same with 2 for timer
+            rom[0x58..0x5C].copy_from_slice(&[0x16, 0x03, 0x18, 0xFE]); // and 3 for serial
+            rom[0x100..0x103].copy_from_slice(&[0xC3, at as u8, (at >> 8) as u8]);
+            let program = [
+                0x31, 0x00, 0x00, // LD SP, 0
+                0x3E, 0x04, 0xE0, 0xFF, // only the timer interrupt enabled
+                0x3E, requested, 0xE0, 0x0F,
+                0xFB, 0x00, 0x18, 0xFE // EI, taken after the NOP
+            ];
+            rom[at..at + program.len()].copy_from_slice(&program);
+            let mut emulator = Emulator::new();
+            emulator.load_catridge(rom).unwrap();
+            emulator.run_frames(1);
+            (emulator.core.registers[Register::D], emulator.core.bus.IF & 0x0C) // vblank comes in meanwhile
+        };
+
+        // nothing enabled but vblank once IE is written, so PC lands on 0 and the timer stays requested
+        assert_eq!(dispatch(0x150, 0x04), (0x01, 0x04));
+        // 0x08 leaves IE with serial instead, which is taken in place of the timer
+        assert_eq!(dispatch(0x0800, 0x0C), (0x03, 0x04));
+        // and the timer again when IE still has it after the push
+        assert_eq!(dispatch(0x0400, 0x04), (0x02, 0x00));
+    }
+
+    struct SharedWriter(Rc<RefCell<Vec<u8>>>);
+
+    impl std::io::Write for SharedWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn trace_logs_every_instruction_like_gameboy_doctor() {
+        let mut rom = vec![0x00; 0x8000];
+        rom[0x100..0x105].copy_from_slice(&[0x00, 0x06, 0x42, 0x18, 0xFE]); // NOP, LD B, 0x42 and spin
+        rom[0x14D] = 0x01; // any header checksum but 0 for the F the logs start with
+        let written = Rc::new(RefCell::new(vec![]));
+        let mut emulator = Emulator::new();
+        emulator.set_trace_writer(SharedWriter(written.clone()));
+        emulator.load_catridge(rom).unwrap();
+
+        emulator.run_frames(1);
+        let written = String::from_utf8(written.borrow().clone()).unwrap();
+        let lines: Vec<_> = written.lines().take(4).collect();
+        assert_eq!(lines, [
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,06,42,18",
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0101 PCMEM:06,42,18,FE",
+            "A:01 F:B0 B:42 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0103 PCMEM:18,FE,00,00",
+            "A:01 F:B0 B:42 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0103 PCMEM:18,FE,00,00"
+        ]);
+        assert_eq!(emulator.take_trace(), "");
+
+        emulator.set_trace_buffer(2);
+        emulator.run_frames(1);
+        assert_eq!(emulator.take_trace().lines().count(), 2);
+        emulator.set_trace_buffer(0);
+        emulator.run_frames(1);
+        assert_eq!(emulator.take_trace(), "");
+    }
+
+    #[test]
+    fn disassembles_the_mapped_rom_and_any_bank() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+        rom[0x7FFD..0x8000].copy_from_slice(&[0x00, 0xC3, 0x00]);
+        let mut emulator = Emulator::new();
+        emulator.load_catridge(rom).unwrap();
+
+        let texts = |lines: Vec<DisasmLine>| lines.into_iter().map(|line| line.text).collect::<Vec<_>>();
+        assert_eq!(texts(emulator.disassemble(0x100, 2)), ["NOP", "JP $0150"]);
+        // the jump's operands would be past the end of the bank
+        assert_eq!(texts(emulator.disassemble_bank(1, 0x7FFD, 4)), ["NOP", "DB $C3"]);
+        assert_eq!(texts(emulator.disassemble_bank(4, 0x4000, 4)), [] as [String; 0]);
+    }
+
+    // JP 0150, then a CALL to 0200 that increments C in a loop incrementing B
+    fn calling_rom() -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x103].copy_from_slice(&[0xC3, 0x50, 0x01]);
+        rom[0x150..0x156].copy_from_slice(&[0xCD, 0x00, 0x02, 0x04, 0x18, 0xFA]);
+        rom[0x200..0x202].copy_from_slice(&[0x0C, 0xC9]);
+        rom
+    }
+
+    #[test]
+    fn breakpoints_stop_mid_frame_and_resume_in_phase() {
+        use crate::internal::core::registers::Register;
+        let mut debugged = Emulator::new();
+        debugged.load_catridge(calling_rom()).unwrap();
+        debugged.add_breakpoint(0x150, None);
+        debugged.run_frames(3);
+        assert_eq!(debugged.break_reason().as_deref(), Some("breakpoint at 0150"));
+        assert_eq!(debugged.core.pc, 0x150);
+        assert_eq!(debugged.core.registers[Register::B], 0x00);
+
+        // a few more laps of the loop, then the rest of the frame it stopped in and one more
+        for _ in 0..3 {
+            debugged.run_frames(1);
+        }
+        assert_eq!(debugged.core.registers[Register::B], 0x03);
+        debugged.remove_breakpoint(0x150, None);
+        debugged.run_frames(2);
+        assert_eq!(debugged.break_reason(), None);
+
+        let mut plain = Emulator::new();
+        plain.load_catridge(calling_rom()).unwrap();
+        plain.run_frames(2);
+        assert_eq!(debugged.snapshot().to_bytes(), plain.snapshot().to_bytes());
+    }
+
+    #[test]
+    fn swapping_cartridges_starts_a_new_frame() {
+        let mut emulator = Emulator::new();
+        emulator.load_catridge(calling_rom()).unwrap();
+        emulator.set_autosave_interval(1);
+        emulator.add_breakpoint(0x150, None);
+        emulator.run_frames(1);
+        assert!(emulator.mid_frame);
+
+        // the first frame of the new game goes into the recording and is autosaved
+        emulator.load_catridge(vec![0; 0x8000]).unwrap();
+        emulator.start_recording();
+        emulator.press_button(Button::Start);
+        emulator.render(-1);
+        assert_eq!(Movie::from_bytes(&emulator.save_movie()).unwrap().frames, [1 << Button::Start as u8]);
+        assert_eq!(emulator.recover().unwrap().to_bytes(), emulator.save_state());
+    }
+
+    #[test]
+    fn steps_over_calls_and_into_breakpoints() {
+        use crate::internal::core::registers::Register;
+        let mut emulator = Emulator::new();
+        emulator.load_catridge(calling_rom()).unwrap();
+        emulator.add_breakpoint(0x150, None);
+        emulator.continue_until_break();
+
+        assert_eq!(emulator.step_over(), BreakReason::Step);
+        assert_eq!((emulator.core.pc, emulator.core.registers[Register::C]), (0x153, 0x14));
+        emulator.step_instruction();
+        assert_eq!((emulator.core.pc, emulator.core.registers[Register::B]), (0x154, 0x01));
+        assert_eq!(emulator.step(), "step");
+        assert_eq!(emulator.core.pc, 0x150);
+
+        emulator.add_breakpoint(0x200, None);
+        assert_eq!(emulator.step_over(), BreakReason::Breakpoint(BankedAddr { addr: 0x200, bank: None }));
+        assert_eq!(emulator.core.pc, 0x200);
+        assert_eq!(emulator.continue_until_break(), BreakReason::Breakpoint(BankedAddr { addr: 0x150, bank: None }));
+        assert_eq!(emulator.core.registers[Register::B], 0x02);
+
+        // only while bank 1 is mapped, which it always is without an MBC
+        emulator.remove_breakpoint(0x150, None);
+        emulator.remove_breakpoint(0x200, None);
+        emulator.add_breakpoint(0x200, Some(2));
+        assert_eq!(emulator.continue_for(2), None);
+        emulator.add_breakpoint(0x4000, Some(1));
+        assert_eq!(emulator.continue_for(2), None);
+    }
+
+    #[test]
+    fn watchpoints_report_the_access_after_the_instruction() {
+        let mut rom = vec![0; 0x8000];
+        // LD A, 42; LD (C000), A; LD A, (C001); INC A, then an OAM DMA from C000
+        rom[0x100..0x10E].copy_from_slice(&[0x3E, 0x42, 0xEA, 0x00, 0xC0, 0xFA, 0x01, 0xC0, 0x3C, 0x3E, 0xC0, 0xE0, 0x46, 0x00]);
+        rom[0x10E..0x110].copy_from_slice(&[0x18, 0xFE]);
+        let mut emulator = Emulator::new();
+        emulator.load_catridge(rom).unwrap();
+        emulator.add_watchpoint(0xC000..=0xC001, WatchKind::Write);
+        emulator.add_watchpoint(0xC001..=0xC001, WatchKind::Read);
+
+        let hit = |reason| match reason {
+            BreakReason::Watchpoint(hit) => hit,
+            reason => panic!("stopped for {}", reason)
+        };
+        let write = hit(emulator.continue_until_break());
+        assert_eq!(write, WatchHit { pc: 0x102, addr: 0xC000, value: 0x42, access: Access::Write, dma: false });
+        assert_eq!(emulator.core.pc, 0x105);
+        let read = hit(emulator.continue_until_break());
+        assert_eq!((read.pc, read.addr, read.access), (0x105, 0xC001, Access::Read));
+        assert_eq!(emulator.break_reason().as_deref(), Some("watchpoint, read of 00 at C001 by 0105"));
+
+        // the DMA's reads of the source only count with watch_dma
+        emulator.remove_watchpoint(0xC001..=0xC001, WatchKind::Read);
+        emulator.add_watchpoint(0xC000..=0xC000, WatchKind::Read);
+        emulator.watch_dma(true);
+        let dma = hit(emulator.continue_until_break());
+        assert_eq!((dma.addr, dma.value, dma.access, dma.dma), (0xC000, 0x42, Access::Read, true));
+    }
+
+    #[test]
+    fn recent_pcs_wrap_around() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x106].copy_from_slice(&[0x00, 0x00, 0x00, 0xC3, 0x00, 0x01]);
+        let mut emulator = Emulator::new();
+        emulator.load_catridge(rom).unwrap();
+        emulator.set_pc_history_len(4);
+        for _ in 0..6 {
+            emulator.step_instruction();
+        }
+        let pcs: Vec<u16> = emulator.recent_pcs().iter().map(|pc| pc.addr).collect();
+        assert_eq!(pcs, [0x102, 0x103, 0x100, 0x101]);
+        assert!(emulator.recent_pcs().iter().all(|pc| pc.bank == Some(0)));
+    }
+
+    #[test]
+    fn illegal_opcode_locks_up_the_cpu_only() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x102].copy_from_slice(&[0x00, 0xD3]);
+        let mut emulator = Emulator::new();
+        emulator.load_catridge(rom).unwrap();
+        emulator.run_frames(1);
+        assert_eq!(emulator.break_reason().as_deref(), Some("locked up on opcode D3 at 0101"));
+        assert_eq!(emulator.lock_report().as_deref(), Some("locked up on opcode D3 at 0101\nrecent PCs, oldest first: 00:0100 00:0101"));
+
+        // interrupts don't get it out either
+        emulator.core.bus.IE = 0x01;
+        emulator.core.ime = true;
+        emulator.take_frame_ready();
+        let frames = (0..3).filter(|_| {
+            emulator.run_frames(1);
+            emulator.take_frame_ready()
+        }).count();
+        assert_eq!(frames, 3);
+        assert_eq!(emulator.core.pc, 0x102);
+        assert!(emulator.is_locked());
+        assert_eq!(emulator.break_reason(), None);
+    }
+
+    #[test]
+    fn call_stack_follows_calls_and_resyncs() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x103].copy_from_slice(&[0xC3, 0x50, 0x01]);
+        rom[0x150..0x153].copy_from_slice(&[0xCD, 0x00, 0x02]);
+        rom[0x0008] = 0xC9;
+        // RST 08 and back, then a call that leaves through a PUSH and RET jump
+        rom[0x200..0x20D].copy_from_slice(&[0xCF, 0xCD, 0x00, 0x03, 0x31, 0xFE, 0xFF, 0xCD, 0x00, 0x05, 0x18, 0xFE, 0x00]);
+        rom[0x300..0x305].copy_from_slice(&[0x21, 0x00, 0x04, 0xE5, 0xC9]);
+        rom[0x400] = 0xC9;
+        rom[0x500..0x502].copy_from_slice(&[0x18, 0xFE]);
+        let mut emulator = Emulator::new();
+        emulator.load_catridge(rom).unwrap();
+        emulator.track_calls(true);
+
+        let frames = |emulator: &Emulator| emulator.call_stack().iter().map(|frame| (frame.kind, frame.return_addr, frame.call_target)).collect::<Vec<_>>();
+        emulator.add_breakpoint(0x0008, None);
+        emulator.continue_until_break();
+        assert_eq!(frames(&emulator), [(CallKind::Call, 0x153, 0x200), (CallKind::Rst, 0x201, 0x0008)]);
+        emulator.add_breakpoint(0x400, None);
+        emulator.continue_until_break();
+        assert_eq!(frames(&emulator), [(CallKind::Call, 0x153, 0x200), (CallKind::Call, 0x204, 0x300)]);
+        // SP is set back to the top before the last call
+        emulator.add_breakpoint(0x500, None);
+        emulator.continue_until_break();
+        assert_eq!(frames(&emulator), [(CallKind::Call, 0x20A, 0x500)]);
+
+        emulator.track_calls(false);
+        assert_eq!(emulator.call_stack(), []);
+    }
+
+    #[test]
+    fn cpu_state_sets_registers_and_drops_what_was_in_flight() {
+        let mut rom = vec![0; 0x8000];
+        // LD BC, 1234 then a HALT with nothing enabled to wake it
+        rom[0x100..0x105].copy_from_slice(&[0x01, 0x34, 0x12, 0x76, 0x00]);
+        let mut emulator = Emulator::new();
+        emulator.load_catridge(rom).unwrap();
+        let start = emulator.cpu_state();
+        assert_eq!(start, CpuState { a: 0x01, f: 0x80, b: 0x00, c: 0x13, d: 0x00, e: 0xD8, h: 0x01, l: 0x4D, sp: 0xFFFE, pc: 0x100, ime: false, halted: false });
+        assert_eq!(emulator.registers(), [0x01, 0x80, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D, 0xFFFE, 0x100, 0, 0]);
+
+        // halfway through the LD, which is dropped
+        emulator.run_cycles(-1, 2);
+        emulator.set_cpu_state(&CpuState { a: 0x42, ..start }).unwrap();
+        emulator.step_instruction();
+        assert_eq!((emulator.cpu_state().b, emulator.cpu_state().c, emulator.cpu_state().a), (0x12, 0x34, 0x42));
+        emulator.step_instruction();
+        assert!(emulator.cpu_state().halted);
+        assert!(emulator.snapshot().cpu_state().halted);
+
+        assert_eq!(emulator.set_cpu_state(&CpuState { f: 0xB1, ..start }), Err(StateError::InvalidData("F has its low nibble set")));
+        // halted resumes in the HALT, PC stays put
+        let halted = CpuState { pc: 0x150, halted: true, ..start };
+        emulator.set_cpu_state(&halted).unwrap();
+        emulator.run_frames(1);
+        assert_eq!(emulator.cpu_state(), halted);
+        let bess = emulator.save_file();
+        emulator.load_save_file(bess).unwrap();
+        assert_eq!(emulator.cpu_state(), halted);
+    }
+
+    #[test]
+    fn starts_where_the_boot_rom_leaves_off() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x14D] = 0xE7;
+        let mut emulator = Emulator::new();
+        emulator.load_catridge(rom.clone()).unwrap();
+        assert_eq!(emulator.cpu_state().f, 0xB0);
+        let bus = &emulator.core.bus;
+        let io = [0xFF00, 0xFF02, 0xFF04, 0xFF07, 0xFF0F, 0xFF26, 0xFF40, 0xFF46, 0xFF47, 0xFFFF].map(|addr| bus.peek(addr));
+        assert_eq!(io, [0xCF, 0x7E, 0xAB, 0xF8, 0xE1, 0xF1, 0x91, 0xFF, 0xFC, 0x00]);
+        assert_eq!(bus.timer.state().div, 0xABCC);
+
+        // a checksum of 0 leaves carry and half carry clear
+        rom[0x14D] = 0x00;
+        emulator.load_catridge(rom).unwrap();
+        emulator.run_frames(10);
+        emulator.skip_bootrom();
+        assert_eq!((emulator.cpu_state().pc, emulator.cpu_state().f, emulator.core.bus.peek(0xFF04)), (0x100, 0x80, 0xAB));
+    }
+
+    #[test]
+    fn profile_finds_the_busy_loop() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x105].copy_from_slice(&[0xCD, 0x50, 0x01, 0x18, 0xFB]); // CALL 0x150 over and over
+        rom[0x150..0x154].copy_from_slice(&[0x3D, 0x20, 0xFD, 0xC9]); // DEC A until it's 0, RET
+        let mut emulator = Emulator::new();
+        emulator.load_catridge(rom).unwrap();
+        emulator.run_frames(1);
+        assert!(emulator.profile(10).is_empty());
+
+        emulator.start_profiling();
+        emulator.run_frames(2);
+        emulator.stop_profiling();
+        let profile = emulator.profile(3);
+        assert_eq!(profile.iter().map(|entry| entry.at).collect::<Vec<_>>(), [
+            BankedAddr { addr: 0x151, bank: Some(0) },
+            BankedAddr { addr: 0x150, bank: Some(0) },
+            BankedAddr { addr: 0x100, bank: Some(0) }
+        ]);
+        assert!(profile[0].cycles > profile[0].instructions * 2); // mostly taken, 3 cycles to 2
+        assert_eq!(profile[1].cycles, profile[1].instructions);
+        assert_eq!(emulator.profile(1), emulator.profile(3)[..1]);
+
+        emulator.run_frames(1);
+        assert_eq!(emulator.profile(3), profile);
+        emulator.reset_profile();
+        assert!(emulator.profile_report(10).is_empty());
+    }
+
+    #[test]
+    fn steps_back_to_the_exact_state() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x105].copy_from_slice(&[0xCD, 0x50, 0x01, 0x18, 0xFB]); // CALL 0x150 over and over
+        rom[0x150..0x155].copy_from_slice(&[0x3C, 0xE0, 0x80, 0x18, 0xFB]); // INC A, LDH (0x80), A and again
+        let mut emulator = Emulator::new();
+        emulator.load_catridge(rom).unwrap();
+        assert!(!emulator.step_back());
+
+        emulator.set_step_back(true);
+        emulator.add_breakpoint(0x153, None);
+        let mut states = vec![emulator.snapshot().to_bytes()];
+        for _ in 0..10 {
+            emulator.step_instruction();
+            states.push(emulator.snapshot().to_bytes());
+        }
+        for _ in 0..3 {
+            assert!(emulator.step_back());
+        }
+        assert_eq!(emulator.snapshot().to_bytes(), states[7]);
+        assert_eq!(emulator.recent_pcs().last(), Some(&BankedAddr { addr: 0x153, bank: Some(0) }));
+        emulator.step_instruction();
+        assert_eq!(emulator.snapshot().to_bytes(), states[8]);
+
+        // across checkpoints, and not past the first
+        for _ in 0..2500 {
+            emulator.step_instruction();
+        }
+        let before = emulator.snapshot().to_bytes();
+        emulator.step_instruction();
+        assert!(emulator.step_back());
+        assert_eq!(emulator.snapshot().to_bytes(), before);
+
+        // a button changes what runs from here on, the instruction that first sees it is as far back as it goes
+        emulator.press_button(Button::A);
+        emulator.step_instruction();
+        emulator.step_instruction();
+        assert!(emulator.step_back());
+        assert!(!emulator.step_back());
+    }
+
+    // FF00-FF7F of a DMG right after the boot ROM, with DIV where the boot ROM leaves it. the wave RAM, OBP0 and OBP1
+    // are random on hardware and zeroed here, and a DMG is at the end of a vblank (0x85) where the PPU starts a line
+    const POST_BOOT_IO: [u8; 0x80] = [
+        0xCF, 0x00, 0x7E, 0xFF, 0xAB, 0x00, 0x00, 0xF8, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xE1,
+        0x80, 0xBF, 0xF3, 0xFF, 0xBF, 0xFF, 0x3F, 0x00, 0xFF, 0xBF, 0x7F, 0xFF, 0x9F, 0xFF, 0xBF, 0xFF,
+        0xFF, 0x00, 0x00, 0xBF, 0x77, 0xF3, 0xF1, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x91, 0x84, 0x00, 0x00, 0x00, 0x00, 0xFF, 0xFC, 0x00, 0x00, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0xFF,
+        0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+        0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+        0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF
+    ];
+
+    #[test]
+    fn io_page_reads_like_a_fresh_dmg() {
+        let mut emulator = Emulator::new();
+        emulator.load_catridge(vec![0; 0x8000]).unwrap();
+        let bus = &mut emulator.core.bus;
+        for (addr, &expected) in (0xFF00..=0xFF7F).zip(POST_BOOT_IO.iter()) {
+            assert_eq!(bus.peek(addr), expected, "{:04X}", addr);
+        }
+
+        // the unused bits stay 1 whatever is written
+        let registers = [0xFF07, 0xFF0F, 0xFF41, 0xFF03, 0xFF50, 0xFF7F];
+        for addr in registers {
+            bus.write(addr, 0x00);
+        }
+        assert_eq!(registers.map(|addr| bus.peek(addr) & 0xF8), [0xF8, 0xE0, 0x80, 0xF8, 0xF8, 0xF8]);
+    }
+
+    #[test]
+    fn boot_rom_unmaps_for_good_until_a_reset() {
+        use crate::internal::core::registers::Register;
+        let mut boot_rom = vec![0x00; 0x100];
+        boot_rom[0x00..0x03].copy_from_slice(&[0xF0, 0x50, 0x47]); // LDH A, (0x50) and LD B, A
+        boot_rom[0xFC..0x100].copy_from_slice(&[0x3E, 0x01, 0xE0, 0x50]); // unmapped right before 0x100, like the real one
+        let mut rom = vec![0x00; 0x8000];
+        rom[0x00] = 0x42;
+        let program = [
+            0xF0, 0x50, 0x4F, // LD C with FF50
+            0xAF, 0xE0, 0x50, // trying to map it again
+            0xF0, 0x50, 0x57, 0x18, 0xFE // LD D with FF50 and spin
+        ];
+        rom[0x100..0x100 + program.len()].copy_from_slice(&program);
+        let mut emulator = Emulator::new();
+        assert_eq!(emulator.mount_bootrom(vec![0; 0x200]), Err("no boot ROM is 512 bytes".to_string()));
+        emulator.mount_bootrom(boot_rom).unwrap();
+        emulator.load_catridge(rom).unwrap();
+        assert_eq!((emulator.core.pc, emulator.core.bus.peek(0x0000), emulator.core.bus.peek(0x0100)), (0x0000, 0xF0, 0xF0));
+
+        emulator.run_frames(1);
+        let r = &emulator.core.registers;
+        assert_eq!((r[Register::B], r[Register::C], r[Register::D]), (0xFE, 0xFF, 0xFF));
+        assert_eq!(emulator.core.bus.peek(0x0000), 0x42);
+
+        emulator.reset();
+        assert_eq!((emulator.core.pc, emulator.core.bus.peek(0x0000), emulator.core.bus.peek(0xFF50)), (0x0000, 0xF0, 0xFE));
+        emulator.skip_bootrom();
+        assert_eq!((emulator.core.pc, emulator.core.bus.peek(0x0000), emulator.core.bus.peek(0xFF50)), (0x0100, 0x42, 0xFF));
+    }
+
+    #[test]
+    fn observer_sees_cpu_and_dma_accesses_apart() {
+        // reads and writes by the CPU, then by the DMA
+        struct Counter(Rc<RefCell<[u32; 4]>>);
+        impl BusObserver for Counter {
+            fn on_read(&mut self, _addr: u16, _val: u8, source: BusSource) {
+                self.0.borrow_mut()[source as usize * 2] += 1;
+            }
+            fn on_write(&mut self, _addr: u16, _val: u8, source: BusSource) {
+                self.0.borrow_mut()[source as usize * 2 + 1] += 1;
+            }
+        }
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x10A].copy_from_slice(&[
+            0x21, 0x00, 0xC0, // LD HL, 0xC000
+            0x77, // LD (HL), A
+            0x7E, // LD A, (HL)
+            0x3E, 0xC0, // LD A, 0xC0
+            0xE0, 0x46, // LDH (0x46), A
+            0x76 // HALT, fetched before the DMA takes the bus and never woken up
+        ]);
+        let mut emulator = Emulator::new();
+        emulator.load_catridge(rom).unwrap();
+        let counts = Rc::new(RefCell::new([0; 4]));
+        emulator.set_observer(Box::new(Counter(counts.clone())));
+
+        for _ in 0..5 {
+            emulator.step_instruction();
+        }
+        assert_eq!(*counts.borrow(), [10, 2, 0, 0]); // every byte of the instructions, 0xC000 once each way
+        assert_eq!(emulator.peek(0xC000), 0x01);
+
+        emulator.run_frames(1);
+        assert_eq!(counts.borrow()[2..], [160, 160]);
+        assert!(emulator.take_observer().is_some());
+        emulator.run_frames(1);
+        assert_eq!(counts.borrow()[2..], [160, 160]);
+    }
+
+    #[test]
+    fn power_on_ram_is_the_same_for_the_same_seed() {
+        let wram = |ram: Option<PowerOnRam>| {
+            let mut emulator = Emulator::new();
+            if let Some(ram) = ram {
+                emulator.set_power_on_ram(ram);
+            }
+            emulator.load_catridge(vec![0; 0x8000]).unwrap();
+            emulator.peek_range(0xC000, 0x2000)
+        };
+        let seeded = wram(Some(PowerOnRam::Random { seed: 1 }));
+        assert_eq!(seeded, wram(Some(PowerOnRam::Random { seed: 1 })));
+        assert_ne!(seeded, wram(Some(PowerOnRam::Random { seed: 2 })));
+        assert!(seeded.iter().any(|&byte| byte != 0));
+        assert!(wram(None).iter().all(|&byte| byte == 0));
+        assert_eq!(wram(Some(PowerOnRam::Pattern55AA))[..4], [0x55, 0xAA, 0x55, 0xAA]);
+
+        // a state made with the seed resets into the same RAM in an emulator that was never given it
+        let mut emulator = Emulator::new();
+        emulator.set_power_on_ram(PowerOnRam::Random { seed: 1 });
+        emulator.load_catridge(vec![0; 0x8000]).unwrap();
+        let state = emulator.save_state();
+        let mut other = Emulator::new();
+        other.load_catridge(vec![0; 0x8000]).unwrap();
+        other.load_state(&state).unwrap();
+        other.poke(0xC000, !seeded[0]);
+        other.reset();
+        assert_eq!(other.peek_range(0xC000, 0x2000), seeded);
+    }
+
+    #[test]
+    fn save_file_keeps_if_and_ie() {
+        // nothing enabled, so the requests stay pending through saving
+        let mut emulator = Emulator::new();
+        emulator.load_catridge(vec![0; 0x8000]).unwrap();
+        emulator.poke(0xFF0F, 0x15);
+        emulator.poke(0xFFFF, 0xE0);
+        let bess = emulator.save_file();
+        let mut other = Emulator::new();
+        other.load_catridge(vec![0; 0x8000]).unwrap();
+        other.load_save_file(bess).unwrap();
+        assert_eq!((other.peek(0xFF0F), other.peek(0xFFFF)), (0xF5, 0xE0));
+    }
+
+    #[test]
+    fn builder_checks_what_load_catridge_would_choke_on() {
+        // the README's example
+        let mut emulator = Emulator::builder()
+            .cartridge(fs::read("./tests/blargg/roms/2.gb").expect("File not found!"))
+            .model(Model::Dmg)
+            .build()
+            .unwrap();
+        emulator.run_frames(149);
+        assert_eq!(emulator.run_frame(), 0x66812A5916480810); // where run_frames_hash_known_screens has it
+
+        // one place for the emulators that aren't built, each would get its own room on the stack otherwise
+        let error = |builder: EmulatorBuilder| builder.build().err();
+        let rom = vec![0; 0x8000];
+        assert_eq!(error(Emulator::builder()), Some(BuildError::NoCartridge));
+        assert_eq!(error(Emulator::builder().cartridge(vec![0; 0x150])), Some(BuildError::CartridgeTooShort(0x150)));
+        let mut mbc2 = rom.clone();
+        mbc2[MBC_TYPE] = 0x05;
+        assert_eq!(error(Emulator::builder().cartridge(mbc2)), Some(BuildError::UnsupportedMapper(0x05)));
+        let builder = || Emulator::builder().cartridge(rom.clone());
+        assert_eq!(error(builder().boot_rom(vec![0; 0x200])), Some(BuildError::BootRomSize(0x200)));
+        assert_eq!(
+            error(builder().boot_rom(vec![0; 0x100]).model(Model::Cgb)),
+            Some(BuildError::ModelMismatch { boot_rom: Model::Dmg, model: Model::Cgb })
+        );
+
+        // the boot ROM decides the model when none is asked for, and runs first
+        let emulator = builder().boot_rom(vec![0; 0x900]).build().unwrap();
+        assert_eq!((emulator.model, emulator.cpu_state().pc), (Model::Cgb, 0x0000));
+    }
+
+    #[test]
+    fn frontend_settings_outlast_reset_and_new_cartridges() {
+        let mut emulator = running_emulator();
+        emulator.set_ppu_mode(PpuMode::Scanline);
+        emulator.set_oam_bug(true);
+        emulator.set_frame_blend(FrameBlend::Blend2);
+        emulator.set_palette(&[0xFF, 0xFF, 0xFF, 0xAA, 0xAA, 0xAA, 0x55, 0x55, 0x55, 0x00, 0x00, 0x00]).unwrap();
+        emulator.reset();
+        assert_eq!((emulator.ppu_mode(), emulator.core.bus.oam_bug, emulator.frame_blend()), (PpuMode::Scanline, true, FrameBlend::Blend2));
+        emulator.set_frame_blend(FrameBlend::Off); // so the palette can be checked on a single frame
+        emulator.run_frames(30);
+        let ptr = emulator.render_rgba(-1);
+        let pixels = unsafe { std::slice::from_raw_parts(ptr, emulator.rgba_len()) }.to_vec();
+        for (shade, pixel) in emulator.display().iter().zip(pixels.chunks_exact(4)) {
+            assert_eq!(pixel, [0xFF - shade * 0x55, 0xFF - shade * 0x55, 0xFF - shade * 0x55, 0xFF]);
+        }
+        emulator.set_frame_blend(FrameBlend::Blend2);
+        emulator.load_catridge(vec![0; 0x8000]).unwrap();
+        assert_eq!((emulator.ppu_mode(), emulator.core.bus.oam_bug, emulator.frame_blend()), (PpuMode::Scanline, true, FrameBlend::Blend2));
+    }
+
+    #[test]
+    fn saving_twice_gives_the_same_file() {
+        let mut emulator = running_emulator();
+        emulator.run_frames(3);
+        emulator.run_cycles(-1, 3); // most likely partway into an instruction
+        let first = emulator.save_file();
+        assert_eq!(emulator.save_file(), first);
+    }
+
+    #[test]
+    fn save_file_in_a_stop_with_no_button_held() {
+        let mut rom = vec![0x00; 0x8000];
+        rom[0x100..0x105].copy_from_slice(&[0x10, 0x00, 0x04, 0x18, 0xFE]); // STOP, INC B and spin
+        let mut emulator = Emulator::new();
+        emulator.load_catridge(rom.clone()).unwrap();
+        emulator.run_frames(2);
+        let bess = emulator.save_file();
+        assert_eq!(emulator.save_file(), bess);
+
+        // still stopped after loading, the timer stands still until a button goes down
+        let mut other = Emulator::new();
+        other.load_catridge(rom).unwrap();
+        other.load_save_file(bess).unwrap();
+        let div = other.peek(0xFF04);
+        other.run_frames(2);
+        assert_eq!((other.peek(0xFF04), other.cpu_state().b), (div, 0x00));
+        other.press_button(Button::A);
+        other.run_frames(1);
+        assert_eq!(other.cpu_state().b, 0x01);
+    }
+
+    #[test]
+    fn loading_a_save_file_only_sets_the_registers() {
+        // SC with a transfer going, LCDC on and a DMA source, none of which may set anything off
+        let mut emulator = Emulator::new();
+        emulator.load_catridge(vec![0; 0x8000]).unwrap();
+        emulator.poke(0xFF01, 0x55);
+        emulator.poke(0xFF02, 0x81);
+        emulator.poke(0xFF46, 0xC0);
+        let bess = emulator.save_file();
+
+        let mut other = Emulator::new();
+        other.load_catridge(vec![0; 0x8000]).unwrap();
+        other.add_watchpoint(0xFF00..=0xFFFF, WatchKind::ReadWrite);
+        other.load_save_file(bess).unwrap();
+        assert_eq!(other.take_serial_output(), "");
+        assert_eq!(other.core.bus.take_watch_hit(), None);
+        assert_eq!(other.peek_range(0xFF01, 2), [0x55, 0xFF]);
+        assert_eq!((other.peek(0xFF46), other.peek(0xFE00)), (0xC0, emulator.peek(0xFE00)));
+    }
+
+    #[test]
+    fn a_cartridge_cut_short_is_turned_away() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100] = 0x04;
+        let mut emulator = Emulator::new();
+        emulator.load_catridge(rom.clone()).unwrap();
+        emulator.run_frame();
+        let pc = emulator.core.pc;
+
+        assert!(emulator.load_catridge(vec![0; 0x100]).is_err());
+        let mut unsupported = vec![0; 0x8000];
+        unsupported[0x147] = 0xFF;
+        assert!(emulator.load_catridge(unsupported).is_err());
+        assert_eq!(emulator.core.bus.rom()[0x100], 0x04);
+        assert_eq!(emulator.core.pc, pc);
+    }
+
+    #[test]
+    fn stop_with_a_button_held_skips() {
+        use crate::internal::core::registers::Register;
+        let mut rom = vec![0x00; 0x8000];
+        let program = [
+            0x3E, 0x10, 0xE0, 0x00, // select the action buttons
+            0x3E, 0x10, 0xE0, 0xFF, 0xE0, 0x0F, // the joypad interrupt enabled and pending, IME stays off
+            0x10, // STOP, a 1 byte NOP with something pending
+            0x06, 0x42, // LD B, 0x42
+            0xAF, 0xE0, 0x0F,
+            0x10, 0x04, // STOP halts with nothing pending, the INC B after it is skipped
+            0x16, 0x42, 0x18, 0xFE // LD D, 0x42 and spin
+        ];
+        rom[0x100..0x100 + program.len()].copy_from_slice(&program);
+        let mut emulator = Emulator::new();
+        emulator.load_catridge(rom).unwrap();
+        emulator.press_button(Button::A);
+
+        emulator.run_frames(5);
+        assert_eq!((emulator.core.registers[Register::B], emulator.core.registers[Register::D]), (0x42, 0x00));
+
+        // A going down again requests the interrupt that ends the halt
+        emulator.release_button(Button::A);
+        emulator.press_button(Button::A);
+        emulator.run_frames(1);
+        assert_eq!((emulator.core.registers[Register::B], emulator.core.registers[Register::D]), (0x42, 0x42));
+    }
+
+    #[test]
+    fn stop_waits_for_a_selected_press() {
+        use crate::internal::core::registers::Register;
+        let mut rom = vec![0x00; 0x8000];
+        let program = [
+            0x3E, 0x10, 0xE0, 0x00, // select the action buttons
+            0xAF, 0xE0, 0xFF, 0xE0, 0x0F, // nothing enabled or pending
+            0x10, 0x04, // STOP, the INC B after it is skipped
+            0x06, 0x42, 0x18, 0xFE // LD B, 0x42 and spin
+        ];
+        rom[0x100..0x100 + program.len()].copy_from_slice(&program);
+        let mut emulator = Emulator::new();
+        emulator.load_catridge(rom).unwrap();
+
+        emulator.run_frames(5);
+        assert_eq!(emulator.core.registers[Register::B], 0x00);
+        // DIV was reset and stands still
+        assert_eq!(emulator.core.bus.read(0xFF04), 0x00);
+        emulator.press_button(Button::Up);
+        emulator.run_frames(1);
+        assert_eq!(emulator.core.registers[Register::B], 0x00);
+        assert_eq!(emulator.core.bus.read(0xFF04), 0x00);
+
+        emulator.press_button(Button::A);
+        emulator.run_frames(1);
+        assert_eq!(emulator.core.registers[Register::B], 0x42);
+        assert_ne!(emulator.core.bus.read(0xFF04), 0x00);
+    }
+
+    #[test]
+    fn movie_replays_identical_frames() {
+        let mut emulator = running_emulator();
+        emulator.run_frames(30);
+        emulator.start_recording();
+
+        // 30 seconds of buttons going down and up all over the place
+        let mut seed: u32 = 0x1234_5678;
+        let mut expected = vec![];
+        for _ in 0..30 * 60 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            if seed >> 31 == 0 {
+                emulator.set_buttons((seed >> 16) as u8);
+            }
+            expected.push(hash_display(&emulator.render(-1)));
+        }
+        let movie = emulator.save_movie();
+        emulator.stop_recording();
+
+        emulator.set_buttons(0x00);
+        emulator.load_movie(&movie).unwrap();
+        for (frame, expected) in expected.iter().enumerate() {
+            // live input doesn't reach the game while the movie plays
+            emulator.press_button(Button::Start);
+            assert_eq!(hash_display(&emulator.render(5)), *expected, "frame {}", frame);
+        }
+        assert!(emulator.movie_playing());
+        emulator.render(-1);
+        assert!(!emulator.movie_playing());
+        assert_eq!(emulator.buttons(), 1 << Button::Start as u8);
+    }
+
+    #[test]
+    fn movie_refuses_other_games_and_garbage() {
+        let mut emulator = running_emulator();
+        emulator.start_recording();
+        emulator.render(-1);
+        let movie = emulator.save_movie();
+
+        let mut other = Emulator::new();
+        let mut rom = fs::read("./tests/blargg/roms/2.gb").unwrap();
+        rom[0x134] ^= 0xFF;
+        other.load_catridge(rom).unwrap();
+        assert_eq!(other.load_movie(&movie).err(), Some(MovieError::WrongCartridge));
+
+        assert_eq!(emulator.load_movie(b"GBSN").err(), Some(MovieError::InvalidMagic));
+        assert_eq!(emulator.load_movie(&movie[..movie.len() - 1]).err(), Some(MovieError::Truncated));
+        let mut newer = movie.clone();
+        newer[4] = 0xFF;
+        assert_eq!(emulator.load_movie(&newer).err(), Some(MovieError::NewerVersion(0xFF)));
+    }
+
+    #[test]
+    fn swapping_cartridges_stops_movies() {
+        let mut emulator = running_emulator();
+        emulator.set_autosave_interval(1);
+        emulator.start_recording();
+        emulator.run_frames(5);
+        let movie = emulator.save_movie();
+        emulator.load_movie(&movie).unwrap();
+        emulator.start_recording();
+
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x103].copy_from_slice(&[0x3C, 0x18, 0xFD]); // INC A and back
+        emulator.load_catridge(rom).unwrap();
+        assert!(!emulator.movie_playing());
+        assert!(emulator.save_movie().is_empty());
+
+        // nothing from the other game's movie is played into this one's autosave either
+        emulator.press_button(Button::Start);
+        emulator.render(-1);
+        assert_eq!(emulator.recover().unwrap().to_bytes(), emulator.save_state());
+        assert_eq!(emulator.buttons(), 1 << Button::Start as u8);
+    }
+
+    #[test]
+    fn movie_recorded_with_a_boot_rom_needs_one() {
+        let rom = fs::read("./tests/blargg/roms/2.gb").unwrap();
+        let mut emulator = Emulator::new();
+        emulator.mount_bootrom(vec![0x00; 0x100]).unwrap();
+        emulator.load_catridge(rom.clone()).unwrap();
+        emulator.start_recording();
+        let expected: Vec<u64> = (0..10).map(|_| hash_display(&emulator.render(-1))).collect();
+        let movie = emulator.save_movie();
+        emulator.stop_recording();
+
+        let mut without = Emulator::new();
+        without.load_catridge(rom).unwrap();
+        assert_eq!(without.load_movie(&movie).err(), Some(MovieError::NeedsBootRom));
+
+        // the recording started with the boot ROM still mapped
+        emulator.load_movie(&movie).unwrap();
+        assert_eq!(emulator.core.bus.read(0xFF50), 0xFE);
+        assert_eq!((0..10).map(|_| hash_display(&emulator.render(-1))).collect::<Vec<_>>(), expected);
+    }
+}
@@ -0,0 +1,223 @@
+// runners for test roms that report through the blargg signature in cartridge RAM, the serial port or mooneye's
+// registers, shared by the suites below
+use std::fs;
+use std::path::PathBuf;
+use crate::Emulator;
+use crate::internal::core::registers::Register;
+
+const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61]; // at 0xA001 once 0xA000 holds a meaningful status
+const RUNNING: u8 = 0x80;
+const CHECK_EVERY: u32 = 10; // frames
+const MOONEYE_PASSED: [u8; 6] = [3, 5, 8, 13, 21, 34]; // B, C, D, E, H and L once a mooneye rom hit its LD B, B
+const MOONEYE_FAILED: u8 = 0x42; // in all six
+
+#[derive(PartialEq, Debug)]
+pub enum RomResult {
+    Passed,
+    Failed(u8, String), // the result code and whatever the rom printed
+    TimedOut(String) // whatever was printed over the serial port by then
+}
+
+// runs until the status at 0xA000 leaves RUNNING or max_frames go by
+pub fn run_blargg_rom(rom: Vec<u8>, max_frames: u32) -> RomResult {
+    let mut emulator = Emulator::new();
+    emulator.load_catridge(rom).unwrap();
+    let mut frames = 0;
+    while frames < max_frames {
+        emulator.run_frames(CHECK_EVERY);
+        frames += CHECK_EVERY;
+
+        let sram = &emulator.core.bus.sram;
+        if sram[1..4] != SIGNATURE || sram[0] == RUNNING {
+            continue;
+        }
+        let text: Vec<u8> = sram[4..].iter().take_while(|&&byte| byte != 0).copied().collect();
+        return match sram[0] {
+            0x00 => RomResult::Passed,
+            code => RomResult::Failed(code, String::from_utf8_lossy(&text).trim().to_string())
+        };
+    }
+    RomResult::TimedOut(emulator.take_serial_output().trim().to_string())
+}
+
+// for blargg's roms that only print, runs until the text sent over the serial port says how it went
+pub fn run_blargg_serial_rom(rom: Vec<u8>, max_frames: u32) -> RomResult {
+    let mut emulator = Emulator::new();
+    emulator.load_catridge(rom).unwrap();
+    let mut output = String::new();
+    let mut frames = 0;
+    while frames < max_frames {
+        emulator.run_frames(CHECK_EVERY);
+        frames += CHECK_EVERY;
+
+        output += &emulator.take_serial_output();
+        if output.contains("Passed") {
+            return RomResult::Passed;
+        }
+        if output.contains("Failed") {
+            return RomResult::Failed(0x01, output.trim().to_string());
+        }
+    }
+    RomResult::TimedOut(output.trim().to_string())
+}
+
+// runs until the registers hold either of mooneye's results, the rom spins on its LD B, B breakpoint after
+pub fn run_mooneye_rom(rom: Vec<u8>, max_frames: u32) -> RomResult {
+    let mut emulator = Emulator::new();
+    emulator.load_catridge(rom).unwrap();
+    let mut frames = 0;
+    while frames < max_frames {
+        emulator.run_frames(CHECK_EVERY);
+        frames += CHECK_EVERY;
+
+        let registers = [Register::B, Register::C, Register::D, Register::E, Register::H, Register::L].map(|register| emulator.core.registers[register]);
+        if registers == MOONEYE_PASSED {
+            return RomResult::Passed;
+        }
+        if registers == [MOONEYE_FAILED; 6] {
+            return RomResult::Failed(MOONEYE_FAILED, String::new());
+        }
+    }
+    RomResult::TimedOut(String::new())
+}
+
+// every .gb in dir, by name
+pub fn roms_in(dir: &str) -> Option<Vec<PathBuf>> {
+    let entries = fs::read_dir(dir).ok()?;
+    let mut roms: Vec<_> = entries.map(|entry| entry.unwrap().path()).filter(|path| path.extension().is_some_and(|ext| ext == "gb")).collect();
+    roms.sort();
+    Some(roms)
+}
+
+// lists every rom on stderr and only fails for roms that aren't expected to, so a regression stands out. an
+// expected failure that starts passing is pointed out so it can come off the list
+pub fn run_suite(suite: &str, roms: &[PathBuf], run: fn(Vec<u8>, u32) -> RomResult, max_frames: u32, expected_failures: &[&str]) {
+    let mut regressions = vec![];
+    for rom in roms {
+        let name = rom.file_stem().unwrap().to_str().unwrap();
+        let expected_to_fail = expected_failures.contains(&name);
+        match (run(fs::read(rom).unwrap(), max_frames), expected_to_fail) {
+            (RomResult::Passed, false) => eprintln!("pass {}", name),
+            (RomResult::Passed, true) => eprintln!("pass {} (expected to fail, take it off the list)", name),
+            (result, true) => eprintln!("xfail {} ({:?})", name, result),
+            (result, false) => {
+                eprintln!("FAIL {} ({:?})", name, result);
+                regressions.push(name.to_string());
+            }
+        }
+    }
+    assert!(regressions.is_empty(), "{} of {} {} roms failed: {:?}", regressions.len(), roms.len(), suite, regressions);
+}
+
+// a suite is one line below: the test, the directory of its roms, how they report, how long they get and the roms
+// that are known to fail. every directory has a README.md saying where the roms come from, run them with
+// cargo test -- --ignored
+macro_rules! rom_suite {
+    ($test:ident, $dir:expr, $run:expr, $max_frames:expr, $expected_failures:expr) => {
+        #[test]
+        #[ignore]
+        fn $test() {
+            let Some(roms) = roms_in($dir) else {
+                eprintln!("no roms in {}, skipping", $dir);
+                return
+            };
+            run_suite(stringify!($test), &roms, $run, $max_frames, &$expected_failures);
+        }
+    };
+}
+
+const CPU_INSTRS_FRAMES: u32 = 60 * 60;
+const INSTR_TIMING_FRAMES: u32 = 60 * 10;
+const MEM_TIMING_FRAMES: u32 = 60 * 10;
+const HALT_BUG_FRAMES: u32 = 60 * 10;
+const DMG_SOUND_FRAMES: u32 = 60 * 60;
+const MOONEYE_FRAMES: u32 = 60 * 10;
+
+// 07-len sweep period sync isn't emulated yet, and 09, 10 and 12 time wave RAM accesses down to the T-cycle, which
+// the APU stepped once per M-cycle only gets roughly right
+const DMG_SOUND_EXPECTED_FAILURES: [&str; 4] = ["07-len sweep period sync", "09-wave read while on", "10-wave trigger while on", "12-wave write while on"];
+const NONE_EXPECTED: [&str; 0] = [];
+
+rom_suite!(blargg_cpu_instrs, "./tests/blargg/cpu_instrs/roms", run_blargg_serial_rom, CPU_INSTRS_FRAMES, NONE_EXPECTED);
+rom_suite!(blargg_instr_timing, "./tests/blargg/instr_timing/roms", run_blargg_serial_rom, INSTR_TIMING_FRAMES, NONE_EXPECTED);
+rom_suite!(blargg_mem_timing, "./tests/blargg/mem_timing/roms", run_blargg_serial_rom, MEM_TIMING_FRAMES, NONE_EXPECTED);
+rom_suite!(blargg_halt_bug, "./tests/blargg/halt_bug/roms", run_blargg_serial_rom, HALT_BUG_FRAMES, NONE_EXPECTED);
+rom_suite!(blargg_dmg_sound, "./tests/blargg/dmg_sound/roms", run_blargg_rom, DMG_SOUND_FRAMES, DMG_SOUND_EXPECTED_FAILURES);
+rom_suite!(mooneye_timer, "./tests/mooneye/acceptance/timer", run_mooneye_rom, MOONEYE_FRAMES, NONE_EXPECTED);
+rom_suite!(mooneye_interrupts, "./tests/mooneye/acceptance/interrupts", run_mooneye_rom, MOONEYE_FRAMES, NONE_EXPECTED);
+rom_suite!(mooneye_serial, "./tests/mooneye/acceptance/serial", run_mooneye_rom, MOONEYE_FRAMES, NONE_EXPECTED);
+
+// the roms among the rest of acceptance that pin down when EI and DI take effect, when an interrupt is taken and which
+const MOONEYE_INTERRUPT_TIMING_ROMS: [&str; 5] = ["ei_timing", "ei_sequence", "di_timing-GS", "intr_timing", "if_ie_registers"];
+
+#[test]
+#[ignore]
+fn mooneye_interrupt_timing() {
+    let Some(roms) = roms_in("./tests/mooneye/acceptance") else {
+        eprintln!("no mooneye roms in tests/mooneye/acceptance, skipping");
+        return
+    };
+    let roms: Vec<_> = roms.into_iter().filter(|rom| MOONEYE_INTERRUPT_TIMING_ROMS.contains(&rom.file_stem().unwrap().to_str().unwrap())).collect();
+    run_suite("mooneye_interrupt_timing", &roms, run_mooneye_rom, MOONEYE_FRAMES, &NONE_EXPECTED);
+}
+
+// an MBC1 cartridge with RAM that reports `code` and `text` the way blargg's roms do, then spins
+fn reporting_rom(code: u8, text: &str) -> Vec<u8> {
+    let mut rom = vec![0x00; 0x8000];
+    rom[0x147] = 0x03;
+    rom[0x149] = 0x02;
+    let mut program = vec![0x3E, 0x0A, 0xEA, 0x00, 0x00, 0x21, 0x00, 0xA0]; // enable RAM, HL = 0xA000
+    for byte in [RUNNING].iter().chain(&SIGNATURE).chain(text.as_bytes()).chain(&[0x00]) {
+        program.extend_from_slice(&[0x3E, *byte, 0x22]); // LD A, byte; LD (HL+), A
+    }
+    program.extend_from_slice(&[0x3E, code, 0xEA, 0x00, 0xA0, 0x18, 0xFE]); // status last, then JR to itself
+    rom[0x100..0x100 + program.len()].copy_from_slice(&program);
+    rom
+}
+
+// a plain cartridge that sends `text` over the serial port a byte at a time, then spins
+fn printing_rom(text: &str) -> Vec<u8> {
+    let mut rom = vec![0x00; 0x8000];
+    rom[0x100..0x103].copy_from_slice(&[0xC3, 0x50, 0x01]); // JP past the header, the text doesn't fit before it
+    let mut program = vec![];
+    for byte in text.bytes() {
+        program.extend_from_slice(&[0x3E, byte, 0xE0, 0x01, 0x3E, 0x81, 0xE0, 0x02]); // SB = byte, start on the internal clock
+        program.extend_from_slice(&[0xF0, 0x02, 0x87, 0x38, 0xFB]); // until SC's start bit clears
+    }
+    program.extend_from_slice(&[0x18, 0xFE]);
+    rom[0x150..0x150 + program.len()].copy_from_slice(&program);
+    rom
+}
+
+// a plain cartridge that loads B-L with `registers`, then spins on LD B, B like mooneye's roms do
+fn registers_rom(registers: [u8; 6]) -> Vec<u8> {
+    let mut rom = vec![0x00; 0x8000];
+    let mut program = vec![];
+    for (opcode, val) in [0x06, 0x0E, 0x16, 0x1E, 0x26, 0x2E].into_iter().zip(registers) {
+        program.extend_from_slice(&[opcode, val]); // LD r, val
+    }
+    program.extend_from_slice(&[0x40, 0x18, 0xFD]); // LD B, B; JR back to it
+    rom[0x100..0x100 + program.len()].copy_from_slice(&program);
+    rom
+}
+
+#[test]
+fn runner_reads_the_result_below_the_signature() {
+    assert_eq!(run_blargg_rom(reporting_rom(0x00, "Passed\n"), 30), RomResult::Passed);
+    assert_eq!(run_blargg_rom(reporting_rom(0x02, "Failed #2\n"), 30), RomResult::Failed(0x02, "Failed #2".to_string()));
+    assert_eq!(run_blargg_rom(reporting_rom(RUNNING, ""), 30), RomResult::TimedOut(String::new()));
+}
+
+#[test]
+fn serial_runner_reads_what_was_printed() {
+    assert_eq!(run_blargg_serial_rom(printing_rom("halt bug\n\nPassed\n"), 30), RomResult::Passed);
+    assert_eq!(run_blargg_serial_rom(printing_rom("halt bug\n\nFailed\n"), 30), RomResult::Failed(0x01, "halt bug\n\nFailed".to_string()));
+    assert_eq!(run_blargg_serial_rom(printing_rom("halt bug\n"), 30), RomResult::TimedOut("halt bug".to_string()));
+}
+
+#[test]
+fn mooneye_runner_reads_the_registers() {
+    assert_eq!(run_mooneye_rom(registers_rom(MOONEYE_PASSED), 30), RomResult::Passed);
+    assert_eq!(run_mooneye_rom(registers_rom([MOONEYE_FAILED; 6]), 30), RomResult::Failed(MOONEYE_FAILED, String::new()));
+    assert_eq!(run_mooneye_rom(registers_rom([0; 6]), 30), RomResult::TimedOut(String::new()));
+}
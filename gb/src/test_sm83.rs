@@ -0,0 +1,129 @@
+// runs the SingleStepTests sm83 suite (https://github.com/SingleStepTests/sm83, jsmoo's tests before they moved) on
+// flat RAM. SM83_TESTS is the directory of its v1 JSON files and SM83_OPCODES picks some of them by name, like
+// SM83_TESTS=../sm83/v1 SM83_OPCODES="27,cb 11" cargo test single_step_tests
+use std::env;
+use std::fs;
+use serde::Deserialize;
+use crate::internal::core::component::CPU;
+use crate::internal::core::registers::Register;
+
+const REGISTERS: [Register; 8] = [Register::A, Register::B, Register::C, Register::D, Register::E, Register::F, Register::H, Register::L];
+const FLAT_RAM_SKIPS: [&str; 2] = ["10", "76"]; // STOP and HALT, which don't stop anything on flat RAM
+
+#[derive(Deserialize)]
+struct Case {
+    name: String,
+    initial: State,
+    r#final: State,
+    cycles: Vec<Option<(u16, Option<u8>, String)>>
+}
+
+#[derive(Deserialize)]
+struct State {
+    a: u8, b: u8, c: u8, d: u8, e: u8, f: u8, h: u8, l: u8,
+    pc: u16,
+    sp: u16,
+    ime: u8,
+    ie: Option<u8>,
+    ei: Option<u8>, // only in the final state of EI, set while IME waits for the next instruction
+    ram: Vec<(u16, u8)>
+}
+
+impl State {
+    fn registers(&self) -> [u8; 8] {
+        [self.a, self.b, self.c, self.d, self.e, self.f, self.h, self.l]
+    }
+}
+
+// the writes the case expects in each M-cycle. reads aren't compared, the CPU reads the bus as often as a micro-op
+// likes and only what it writes ends up anywhere
+fn expected_writes(case: &Case) -> Vec<Vec<(u16, u8)>> {
+    case.cycles.iter().map(|cycle| match cycle {
+        Some((addr, Some(val), activity)) if activity.contains('w') => vec![(*addr, *val)],
+        _ => vec![]
+    }).collect()
+}
+
+// every way the CPU ended up differently from the case, empty if it passed
+fn run_case(case: &Case, opcode: &str) -> Vec<String> {
+    let mut cpu = CPU::flat();
+    for (register, val) in REGISTERS.into_iter().zip(case.initial.registers()) {
+        cpu.registers[register] = val;
+    }
+    cpu.pc = case.initial.pc;
+    cpu.sp = case.initial.sp;
+    cpu.ime = case.initial.ime != 0;
+    cpu.bus.IE = case.initial.ie.unwrap_or(0);
+    for &(addr, val) in &case.initial.ram {
+        cpu.bus.write(addr, val);
+    }
+    cpu.bus.take_flat_writes();
+
+    let writes = cpu.step_instruction();
+    let mut mismatches = vec![];
+    let registers = REGISTERS.map(|register| cpu.registers[register]);
+    if registers != case.r#final.registers() {
+        mismatches.push(format!("registers {:02X?}, expected {:02X?}", registers, case.r#final.registers()));
+    }
+    if (cpu.pc, cpu.sp) != (case.r#final.pc, case.r#final.sp) {
+        mismatches.push(format!("PC {:04X} SP {:04X}, expected {:04X} {:04X}", cpu.pc, cpu.sp, case.r#final.pc, case.r#final.sp));
+    }
+    if cpu.ime != (case.r#final.ime != 0) {
+        mismatches.push(format!("IME {}, expected {}", cpu.ime, case.r#final.ime));
+    }
+    if let Some(ei) = case.r#final.ei {
+        if cpu.ime_pending() != (ei != 0) {
+            mismatches.push(format!("IME pending {}, expected {}", cpu.ime_pending(), ei));
+        }
+    }
+    for &(addr, val) in &case.r#final.ram {
+        if cpu.bus.read(addr) != val {
+            mismatches.push(format!("{:04X} holds {:02X}, expected {:02X}", addr, cpu.bus.read(addr), val));
+        }
+    }
+    if !FLAT_RAM_SKIPS.contains(&opcode) && writes != expected_writes(case) {
+        mismatches.push(format!("writes by M-cycle {:X?}, expected {:X?}", writes, expected_writes(case)));
+    }
+    mismatches
+}
+
+#[test]
+fn single_step_tests() {
+    let Ok(dir) = env::var("SM83_TESTS") else {
+        eprintln!("SM83_TESTS isn't set, skipping");
+        return
+    };
+    let filter = env::var("SM83_OPCODES").ok();
+    let opcodes: Option<Vec<String>> = filter.map(|filter| filter.split(',').map(|opcode| opcode.trim().to_lowercase()).collect());
+
+    let mut files: Vec<_> = fs::read_dir(&dir).expect("SM83_TESTS isn't a directory").map(|entry| entry.unwrap().path()).filter(|path| path.extension().is_some_and(|ext| ext == "json")).collect();
+    files.sort();
+
+    let mut failed = vec![];
+    let mut ran = 0;
+    for file in files {
+        let opcode = file.file_stem().unwrap().to_str().unwrap().to_string();
+        if opcodes.as_ref().is_some_and(|opcodes| !opcodes.contains(&opcode)) {
+            continue;
+        }
+        let cases: Vec<Case> = serde_json::from_str(&fs::read_to_string(&file).unwrap()).expect("JSON was not well-formatted");
+        let mut first_failure = None;
+        let mut failures = 0;
+        for case in &cases {
+            let mismatches = run_case(case, &opcode);
+            if !mismatches.is_empty() {
+                failures += 1;
+                first_failure.get_or_insert_with(|| format!("{}: {}", case.name, mismatches.join(", ")));
+            }
+        }
+        ran += cases.len();
+        match first_failure {
+            None => eprintln!("pass {}", opcode),
+            Some(failure) => {
+                eprintln!("FAIL {} ({} of {} cases, first {})", opcode, failures, cases.len(), failure);
+                failed.push(opcode);
+            }
+        }
+    }
+    assert!(failed.is_empty(), "{} opcodes failed out of {} cases: {:?}", failed.len(), ran, failed);
+}